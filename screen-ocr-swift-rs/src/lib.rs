@@ -5,11 +5,54 @@ use std::io::prelude::*;
 use std::io::BufWriter;
 
 swift!(fn perform_ocr_swift(path: &SRString) -> Option<SRString>);
+swift!(fn perform_ocr_data_swift(image: SRData) -> Option<SRString>);
+swift!(fn perform_ocr_layout_data_swift(image: SRData) -> Option<SRString>);
 swift!(fn screen_capture_swift() -> Option<SRData>);    
 swift!(fn write_images_in_dir_to_mp4_swift(directory_path: &SRString, target_filename: &SRString, use_bitrate_key: Bool) -> ());
+swift!(fn write_images_to_mp4_swift(image_paths: &SRString, target_filename: &SRString, use_bitrate_key: Bool, keyframe_interval: Int) -> ());
 swift!(fn extract_frame_from_mp4_swift(mp4_path: &SRString, frame_id: Int) -> Option<SRData>);    
+swift!(fn extract_frames_from_mp4_swift(mp4_path: &SRString, frame_ids: &SRString) -> Option<SRData>);
+swift!(fn get_mp4_frame_count_swift(mp4_path: &SRString) -> Int);
 swift!(fn get_frontmost_app_swift() -> SRString);
 swift!(fn resize_image_swift(image: SRData, scale: Float) ->  Option<SRData>);
+swift!(fn get_displays_swift() -> SRString);
+swift!(fn screen_capture_display_swift(display_id: Int) -> Option<SRData>);
+swift!(fn get_frontmost_window_bounds_swift() -> SRString);
+
+
+/**
+ * A connected display and its geometry in global display coordinates (points)
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DisplayInfo {
+    pub display_id: u32,
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+    pub is_main: bool,
+}
+
+impl DisplayInfo {
+
+    /**
+     * Parse a line of the form "id,x,y,width,height,is_main" as returned by get_displays_swift
+     */
+    fn parse(line: &str) -> Option<DisplayInfo> {
+        let fields: Vec<&str> = line.trim().split(',').collect();
+        if fields.len() != 6 {
+            return None;
+        }
+        Some(DisplayInfo {
+            display_id: fields[0].parse().ok()?,
+            x: fields[1].parse().ok()?,
+            y: fields[2].parse().ok()?,
+            width: fields[3].parse().ok()?,
+            height: fields[4].parse().ok()?,
+            is_main: fields[5] == "1",
+        })
+    }
+}
 
 
 pub fn extract_frame_from_mp4(mp4_path: &str, frame_id: isize) -> Option<SRData> {
@@ -20,6 +63,54 @@ pub fn extract_frame_from_mp4(mp4_path: &str, frame_id: isize) -> Option<SRData>
 }
 
 
+/**
+ * Extract several frames from one mp4 file in a single pass.  Returns the PNG data of each
+ * frame in the order of frame_ids, or None for a frame that couldn't be extracted.  Returns
+ * None if the mp4 file can't be read at all.
+ */
+pub fn extract_frames_from_mp4(mp4_path: &str, frame_ids: &[isize]) -> Option<Vec<Option<Vec<u8>>>> {
+    let mp4_path_str: SRString = mp4_path.into();
+    let frame_ids_str: SRString = frame_ids.iter()
+        .map(|frame_id| frame_id.to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
+        .as_str()
+        .into();
+
+    let result = unsafe { extract_frames_from_mp4_swift(&mp4_path_str, &frame_ids_str) }?;
+
+    // Each frame is a 4 byte big endian length followed by the PNG data, see extract_frames_from_mp4 in swift
+    let mut data = result.as_slice();
+    let mut frames = Vec::with_capacity(frame_ids.len());
+    while data.len() >= 4 {
+        let length = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+        data = &data[4..];
+        if length > data.len() {
+            return None;
+        }
+        let (png_data, rest) = data.split_at(length);
+        frames.push(if length == 0 { None } else { Some(png_data.to_vec()) });
+        data = rest;
+    }
+
+    if frames.len() != frame_ids.len() {
+        return None;
+    }
+
+    Some(frames)
+}
+
+
+/**
+ * Count the frames in an mp4 file.  Returns None if the file can't be read.
+ */
+pub fn get_mp4_frame_count(mp4_path: &str) -> Option<usize> {
+    let mp4_path_str: SRString = mp4_path.into();
+    let frame_count = unsafe { get_mp4_frame_count_swift(&mp4_path_str) };
+    usize::try_from(frame_count).ok()
+}
+
+
 pub fn resize_image(png_data: &[u8], scale: f32) -> Option<Vec<u8>> {
 
     // Convert the vector to a SRData
@@ -61,12 +152,123 @@ pub fn write_images_in_dir_to_mp4(directory_path: &str, target_filename: &str, u
 
 
 /**
- * Given a path to an image, extract the text from it using OCR
+ * Given a list of image paths, write them to an mp4 in that order.  The paths must not
+ * contain newlines, since they are passed to swift as a single newline separated string.
+ *
+ * keyframe_interval is the maximum number of frames between keyframes, or 0 to let the
+ * encoder decide.
  */
-pub fn extract_text(path: &str) -> String {
+pub fn write_images_to_mp4(image_paths: &[&str], target_filename: &str, use_bitrate_key: bool, keyframe_interval: u32) {
+    let image_paths_str: SRString = image_paths.join("\n").as_str().into();
+    let target_filename_str: SRString = target_filename.into();
+
+    println!(
+        "Writing {} images to mp4: {} with use_bitrate_key: {} keyframe_interval: {}",
+        image_paths.len(),
+        target_filename_str.as_str(),
+        use_bitrate_key,
+        keyframe_interval
+    );
+
+    unsafe {
+        write_images_to_mp4_swift(
+            &image_paths_str,
+            &target_filename_str,
+            use_bitrate_key,
+            keyframe_interval as Int
+        )
+    };
+}
+
+/**
+ * Given a path to an image, extract the text from it using OCR.  Returns None if the
+ * image could not be read or the OCR request failed.
+ */
+pub fn extract_text(path: &str) -> Option<String> {
     let value: SRString = path.into();
     let result = unsafe { perform_ocr_swift(&value) };
-    String::from(result.unwrap().as_str())
+    result.map(|text| String::from(text.as_str()))
+}
+
+/**
+ * Extract the text from an image in memory using OCR, eg a capture that hasn't been written
+ * to disk yet.  Any format ImageIO can read works.  Returns None if the image could not be
+ * decoded or the OCR request failed.
+ */
+pub fn extract_text_from_image_data(image_data: &[u8]) -> Option<String> {
+    let image = SRData::from(image_data);
+    let result = unsafe { perform_ocr_data_swift(image) };
+    result.map(|text| String::from(text.as_str()))
+}
+
+/**
+ * A bounding box of OCR'd text, normalized to 0..1 with the origin at the top left of the image
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OcrRect {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+/**
+ * A word of an OcrLine
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OcrWord {
+    pub text: String,
+    pub confidence: f32,
+    pub bounds: OcrRect,
+}
+
+/**
+ * A line of text found by OCR, with its words
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OcrLine {
+    pub text: String,
+    pub confidence: f32,
+    pub bounds: OcrRect,
+    pub words: Vec<OcrWord>,
+}
+
+/**
+ * Parse a record of the form "kind\tconfidence\tx\ty\twidth\theight\ttext" as returned by
+ * perform_ocr_layout_data_swift
+ */
+fn parse_layout_record(record: &str) -> Option<(&str, f32, OcrRect, String)> {
+    let fields: Vec<&str> = record.splitn(7, '\t').collect();
+    if fields.len() != 7 {
+        return None;
+    }
+    let bounds = OcrRect {
+        x: fields[2].parse().ok()?,
+        y: fields[3].parse().ok()?,
+        width: fields[4].parse().ok()?,
+        height: fields[5].parse().ok()?,
+    };
+    Some((fields[0], fields[1].parse().ok()?, bounds, fields[6].to_string()))
+}
+
+/**
+ * Extract the lines and words of text from an image in memory using OCR, with their bounding
+ * boxes.  Lines are in the order Vision found them.  Returns None if the image could not be
+ * decoded or the OCR request failed.
+ */
+pub fn extract_layout_from_image_data(image_data: &[u8]) -> Option<Vec<OcrLine>> {
+    let image = SRData::from(image_data);
+    let result = unsafe { perform_ocr_layout_data_swift(image) }?;
+
+    let mut lines: Vec<OcrLine> = vec![];
+    for (kind, confidence, bounds, text) in result.as_str().lines().filter_map(parse_layout_record) {
+        match (kind, lines.last_mut()) {
+            ("L", _) => lines.push(OcrLine { text, confidence, bounds, words: vec![] }),
+            ("W", Some(line)) => line.words.push(OcrWord { text, confidence, bounds }),
+            _ => {},
+        }
+    }
+    Some(lines)
 }
 
 /**
@@ -85,12 +287,88 @@ pub fn get_frontmost_app() -> String {
 /**
  * Capture the screen and write to a file
  */
-pub fn screen_capture_to_file(dest_file: &str) -> Vec<u8> {
+pub fn screen_capture_to_file(dest_file: &str) -> Option<Vec<u8>> {
     let png_sr_data: Option<SRData> = unsafe { screen_capture_swift() };
-    let png_data = png_sr_data.unwrap().to_vec();
-    // Print the length of the vector
+    let png_data = png_sr_data?.to_vec();
+    let _ = write_png_to_file(&png_data, dest_file);
+    Some(png_data)
+}
+
+/**
+ * A window's bounds in global display coordinates (points)
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WindowRect {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl WindowRect {
+
+    /**
+     * Get the overlapping part of this rect and another rect, if any
+     */
+    pub fn intersect(&self, other: &WindowRect) -> Option<WindowRect> {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = (self.x + self.width).min(other.x + other.width);
+        let bottom = (self.y + self.height).min(other.y + other.height);
+        if right <= x || bottom <= y {
+            return None;
+        }
+        Some(WindowRect { x, y, width: right - x, height: bottom - y })
+    }
+}
+
+impl From<&DisplayInfo> for WindowRect {
+    fn from(display: &DisplayInfo) -> Self {
+        WindowRect { x: display.x, y: display.y, width: display.width, height: display.height }
+    }
+}
+
+/**
+ * Get the bounds of the frontmost app's topmost window, if any
+ */
+pub fn get_frontmost_window_bounds() -> Option<WindowRect> {
+    let result = unsafe { get_frontmost_window_bounds_swift() };
+    let fields: Vec<i32> = result.as_str()
+        .split(',')
+        .filter_map(|field| field.trim().parse().ok())
+        .collect();
+    if fields.len() != 4 {
+        return None;
+    }
+    Some(WindowRect { x: fields[0], y: fields[1], width: fields[2], height: fields[3] })
+}
+
+/**
+ * Get the currently connected displays
+ */
+pub fn get_displays() -> Vec<DisplayInfo> {
+    let result = unsafe { get_displays_swift() };
+    result.as_str()
+        .lines()
+        .filter_map(DisplayInfo::parse)
+        .collect()
+}
+
+/**
+ * Capture a single display and return the PNG data
+ */
+pub fn screen_capture_display(display_id: u32) -> Option<Vec<u8>> {
+    let result = unsafe { screen_capture_display_swift(display_id as isize) };
+    result.map(|data| data.to_vec())
+}
+
+/**
+ * Capture a single display and write it to a file
+ */
+pub fn screen_capture_display_to_file(display_id: u32, dest_file: &str) -> Option<Vec<u8>> {
+    let png_data = screen_capture_display(display_id)?;
     let _ = write_png_to_file(&png_data, dest_file);
-    png_data
+    Some(png_data)
 }
 
 /**
@@ -32,15 +32,113 @@ func swiftWriteImagesInDirToMp4(_ directoryPath: SRString, targetFilename: SRStr
     
 }
 
+/**
+ * Write the PNG images in imagePaths, a newline separated list of paths, into an mp4
+ * file given by targetFilename.  Frame N of the mp4 is the Nth path in the list.
+ *
+ * keyframeInterval is the maximum number of frames between keyframes, which bounds how
+ * many frames have to be decoded to seek to any frame.  0 leaves it up to the encoder.
+ */
+@_cdecl("write_images_to_mp4_swift")
+@available(macOS 10.15, *)
+func swiftWriteImagesToMp4FromPaths(_ imagePaths: SRString, targetFilename: SRString, useBitRateKey: Bool = false, keyframeInterval: Int = 0) {
+
+    var images: [CGImage] = []
+    for imagePath in imagePaths.toString().split(separator: "\n") {
+        let fileUrl = URL(fileURLWithPath: String(imagePath))
+        guard let imageSource = CGImageSourceCreateWithURL(fileUrl as CFURL, nil),
+              let image = CGImageSourceCreateImageAtIndex(imageSource, 0, nil) else {
+            // Bail out rather than skipping the image, since that would shift every later frame id
+            print("Failed to load image \(fileUrl.path)")
+            return
+        }
+        images.append(image)
+    }
+
+    if images.isEmpty {
+        print("No images to write to \(targetFilename.toString())")
+        return
+    }
+
+    swiftWriteImagesToMp4(
+        images,
+        targetFilename: targetFilename.toString(),
+        blockUntilFinished: true,
+        useBitRateKey: useBitRateKey,
+        keyframeInterval: keyframeInterval
+    )
+
+}
+
+/**
+ * Opening an AVAsset and creating an image generator for every frame is slow, so keep
+ * the generators of the most recently used mp4 files around.  A generator is reused only
+ * while the file's modification date is unchanged, in case the mp4 was rewritten.
+ */
+@available(macOS 10.15, *)
+class FrameGeneratorCache {
+
+    static let shared = FrameGeneratorCache(capacity: 8)
+
+    private struct Entry {
+        let generator: AVAssetImageGenerator
+        let fps: Float
+        let modificationDate: Date?
+    }
+
+    private let capacity: Int
+    private let lock = NSLock()
+    private var entries: [String: Entry] = [:]
+
+    // Most recently used path last
+    private var usageOrder: [String] = []
+
+    init(capacity: Int) {
+        self.capacity = capacity
+    }
+
+    func generator(forPath path: String) -> (AVAssetImageGenerator, Float)? {
+
+        lock.lock()
+        defer { lock.unlock() }
+
+        let modificationDate = (try? FileManager.default.attributesOfItem(atPath: path))?[.modificationDate] as? Date
+
+        if let entry = entries[path], entry.modificationDate == modificationDate {
+            usageOrder.removeAll { $0 == path }
+            usageOrder.append(path)
+            return (entry.generator, entry.fps)
+        }
+
+        let mp4Url = URL(fileURLWithPath: path)
+        guard let fps = getVideoFPS(from: mp4Url) else {
+            print("Failed to retrieve FPS for \(path)")
+            return nil
+        }
+
+        let imageGenerator = AVAssetImageGenerator(asset: AVAsset(url: mp4Url))
+        imageGenerator.appliesPreferredTrackTransform = true
+        imageGenerator.requestedTimeToleranceBefore = .zero
+        imageGenerator.requestedTimeToleranceAfter = .zero
+
+        entries[path] = Entry(generator: imageGenerator, fps: fps, modificationDate: modificationDate)
+        usageOrder.removeAll { $0 == path }
+        usageOrder.append(path)
+        while usageOrder.count > capacity {
+            entries.removeValue(forKey: usageOrder.removeFirst())
+        }
+
+        return (imageGenerator, fps)
+    }
+}
+
 @_cdecl("extract_frame_from_mp4_swift")
 @available(macOS 10.15, *)
 public func extract_frame_from_mp4(mp4_path: SRString, frame_id: Int) -> SRData? {
 
-    let mp4Url = URL(fileURLWithPath: mp4_path.toString())
-
-    if let fps = getVideoFPS(from: mp4Url) {
+    if let (imageGenerator, fps) = FrameGeneratorCache.shared.generator(forPath: mp4_path.toString()) {
         
-        if let extractedCGImage = getCGImageFromMP4Frame(url: mp4Url, fps: fps, frameID: frame_id) {
+        if let extractedCGImage = getCGImageFromMP4Frame(imageGenerator: imageGenerator, fps: fps, frameID: frame_id) {
             
             if let byteArray = convertCGImageToByteArray(image: extractedCGImage) {
                 return SRData(byteArray)
@@ -54,12 +152,93 @@ public func extract_frame_from_mp4(mp4_path: SRString, frame_id: Int) -> SRData?
         
         
     } else {
-        print("Failed to retrieve FPS")
+        print("Failed to open \(mp4_path.toString())")
     }
 
     return nil
 }
 
+/**
+ * Extract several frames from one mp4 with a single image generator.  frameIds is a
+ * newline separated list of frame ids.
+ *
+ * The result holds one record per requested frame id, in the requested order: a 4 byte
+ * big endian length followed by that many bytes of PNG data.  A length of 0 means the
+ * frame could not be extracted.  Returns nil if the mp4 can't be opened.
+ */
+@_cdecl("extract_frames_from_mp4_swift")
+@available(macOS 10.15, *)
+public func extract_frames_from_mp4(mp4_path: SRString, frame_ids: SRString) -> SRData? {
+
+    guard let (imageGenerator, fps) = FrameGeneratorCache.shared.generator(forPath: mp4_path.toString()) else {
+        print("Failed to open \(mp4_path.toString())")
+        return nil
+    }
+
+    var result: [UInt8] = []
+    for frameIdStr in frame_ids.toString().split(separator: "\n") {
+
+        var pngData: [UInt8] = []
+        if let frameId = Int(frameIdStr),
+           let extractedCGImage = getCGImageFromMP4Frame(imageGenerator: imageGenerator, fps: fps, frameID: frameId),
+           let byteArray = convertCGImageToByteArray(image: extractedCGImage) {
+            pngData = byteArray
+        }
+
+        let length = UInt32(pngData.count)
+        result.append(contentsOf: [
+            UInt8((length >> 24) & 0xff),
+            UInt8((length >> 16) & 0xff),
+            UInt8((length >> 8) & 0xff),
+            UInt8(length & 0xff),
+        ])
+        result.append(contentsOf: pngData)
+    }
+
+    return SRData(result)
+}
+
+/**
+ * Count the video frames in an mp4 file by reading every sample of the video track.
+ * Returns -1 if the file can't be read.
+ */
+@_cdecl("get_mp4_frame_count_swift")
+@available(macOS 10.15, *)
+public func get_mp4_frame_count(mp4_path: SRString) -> Int {
+
+    let asset = AVAsset(url: URL(fileURLWithPath: mp4_path.toString()))
+
+    guard let track = asset.tracks(withMediaType: .video).first else {
+        print("No video track in \(mp4_path.toString())")
+        return -1
+    }
+
+    do {
+        let reader = try AVAssetReader(asset: asset)
+        let output = AVAssetReaderTrackOutput(track: track, outputSettings: nil)
+        reader.add(output)
+        if !reader.startReading() {
+            print("Failed to start reading \(mp4_path.toString()): \(String(describing: reader.error))")
+            return -1
+        }
+
+        var frameCount = 0
+        while let sampleBuffer = output.copyNextSampleBuffer() {
+            frameCount += CMSampleBufferGetNumSamples(sampleBuffer)
+        }
+
+        if reader.status != .completed {
+            print("Failed to read every frame of \(mp4_path.toString()): \(String(describing: reader.error))")
+            return -1
+        }
+
+        return frameCount
+    } catch {
+        print("Error reading \(mp4_path.toString()): \(error)")
+        return -1
+    }
+}
+
 /**
  * NOTE: no longer used because it was returning stale values, and using KVO observing
  * or NSWorkspace.DidActivateApplicationNotification appears to be difficult to do
@@ -145,6 +324,97 @@ public func screen_capture() -> SRData? {
 
 }
 
+/**
+ * Get the active displays as a string of the form "id,x,y,width,height,is_main" with one
+ * display per line, since arrays can't be passed over the swift-rs bridge.  The geometry
+ * is in global display coordinates (points, not pixels).
+ */
+@_cdecl("get_displays_swift")
+@available(macOS 10.15, *)
+public func get_displays() -> SRString {
+
+    let mainDisplayID = CGMainDisplayID()
+
+    let lines = getActiveDisplays().map { displayID -> String in
+        let bounds = CGDisplayBounds(displayID)
+        let isMain = displayID == mainDisplayID ? 1 : 0
+        return "\(displayID),\(Int(bounds.origin.x)),\(Int(bounds.origin.y)),\(Int(bounds.width)),\(Int(bounds.height)),\(isMain)"
+    }
+
+    return SRString(lines.joined(separator: "\n"))
+}
+
+/**
+ * Capture a single display and return the image as a PNG encoded byte array
+ */
+@_cdecl("screen_capture_display_swift")
+@available(macOS 10.15, *)
+public func screen_capture_display(display_id: Int) -> SRData? {
+
+    let displayID = CGDirectDisplayID(display_id)
+
+    guard let image = CGDisplayCreateImage(displayID) else {
+        print("Failed to capture display \(displayID)")
+        return nil
+    }
+
+    guard let byteArray = convertCGImageToByteArray(image: image) else {
+        return nil
+    }
+
+    return SRData(byteArray)
+
+}
+
+/**
+ * Get the bounds of the frontmost app's topmost window as a string of the form "x,y,width,height"
+ * in global display coordinates (points), or an empty string if there is no such window.
+ */
+@_cdecl("get_frontmost_window_bounds_swift")
+@available(macOS 10.15, *)
+public func get_frontmost_window_bounds() -> SRString {
+
+    let options: CGWindowListOption = [.optionOnScreenOnly, .excludeDesktopElements]
+    guard let windowList = CGWindowListCopyWindowInfo(options, kCGNullWindowID) as? [[String: Any]] else {
+        print("Failed to get window list")
+        return SRString("")
+    }
+
+    // The window list is ordered front to back, so the first visible normal window (layer 0)
+    // belongs to the frontmost app, and is its topmost window.  The frontmost app isn't taken
+    // from NSWorkspace, which is only updated on a thread with a run loop, so it's stale on
+    // the capture thread.
+    for window in windowList {
+        guard let layer = window[kCGWindowLayer as String] as? Int, layer == 0,
+              (window[kCGWindowAlpha as String] as? Double ?? 1.0) > 0,
+              let boundsDict = window[kCGWindowBounds as String] as? NSDictionary,
+              let bounds = CGRect(dictionaryRepresentation: boundsDict),
+              bounds.width > 1, bounds.height > 1 else {
+            continue
+        }
+        return SRString("\(Int(bounds.origin.x)),\(Int(bounds.origin.y)),\(Int(bounds.width)),\(Int(bounds.height))")
+    }
+
+    return SRString("")
+}
+
+func getActiveDisplays() -> [CGDirectDisplayID] {
+
+    var displayCount: UInt32 = 0
+    guard CGGetActiveDisplayList(0, nil, &displayCount) == .success else {
+        print("Failed to get display count")
+        return []
+    }
+
+    var displays = [CGDirectDisplayID](repeating: 0, count: Int(displayCount))
+    guard CGGetActiveDisplayList(displayCount, &displays, &displayCount) == .success else {
+        print("Failed to get active displays")
+        return []
+    }
+
+    return Array(displays.prefix(Int(displayCount)))
+}
+
 @_cdecl("perform_ocr_swift")
 @available(macOS 10.15, *)
 public func perform_ocr(path: SRString) -> SRString? {
@@ -165,6 +435,94 @@ public func perform_ocr(path: SRString) -> SRString? {
         print("Cannot create CGImage for: " + fileUrl.path)
         return nil
     }
+    return recognizeText(image: image)
+}
+
+/**
+ * Extract the text from an image held in memory, in any format ImageIO can read.  This
+ * avoids writing the capture to disk and reading it back just for the OCR.
+ */
+@_cdecl("perform_ocr_data_swift")
+@available(macOS 10.15, *)
+public func perform_ocr_data(image: SRData) -> SRString? {
+    guard let cgImage = byteArrayToCGImage(byteArray: image.toArray()) else {
+        print("Cannot create CGImage from \(image.toArray().count) bytes of image data")
+        return nil
+    }
+    return recognizeText(image: cgImage)
+}
+
+/**
+ * Extract the lines and words of text from an image held in memory, with their bounding boxes
+ * and confidence.  Each line of the result is tab separated:
+ *
+ *   L <confidence> <x> <y> <width> <height> <text>
+ *   W <confidence> <x> <y> <width> <height> <text>
+ *
+ * W records are the words of the L record before them.  Boxes are normalized to 0..1 with the
+ * origin at the top left of the image.  Tabs and newlines in the text are replaced by spaces.
+ */
+@_cdecl("perform_ocr_layout_data_swift")
+@available(macOS 10.15, *)
+public func perform_ocr_layout_data(image: SRData) -> SRString? {
+    guard let cgImage = byteArrayToCGImage(byteArray: image.toArray()) else {
+        print("Cannot create CGImage from \(image.toArray().count) bytes of image data")
+        return nil
+    }
+    guard let observations = recognizeTextObservations(image: cgImage) else {
+        return nil
+    }
+
+    var records: [String] = []
+    for observation in observations {
+        guard let candidate = observation.topCandidates(1).first else {
+            continue
+        }
+        let lineText = candidate.string
+        records.append(formatLayoutRecord(kind: "L", confidence: candidate.confidence, box: observation.boundingBox, text: lineText))
+
+        lineText.enumerateSubstrings(in: lineText.startIndex..<lineText.endIndex, options: .byWords) { word, wordRange, _, _ in
+            guard let word = word,
+                  let wordBox = try? candidate.boundingBox(for: wordRange) else {
+                return
+            }
+            records.append(formatLayoutRecord(kind: "W", confidence: candidate.confidence, box: wordBox.boundingBox, text: word))
+        }
+    }
+
+    return SRString(records.joined(separator: "\n"))
+}
+
+/**
+ * Vision's bounding boxes have their origin at the bottom left, so flip them to the top left
+ */
+func formatLayoutRecord(kind: String, confidence: VNConfidence, box: CGRect, text: String) -> String {
+    let topLeftY = 1.0 - box.origin.y - box.height
+    let sanitizedText = text.replacingOccurrences(of: "\t", with: " ").replacingOccurrences(of: "\n", with: " ")
+    return [
+        kind,
+        String(confidence),
+        String(Float(box.origin.x)),
+        String(Float(topLeftY)),
+        String(Float(box.width)),
+        String(Float(box.height)),
+        sanitizedText,
+    ].joined(separator: "\t")
+}
+
+@available(macOS 10.15, *)
+func recognizeText(image: CGImage) -> SRString? {
+    guard let observations = recognizeTextObservations(image: image) else {
+        return nil
+    }
+    let recognizedText = observations.compactMap { observation in
+        observation.topCandidates(1).first?.string
+    }.joined(separator: "\n")
+    return SRString(recognizedText)
+}
+
+@available(macOS 10.15, *)
+func recognizeTextObservations(image: CGImage) -> [VNRecognizedTextObservation]? {
     let request = VNRecognizeTextRequest(completionHandler: { _, error in
         if let error = error {
             print("Error: \(error)")
@@ -177,12 +535,10 @@ public func perform_ocr(path: SRString) -> SRString? {
     do {
         try handler.perform([request])
         guard let observations = request.results else {
-            fatalError("Error translating image")
+            print("Error: no text recognition results")
+            return nil
         }
-        let recognizedText = observations.compactMap { observation in
-            observation.topCandidates(1).first?.string
-        }.joined(separator: "\n")
-        return SRString(recognizedText)
+        return observations
     } catch {
         print("Error: \(error)")
         return nil
@@ -254,7 +610,7 @@ func fetchSortedPngImages(from directory: URL) -> [CGImage] {
  *                  https://stackoverflow.com/questions/76811431/avfoundation-compression-property-quality-is-not-supported-for-video-codec-type/76848093#76848093
  *                  and https://forums.developer.apple.com/forums/thread/734885
  */
-func swiftWriteImagesToMp4(_ images: [CGImage], targetFilename: String, blockUntilFinished: Bool = true, useBitRateKey: Bool = false) {
+func swiftWriteImagesToMp4(_ images: [CGImage], targetFilename: String, blockUntilFinished: Bool = true, useBitRateKey: Bool = false, keyframeInterval: Int = 0) {
 
     let outputURL = URL(fileURLWithPath: targetFilename)
 
@@ -273,27 +629,29 @@ func swiftWriteImagesToMp4(_ images: [CGImage], targetFilename: String, blockUnt
     let imageHeight = images[0].height
 
     // See comments in method definition for explanation of useBitRateKey
-    let videoSettings: [String: Any]
+    var compressionProperties: [String: Any]
     if useBitRateKey {
-        videoSettings = [
-            AVVideoCodecKey: AVVideoCodecType.h264,
-            AVVideoWidthKey: imageWidth,
-            AVVideoHeightKey: imageHeight,
-            AVVideoCompressionPropertiesKey: [
-                AVVideoAverageBitRateKey: 1000000
-            ]
+        compressionProperties = [
+            AVVideoAverageBitRateKey: 1000000
         ]
     } else {
-        videoSettings = [
-            AVVideoCodecKey: AVVideoCodecType.h264,
-            AVVideoWidthKey: imageWidth,
-            AVVideoHeightKey: imageHeight,
-            AVVideoCompressionPropertiesKey: [
-                AVVideoQualityKey: 0.4
-            ]
+        compressionProperties = [
+            AVVideoQualityKey: 0.4
         ]
     }
 
+    // Bound the distance between keyframes so that seeking to a frame stays cheap
+    if keyframeInterval > 0 {
+        compressionProperties[AVVideoMaxKeyFrameIntervalKey] = keyframeInterval
+    }
+
+    let videoSettings: [String: Any] = [
+        AVVideoCodecKey: AVVideoCodecType.h264,
+        AVVideoWidthKey: imageWidth,
+        AVVideoHeightKey: imageHeight,
+        AVVideoCompressionPropertiesKey: compressionProperties
+    ]
+
     let videoWriterInput = AVAssetWriterInput(
         mediaType: .video,
         outputSettings: videoSettings
@@ -447,14 +805,7 @@ func getVideoFPS(from url: URL) -> Float? {
     return track.nominalFrameRate
 }
 
-func getCGImageFromMP4Frame(url: URL, fps: Float, frameID: Int) -> CGImage? {
-    let asset = AVAsset(url: url)
-
-    // Create an AVAssetImageGenerator
-    let imageGenerator = AVAssetImageGenerator(asset: asset)
-    imageGenerator.appliesPreferredTrackTransform = true
-    imageGenerator.requestedTimeToleranceBefore = .zero
-    imageGenerator.requestedTimeToleranceAfter = .zero
+func getCGImageFromMP4Frame(imageGenerator: AVAssetImageGenerator, fps: Float, frameID: Int) -> CGImage? {
 
     // Calculate the CMTime for the specified frameID
     let frameTime = CMTime(value: Int64(frameID), timescale: Int32(fps))
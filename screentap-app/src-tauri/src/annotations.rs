@@ -0,0 +1,315 @@
+use std::path::Path;
+use rusqlite::params;
+use serde::Serialize;
+
+use crate::db;
+use crate::error::ScreentapError;
+use crate::video::FrameExtractor;
+
+
+/**
+ * A tag with the number of screenshots it's on.  Bookmarks are the bookmark tag.
+ */
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Tag {
+    pub id: i64,
+    pub name: String,
+    pub screenshot_count: i64,
+}
+
+/**
+ * A note on a screenshot.  Notes are searched alongside the OCR text, see
+ * search::find_screenshots().
+ */
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Note {
+    pub id: i64,
+    pub document_id: i64,
+    pub text: String,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+
+/**
+ * Tag a screenshot, creating the tag if it's new, and pin the screenshot.  Tag names are
+ * matched ignoring case.  Returns false if the screenshot already had the tag.
+ */
+pub fn add_tag(dataset_root: &Path, db_filename: &Path, document_id: i64, name: &str, now: i64, frame_extractor: &dyn FrameExtractor) -> Result<bool, ScreentapError> {
+
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(ScreentapError::InvalidInput("Tags need a name".to_string()));
+    }
+
+    pin_screenshot(dataset_root, db_filename, document_id, frame_extractor)?;
+
+    let conn = db::get_db_conn(dataset_root, db_filename)?;
+
+    conn.execute("INSERT INTO tags (name) VALUES (?1) ON CONFLICT (name) DO NOTHING", params![name])?;
+    let added = conn.execute(
+        "INSERT INTO document_tags (document_id, tag_id, created_at)
+            SELECT ?1, id, ?2 FROM tags WHERE name = ?3
+            ON CONFLICT (document_id, tag_id) DO NOTHING",
+        params![document_id, now, name],
+    )?;
+
+    Ok(added > 0)
+
+}
+
+/**
+ * Remove a tag from a screenshot.  A screenshot without tags or notes is no longer pinned, and
+ * is compacted like any other.  Returns false if the screenshot didn't have the tag.
+ */
+pub fn remove_tag(dataset_root: &Path, db_filename: &Path, document_id: i64, name: &str) -> Result<bool, ScreentapError> {
+    let conn = db::get_db_conn(dataset_root, db_filename)?;
+    let removed = conn.execute(
+        "DELETE FROM document_tags WHERE document_id = ?1 AND tag_id IN (SELECT id FROM tags WHERE name = ?2)",
+        params![document_id, name.trim()],
+    )?;
+    Ok(removed > 0)
+}
+
+/**
+ * All tags, with the number of screenshots they're on, by name.  Tags stay when their last
+ * screenshot is untagged or deleted, so they can be picked again.
+ */
+pub fn list_tags(dataset_root: &Path, db_filename: &Path) -> Result<Vec<Tag>, ScreentapError> {
+
+    let conn = db::get_db_conn(dataset_root, db_filename)?;
+
+    let mut stmt = conn.prepare(
+        "SELECT t.id, t.name, COUNT(dt.document_id) FROM tags t
+            LEFT JOIN document_tags dt ON dt.tag_id = t.id
+            GROUP BY t.id
+            ORDER BY t.name"
+    )?;
+    let tags = stmt.query_map([], |row| {
+        Ok(Tag {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            screenshot_count: row.get(2)?,
+        })
+    })?
+    .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(tags)
+
+}
+
+/**
+ * The names of the tags of a screenshot, in the order they were added
+ */
+pub fn list_screenshot_tags(dataset_root: &Path, db_filename: &Path, document_id: i64) -> Result<Vec<String>, ScreentapError> {
+
+    let conn = db::get_db_conn(dataset_root, db_filename)?;
+
+    let mut stmt = conn.prepare(
+        "SELECT t.name FROM document_tags dt
+            JOIN tags t ON t.id = dt.tag_id
+            WHERE dt.document_id = ?1
+            ORDER BY dt.created_at, t.name"
+    )?;
+    let names = stmt.query_map(params![document_id], |row| row.get(0))?
+        .collect::<Result<Vec<String>, _>>()?;
+
+    Ok(names)
+
+}
+
+/**
+ * The ids of the screenshots with a tag, newest first
+ */
+pub fn find_tagged_screenshot_ids(dataset_root: &Path, db_filename: &Path, name: &str) -> Result<Vec<i64>, ScreentapError> {
+
+    let conn = db::get_db_conn(dataset_root, db_filename)?;
+
+    let mut stmt = conn.prepare(
+        "SELECT d.id FROM documents d
+            JOIN document_tags dt ON dt.document_id = d.id
+            JOIN tags t ON t.id = dt.tag_id
+            WHERE t.name = ?1
+            ORDER BY d.timestamp DESC, d.id DESC"
+    )?;
+    let ids = stmt.query_map(params![name.trim()], |row| row.get(0))?
+        .collect::<Result<Vec<i64>, _>>()?;
+
+    Ok(ids)
+
+}
+
+/**
+ * Add a note to a screenshot and pin the screenshot.  Returns the id of the note.
+ */
+pub fn add_note(dataset_root: &Path, db_filename: &Path, document_id: i64, text: &str, now: i64, frame_extractor: &dyn FrameExtractor) -> Result<i64, ScreentapError> {
+
+    if text.trim().is_empty() {
+        return Err(ScreentapError::InvalidInput("Notes can't be empty".to_string()));
+    }
+
+    pin_screenshot(dataset_root, db_filename, document_id, frame_extractor)?;
+
+    let conn = db::get_db_conn(dataset_root, db_filename)?;
+    conn.execute(
+        "INSERT INTO notes (document_id, text, created_at, updated_at) VALUES (?1, ?2, ?3, ?3)",
+        params![document_id, text, now],
+    )?;
+
+    // The notes index is updated by the notes_notes_index_insert trigger
+    Ok(conn.last_insert_rowid())
+
+}
+
+/**
+ * Change the text of a note.  Returns false if there was no such note.
+ */
+pub fn update_note(dataset_root: &Path, db_filename: &Path, id: i64, text: &str, now: i64) -> Result<bool, ScreentapError> {
+
+    if text.trim().is_empty() {
+        return Err(ScreentapError::InvalidInput("Notes can't be empty".to_string()));
+    }
+
+    let conn = db::get_db_conn(dataset_root, db_filename)?;
+    Ok(conn.execute("UPDATE notes SET text = ?1, updated_at = ?2 WHERE id = ?3", params![text, now, id])? > 0)
+
+}
+
+/**
+ * Delete a note.  Returns false if there was no such note.
+ */
+pub fn delete_note(dataset_root: &Path, db_filename: &Path, id: i64) -> Result<bool, ScreentapError> {
+    let conn = db::get_db_conn(dataset_root, db_filename)?;
+    Ok(conn.execute("DELETE FROM notes WHERE id = ?1", params![id])? > 0)
+}
+
+/**
+ * The notes of a screenshot, oldest first
+ */
+pub fn list_notes(dataset_root: &Path, db_filename: &Path, document_id: i64) -> Result<Vec<Note>, ScreentapError> {
+
+    let conn = db::get_db_conn(dataset_root, db_filename)?;
+
+    let mut stmt = conn.prepare(
+        "SELECT id, document_id, text, created_at, updated_at FROM notes WHERE document_id = ?1 ORDER BY created_at, id"
+    )?;
+    let notes = stmt.query_map(params![document_id], |row| {
+        Ok(Note {
+            id: row.get(0)?,
+            document_id: row.get(1)?,
+            text: row.get(2)?,
+            created_at: row.get(3)?,
+            updated_at: row.get(4)?,
+        })
+    })?
+    .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(notes)
+
+}
+
+/**
+ * Keep a screenshot at full quality for good.  Compaction skips pinned screenshots, so a loose
+ * screenshot stays loose.  A screenshot that was already compacted is unpacked from its mp4,
+ * since tiering downsamples the frames of a segment and retention deletes it.
+ *
+ * Screenshots are pinned by their tags and notes, see the pinned_documents view, so this is
+ * called before adding the first one.
+ */
+pub fn pin_screenshot(dataset_root: &Path, db_filename: &Path, document_id: i64, frame_extractor: &dyn FrameExtractor) -> Result<(), ScreentapError> {
+    if db::unpack_screenshot(dataset_root, db_filename, document_id, frame_extractor)? {
+        println!("Unpacked pinned screenshot #{} from its mp4", document_id);
+    }
+    Ok(())
+}
+
+/**
+ * Unpack the pinned screenshots of a segment that's about to be deleted, eg ones that were
+ * compacted while they were being tagged.  Returns how many were unpacked.
+ */
+pub fn unpack_pinned_in_segment(dataset_root: &Path, db_filename: &Path, mp4_file_path: &str, frame_extractor: &dyn FrameExtractor) -> Result<usize, ScreentapError> {
+
+    let pinned_ids = {
+        let conn = db::get_db_conn(dataset_root, db_filename)?;
+        let mut stmt = conn.prepare(
+            "SELECT id FROM documents WHERE mp4_file_path = ?1 AND id IN (SELECT document_id FROM pinned_documents)"
+        )?;
+        let pinned_ids = stmt.query_map(params![mp4_file_path], |row| row.get(0))?
+            .collect::<Result<Vec<i64>, _>>()?;
+        pinned_ids
+    };
+
+    for document_id in &pinned_ids {
+        pin_screenshot(dataset_root, db_filename, *document_id, frame_extractor)?;
+    }
+
+    Ok(pinned_ids.len())
+
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use super::{add_note, add_tag, delete_note, find_tagged_screenshot_ids, list_notes, list_screenshot_tags, list_tags, remove_tag, update_note};
+    use crate::config::ScreentapConfig;
+    use crate::db;
+    use crate::error::ScreentapError;
+    use crate::video;
+    use rusqlite::params;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_tags_and_notes() {
+
+        let tmp_dir = tempdir().unwrap();
+        let data_root = tmp_dir.path();
+        let db_filename = Path::new("test.db");
+        db::create_db(data_root, db_filename).unwrap();
+
+        let is_pinned = |document_id: i64| -> bool {
+            let conn = db::get_db_conn(data_root, db_filename).unwrap();
+            conn.query_row("SELECT COUNT(*) FROM pinned_documents WHERE document_id = ?1", params![document_id], |row| row.get::<_, i64>(0)).unwrap() > 0
+        };
+
+        db::insert_test_screenshots(data_root, db_filename, &[(0, "Quarterly report"), (1, "Quarterly report"), (2, "Quarterly report")]);
+
+        let frame_extractor = video::create_frame_extractor(&ScreentapConfig::default());
+        let frame_extractor = frame_extractor.as_ref();
+
+        // Tags are matched ignoring case
+        assert!(add_tag(data_root, db_filename, 1, "bookmark", 0, frame_extractor).unwrap());
+        assert!(add_tag(data_root, db_filename, 3, "Bookmark", 0, frame_extractor).unwrap());
+        assert!(!add_tag(data_root, db_filename, 3, "bookmark ", 0, frame_extractor).unwrap());
+        assert!(add_tag(data_root, db_filename, 3, "finance", 1, frame_extractor).unwrap());
+        assert!(matches!(add_tag(data_root, db_filename, 2, " ", 0, frame_extractor), Err(ScreentapError::InvalidInput(_))));
+
+        assert_eq!(list_screenshot_tags(data_root, db_filename, 3).unwrap(), vec!["bookmark", "finance"]);
+        assert_eq!(find_tagged_screenshot_ids(data_root, db_filename, "BOOKMARK").unwrap(), vec![3, 1]);
+        let tags = list_tags(data_root, db_filename).unwrap();
+        assert_eq!(tags.iter().map(|tag| (tag.name.as_str(), tag.screenshot_count)).collect::<Vec<_>>(), vec![("bookmark", 2), ("finance", 1)]);
+
+        // Notes are searchable and pin their screenshot
+        let note_id = add_note(data_root, db_filename, 2, "numbers for the board meeting", 5, frame_extractor).unwrap();
+        assert!(is_pinned(2));
+        assert_eq!(db::find_screenshots_notes("board", data_root, db_filename, 10, None).unwrap()[0].get_id(), 2);
+        assert!(update_note(data_root, db_filename, note_id, "numbers for the offsite", 6).unwrap());
+        assert!(db::find_screenshots_notes("board", data_root, db_filename, 10, None).unwrap().is_empty());
+        assert_eq!(list_notes(data_root, db_filename, 2).unwrap()[0].updated_at, 6);
+        assert!(delete_note(data_root, db_filename, note_id).unwrap());
+        assert!(!is_pinned(2));
+
+        assert!(remove_tag(data_root, db_filename, 1, "bookmark").unwrap());
+        assert!(!remove_tag(data_root, db_filename, 1, "bookmark").unwrap());
+        assert!(!is_pinned(1));
+
+        // Deleting a screenshot deletes its tags and notes, but not the tags themselves
+        add_note(data_root, db_filename, 3, "offsite", 7, frame_extractor).unwrap();
+        let conn = db::get_db_conn(data_root, db_filename).unwrap();
+        conn.execute("DELETE FROM documents WHERE id = ?1", params![3]).unwrap();
+        assert!(db::find_screenshots_notes("offsite", data_root, db_filename, 10, None).unwrap().is_empty());
+        assert_eq!(list_tags(data_root, db_filename).unwrap().iter().map(|tag| tag.screenshot_count).sum::<i64>(), 0);
+    }
+
+}
@@ -0,0 +1,511 @@
+use std::path::{Path, PathBuf};
+
+use crate::config::ScreentapConfig;
+use crate::data_root;
+use crate::db;
+use crate::embeddings;
+use crate::export::{ExportFormat, ExportSelection, Exporter};
+use crate::ranking::RankingModel;
+use crate::saved_searches;
+use crate::search::{self, SearchContext, SearchMode, SearchQuery};
+use crate::storage_stats::{self, format_bytes};
+
+
+/**
+ * Maintenance commands that run without starting the app, eg:
+ *
+ *   screentap-app move-data-dir /Volumes/Encrypted/screentap
+ *   screentap-app verify-data-dir
+ *   screentap-app stats --json
+ *   screentap-app search-index integrity-check
+ *   screentap-app explain-search invoice
+ *   screentap-app saved-search add "Acme tickets" "ACME" --mode fuzzy --watch
+ *   screentap-app export gif ~/standup.gif --from "2024-03-07 09:00:00" --to "2024-03-07 09:15:00"
+ */
+static USAGE: &str = "Usage:
+  screentap-app move-data-dir <new data dir>   Move the screenshot DB and media to a new dir
+  screentap-app verify-data-dir                Check that every screenshot's media file exists
+  screentap-app stats [--json]                 Show the storage used by category, day and app
+  screentap-app search-index rebuild           Rebuild the OCR search indexes from the screenshots
+  screentap-app search-index integrity-check   Check that the OCR search indexes match the screenshots
+  screentap-app explain-search <term>          Show how the exact search hits for a term are scored
+  screentap-app saved-search list              List the saved searches
+  screentap-app saved-search add <name> <term> [--mode <mode>] [--watch]
+                                               Save a search, or update the saved search with that name
+  screentap-app saved-search run <id or name>  Run a saved search
+  screentap-app saved-search delete <id or name>
+                                               Delete a saved search and its alerts
+  screentap-app alerts                         List the latest matches of watched searches
+  screentap-app export <mp4|gif|zip> <output> (--from <time> --to <time> [--display <id>] | --ids <id,id,...>)
+                                               Export screenshots with a sidecar JSON of their OCR text.
+                                               Times are UTC, as \"YYYY-MM-DD HH:MM:SS\" or epoch seconds";
+
+
+/**
+ * Is this the name of a CLI command?  Anything else starts the app, since macOS can pass
+ * its own arguments when launching an app bundle.
+ */
+pub fn is_cli_command(args: &[String]) -> bool {
+    matches!(args.first().map(String::as_str), Some("move-data-dir") | Some("verify-data-dir") | Some("stats") | Some("search-index") | Some("explain-search") | Some("saved-search") | Some("alerts") | Some("export") | Some("help"))
+}
+
+/**
+ * Run a CLI command and return the process exit code
+ */
+pub fn run(args: &[String], app_data_dir: &Path, db_filename: &Path) -> i32 {
+
+    match args.first().map(String::as_str) {
+        Some("move-data-dir") => match args.get(1) {
+            Some(new_data_root) => move_data_dir(app_data_dir, db_filename, &PathBuf::from(new_data_root)),
+            None => {
+                eprintln!("{}", USAGE);
+                2
+            }
+        },
+        Some("verify-data-dir") => verify_data_dir(app_data_dir, db_filename),
+        Some("stats") => stats(app_data_dir, db_filename, args.iter().any(|arg| arg == "--json")),
+        Some("search-index") => match args.get(1).map(String::as_str) {
+            Some("rebuild") => rebuild_search_index(app_data_dir, db_filename),
+            Some("integrity-check") => check_search_index(app_data_dir, db_filename),
+            _ => {
+                eprintln!("{}", USAGE);
+                2
+            }
+        },
+        Some("explain-search") => match args.get(1) {
+            Some(term) => explain_search(app_data_dir, db_filename, term),
+            None => {
+                eprintln!("{}", USAGE);
+                2
+            }
+        },
+        Some("saved-search") => match (args.get(1).map(String::as_str), args.get(2), args.get(3)) {
+            (Some("list"), _, _) => list_saved_searches(app_data_dir, db_filename),
+            (Some("add"), Some(name), Some(term)) => add_saved_search(app_data_dir, db_filename, name, term, &args[4..]),
+            (Some("run"), Some(id_or_name), _) => run_saved_search(app_data_dir, db_filename, id_or_name),
+            (Some("delete"), Some(id_or_name), _) => delete_saved_search(app_data_dir, db_filename, id_or_name),
+            _ => {
+                eprintln!("{}", USAGE);
+                2
+            }
+        },
+        Some("alerts") => list_alerts(app_data_dir, db_filename),
+        Some("export") => match (args.get(1).map(String::as_str).and_then(ExportFormat::parse), args.get(2)) {
+            (Some(format), Some(output_path)) => export(app_data_dir, db_filename, format, &PathBuf::from(output_path), &args[3..]),
+            _ => {
+                eprintln!("{}", USAGE);
+                2
+            }
+        },
+        _ => {
+            println!("{}", USAGE);
+            0
+        }
+    }
+
+}
+
+fn move_data_dir(app_data_dir: &Path, db_filename: &Path, new_data_root: &Path) -> i32 {
+
+    let old_data_root = data_root::resolve_data_root(app_data_dir);
+    println!("Moving data dir from {} to {}", old_data_root.display(), new_data_root.display());
+
+    match data_root::move_data_dir(app_data_dir, db_filename, new_data_root) {
+        Ok(report) => {
+            println!(
+                "Copied and verified {} files ({} bytes), rewrote {} paths.  Screentap now uses {}.",
+                report.files_copied, report.bytes_copied, report.paths_rewritten, new_data_root.display()
+            );
+            println!("The old data dir {} was left in place and can be deleted.", old_data_root.display());
+            0
+        },
+        Err(e) => {
+            eprintln!("Failed to move data dir, screentap still uses {}: {}", old_data_root.display(), e);
+            1
+        }
+    }
+
+}
+
+fn verify_data_dir(app_data_dir: &Path, db_filename: &Path) -> i32 {
+
+    let data_root = data_root::resolve_data_root(app_data_dir);
+    println!("Verifying data dir {}", data_root.display());
+
+    match data_root::verify_data_dir(app_data_dir, db_filename) {
+        Ok(report) if report.missing_files.is_empty() => {
+            println!("All {} screenshots have their media files", report.screenshots_checked);
+            0
+        },
+        Ok(report) => {
+            for (screenshot_id, missing_file) in &report.missing_files {
+                println!("Screenshot #{}: missing {}", screenshot_id, missing_file.display());
+            }
+            println!("{} of {} screenshots are missing their media files", report.missing_files.len(), report.screenshots_checked);
+            1
+        },
+        Err(e) => {
+            eprintln!("Failed to verify data dir: {}", e);
+            1
+        }
+    }
+
+}
+
+fn stats(app_data_dir: &Path, db_filename: &Path, json: bool) -> i32 {
+
+    let data_root = data_root::resolve_data_root(app_data_dir);
+    let config = ScreentapConfig::load(app_data_dir).unwrap_or_default();
+
+    let stats = match storage_stats::collect_storage_stats(app_data_dir, &data_root, db_filename, &config) {
+        Ok(stats) => stats,
+        Err(e) => {
+            eprintln!("Failed to collect storage stats: {}", e);
+            return 1;
+        }
+    };
+
+    if json {
+        match serde_json::to_string_pretty(&stats) {
+            Ok(stats_json) => println!("{}", stats_json),
+            Err(e) => {
+                eprintln!("Failed to serialize storage stats: {}", e);
+                return 1;
+            }
+        }
+        return 0;
+    }
+
+    let categories = &stats.categories;
+    println!("Storage used by screentap in {}", stats.data_root.display());
+    println!("  DB                {:>10}", format_bytes(categories.db_bytes));
+    println!("  OCR index         {:>10}", format_bytes(categories.fts_index_bytes));
+    println!("  Loose screenshots {:>10}", format_bytes(categories.loose_png_bytes));
+    println!("  MP4 segments      {:>10}", format_bytes(categories.mp4_bytes));
+    println!("  FocusGuard        {:>10}", format_bytes(categories.focusguard_bytes));
+    println!("  Total             {:>10}", format_bytes(stats.total_bytes));
+
+    match stats.quota_bytes {
+        Some(quota_bytes) => println!("Quota: {} of {} used", format_bytes(categories.data_root_bytes()), format_bytes(quota_bytes)),
+        None => println!("Quota: none"),
+    }
+    if let Some(free_disk_bytes) = stats.free_disk_bytes {
+        println!("Free disk: {}", format_bytes(free_disk_bytes));
+    }
+    println!("Status: {:?}", stats.status);
+
+    println!("\nPer day:");
+    for bucket in &stats.per_day {
+        println!("  {:<40} {:>8} screenshots {:>10}", bucket.key, bucket.screenshots, format_bytes(bucket.bytes));
+    }
+
+    println!("\nPer app:");
+    for bucket in &stats.per_app {
+        println!("  {:<40} {:>8} screenshots {:>10}", bucket.key, bucket.screenshots, format_bytes(bucket.bytes));
+    }
+
+    0
+
+}
+
+fn rebuild_search_index(app_data_dir: &Path, db_filename: &Path) -> i32 {
+
+    let data_root = data_root::resolve_data_root(app_data_dir);
+    println!("Rebuilding the OCR search indexes in {}", data_root.display());
+
+    match db::rebuild_ocr_indexes(&data_root, db_filename) {
+        Ok(()) => {
+            println!("Rebuilt the OCR search indexes");
+            0
+        },
+        Err(e) => {
+            eprintln!("Failed to rebuild the OCR search indexes: {}", e);
+            1
+        }
+    }
+
+}
+
+fn check_search_index(app_data_dir: &Path, db_filename: &Path) -> i32 {
+
+    let data_root = data_root::resolve_data_root(app_data_dir);
+    println!("Checking the OCR search indexes in {}", data_root.display());
+
+    match db::check_ocr_indexes(&data_root, db_filename) {
+        Ok(true) => {
+            println!("The OCR search indexes match the screenshots");
+            0
+        },
+        Ok(false) => {
+            println!("The OCR search indexes don't match the screenshots, run `screentap-app search-index rebuild` to fix them");
+            1
+        },
+        Err(e) => {
+            eprintln!("Failed to check the OCR search indexes: {}", e);
+            1
+        }
+    }
+
+}
+
+fn explain_search(app_data_dir: &Path, db_filename: &Path, term: &str) -> i32 {
+
+    let data_root = data_root::resolve_data_root(app_data_dir);
+    let config = ScreentapConfig::load(app_data_dir).unwrap_or_default();
+    let ranking = RankingModel::from_config(&config);
+
+    let ranked_hits = match search::explain_search(term, &ranking, &data_root, db_filename, 25, None) {
+        Ok(ranked_hits) => ranked_hits,
+        Err(e) => {
+            eprintln!("Failed to search for {}: {}", term, e);
+            return 1;
+        }
+    };
+
+    println!(
+        "Score = {} x text + {} x recency + {} x dwell, recency halves every {} days",
+        ranking.text_weight, ranking.recency_weight, ranking.dwell_weight, ranking.recency_half_life_days
+    );
+    println!("{:>10} {:>7} {:>10} {:>7} {:>10} {:>7} {:>10} {:>7}", "id", "score", "bm25", "text", "age days", "recency", "on screen", "dwell");
+    for (record, explanation) in &ranked_hits {
+        println!(
+            "{:>10} {:>7.3} {:>10.4} {:>7.3} {:>10.1} {:>7.3} {:>9}s {:>7.3}",
+            record.get_id(), explanation.score, explanation.bm25, explanation.text_score, explanation.age_days,
+            explanation.recency_score, explanation.dwell_secs, explanation.dwell_score
+        );
+    }
+
+    0
+
+}
+
+fn list_saved_searches(app_data_dir: &Path, db_filename: &Path) -> i32 {
+
+    let data_root = data_root::resolve_data_root(app_data_dir);
+
+    match saved_searches::list_saved_searches(&data_root, db_filename) {
+        Ok(saved_searches) => {
+            for saved_search in &saved_searches {
+                let display = saved_search.display_id.map(|display_id| format!(" on display {}", display_id)).unwrap_or_default();
+                let watch = if saved_search.watch { ", watched" } else { "" };
+                println!("{:>6}  {}: {} ({}{}{})", saved_search.id, saved_search.name, saved_search.term, saved_search.mode, display, watch);
+            }
+            if saved_searches.is_empty() {
+                println!("No saved searches");
+            }
+            0
+        },
+        Err(e) => {
+            eprintln!("Failed to list saved searches: {}", e);
+            1
+        }
+    }
+
+}
+
+fn add_saved_search(app_data_dir: &Path, db_filename: &Path, name: &str, term: &str, options: &[String]) -> i32 {
+
+    let data_root = data_root::resolve_data_root(app_data_dir);
+
+    let mut mode = SearchMode::default();
+    let mut watch = false;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match (option.as_str(), options.clone().next().map(String::as_str).and_then(SearchMode::parse)) {
+            ("--watch", _) => watch = true,
+            ("--mode", Some(option_mode)) => {
+                mode = option_mode;
+                options.next();
+            },
+            _ => {
+                eprintln!("{}", USAGE);
+                return 2;
+            }
+        }
+    }
+
+    let now = chrono::Local::now().naive_utc().timestamp();
+    match saved_searches::save_search(&data_root, db_filename, name, &SearchQuery::new(term, mode, 0), watch, now) {
+        Ok(id) => {
+            println!("Saved search #{} {}", id, name);
+            0
+        },
+        Err(e) => {
+            eprintln!("Failed to save search {}: {}", name, e);
+            1
+        }
+    }
+
+}
+
+fn run_saved_search(app_data_dir: &Path, db_filename: &Path, id_or_name: &str) -> i32 {
+
+    let data_root = data_root::resolve_data_root(app_data_dir);
+    let config = ScreentapConfig::load(app_data_dir).unwrap_or_default();
+
+    let saved_search = match saved_searches::get_saved_search(&data_root, db_filename, id_or_name) {
+        Ok(Some(saved_search)) => saved_search,
+        Ok(None) => {
+            eprintln!("No saved search {}", id_or_name);
+            return 1;
+        },
+        Err(e) => {
+            eprintln!("Failed to load saved search {}: {}", id_or_name, e);
+            return 1;
+        }
+    };
+
+    let mode = saved_search.search_mode();
+    let embedder = match mode {
+        SearchMode::Semantic | SearchMode::Hybrid if config.embeddings_enabled => {
+            embeddings::get_embedder(&embeddings::get_model_dir(app_data_dir, &config)).ok()
+        },
+        _ => None,
+    };
+
+    let ranking = RankingModel::from_config(&config);
+    let context = SearchContext { embedder: embedder.as_deref(), min_similarity: config.embedding_min_similarity, ranking: &ranking };
+    let hits = match search::find_screenshots(&saved_search.query(25), &context, &data_root, db_filename) {
+        Ok(hits) => hits,
+        Err(e) => {
+            eprintln!("Failed to run saved search {}: {}", saved_search.name, e);
+            return 1;
+        }
+    };
+
+    for record in &hits {
+        let first_line = record.get_ocr_text().lines().next().unwrap_or("");
+        println!("{:>10}  {}  {}", record.get_id(), format_timestamp(record.get_timestamp() as i64), first_line);
+    }
+    println!("{} screenshots match {}", hits.len(), saved_search.term);
+
+    0
+
+}
+
+fn delete_saved_search(app_data_dir: &Path, db_filename: &Path, id_or_name: &str) -> i32 {
+
+    let data_root = data_root::resolve_data_root(app_data_dir);
+
+    let deleted = saved_searches::get_saved_search(&data_root, db_filename, id_or_name)
+        .and_then(|saved_search| match saved_search {
+            Some(saved_search) => saved_searches::delete_saved_search(&data_root, db_filename, saved_search.id),
+            None => Ok(false),
+        });
+
+    match deleted {
+        Ok(true) => {
+            println!("Deleted saved search {}", id_or_name);
+            0
+        },
+        Ok(false) => {
+            eprintln!("No saved search {}", id_or_name);
+            1
+        },
+        Err(e) => {
+            eprintln!("Failed to delete saved search {}: {}", id_or_name, e);
+            1
+        }
+    }
+
+}
+
+fn list_alerts(app_data_dir: &Path, db_filename: &Path) -> i32 {
+
+    let data_root = data_root::resolve_data_root(app_data_dir);
+
+    match saved_searches::list_alerts(&data_root, db_filename, 50) {
+        Ok(alerts) => {
+            for alert in &alerts {
+                println!("{}  {}: {} in screenshot #{}", format_timestamp(alert.timestamp), alert.saved_search_name, alert.term, alert.document_id);
+            }
+            if alerts.is_empty() {
+                println!("No alerts");
+            }
+            0
+        },
+        Err(e) => {
+            eprintln!("Failed to list alerts: {}", e);
+            1
+        }
+    }
+
+}
+
+fn export(app_data_dir: &Path, db_filename: &Path, format: ExportFormat, output_path: &Path, options: &[String]) -> i32 {
+
+    let data_root = data_root::resolve_data_root(app_data_dir);
+
+    let mut start_timestamp = None;
+    let mut end_timestamp = None;
+    let mut display_id = None;
+    let mut ids = None;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let value = options.next().map(String::as_str);
+        match (option.as_str(), value) {
+            ("--from", Some(value)) if parse_timestamp(value).is_some() => start_timestamp = parse_timestamp(value),
+            ("--to", Some(value)) if parse_timestamp(value).is_some() => end_timestamp = parse_timestamp(value),
+            ("--display", Some(value)) if value.parse::<u32>().is_ok() => display_id = value.parse().ok(),
+            ("--ids", Some(value)) => match value.split(',').map(|id| id.trim().parse::<i64>()).collect::<Result<Vec<_>, _>>() {
+                Ok(value_ids) => ids = Some(value_ids),
+                Err(_) => {
+                    eprintln!("{}", USAGE);
+                    return 2;
+                }
+            },
+            _ => {
+                eprintln!("{}", USAGE);
+                return 2;
+            }
+        }
+    }
+
+    let selection = match (ids, start_timestamp, end_timestamp) {
+        (Some(ids), None, None) => ExportSelection::Ids(ids),
+        (None, Some(start_timestamp), Some(end_timestamp)) => ExportSelection::TimeRange { start_timestamp, end_timestamp, display_id },
+        _ => {
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+    };
+
+    // An invalid config would otherwise export without the redaction rules
+    let config = match ScreentapConfig::load(app_data_dir) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Failed to load config, not exporting: {}", e);
+            return 1;
+        }
+    };
+
+    match Exporter::from_config(data_root, db_filename.to_path_buf(), &config).and_then(|exporter| exporter.export(&selection, format, output_path)) {
+        Ok(report) => {
+            println!(
+                "Exported {} screenshots ({} redacted) to {}, metadata in {}",
+                report.frame_count, report.redacted_frame_count, report.output_path.display(), report.sidecar_path.display()
+            );
+            0
+        },
+        Err(e) => {
+            eprintln!("Failed to export to {}: {}", output_path.display(), e);
+            1
+        }
+    }
+
+}
+
+/**
+ * A UTC time as "YYYY-MM-DD HH:MM:SS", like format_timestamp(), or as epoch seconds
+ */
+fn parse_timestamp(value: &str) -> Option<i64> {
+    value.parse::<i64>().ok().or_else(|| {
+        chrono::NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S").ok().map(|timestamp| timestamp.timestamp())
+    })
+}
+
+fn format_timestamp(timestamp: i64) -> String {
+    chrono::NaiveDateTime::from_timestamp_opt(timestamp, 0)
+        .map(|timestamp| timestamp.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_default()
+}
@@ -0,0 +1,324 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+
+use crate::db::ScreenshotRecord;
+use crate::error::ScreentapError;
+
+
+// How many hash functions make up a minhash signature.  More is more accurate and slower.
+static MINHASH_PERMUTATIONS: usize = 64;
+
+// How many consecutive words make up a shingle
+static SHINGLE_WORDS: usize = 3;
+
+// The estimated fraction of shingles two screenshots must share to be in the same cluster
+static MIN_CLUSTER_SIMILARITY: f64 = 0.8;
+
+// The longest gap between the screenshots of a cluster.  Text that comes back on screen after
+// a longer gap is a separate cluster.
+static CLUSTER_MAX_GAP_SECS: i32 = 120;
+
+// How many seconds of screenshots are read at a time while extending a cluster, see extend_cluster()
+static EXTEND_WINDOW_SECS: i64 = 3600;
+
+
+/**
+ * Search hits from the same display with near-identical OCR text, captured close together in
+ * time.  Shown as its representative, which is its best ranked hit.
+ */
+pub struct ScreenshotCluster {
+    pub representative: ScreenshotRecord,
+
+    // The ids of all screenshots in the cluster including the representative, oldest first
+    pub screenshot_ids: Vec<i64>,
+
+    pub first_timestamp: i32,
+    pub last_timestamp: i32,
+}
+
+impl ScreenshotCluster {
+
+    pub fn size(&self) -> usize {
+        self.screenshot_ids.len()
+    }
+
+}
+
+
+/**
+ * Group ranked search hits into clusters, keeping the order of each cluster's best ranked hit.
+ * Hits are compared by the minhash of their OCR text's word shingles, which is cheap and
+ * ignores the small differences between frames, like a clock or a blinking cursor.
+ *
+ * Screenshots without text are never clustered, since there's nothing to compare.
+ */
+pub fn cluster_screenshots(records: Vec<ScreenshotRecord>) -> Vec<ScreenshotCluster> {
+
+    // The same OCR text is often captured many times in a row, so only hash it once
+    let mut signature_by_text: HashMap<String, Option<Vec<u64>>> = HashMap::new();
+
+    // Each cluster with the signature of its representative, so that clusters don't drift
+    let mut clusters: Vec<(ScreenshotCluster, Option<Vec<u64>>, Vec<(i32, i64)>)> = vec![];
+
+    for record in records {
+
+        let signature = signature_by_text.entry(record.get_ocr_text().to_string())
+            .or_insert_with(|| get_minhash_signature(&get_shingles(record.get_ocr_text())))
+            .clone();
+
+        let matching_cluster = signature.as_ref().and_then(|signature| {
+            clusters.iter_mut().find(|(cluster, cluster_signature, _)| {
+                cluster.representative.get_display_id() == record.get_display_id()
+                    && record.get_timestamp() >= cluster.first_timestamp - CLUSTER_MAX_GAP_SECS
+                    && record.get_timestamp() <= cluster.last_timestamp + CLUSTER_MAX_GAP_SECS
+                    && cluster_signature.as_ref().is_some_and(|cluster_signature| {
+                        get_estimated_similarity(signature, cluster_signature) >= MIN_CLUSTER_SIMILARITY
+                    })
+            })
+        });
+
+        match matching_cluster {
+            Some((cluster, _, members)) => {
+                cluster.first_timestamp = cluster.first_timestamp.min(record.get_timestamp());
+                cluster.last_timestamp = cluster.last_timestamp.max(record.get_timestamp());
+                members.push((record.get_timestamp(), record.get_id() as i64));
+            },
+            None => {
+                let members = vec![(record.get_timestamp(), record.get_id() as i64)];
+                let cluster = ScreenshotCluster {
+                    first_timestamp: record.get_timestamp(),
+                    last_timestamp: record.get_timestamp(),
+                    representative: record,
+                    screenshot_ids: vec![],
+                };
+                clusters.push((cluster, signature, members));
+            },
+        }
+    }
+
+    clusters.into_iter()
+        .map(|(mut cluster, _, mut members)| {
+            members.sort();
+            cluster.screenshot_ids = members.into_iter().map(|(_, id)| id).collect();
+            cluster
+        })
+        .collect()
+
+}
+
+/**
+ * Add the screenshots before and after a cluster that are near-identical to its representative,
+ * as if they had all been clustered.  Searches only cluster their best hits, so without this a
+ * text that stayed on screen for hours would have the size and time span of the hits that made
+ * the cut.  find_in_range(start, end) gets the screenshots of the cluster's display between two
+ * timestamps, inclusive, oldest first.
+ */
+pub fn extend_cluster<F>(cluster: &mut ScreenshotCluster, mut find_in_range: F) -> Result<(), ScreentapError>
+where F: FnMut(i64, i64) -> Result<Vec<ScreenshotRecord>, ScreentapError> {
+
+    let Some(signature) = get_minhash_signature(&get_shingles(cluster.representative.get_ocr_text())) else {
+        return Ok(());
+    };
+
+    let member_ids: HashSet<i64> = cluster.screenshot_ids.iter().copied().collect();
+    let mut signature_by_text: HashMap<String, bool> = HashMap::new();
+    let mut is_member = |record: &ScreenshotRecord| -> bool {
+        !member_ids.contains(&(record.get_id() as i64)) && *signature_by_text.entry(record.get_ocr_text().to_string()).or_insert_with(|| {
+            get_minhash_signature(&get_shingles(record.get_ocr_text()))
+                .is_some_and(|record_signature| get_estimated_similarity(&signature, &record_signature) >= MIN_CLUSTER_SIMILARITY)
+        })
+    };
+
+    // Walk back from the first screenshot, a window at a time, until there's a gap
+    let mut older_members: Vec<i64> = vec![];
+    let mut window_end = cluster.first_timestamp as i64;
+    loop {
+        let window_start = window_end - EXTEND_WINDOW_SECS;
+        for record in find_in_range(window_start, window_end)?.iter().rev() {
+            if record.get_timestamp() < cluster.first_timestamp - CLUSTER_MAX_GAP_SECS {
+                break;
+            }
+            if is_member(record) {
+                cluster.first_timestamp = record.get_timestamp();
+                older_members.push(record.get_id() as i64);
+            }
+        }
+        if window_start < (cluster.first_timestamp - CLUSTER_MAX_GAP_SECS) as i64 {
+            break;
+        }
+        window_end = window_start - 1;
+    }
+
+    // And forward from the last screenshot
+    let mut newer_members: Vec<i64> = vec![];
+    let mut window_start = cluster.last_timestamp as i64;
+    loop {
+        let window_end = window_start + EXTEND_WINDOW_SECS;
+        for record in find_in_range(window_start, window_end)? {
+            if record.get_timestamp() > cluster.last_timestamp + CLUSTER_MAX_GAP_SECS {
+                break;
+            }
+            if is_member(&record) {
+                cluster.last_timestamp = record.get_timestamp();
+                newer_members.push(record.get_id() as i64);
+            }
+        }
+        if window_end > (cluster.last_timestamp + CLUSTER_MAX_GAP_SECS) as i64 {
+            break;
+        }
+        window_start = window_end + 1;
+    }
+
+    older_members.reverse();
+    cluster.screenshot_ids = older_members.into_iter()
+        .chain(std::mem::take(&mut cluster.screenshot_ids))
+        .chain(newer_members)
+        .collect();
+
+    Ok(())
+
+}
+
+/**
+ * The hashes of each run of SHINGLE_WORDS words, ignoring case.  Text with fewer words is a
+ * single shingle.
+ */
+fn get_shingles(text: &str) -> HashSet<u64> {
+
+    let words: Vec<String> = text.split_whitespace().map(|word| word.to_lowercase()).collect();
+    if words.is_empty() {
+        return HashSet::new();
+    }
+
+    words.windows(SHINGLE_WORDS.min(words.len()))
+        .map(|shingle| {
+            let mut hasher = DefaultHasher::new();
+            shingle.hash(&mut hasher);
+            hasher.finish()
+        })
+        .collect()
+
+}
+
+/**
+ * The minimum of each of MINHASH_PERMUTATIONS hash functions over the shingles, or None if there
+ * are no shingles.  The fraction of equal minimums of two signatures estimates the Jaccard
+ * similarity of their shingles.
+ */
+fn get_minhash_signature(shingles: &HashSet<u64>) -> Option<Vec<u64>> {
+
+    if shingles.is_empty() {
+        return None;
+    }
+
+    let signature = (0..MINHASH_PERMUTATIONS)
+        .map(|permutation| {
+            let seed = (permutation as u64 + 1).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+            shingles.iter().map(|shingle| mix_hash(shingle ^ seed)).min().unwrap_or(u64::MAX)
+        })
+        .collect();
+
+    Some(signature)
+
+}
+
+/**
+ * The splitmix64 finalizer, which turns a seeded shingle hash into a hash that's independent
+ * of the other seeds
+ */
+fn mix_hash(value: u64) -> u64 {
+    let mut z = value;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+fn get_estimated_similarity(a: &[u64], b: &[u64]) -> f64 {
+    let equal = a.iter().zip(b).filter(|(a, b)| a == b).count();
+    equal as f64 / MINHASH_PERMUTATIONS as f64
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use super::{cluster_screenshots, extend_cluster, get_estimated_similarity, get_minhash_signature, get_shingles};
+    use crate::db;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    static REPORT: &str = "Quarterly report draft for the finance team with revenue by region, headcount by \
+        department, the forecast for next year, open questions from the last review, and the \
+        action items that were agreed on with the board before the summer";
+
+    #[test]
+    fn test_minhash_similarity() {
+
+        let signature = get_minhash_signature(&get_shingles(REPORT)).unwrap();
+
+        // Only the clock changed
+        let same = get_minhash_signature(&get_shingles(&format!("{} 10:42", REPORT))).unwrap();
+        assert!(get_estimated_similarity(&signature, &same) >= 0.8);
+
+        let different = get_minhash_signature(&get_shingles("Flight to Lisbon boarding at gate 12 in twenty minutes")).unwrap();
+        assert!(get_estimated_similarity(&signature, &different) < 0.2);
+
+        assert_eq!(get_minhash_signature(&get_shingles("  ")), None);
+    }
+
+    #[test]
+    fn test_cluster_screenshots() {
+
+        let tmp_dir = tempdir().unwrap();
+        let data_root = tmp_dir.path();
+        let db_filename = Path::new("test.db");
+        db::create_db(data_root, db_filename).unwrap();
+
+        // Three captures of the report a few seconds apart with a ticking clock, an unrelated
+        // screenshot, the report again an hour later, and two screenshots without text
+        let report_at = |second: i64| format!("{} 10:00:{:02}", REPORT, second % 60);
+        let captures = [(0, report_at(0)), (5, report_at(5)), (10, report_at(10)), (12, "Flight to Lisbon".to_string()), (3600, report_at(3600)), (3605, String::new()), (3610, String::new())];
+        let captures: Vec<(i64, &str)> = captures.iter().map(|(second, ocr_text)| (*second, ocr_text.as_str())).collect();
+        db::insert_test_screenshots(data_root, db_filename, &captures);
+
+        // In the order of a search ranking
+        let records = db::find_screenshots_by_ids(data_root, db_filename, &[2, 4, 1, 3, 5, 6, 7]).unwrap();
+        let clusters = cluster_screenshots(records);
+
+        let representatives: Vec<i32> = clusters.iter().map(|cluster| cluster.representative.get_id()).collect();
+        assert_eq!(representatives, vec![2, 4, 5, 6, 7]);
+        assert_eq!(clusters[0].screenshot_ids, vec![1, 2, 3]);
+        assert_eq!(clusters[0].size(), 3);
+        assert_eq!(clusters[0].last_timestamp - clusters[0].first_timestamp, 10);
+        assert_eq!(clusters[2].screenshot_ids, vec![5]);
+    }
+
+    #[test]
+    fn test_extend_cluster() {
+
+        let tmp_dir = tempdir().unwrap();
+        let data_root = tmp_dir.path();
+        let db_filename = Path::new("test.db");
+        db::create_db(data_root, db_filename).unwrap();
+
+        // The report was on screen for three hours, after something else and before a 5 minute break
+        let mut captures = vec![(0, "Flight to Lisbon".to_string())];
+        captures.extend((1..=360).map(|capture| (capture * 30, format!("{} {}", REPORT, capture))));
+        captures.push((360 * 30 + 300, REPORT.to_string()));
+        let captures: Vec<(i64, &str)> = captures.iter().map(|(second, ocr_text)| (*second, ocr_text.as_str())).collect();
+        db::insert_test_screenshots(data_root, db_filename, &captures);
+
+        // Only two of the captures were search hits
+        let mut clusters = cluster_screenshots(db::find_screenshots_by_ids(data_root, db_filename, &[100, 101]).unwrap());
+        extend_cluster(&mut clusters[0], |start_timestamp, end_timestamp| {
+            db::find_screenshots_in_range(data_root, db_filename, start_timestamp, end_timestamp, Some(0))
+        }).unwrap();
+
+        assert_eq!(clusters[0].representative.get_id(), 100);
+        assert_eq!(clusters[0].size(), 360);
+        assert_eq!(clusters[0].screenshot_ids, (2..=361).collect::<Vec<i64>>());
+        assert_eq!((clusters[0].last_timestamp - clusters[0].first_timestamp) as i64, 359 * 30);
+    }
+
+}
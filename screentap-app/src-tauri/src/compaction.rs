@@ -1,16 +1,241 @@
-extern crate screen_ocr_swift_rs;
-
-use std::path::PathBuf;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use chrono::{Duration, Local, NaiveDateTime, TimeZone, Timelike};
+use crate::config::{CompactionSegmentBy, CompactionWindow, ScreentapConfig};
 use crate::db;
-use rusqlite::params;
+use crate::error::ScreentapError;
+use crate::frame_format::FrameFormat;
+use crate::storage::StorageLayout;
+use crate::utils;
+use crate::video::{FrameExtractor, VideoEncoder};
+use rusqlite::{params, Connection, OptionalExtension};
 
 
-// The maximum number of image files allowed to accumulate before compacting to an MP4
-// 
-// TODO: make this configurable via toml file.  
-//       I need to make it larger for dev purposes, but it should be more like 100
+// The default maximum number of image files allowed to accumulate before compacting to an MP4.
+// This can be overridden with compaction_max_image_files in config.toml
 pub const DEFAULT_MAX_IMAGE_FILES: u32 = 500;
 
+// The default size of the loose screenshots that are compacted into one segment, for the
+// "size" segment boundary
+pub const DEFAULT_MAX_SEGMENT_MB: u64 = 200;
+
+// By default, loose screenshots older than this are compacted even if their segment isn't complete
+pub const DEFAULT_MAX_LOOSE_AGE_HOURS: u64 = 24;
+
+
+/**
+ * Where compaction cuts the loose screenshots into mp4 segments
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SegmentBoundary {
+
+    // Once there are more than this many loose screenshots, compact all of them into one segment
+    Count(u32),
+
+    // One segment per hour or day of local time, which is compacted once the window has passed
+    Window(CompactionWindow),
+
+    // Cut a segment once the loose screenshots add up to this many bytes
+    Size(u64),
+}
+
+/**
+ * Decides which loose screenshots are compacted together, see plan_segments()
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompactionPolicy {
+    pub boundary: SegmentBoundary,
+
+    // Loose screenshots older than this are compacted even if their segment isn't complete,
+    // so that a quiet week doesn't leave them uncompacted.  None to wait for the boundary.
+    pub max_loose_age: Option<Duration>,
+}
+
+impl CompactionPolicy {
+
+    pub fn from_config(config: &ScreentapConfig) -> Self {
+        let boundary = match config.compaction_segment_by {
+            CompactionSegmentBy::Count => SegmentBoundary::Count(config.compaction_max_image_files),
+            CompactionSegmentBy::Window => SegmentBoundary::Window(config.compaction_window),
+            CompactionSegmentBy::Size => SegmentBoundary::Size(config.compaction_max_segment_mb * 1024 * 1024),
+        };
+        let max_loose_age = match config.compaction_max_loose_age_hours {
+            0 => None,
+            hours => Some(Duration::hours(hours as i64)),
+        };
+        Self { boundary, max_loose_age }
+    }
+
+    /**
+     * Compact once there are more than max_image_files loose screenshots, with no max age
+     */
+    pub fn count(max_image_files: u32) -> Self {
+        Self { boundary: SegmentBoundary::Count(max_image_files), max_loose_age: None }
+    }
+
+}
+
+/**
+ * An un-compacted screenshot that is tracked in the DB
+ */
+#[derive(Debug, Clone)]
+struct LooseScreenshot {
+    file_path: PathBuf,
+
+    // When the screenshot was taken, in UTC
+    timestamp: NaiveDateTime,
+
+    // The size of the image file, which is only read for the "size" segment boundary
+    size_bytes: u64,
+}
+
+/**
+ * Get the start of the hour or day (in time zone tz) that a UTC timestamp falls in
+ */
+fn window_start<Tz: TimeZone>(window: CompactionWindow, timestamp: NaiveDateTime, tz: &Tz) -> NaiveDateTime {
+    let local = tz.from_utc_datetime(&timestamp).naive_local();
+    let start = match window {
+        CompactionWindow::Hour => local.date().and_hms_opt(local.hour(), 0, 0),
+        CompactionWindow::Day => local.date().and_hms_opt(0, 0, 0),
+    };
+    start.unwrap_or(local)
+}
+
+/**
+ * Split the loose screenshots, oldest first, into the segments that are ready to be compacted
+ * at time now.  Each segment is a range of indexes into loose_screenshots.  Screenshots after
+ * the last segment stay loose until their segment is complete.  Hour and day windows start on
+ * the hour and at midnight in time zone tz, which is Local outside of tests.
+ */
+fn plan_segments<Tz: TimeZone>(policy: &CompactionPolicy, loose_screenshots: &[LooseScreenshot], now: NaiveDateTime, tz: &Tz) -> Vec<Range<usize>> {
+
+    let mut segments = vec![];
+    let mut segment_start = 0;
+
+    match policy.boundary {
+        SegmentBoundary::Count(max_image_files) => {
+            if loose_screenshots.len() > max_image_files as usize {
+                segment_start = loose_screenshots.len();
+                segments.push(0..segment_start);
+            }
+        },
+        SegmentBoundary::Window(window) => {
+            let current_window = window_start(window, now, tz);
+            for (i, loose_screenshot) in loose_screenshots.iter().enumerate() {
+                // The current window is still filling up
+                let screenshot_window = window_start(window, loose_screenshot.timestamp, tz);
+                if screenshot_window >= current_window {
+                    break;
+                }
+                let next_in_same_window = loose_screenshots.get(i + 1)
+                    .is_some_and(|next| window_start(window, next.timestamp, tz) == screenshot_window);
+                if !next_in_same_window {
+                    segments.push(segment_start..i + 1);
+                    segment_start = i + 1;
+                }
+            }
+        },
+        SegmentBoundary::Size(max_segment_bytes) => {
+            let mut segment_bytes = 0;
+            for (i, loose_screenshot) in loose_screenshots.iter().enumerate() {
+                segment_bytes += loose_screenshot.size_bytes;
+                if segment_bytes >= max_segment_bytes {
+                    segments.push(segment_start..i + 1);
+                    segment_start = i + 1;
+                    segment_bytes = 0;
+                }
+            }
+        },
+    }
+
+    // Whatever is left over is compacted early once its oldest screenshot is too old
+    if let (Some(max_loose_age), Some(oldest)) = (policy.max_loose_age, loose_screenshots.get(segment_start)) {
+        if now - oldest.timestamp > max_loose_age {
+            segments.push(segment_start..loose_screenshots.len());
+        }
+    }
+
+    segments
+
+}
+
+
+/**
+ * The state of a compaction job in the compaction_jobs journal.  Each state is written
+ * before the next step starts, so that after a crash recover_incomplete_jobs() knows
+ * whether to resume or roll back the job.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompactionJobState {
+
+    // The mp4 may be missing or partially written, and no DB rows point to it
+    Pending,
+
+    // The mp4 was written and verified, but no DB rows point to it yet
+    Encoded,
+
+    // The DB rows point to the mp4, so the screenshot images can be deleted
+    Committed,
+}
+
+impl CompactionJobState {
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            CompactionJobState::Pending => "pending",
+            CompactionJobState::Encoded => "encoded",
+            CompactionJobState::Committed => "committed",
+        }
+    }
+
+    pub(crate) fn parse(state: &str) -> Option<CompactionJobState> {
+        match state {
+            "pending" => Some(CompactionJobState::Pending),
+            "encoded" => Some(CompactionJobState::Encoded),
+            "committed" => Some(CompactionJobState::Committed),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * A row in the compaction_jobs journal
+ */
+#[derive(Debug, Clone)]
+struct CompactionJob {
+    id: i64,
+    mp4_file_path: PathBuf,
+
+    // The screenshot images in frame order, ie png_files[N] is frame N of the mp4
+    png_files: Vec<PathBuf>,
+
+    state: CompactionJobState,
+}
+
+
+/**
+ * The result of CompactionHelper::reconcile(), which compares the screenshot images in
+ * the app data dir against the DB
+ */
+#[derive(Debug, Default, PartialEq)]
+pub struct ReconcileReport {
+
+    // PNG files that have no row in the DB.  These are never compacted or deleted.
+    pub untracked_pngs: Vec<PathBuf>,
+
+    // PNG files whose row already points to an existing mp4.  These were deleted.
+    pub deleted_compacted_pngs: Vec<PathBuf>,
+
+    // Ids of rows that are not compacted but whose PNG file is missing, so their screenshot is lost
+    pub missing_file_rows: Vec<i64>,
+}
+
+impl ReconcileReport {
+    pub fn is_empty(&self) -> bool {
+        self.untracked_pngs.is_empty() && self.deleted_compacted_pngs.is_empty() && self.missing_file_rows.is_empty()
+    }
+}
+
 
 /**
  * Compact screenshot images to MP4 video
@@ -18,183 +243,556 @@ pub const DEFAULT_MAX_IMAGE_FILES: u32 = 500;
 pub struct CompactionHelper {
     app_data_dir: PathBuf,
     db_filename_path: PathBuf,
-    max_image_files: u32,
+    policy: CompactionPolicy,
+    video_encoder: Box<dyn VideoEncoder>,
+    frame_extractor: Box<dyn FrameExtractor>,
 }
 
 impl CompactionHelper {
 
-    pub fn new(app_data_dir: PathBuf, db_filename_path: PathBuf, max_image_files: u32) -> Self {
+    pub fn new(app_data_dir: PathBuf, db_filename_path: PathBuf, policy: CompactionPolicy, video_encoder: Box<dyn VideoEncoder>, frame_extractor: Box<dyn FrameExtractor>) -> Result<Self, ScreentapError> {
 
         if !app_data_dir.is_dir() {
-            panic!("app_data_dir is not a directory");
+            return Err(ScreentapError::Io(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("app_data_dir {} is not a directory", app_data_dir.display())
+            )));
         }
 
-        Self {
+        Ok(Self {
             app_data_dir,
             db_filename_path,
-            max_image_files,
-        }
+            policy,
+            video_encoder,
+            frame_extractor,
+        })
+    }
+
+    /**
+     * Change the compaction policy, eg after the config is reloaded
+     */
+    pub fn set_policy(&mut self, policy: CompactionPolicy) {
+        self.policy = policy;
+    }
+
+    /**
+     * Change the video encoder and frame extractor, eg after the video backend is changed in the config
+     */
+    pub fn set_video_backend(&mut self, video_encoder: Box<dyn VideoEncoder>, frame_extractor: Box<dyn FrameExtractor>) {
+        self.video_encoder = video_encoder;
+        self.frame_extractor = frame_extractor;
+    }
+
+    fn storage_layout(&self) -> StorageLayout {
+        StorageLayout::new(&self.app_data_dir)
     }
 
-    fn count_png_files(&self) -> u32 {
-        let png_files = self.get_png_files();
-        png_files.len() as u32
+    fn get_db_conn(&self) -> Result<Connection, ScreentapError> {
+        db::get_db_conn(
+            self.app_data_dir.as_path(), 
+            self.db_filename_path.as_path()
+        )
     }
 
-    fn get_png_files(&self) -> Vec<PathBuf> {
+    /**
+     * Get the screenshot files in the media dir in any frame format, and any left in the top
+     * level of the app_data_dir
+     */
+    fn get_png_files(&self) -> Result<Vec<PathBuf>, ScreentapError> {
+        let layout = self.storage_layout();
         let mut png_files = Vec::new();
-        for entry in std::fs::read_dir(&self.app_data_dir).unwrap() {
-            let entry = entry.unwrap();
-            let path = entry.path();
-            if path.is_file() {
-                if let Some(ext) = path.extension() {
-                    if ext == "png" {
-                        png_files.push(path);
-                    }
-                }
+        for frame_format in FrameFormat::ALL {
+            png_files.extend(layout.list_media_files(frame_format.extension())?);
+        }
+        Ok(png_files)
+    }
+
+    /**
+     * Get the un-compacted screenshots that are tracked in the DB, as (id, file_path), in the
+     * order they were taken
+     */
+    fn get_uncompacted_screenshots(&self, conn: &Connection) -> Result<Vec<(i64, PathBuf)>, ScreentapError> {
+        let mut stmt = conn.prepare(
+            "SELECT id, file_path FROM documents WHERE mp4_file_path = '' ORDER BY timestamp, id"
+        )?;
+        let layout = self.storage_layout();
+        let screenshots = stmt.query_map([], |row| {
+            Ok((row.get::<_, i64>(0)?, layout.to_absolute(&row.get::<_, String>(1)?)))
+        })?.collect::<Result<Vec<_>, _>>()?;
+        Ok(screenshots)
+    }
+
+    /**
+     * Get the un-compacted screenshots with their timestamps, oldest first.  The file size is
+     * only read if the policy needs it, since this runs after every screenshot.  Pinned
+     * screenshots stay loose, see annotations::pin_screenshot().
+     */
+    fn get_loose_screenshots(&self, conn: &Connection) -> Result<Vec<LooseScreenshot>, ScreentapError> {
+        let mut stmt = conn.prepare(
+            "SELECT file_path, timestamp FROM documents WHERE mp4_file_path = '' AND id NOT IN (SELECT document_id FROM pinned_documents) ORDER BY timestamp, id"
+        )?;
+        let layout = self.storage_layout();
+        let read_size = matches!(self.policy.boundary, SegmentBoundary::Size(_));
+        let loose_screenshots = stmt.query_map([], |row| {
+            let file_path = layout.to_absolute(&row.get::<_, String>(0)?);
+            let size_bytes = if read_size {
+                file_path.metadata().map(|metadata| metadata.len()).unwrap_or(0)
+            } else {
+                0
+            };
+            // Screenshot timestamps are stored as seconds since the epoch
+            let timestamp = NaiveDateTime::from_timestamp_opt(row.get(1)?, 0).unwrap_or_default();
+            Ok(LooseScreenshot { file_path, timestamp, size_bytes })
+        })?.collect::<Result<Vec<_>, _>>()?;
+        Ok(loose_screenshots)
+    }
+
+    /**
+     * Get the segments that are ready to be compacted, as lists of screenshot images in frame
+     * order.  Rows whose image is missing are skipped and show up in reconcile() instead.
+     */
+    fn get_segments_to_compact(&self, conn: &Connection, now: NaiveDateTime) -> Result<Vec<Vec<LooseScreenshot>>, ScreentapError> {
+
+        // Count the un-compacted rows first, since it's cheaper than reading all of them
+        if let SegmentBoundary::Count(max_image_files) = self.policy.boundary {
+            let num_uncompacted: u32 = conn.query_row(
+                "SELECT COUNT(*) FROM documents WHERE mp4_file_path = '' AND id NOT IN (SELECT document_id FROM pinned_documents)",
+                [],
+                |row| row.get(0)
+            )?;
+            if num_uncompacted <= max_image_files && self.policy.max_loose_age.is_none() {
+                return Ok(vec![]);
             }
         }
-        png_files
+
+        let loose_screenshots = self.get_loose_screenshots(conn)?;
+
+        Ok(plan_segments(&self.policy, &loose_screenshots, now, &Local)
+            .into_iter()
+            .map(|segment| loose_screenshots[segment].iter()
+                .filter(|loose_screenshot| loose_screenshot.file_path.exists())
+                .cloned()
+                .collect::<Vec<_>>())
+            .filter(|segment| !segment.is_empty())
+            .collect())
+
+    }
+
+    /**
+     * Get the mp4 path for a segment, named after its first screenshot.  A suffix is added if
+     * that name is taken, eg when a count boundary splits the displays of a single capture.
+     */
+    fn get_segment_mp4_path(&self, segment_start: NaiveDateTime) -> Result<PathBuf, ScreentapError> {
+        let mp4_filename = utils::generate_filename(segment_start, "mp4");
+        let mut mp4_path = self.storage_layout().create_media_path(segment_start, &mp4_filename)?;
+        let mut suffix = 1;
+        while mp4_path.exists() || Self::get_partial_mp4_path(&mp4_path).exists() {
+            let mp4_filename = format!("{}_{}.mp4", segment_start.format("%Y_%m_%d_%H_%M_%S"), suffix);
+            mp4_path = mp4_path.with_file_name(mp4_filename);
+            suffix += 1;
+        }
+        Ok(mp4_path)
     }
 
     /**
-     * Get the .png files in the app_data_dir ordered chronologically, oldest to newest
+     * The mp4 is written here first and only renamed to the target after it was verified,
+     * so the target path never holds a half-written mp4
      */
-    fn get_png_files_chronologically(&self) -> Vec<PathBuf> {
-        let mut png_files = self.get_png_files();
-        png_files.sort_by_key(|a| a.metadata().unwrap().modified().unwrap());
-        png_files
+    fn get_partial_mp4_path(target_mp4_fn: &Path) -> PathBuf {
+        target_mp4_fn.with_extension("partial.mp4")
     }
 
-    fn update_db_rows_with_mp4_file(&self, png_files: &[PathBuf], target_mp4_fn: &str) {
+    /**
+     * Point the DB rows of the screenshots at their frame in the mp4, record the segment and
+     * mark the job as committed, all in a single transaction.  Screenshots that were pinned
+     * since the job started stay loose, and their frames in the mp4 are unused.
+     */
+    fn commit_job(&self, conn: &mut Connection, job_id: i64, png_files: &[PathBuf], target_mp4_fn: &Path) -> Result<(), ScreentapError> {
 
-        // Open connection to DB
-        let conn = db::get_db_conn(
-            self.app_data_dir.as_path(), 
-            self.db_filename_path.as_path()
-        );
+        // Paths are stored relative to the app_data_dir
+        let layout = self.storage_layout();
+        let target_mp4_fn_str = layout.to_relative_string(target_mp4_fn);
+
+        let tx = conn.transaction()?;
 
         // Loop over the image file paths, for each one, update the row to set the 
         // mp4_file_path to the target_mp4_fn for each matching row
         for (frame_id, png_file) in png_files.iter().enumerate() {
 
-            let png_file_str = png_file.to_str().unwrap();
+            let png_file_str = layout.to_relative_string(png_file);
 
             // Update the row to set the mp4_file_path to the target_mp4_fn for each matching row
-            let update_result = conn.execute(
-                "UPDATE documents SET mp4_file_path = ?, mp4_frame_id = ? WHERE file_path = ?",
-                params![target_mp4_fn, frame_id, png_file_str],
-            );
+            tx.execute(
+                "UPDATE documents SET mp4_file_path = ?, mp4_frame_id = ? WHERE file_path = ? AND id NOT IN (SELECT document_id FROM pinned_documents)",
+                params![target_mp4_fn_str, frame_id, png_file_str],
+            )?;
+
+        }
+
+        let size_bytes = std::fs::metadata(target_mp4_fn)?.len();
+        insert_segment(&tx, &target_mp4_fn_str, size_bytes)?;
+
+        set_job_state(&tx, job_id, CompactionJobState::Committed)?;
+
+        tx.commit()?;
+
+        Ok(())
+
+    }
 
-            match update_result {
-                Ok(_) => (),
-                Err(e) => panic!("Error setting mp4_file_path in DB: {} for png file {}", e, png_file_str),
+    /**
+     * The png files of a job whose screenshots point to its mp4, which are the ones that can be
+     * deleted once the job is committed
+     */
+    fn get_compacted_png_files(&self, conn: &Connection, png_files: &[PathBuf], target_mp4_fn: &Path) -> Result<Vec<PathBuf>, ScreentapError> {
+        let layout = self.storage_layout();
+        let target_mp4_fn_str = layout.to_relative_string(target_mp4_fn);
+        let mut stmt = conn.prepare("SELECT COUNT(*) FROM documents WHERE file_path = ?1 AND mp4_file_path = ?2")?;
+        let mut compacted_png_files = vec![];
+        for png_file in png_files {
+            let count: i64 = stmt.query_row(params![layout.to_relative_string(png_file), target_mp4_fn_str], |row| row.get(0))?;
+            if count > 0 {
+                compacted_png_files.push(png_file.clone());
             }
+        }
+        Ok(compacted_png_files)
+    }
 
+    /**
+     * Make sure the mp4 decodes to one frame per screenshot before anything points to it
+     */
+    fn verify_mp4(&self, mp4_file_path: &Path, expected_frames: usize) -> Result<(), ScreentapError> {
+        let frame_count = self.frame_extractor.count_frames(mp4_file_path)?;
+        if frame_count != expected_frames {
+            return Err(ScreentapError::Encode(format!(
+                "{} has {} frames, expected {}", mp4_file_path.display(), frame_count, expected_frames
+            )));
         }
+        Ok(())
+    }
+
+    /**
+     * Write the mp4 to the partial path, and only move it to the target path once it's verified
+     */
+    fn encode_and_verify(&self, png_files: &[PathBuf], target_mp4_fn: &Path) -> Result<(), ScreentapError> {
+        let partial_mp4_fn = Self::get_partial_mp4_path(target_mp4_fn);
+        self.video_encoder.encode(png_files, &partial_mp4_fn)?;
+        self.verify_mp4(&partial_mp4_fn, png_files.len())?;
+        std::fs::rename(&partial_mp4_fn, target_mp4_fn)?;
+        Ok(())
+    }
 
+    /**
+     * Undo a job that was not committed.  No DB rows point to its mp4, so the mp4 is
+     * deleted and the screenshot images are left for the next compaction.
+     */
+    fn roll_back_job(&self, conn: &Connection, job: &CompactionJob) -> Result<(), ScreentapError> {
+        for mp4_file_path in [Self::get_partial_mp4_path(&job.mp4_file_path), job.mp4_file_path.clone()] {
+            if mp4_file_path.exists() {
+                std::fs::remove_file(&mp4_file_path)?;
+            }
+        }
+        delete_job(conn, job.id)
     }
 
 
     /**
      * Is it time to run compaction?
      */
-    pub fn should_compact_screenshots(&self) -> bool {
-            
-        // Count the number of .png files in self.app_data_dir
-        let num_png_files = self.count_png_files();
-
-        num_png_files > self.max_image_files
+    pub fn should_compact_screenshots(&self, now: NaiveDateTime) -> Result<bool, ScreentapError> {
+        let conn = self.get_db_conn()?;
+        Ok(!self.get_segments_to_compact(&conn, now)?.is_empty())
     }
 
     /**
-     * Given a directory of images, write them to an mp4
-     * TODO: return a Result<>
+     * Compact every segment that the policy says is ready at time now, see plan_segments().
+     * Returns the mp4 files that were written.
      */
-    pub fn compact_screenshots_in_dir_to_mp4(&self, target_mp4_fn: PathBuf, use_bitrate_key: bool) {  
-        
-        screen_ocr_swift_rs::write_images_in_dir_to_mp4(
-            self.app_data_dir.to_str().unwrap(), 
-            target_mp4_fn.to_str().unwrap(),
-            use_bitrate_key
-        );
+    pub fn compact_screenshots_to_mp4(&self, now: NaiveDateTime) -> Result<Vec<PathBuf>, ScreentapError> {
+
+        // Get the un-compacted screenshots from the DB in the order they were taken.  Untracked
+        // png files in the app dir and screenshots that are already in an mp4 are never
+        // compacted, see reconcile().
+        let mut conn = self.get_db_conn()?;
+        let segments = self.get_segments_to_compact(&conn, now)?;
+
+        let mut mp4_files = vec![];
+        for segment in segments {
+            let target_mp4_fn = self.get_segment_mp4_path(segment[0].timestamp)?;
+            let png_files: Vec<PathBuf> = segment.into_iter().map(|loose_screenshot| loose_screenshot.file_path).collect();
+            self.compact_segment_to_mp4(&mut conn, &png_files, &target_mp4_fn)?;
+            mp4_files.push(target_mp4_fn);
+        }
+
+        Ok(mp4_files)
 
     }
 
     /**
-     * 1. Check if incoming is full (>= 150 images.  30 images per min, 5 mins)
-     * 2. Create target dir if it doesn’t exist
-     * 3. Create the MP4 file
-     * 4. In a single DB transaction, update all entries in the incoming directory to
-     *     1. Set IS_MP4 = True
-     *     2. Add the Frame ID
-     *     3. Update the filename to the MP4 file
-     * 5. Delete all entries in the incoming dir
+     * 1. Record a pending job in the compaction journal
+     * 2. Create the MP4 file, verify its frame count, and mark the job as encoded
+     * 3. In a single DB transaction, update all entries in the segment to
+     *     1. Add the Frame ID
+     *     2. Update the filename to the MP4 file
+     *    and record the segment and mark the job as committed
+     * 4. Delete the screenshot images, then remove the job from the journal
+     *
+     * png_files is passed to the encoder, so frame N of the mp4 is png_files[N].  If the app
+     * dies part way through, recover_incomplete_jobs() picks up from the journal.
      */
-    pub fn compact_screenshots_to_mp4(&self, target_mp4_fn: PathBuf, use_bitrate_key: bool) {
+    fn compact_segment_to_mp4(&self, conn: &mut Connection, png_files: &[PathBuf], target_mp4_fn: &Path) -> Result<(), ScreentapError> {
+
+        let job = insert_job(conn, target_mp4_fn, png_files)?;
 
-        if !self.should_compact_screenshots() {
-            return;
+        // Create an MP4 file for the png files
+        if let Err(e) = self.encode_and_verify(png_files, target_mp4_fn) {
+            println!("Error encoding {}, rolling back compaction job #{}: {}", target_mp4_fn.display(), job.id, e);
+            if let Err(rollback_error) = self.roll_back_job(conn, &job) {
+                println!("Error rolling back compaction job #{}: {}", job.id, rollback_error);
+            }
+            return Err(e);
         }
 
+        set_job_state(conn, job.id, CompactionJobState::Encoded)?;
 
-        // TODO: create the MP4 file in a subdirectory divided by date (year/month/day/hour)
-        //       but for now, just keep it flat
+        // Update the DB
+        self.commit_job(conn, job.id, png_files, target_mp4_fn)?;
 
-        // Get the list of png files in the app dir ordered chronologically.
-        // Even though we don't pass this in to the swift function, due to limitations
-        // of the rust->swift interface that cannot pass arrays of params, we can
-        // be assured that this list won't change because this is happening on 
-        // the same thread that is writing the screenshots to disk.  We can use this
-        // list for updating the DB
-        let png_files = self.get_png_files_chronologically();
+        // Delete the compacted png files
+        self.cleanup_screenshot_images(&self.get_compacted_png_files(conn, png_files, target_mp4_fn)?)?;
 
-        // Make sure these files are all in the DB, otherwise throw an error
+        delete_job(conn, job.id)
 
-        // TODO: it should skip compacting any png files that are already in an mp4 file, based on the DB state.
-        //       this prevents write multiplication in a worst case scenario where png images end up in multiple mp4 files.
-        //       Delete any PNG files that are on disk but already in an MP4 file according to the DB.
+    }
 
-        // Create an MP4 file for the png files in the directory
-        self.compact_screenshots_in_dir_to_mp4(target_mp4_fn.clone(), use_bitrate_key);
-        
-        // Update the DB
-        self.update_db_rows_with_mp4_file(&png_files, target_mp4_fn.to_str().unwrap());
+    /**
+     * Finish or undo any compaction jobs that were interrupted, eg by a crash.  This must run
+     * before the next compaction, on the thread that runs compaction.
+     *
+     * - Pending jobs are rolled back, since the mp4 may be incomplete
+     * - Encoded jobs are committed if the mp4 still verifies, otherwise rolled back
+     * - Committed jobs only need the remaining screenshot images deleted
+     */
+    pub fn recover_incomplete_jobs(&self) -> Result<(), ScreentapError> {
+
+        let mut conn = self.get_db_conn()?;
+
+        for job in get_jobs(&conn)? {
+
+            println!("Recovering compaction job #{} for {} in state {:?}", job.id, job.mp4_file_path.display(), job.state);
+
+            match job.state {
+                CompactionJobState::Pending => {
+                    self.roll_back_job(&conn, &job)?;
+                },
+                CompactionJobState::Encoded => {
+                    match self.verify_mp4(&job.mp4_file_path, job.png_files.len()) {
+                        Ok(()) => {
+                            self.commit_job(&mut conn, job.id, &job.png_files, &job.mp4_file_path)?;
+                            self.cleanup_screenshot_images(&self.get_compacted_png_files(&conn, &job.png_files, &job.mp4_file_path)?)?;
+                            delete_job(&conn, job.id)?;
+                        },
+                        Err(e) => {
+                            println!("Compacted mp4 failed verification, rolling back: {}", e);
+                            self.roll_back_job(&conn, &job)?;
+                        }
+                    }
+                },
+                CompactionJobState::Committed => {
+                    self.cleanup_screenshot_images(&self.get_compacted_png_files(&conn, &job.png_files, &job.mp4_file_path)?)?;
+                    delete_job(&conn, job.id)?;
+                },
+            }
+        }
+
+        Ok(())
+
+    }
+
+    /**
+     * Compare the screenshot images in the app data dir against the DB:
+     *
+     * - PNG files with no row are reported and left alone
+     * - PNG files whose row already points to an existing mp4 are deleted, eg when deleting
+     *   them failed after an earlier compaction
+     * - Un-compacted rows whose PNG file is missing are reported
+     */
+    pub fn reconcile(&self) -> Result<ReconcileReport, ScreentapError> {
+
+        let conn = self.get_db_conn()?;
+        let layout = self.storage_layout();
+        let mut report = ReconcileReport::default();
+
+        for png_file in self.get_png_files()? {
+
+            let mp4_file_path: Option<String> = conn.query_row(
+                "SELECT mp4_file_path FROM documents WHERE file_path = ?1",
+                params![layout.to_relative_string(&png_file)],
+                |row| row.get(0)
+            ).optional()?;
+
+            match mp4_file_path {
+                None => report.untracked_pngs.push(png_file),
+                Some(mp4_file_path) if !mp4_file_path.is_empty() && layout.to_absolute(&mp4_file_path).exists() => {
+                    std::fs::remove_file(&png_file)?;
+                    report.deleted_compacted_pngs.push(png_file);
+                },
+                Some(_) => {},
+            }
+        }
 
-        // Delete all png files in the incoming dir
-        // TODO: only enable this after we know we can access the images from the mp4 files
-        self.cleanup_screenshot_images(&png_files)
+        for (id, file_path) in self.get_uncompacted_screenshots(&conn)? {
+            if !file_path.exists() {
+                report.missing_file_rows.push(id);
+            }
+        }
 
+        Ok(report)
 
     }
 
-    fn cleanup_screenshot_images(&self, png_files: &Vec<PathBuf>) {
+    /**
+     * Delete the compacted png files.  This tries to delete every file even if some fail,
+     * and returns the first error.  Files that are already gone are skipped, since a
+     * recovered job may have deleted some of them before it was interrupted.
+     */
+    fn cleanup_screenshot_images(&self, png_files: &[PathBuf]) -> Result<(), ScreentapError> {
+        let mut first_error = None;
         for png_file in png_files {
-            std::fs::remove_file(png_file.as_path()).unwrap();
+            match std::fs::remove_file(png_file.as_path()) {
+                Ok(()) => {},
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+                Err(e) => {
+                    println!("Error deleting compacted png file {}: {}", png_file.display(), e);
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        match first_error {
+            Some(e) => Err(e.into()),
+            None => Ok(()),
         }
     }
 
 }
 
 
+/**
+ * Record a new pending job in the compaction journal
+ */
+fn insert_job(conn: &Connection, mp4_file_path: &Path, png_files: &[PathBuf]) -> Result<CompactionJob, ScreentapError> {
+
+    let png_files_json = serde_json::to_string(png_files)
+        .map_err(|e| ScreentapError::Encode(format!("Failed to serialize compaction job: {}", e)))?;
+    let now = Local::now().naive_utc();
+
+    conn.execute(
+        "INSERT INTO compaction_jobs (mp4_file_path, png_files, state, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, ?4)",
+        params![mp4_file_path.to_str().unwrap_or_default(), png_files_json, CompactionJobState::Pending.as_str(), now],
+    )?;
+
+    Ok(CompactionJob {
+        id: conn.last_insert_rowid(),
+        mp4_file_path: mp4_file_path.to_path_buf(),
+        png_files: png_files.to_vec(),
+        state: CompactionJobState::Pending,
+    })
+
+}
+
+/**
+ * Record a compacted mp4 in the segments table.  Called after the screenshots were pointed
+ * at the mp4, so the start and end come from their timestamps.
+ */
+fn insert_segment(conn: &Connection, mp4_file_path: &str, size_bytes: u64) -> Result<(), ScreentapError> {
+    conn.execute(
+        "INSERT INTO segments (mp4_file_path, start_timestamp, end_timestamp, frame_count, size_bytes, created_at)
+            SELECT ?1, MIN(timestamp), MAX(timestamp), COUNT(*), ?2, ?3
+            FROM documents WHERE mp4_file_path = ?1",
+        params![mp4_file_path, size_bytes as i64, Local::now().naive_utc()],
+    )?;
+    Ok(())
+}
+
+fn set_job_state(conn: &Connection, job_id: i64, state: CompactionJobState) -> Result<(), ScreentapError> {
+    conn.execute(
+        "UPDATE compaction_jobs SET state = ?1, updated_at = ?2 WHERE id = ?3",
+        params![state.as_str(), Local::now().naive_utc(), job_id],
+    )?;
+    Ok(())
+}
+
+fn delete_job(conn: &Connection, job_id: i64) -> Result<(), ScreentapError> {
+    conn.execute("DELETE FROM compaction_jobs WHERE id = ?1", params![job_id])?;
+    Ok(())
+}
+
+/**
+ * Get the jobs left in the compaction journal, oldest first
+ */
+fn get_jobs(conn: &Connection) -> Result<Vec<CompactionJob>, ScreentapError> {
+
+    let mut stmt = conn.prepare("SELECT id, mp4_file_path, png_files, state FROM compaction_jobs ORDER BY id")?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, String>(3)?,
+        ))
+    })?.collect::<Result<Vec<_>, _>>()?;
+
+    rows.into_iter().map(|(id, mp4_file_path, png_files_json, state)| {
+        let png_files: Vec<PathBuf> = serde_json::from_str(&png_files_json)
+            .map_err(|e| ScreentapError::Encode(format!("Invalid png_files in compaction job #{}: {}", id, e)))?;
+        let state = CompactionJobState::parse(&state)
+            .ok_or_else(|| ScreentapError::Encode(format!("Invalid state {} in compaction job #{}", state, id)))?;
+        Ok(CompactionJob {
+            id,
+            mp4_file_path: PathBuf::from(mp4_file_path),
+            png_files,
+            state,
+        })
+    }).collect()
+
+}
+
+
 
 #[cfg(test)]
 mod test {
 
-    use super::CompactionHelper;
-    use std::path::PathBuf;
+    use super::{plan_segments, CompactionHelper, CompactionJobState, CompactionPolicy, LooseScreenshot, SegmentBoundary};
+    use crate::config::CompactionWindow;
+    use crate::storage::StorageLayout;
+    use chrono::{Duration, FixedOffset, NaiveDate};
+    use std::path::{Path, PathBuf};
     use image::{ImageBuffer, Rgba};
     use rand::{Rng, thread_rng};
+    use crate::annotations;
     use crate::db;
+    use crate::config::ScreentapConfig;
+    use crate::video;
     use chrono::Local;
     use tempfile::tempdir;
     use std::collections::HashMap;
+    use crate::error::ScreentapError;
 
 
     // Use a small number of image files for testing, because I have to make
     // the images relatively big to avoid the isReadyForMoreMediaData=False error
     const MAX_IMAGE_FILES: u32 = 1;
 
+    fn create_test_video_encoder() -> Box<dyn video::VideoEncoder> {
+        let config = ScreentapConfig {
+            // Use bitrate key since this potentially runs on Github Actions, which runs 
+            // on hardware that doessn't support the AVVideoQualityKey
+            use_bitrate_key: true,
+            ..ScreentapConfig::default()
+        };
+        video::create_encoder(&config)
+    }
+
     /**
      * This tests the compact_screenshots_to_mp4() method
      */
@@ -214,12 +812,6 @@ mod test {
 
         // Create paths to test assets
         let db_filename = PathBuf::from("test.db");
-        let target_mp4_file = app_data_dir.join("test_compact_screenshots_to_mp4.mp4");
-
-        // Delete the target mp4 file if it exists
-        if target_mp4_file.exists() {
-            std::fs::remove_file(target_mp4_file.as_path()).unwrap();
-        }
 
         // Create a bunch of image files
         let image_file_paths = create_dummy_image_files(
@@ -228,12 +820,14 @@ mod test {
             true
         );
 
+        let frame_extractor = video::create_frame_extractor(&ScreentapConfig::default());
         let base64_images: HashMap<String, String> = image_file_paths.iter().map(
             |image_file_path| {
                 let base64_string = db::get_screenshot_as_base64_string(
                     image_file_path.as_path().to_str().unwrap(), // Convert PathBuf to &str
                     "", // Placeholder for any additional parameters
-                    -1  // Placeholder for any additional parameters
+                    -1,  // Placeholder for any additional parameters
+                    frame_extractor.as_ref()
                 );
                 (image_file_path.as_path().to_str().unwrap().to_string(), base64_string)
             }
@@ -250,17 +844,16 @@ mod test {
         let compaction_helper = CompactionHelper::new(
             app_data_dir.clone(), 
             db_filename.to_path_buf(),
-            MAX_IMAGE_FILES
-        );
+            CompactionPolicy::count(MAX_IMAGE_FILES),
+            create_test_video_encoder(),
+            video::create_frame_extractor(&ScreentapConfig::default())
+        ).unwrap();
 
-        // Run compaction
-        println!("Running compaction and saving to {:?}", target_mp4_file.as_path());
-        compaction_helper.compact_screenshots_to_mp4(
-            target_mp4_file.clone(),
-            // Use bitrate key since this potentially runs on Github Actions, which runs 
-            // on hardware that doessn't support the AVVideoQualityKey
-            true
-        );
+        // Run compaction, which writes all of the screenshots to a single segment
+        let mp4_files = compaction_helper.compact_screenshots_to_mp4(Local::now().naive_utc()).unwrap();
+        assert_eq!(mp4_files.len(), 1);
+        let target_mp4_file = mp4_files[0].clone();
+        println!("Compacted screenshots to {:?}", target_mp4_file.as_path());
 
         // Assert that the mp4 file was created and has non-zero size
         let metadata = std::fs::metadata(target_mp4_file.as_path()).unwrap();
@@ -283,7 +876,9 @@ mod test {
         let all_screenshots = db::get_all_screenshots(
             app_data_dir.as_path(), 
             db_filename.as_path(), 
-            1000
+            1000,
+            None,
+            frame_extractor.as_ref()
         );
 
         // Loop over screenshots and compare base64 images to values stored in base64_images
@@ -308,92 +903,322 @@ mod test {
     }
 
     /**
-     * Compact a hardcoded directory of image files to an mp4
+     * Only the screenshots tracked in the DB are compacted, in the order they were taken,
+     * while other images in the data root are left alone
      */
-    // #[test] // - disable this since it only works on my machine
-    #[allow(dead_code)]
-    fn test_compact_screenshots_in_harcoded_dir_to_mp4() {
+    #[test]
+    fn test_compact_screenshots_to_mp4_ignores_untracked_images() {
 
-        println!("Running test_compact_screenshots_in_harcoded_dir_to_mp4");
+        let tmp_dir = tempdir().unwrap();
+        let db_filename: PathBuf = PathBuf::from("test.db");
+        let app_data_dir = PathBuf::from(tmp_dir.path());
 
-        let images_dir = PathBuf::from("/Users/tleyden/Development/screentap/local_test_dataset/video_compression");
-        let db_filename: PathBuf = PathBuf::from("dummmy.db");
-        let target_mp4_file = images_dir.join("test_compact_screenshots_in_dir_to_mp4.mp4");
+        let image_file_paths = create_dummy_image_files(
+            &app_data_dir,
+            MAX_IMAGE_FILES + 1,
+            false
+        );
+        create_db_with_image_files(&image_file_paths, &app_data_dir, &db_filename);
 
-        if target_mp4_file.exists() {
-            std::fs::remove_file(target_mp4_file.as_path()).unwrap();
-        }
+        let untracked_png = app_data_dir.join("untracked.png");
+        ImageBuffer::<Rgba<u8>, Vec<u8>>::new(800, 600).save(&untracked_png).unwrap();
 
         let compaction_helper = CompactionHelper::new(
-            images_dir, 
-            db_filename,
-            1
-        );
+            app_data_dir.clone(),
+            db_filename.to_path_buf(),
+            CompactionPolicy::count(MAX_IMAGE_FILES),
+            create_test_video_encoder(),
+            video::create_frame_extractor(&ScreentapConfig::default())
+        ).unwrap();
 
-        compaction_helper.compact_screenshots_in_dir_to_mp4(
-            target_mp4_file,
-            // Use bitrate key since this potentially runs on Github Actions, which runs 
-            // on hardware that doessn't support the AVVideoQualityKey
-            false
-        );
+        let mp4_files = compaction_helper.compact_screenshots_to_mp4(Local::now().naive_utc()).unwrap();
+        assert_eq!(mp4_files.len(), 1);
+        assert!(std::fs::metadata(&mp4_files[0]).unwrap().len() > 0);
+
+        assert_screenshots_point_to_mp4_in_db(&image_file_paths, &app_data_dir, &db_filename, &mp4_files[0]);
+        assert_screenshot_files_deleted(&image_file_paths);
+        assert!(untracked_png.exists());
+
+    }
+
+    /**
+     * An encoder that writes a file that isn't a valid mp4, and an extractor that reports
+     * a fixed frame count, to exercise the journal without a real video backend
+     */
+    struct FakeVideoEncoder {}
+
+    impl video::VideoEncoder for FakeVideoEncoder {
+        fn encode(&self, _frames: &[PathBuf], target_mp4: &Path) -> Result<(), ScreentapError> {
+            std::fs::write(target_mp4, b"not really an mp4")?;
+            Ok(())
+        }
+    }
+
+    struct FakeFrameExtractor {
+        frame_count: usize,
+    }
+
+    impl video::FrameExtractor for FakeFrameExtractor {
+        fn extract_frame(&self, _mp4_path: &Path, _frame_id: i32) -> Result<Vec<u8>, ScreentapError> {
+            Err(ScreentapError::Encode("not supported".to_string()))
+        }
+        fn count_frames(&self, _mp4_path: &Path) -> Result<usize, ScreentapError> {
+            Ok(self.frame_count)
+        }
+    }
+
+    /**
+     * Returns the same PNG for every frame
+     */
+    struct StubFrameExtractor {
+        png_data: Vec<u8>,
+    }
+
+    impl video::FrameExtractor for StubFrameExtractor {
+        fn extract_frame(&self, _mp4_path: &Path, _frame_id: i32) -> Result<Vec<u8>, ScreentapError> {
+            Ok(self.png_data.clone())
+        }
+        fn count_frames(&self, _mp4_path: &Path) -> Result<usize, ScreentapError> {
+            Ok(0)
+        }
+    }
 
+    fn get_job_states(app_data_dir: &PathBuf, db_filename: &PathBuf) -> Vec<String> {
+        let conn = db::get_db_conn(app_data_dir, db_filename).unwrap();
+        let mut stmt = conn.prepare("SELECT state FROM compaction_jobs").unwrap();
+        let states = stmt.query_map([], |row| row.get(0)).unwrap();
+        states.collect::<Result<Vec<String>, _>>().unwrap()
     }
 
     /**
-     * This is an isolated test on the compact_screenshots_in_dir_to_mp4() method
+     * If the mp4 doesn't have one frame per screenshot, nothing should be deleted or updated
      */
     #[test]
-    fn test_compact_screenshots_in_dir_to_mp4() {
-        
-        println!("Running test_compact_screenshots_in_dir_to_mp4");
+    fn test_compaction_rolls_back_when_frame_count_mismatches() {
 
-        // Generate a random temp directory
         let tmp_dir = tempdir().unwrap();
-        let db_filename: PathBuf = PathBuf::from("test.db");
         let app_data_dir = PathBuf::from(tmp_dir.path());
+        let db_filename = PathBuf::from("test.db");
 
-        // Create the app_data_dir if it doesn't exist
-        if !app_data_dir.exists() {
-            std::fs::create_dir_all(app_data_dir.as_path()).unwrap();
+        let image_file_paths = create_dummy_image_files(&app_data_dir, MAX_IMAGE_FILES + 1, false);
+        create_db_with_image_files(&image_file_paths, &app_data_dir, &db_filename);
+
+        let compaction_helper = CompactionHelper::new(
+            app_data_dir.clone(),
+            db_filename.clone(),
+            CompactionPolicy::count(MAX_IMAGE_FILES),
+            Box::new(FakeVideoEncoder {}),
+            Box::new(FakeFrameExtractor { frame_count: image_file_paths.len() - 1 })
+        ).unwrap();
+
+        assert!(compaction_helper.compact_screenshots_to_mp4(Local::now().naive_utc()).is_err());
+
+        // The screenshots are untouched, and there is no mp4 (partial or not) or journal entry
+        assert!(StorageLayout::new(&app_data_dir).list_media_files("mp4").unwrap().is_empty());
+        assert!(image_file_paths.iter().all(|image_file_path| image_file_path.exists()));
+        assert!(get_job_states(&app_data_dir, &db_filename).is_empty());
+        for screenshot in db::get_all_screenshots(&app_data_dir, &db_filename, 1000, None, &FakeFrameExtractor { frame_count: 0 }).unwrap() {
+            assert_eq!(screenshot.get_mp4_file_path(), "");
         }
+    }
+
+    /**
+     * Simulate a crash at each point in the journal and check that recovery resumes or rolls back
+     */
+    #[test]
+    fn test_recover_incomplete_jobs() {
+
+        for state in [CompactionJobState::Pending, CompactionJobState::Encoded, CompactionJobState::Committed] {
+
+            let tmp_dir = tempdir().unwrap();
+            let app_data_dir = PathBuf::from(tmp_dir.path());
+            let db_filename = PathBuf::from("test.db");
+            let target_mp4_file = app_data_dir.join("test.mp4");
+
+            let image_file_paths = create_dummy_image_files(&app_data_dir, MAX_IMAGE_FILES + 1, false);
+            create_db_with_image_files(&image_file_paths, &app_data_dir, &db_filename);
+
+            let compaction_helper = CompactionHelper::new(
+                app_data_dir.clone(),
+                db_filename.clone(),
+                CompactionPolicy::count(MAX_IMAGE_FILES),
+                Box::new(FakeVideoEncoder {}),
+                Box::new(FakeFrameExtractor { frame_count: image_file_paths.len() })
+            ).unwrap();
+
+            // Replay the steps of compact_screenshots_to_mp4() up to the crash
+            let mut conn = db::get_db_conn(&app_data_dir, &db_filename).unwrap();
+            let job = super::insert_job(&conn, &target_mp4_file, &image_file_paths).unwrap();
+            match state {
+                CompactionJobState::Pending => {
+                    std::fs::write(CompactionHelper::get_partial_mp4_path(&target_mp4_file), b"half written").unwrap();
+                },
+                CompactionJobState::Encoded => {
+                    std::fs::write(&target_mp4_file, b"verified").unwrap();
+                    super::set_job_state(&conn, job.id, CompactionJobState::Encoded).unwrap();
+                },
+                CompactionJobState::Committed => {
+                    std::fs::write(&target_mp4_file, b"verified").unwrap();
+                    compaction_helper.commit_job(&mut conn, job.id, &image_file_paths, &target_mp4_file).unwrap();
+                    std::fs::remove_file(&image_file_paths[0]).unwrap();
+                },
+            }
 
-        let target_mp4_file = app_data_dir.join("test_compact_screenshots_in_dir_to_mp4.mp4");
-        if target_mp4_file.exists() {
-            std::fs::remove_file(target_mp4_file.as_path()).unwrap();
+            compaction_helper.recover_incomplete_jobs().unwrap();
+
+            assert!(get_job_states(&app_data_dir, &db_filename).is_empty());
+            assert!(!CompactionHelper::get_partial_mp4_path(&target_mp4_file).exists());
+
+            match state {
+                CompactionJobState::Pending => {
+                    assert!(!target_mp4_file.exists());
+                    assert!(image_file_paths.iter().all(|image_file_path| image_file_path.exists()));
+                },
+                CompactionJobState::Encoded | CompactionJobState::Committed => {
+                    assert!(target_mp4_file.exists());
+                    assert_screenshots_point_to_mp4_in_db(&image_file_paths, &app_data_dir, &db_filename, &target_mp4_file);
+                    assert_screenshot_files_deleted(&image_file_paths);
+                },
+            }
         }
+    }
 
-        create_dummy_image_files(
-            &app_data_dir, 
-            MAX_IMAGE_FILES + 1,
-            false
-        );
-    
-        let compaction_helper = CompactionHelper::new(
-            app_data_dir.clone(), 
-            db_filename.to_path_buf(),
-            MAX_IMAGE_FILES
-        );
+    /**
+     * A screenshot pinned while its segment is being encoded stays loose, and keeps its png
+     */
+    #[test]
+    fn test_compaction_skips_screenshots_pinned_during_the_job() {
 
-        compaction_helper.compact_screenshots_in_dir_to_mp4(
-            target_mp4_file.clone(),
-            // Use bitrate key since this potentially runs on Github Actions, which runs 
-            // on hardware that doessn't support the AVVideoQualityKey
-            true 
-        );
+        let tmp_dir = tempdir().unwrap();
+        let app_data_dir = PathBuf::from(tmp_dir.path());
+        let db_filename = PathBuf::from("test.db");
+        let target_mp4_file = app_data_dir.join("test.mp4");
 
-        // Assert that the mp4 file was created
-        assert!(target_mp4_file.exists());
+        let image_file_paths = create_dummy_image_files(&app_data_dir, 3, false);
+        create_db_with_image_files(&image_file_paths, &app_data_dir, &db_filename);
 
-        // Assert that the mp4 has nonzero size
-        let metadata = std::fs::metadata(target_mp4_file.as_path()).unwrap();
-        let mp4_file_size = metadata.len();
-        assert!(mp4_file_size > 0);
+        let compaction_helper = CompactionHelper::new(
+            app_data_dir.clone(),
+            db_filename.clone(),
+            CompactionPolicy::count(MAX_IMAGE_FILES),
+            Box::new(FakeVideoEncoder {}),
+            Box::new(FakeFrameExtractor { frame_count: image_file_paths.len() })
+        ).unwrap();
+
+        // The segment was planned before the first screenshot was tagged
+        let now = Local::now().naive_utc().timestamp();
+        let mut conn = db::get_db_conn(&app_data_dir, &db_filename).unwrap();
+        annotations::add_tag(&app_data_dir, &db_filename, 1, "keep", now, &FakeFrameExtractor { frame_count: 0 }).unwrap();
+        compaction_helper.compact_segment_to_mp4(&mut conn, &image_file_paths, &target_mp4_file).unwrap();
+
+        let screenshots = db::find_screenshots_by_ids(&app_data_dir, &db_filename, &[1, 2, 3]).unwrap();
+        assert_eq!(screenshots[0].get_mp4_file_path(), "");
+        assert!(image_file_paths[0].exists());
+        for (screenshot, image_file_path) in screenshots[1..].iter().zip(&image_file_paths[1..]) {
+            assert_eq!(Path::new(screenshot.get_mp4_file_path()), target_mp4_file.as_path());
+            assert!(!image_file_path.exists());
+        }
+
+        // Tagging a compacted screenshot unpacks its frame, so it's no longer in the mp4
+        let png_data = b"not really a png".to_vec();
+        annotations::add_tag(&app_data_dir, &db_filename, 2, "keep", now, &StubFrameExtractor { png_data: png_data.clone() }).unwrap();
+        let screenshot = db::find_screenshots_by_ids(&app_data_dir, &db_filename, &[2]).unwrap().remove(0);
+        assert_eq!(screenshot.get_mp4_file_path(), "");
+        assert_eq!(std::fs::read(screenshot.get_file_path()).unwrap(), png_data);
+    }
+
+    #[test]
+    fn test_reconcile() {
 
-        // TODO: assert that the mp4 file has expected number of frames.  Could use 
-        // a swift bridge for this
+        let tmp_dir = tempdir().unwrap();
+        let app_data_dir = PathBuf::from(tmp_dir.path());
+        let db_filename = PathBuf::from("test.db");
+        let target_mp4_file = app_data_dir.join("test.mp4");
 
+        let image_file_paths = create_dummy_image_files(&app_data_dir, 3, false);
+        create_db_with_image_files(&image_file_paths, &app_data_dir, &db_filename);
 
+        let compaction_helper = CompactionHelper::new(
+            app_data_dir.clone(),
+            db_filename.clone(),
+            CompactionPolicy::count(MAX_IMAGE_FILES),
+            Box::new(FakeVideoEncoder {}),
+            Box::new(FakeFrameExtractor { frame_count: 1 })
+        ).unwrap();
+
+        // 0.png was compacted, but deleting it failed
+        std::fs::write(&target_mp4_file, b"verified").unwrap();
+        let mut conn = db::get_db_conn(&app_data_dir, &db_filename).unwrap();
+        let job = super::insert_job(&conn, &target_mp4_file, &image_file_paths[0..1]).unwrap();
+        compaction_helper.commit_job(&mut conn, job.id, &image_file_paths[0..1], &target_mp4_file).unwrap();
+
+        // 1.png was lost before it was compacted
+        std::fs::remove_file(&image_file_paths[1]).unwrap();
+
+        // A png that was never saved to the DB
+        let untracked_png = app_data_dir.join("untracked.png");
+        std::fs::copy(&image_file_paths[2], &untracked_png).unwrap();
+
+        let report = compaction_helper.reconcile().unwrap();
+
+        assert_eq!(report.untracked_pngs, vec![untracked_png.clone()]);
+        assert_eq!(report.deleted_compacted_pngs, vec![image_file_paths[0].clone()]);
+        assert_eq!(report.missing_file_rows.len(), 1);
+        assert!(!image_file_paths[0].exists());
+        assert!(untracked_png.exists());
+        assert!(image_file_paths[2].exists());
+
+        // Only the tracked, un-compacted screenshot is compacted, and the untracked png is ignored
+        let segments = compaction_helper.get_segments_to_compact(&conn, Local::now().naive_utc()).unwrap();
+        assert_eq!(segments.len(), 1);
+        let png_files: Vec<PathBuf> = segments[0].iter().map(|loose_screenshot| loose_screenshot.file_path.clone()).collect();
+        assert_eq!(png_files, vec![image_file_paths[2].clone()]);
+
+        // The committed mp4 was recorded as a segment
+        let frame_count: i64 = conn.query_row("SELECT frame_count FROM segments", [], |row| row.get(0)).unwrap();
+        assert_eq!(frame_count, 1);
+    }
 
+    #[test]
+    fn test_plan_segments() {
+
+        let start = NaiveDate::from_ymd_opt(2024, 3, 7).unwrap().and_hms_opt(10, 0, 0).unwrap();
+
+        // One 100 byte screenshot every 20 minutes, from 10:00 to 12:40
+        let loose_screenshots: Vec<LooseScreenshot> = (0..9).map(|i| LooseScreenshot {
+            file_path: PathBuf::from(format!("{}.png", i)),
+            timestamp: start + Duration::minutes(20 * i),
+            size_bytes: 100,
+        }).collect();
+        let now = start + Duration::minutes(20 * 8 + 5);
+        let utc = FixedOffset::east_opt(0).unwrap();
+
+        // Nothing is due until there are more than 9 screenshots, or the oldest is too old
+        let count_policy = CompactionPolicy::count(9);
+        assert!(plan_segments(&count_policy, &loose_screenshots, now, &utc).is_empty());
+        assert_eq!(plan_segments(&CompactionPolicy::count(8), &loose_screenshots, now, &utc), vec![0..9]);
+        let stale_policy = CompactionPolicy { max_loose_age: Some(Duration::hours(2)), ..count_policy };
+        assert_eq!(plan_segments(&stale_policy, &loose_screenshots, now, &utc), vec![0..9]);
+
+        // Every 400 bytes, and the last 100 bytes stay loose
+        let size_policy = CompactionPolicy { boundary: SegmentBoundary::Size(400), max_loose_age: None };
+        assert_eq!(plan_segments(&size_policy, &loose_screenshots, now, &utc), vec![0..4, 4..8]);
+
+        // The hours before 12:00 are complete
+        let hour_policy = CompactionPolicy { boundary: SegmentBoundary::Window(CompactionWindow::Hour), max_loose_age: None };
+        assert_eq!(plan_segments(&hour_policy, &loose_screenshots, now, &utc), vec![0..3, 3..6]);
+
+        // At UTC+05:30 the screenshots are from 15:30 to 18:10, so the hours before 18:00 are complete
+        let india = FixedOffset::east_opt(5 * 3600 + 30 * 60).unwrap();
+        assert_eq!(plan_segments(&hour_policy, &loose_screenshots, now, &india), vec![0..2, 2..5, 5..8]);
+
+        // The day is still in progress
+        let day_policy = CompactionPolicy { boundary: SegmentBoundary::Window(CompactionWindow::Day), max_loose_age: None };
+        assert!(plan_segments(&day_policy, &loose_screenshots, now, &utc).is_empty());
+
+        // At UTC-11:00 the first 3 screenshots are from the day before
+        let samoa = FixedOffset::west_opt(11 * 3600).unwrap();
+        assert_eq!(plan_segments(&day_policy, &loose_screenshots, now, &samoa), vec![0..3]);
     }
 
     #[test]
@@ -401,21 +1226,31 @@ mod test {
 
         println!("Running test_should_compact_screenshots()");
         
-        let app_data_dir = PathBuf::from("/tmp");
+        let tmp_dir = tempdir().unwrap();
+        let app_data_dir = PathBuf::from(tmp_dir.path());
         let db_filename_path = PathBuf::from("test.db");
 
-        create_dummy_image_files(
+        let image_file_paths = create_dummy_image_files(
             &app_data_dir, 
             MAX_IMAGE_FILES + 1,
             false,
-    );
+        );
+
+        // Only screenshots that are tracked in the DB count towards compaction
+        create_db_with_image_files(
+            &image_file_paths,
+            &app_data_dir,
+            &db_filename_path
+        );
     
         let compaction_helper = CompactionHelper::new(
             app_data_dir.clone(), 
             db_filename_path.to_path_buf(),
-            MAX_IMAGE_FILES
-        );
-        let result = compaction_helper.should_compact_screenshots();
+            CompactionPolicy::count(MAX_IMAGE_FILES),
+            create_test_video_encoder(),
+            video::create_frame_extractor(&ScreentapConfig::default())
+        ).unwrap();
+        let result = compaction_helper.should_compact_screenshots(Local::now().naive_utc()).unwrap();
         assert_eq!(result, true);
     }
 
@@ -425,7 +1260,9 @@ mod test {
         let screenshot_records_result = db::get_all_screenshots(
             app_data_dir.as_path(), 
             db_filename_path, 
-            1000
+            1000,
+            None,
+            video::create_frame_extractor(&ScreentapConfig::default()).as_ref()
         );
 
         let screenshot_records = match screenshot_records_result {
@@ -487,7 +1324,8 @@ mod test {
                 "fake ocr text",
                 &app_data_dir,
                 db_filename_path,
-                now
+                now,
+                &db::CaptureMeta::default()
             );
         
             match save_result {
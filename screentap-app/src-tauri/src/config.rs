@@ -0,0 +1,396 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+use crate::compaction;
+use crate::frame_cache;
+use crate::frame_format::{self, FrameFormat};
+use crate::storage_stats;
+use crate::tiering;
+use crate::video;
+use crate::error::ScreentapError;
+
+
+static CONFIG_FILENAME: &str = "config.toml";
+
+/**
+ * What part of the screen to capture
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptureMode {
+
+    // Capture each display in full
+    FullScreen,
+
+    // Crop each capture to the frontmost window, which keeps background windows
+    // out of the OCR text
+    ActiveWindow,
+}
+
+/**
+ * What the capture stage should do when the OCR queue is full
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackpressurePolicy {
+
+    // Drop the new capture so the capture schedule is never delayed
+    DropNewest,
+
+    // Wait for room in the queue, which delays the next capture
+    Block,
+}
+
+/**
+ * How compaction cuts the loose screenshots into mp4 segments, see compaction::CompactionPolicy
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CompactionSegmentBy {
+
+    // Once there are more than compaction_max_image_files loose screenshots
+    Count,
+
+    // One segment per compaction_window of local time
+    Window,
+
+    // Once the loose screenshots add up to compaction_max_segment_mb
+    Size,
+}
+
+/**
+ * The wall-clock window of a segment when compacting by window
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CompactionWindow {
+    Hour,
+    Day,
+}
+
+/**
+ * What to do when the data root uses more than storage_quota_mb
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageQuotaAction {
+
+    // Stop capturing until space is freed or the quota is raised
+    PauseCapture,
+
+    // Delete the oldest compacted history until the data root fits in the quota again
+    DeleteOldest,
+}
+
+/**
+ * Which video encoder and frame extractor compaction uses
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VideoBackend {
+
+    // AVFoundation on macOS, ffmpeg everywhere else
+    Auto,
+
+    // AVFoundation via the swift bridge.  Only available on macOS.
+    Avfoundation,
+
+    // An ffmpeg subprocess, see ffmpeg_path
+    Ffmpeg,
+}
+
+/**
+ * Core screentap settings, loaded from config.toml in the app data dir.
+ *
+ * Every field has a default so that a missing or partial config.toml still
+ * gives a working app.  See config_sample.toml for documentation of each field.
+ */
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ScreentapConfig {
+
+    // How long to sleep between screen captures
+    pub capture_interval_secs: u64,
+
+    // The on-disk format of screenshots until they are compacted
+    pub frame_format: FrameFormat,
+
+    // The quality of jpeg and avif screenshots, from 1 to 100
+    pub frame_quality: u8,
+
+    // The ids of the displays to capture.  If empty, all connected displays are captured.
+    pub capture_displays: Vec<u32>,
+
+    // The default capture mode
+    pub capture_mode: CaptureMode,
+
+    // Capture mode overrides keyed by app bundle identifier, eg "com.google.Chrome"
+    pub capture_mode_per_app: HashMap<String, CaptureMode>,
+
+    // How many frames can wait between each stage of the capture pipeline.  Only read at startup.
+    pub pipeline_queue_capacity: usize,
+
+    // What to do when the OCR stage falls behind the capture stage
+    pub pipeline_backpressure: BackpressurePolicy,
+
+    // How compaction decides which loose screenshots go in the same mp4
+    pub compaction_segment_by: CompactionSegmentBy,
+
+    // The maximum number of image files allowed to accumulate before compacting to an MP4
+    pub compaction_max_image_files: u32,
+
+    // The wall-clock window of each mp4 when compacting by window
+    pub compaction_window: CompactionWindow,
+
+    // The size of the loose screenshots in each mp4 when compacting by size
+    pub compaction_max_segment_mb: u64,
+
+    // Loose screenshots older than this are compacted regardless of the segment boundary.  0 disables it.
+    pub compaction_max_loose_age_hours: u64,
+
+    // Use AVVideoAverageBitRateKey instead of AVVideoQualityKey when writing mp4 files,
+    // since the latter crashes on certain machines
+    pub use_bitrate_key: bool,
+
+    // The video backend used to write and read the compacted mp4 files
+    pub video_backend: VideoBackend,
+
+    // The ffmpeg binary used by the ffmpeg video backend
+    pub ffmpeg_path: String,
+
+    // The maximum number of frames between keyframes in the compacted mp4 files
+    pub keyframe_interval: u32,
+
+    // How much memory the cache of frames extracted from mp4 files can use
+    pub frame_cache_max_mb: u64,
+
+    // Re-encode old segments at a lower resolution, keeping only frames that changed.  This
+    // is lossy, so it's off by default.
+    pub tiering_enabled: bool,
+
+    // Segments older than this many days are downsampled to half resolution
+    pub tiering_tier1_after_days: u32,
+
+    // Segments older than this many days are downsampled to quarter resolution
+    pub tiering_tier2_after_days: u32,
+
+    // The most disk space the DB and media can use.  0 means no quota.
+    pub storage_quota_mb: u64,
+
+    // What to do when the quota is exceeded
+    pub storage_quota_action: StorageQuotaAction,
+
+    // Capture is paused while the disk with the data root has less than this free.  0 disables it.
+    pub min_free_disk_mb: u64,
+
+    // Compute text embeddings of the OCR text for semantic search
+    pub embeddings_enabled: bool,
+
+    // The dir of the sentence embedding model.  Empty means models/<default model> in the app data dir.
+    pub embedding_model_dir: String,
+
+    // Semantic search hits must have a cosine similarity to the search term above this
+    pub embedding_min_similarity: f32,
+
+    // The weights of text relevance, recency and dwell time in the score of exact search hits
+    pub ranking_text_weight: f64,
+    pub ranking_recency_weight: f64,
+    pub ranking_dwell_weight: f64,
+
+    // The recency score of a search hit halves every this many days
+    pub ranking_recency_half_life_days: f64,
+
+    // How many exact hits are scored for each result.  More finds older hits with a long dwell time.
+    pub ranking_candidates_per_result: u32,
+
+    // Regexes of text that's blacked out in exported frames and removed from exported OCR text
+    pub export_redact_patterns: Vec<String>,
+
+    // Bundle identifiers of apps whose screenshots are exported as blank frames without text
+    pub export_redact_apps: Vec<String>,
+
+}
+
+impl Default for ScreentapConfig {
+    fn default() -> Self {
+        ScreentapConfig {
+            capture_interval_secs: 30,
+            frame_format: FrameFormat::Png,
+            frame_quality: frame_format::DEFAULT_FRAME_QUALITY,
+            capture_displays: vec![],
+            capture_mode: CaptureMode::FullScreen,
+            capture_mode_per_app: HashMap::new(),
+            pipeline_queue_capacity: 8,
+            pipeline_backpressure: BackpressurePolicy::DropNewest,
+            compaction_segment_by: CompactionSegmentBy::Count,
+            compaction_max_image_files: compaction::DEFAULT_MAX_IMAGE_FILES,
+            compaction_window: CompactionWindow::Hour,
+            compaction_max_segment_mb: compaction::DEFAULT_MAX_SEGMENT_MB,
+            compaction_max_loose_age_hours: compaction::DEFAULT_MAX_LOOSE_AGE_HOURS,
+            use_bitrate_key: false,
+            video_backend: VideoBackend::Auto,
+            ffmpeg_path: video::DEFAULT_FFMPEG_PATH.to_string(),
+            keyframe_interval: video::DEFAULT_KEYFRAME_INTERVAL,
+            frame_cache_max_mb: frame_cache::DEFAULT_MAX_MB,
+            tiering_enabled: false,
+            tiering_tier1_after_days: tiering::DEFAULT_TIER1_AFTER_DAYS,
+            tiering_tier2_after_days: tiering::DEFAULT_TIER2_AFTER_DAYS,
+            storage_quota_mb: 0,
+            storage_quota_action: StorageQuotaAction::PauseCapture,
+            min_free_disk_mb: storage_stats::DEFAULT_MIN_FREE_DISK_MB,
+            embeddings_enabled: false,
+            embedding_model_dir: "".to_string(),
+            embedding_min_similarity: 0.25,
+            ranking_text_weight: 1.0,
+            ranking_recency_weight: 0.3,
+            ranking_dwell_weight: 0.75,
+            ranking_recency_half_life_days: 7.0,
+            ranking_candidates_per_result: 16,
+            export_redact_patterns: vec![],
+            export_redact_apps: vec![],
+        }
+    }
+}
+
+impl ScreentapConfig {
+
+    pub fn get_config_path(app_data_dir: &Path) -> PathBuf {
+        app_data_dir.join(CONFIG_FILENAME)
+    }
+
+    /**
+     * Load the config from the app data dir, falling back to defaults if there is no config.toml.
+     *
+     * Unlike the defaults, an existing but broken config.toml is an error so that the
+     * caller can decide whether to keep the previous config.
+     */
+    pub fn load(app_data_dir: &Path) -> Result<ScreentapConfig, ScreentapError> {
+
+        let config_path = ScreentapConfig::get_config_path(app_data_dir);
+        if !config_path.exists() {
+            return Ok(ScreentapConfig::default());
+        }
+
+        let config_str = fs::read_to_string(&config_path)
+            .map_err(|e| ScreentapError::Config(format!("Failed to read {}: {}", config_path.display(), e)))?;
+
+        let config = toml::from_str::<ScreentapConfig>(&config_str)
+            .map_err(|e| ScreentapError::Config(format!("Failed to parse {}: {}", config_path.display(), e)))?;
+
+        config.validate().map_err(ScreentapError::Config)?;
+
+        Ok(config)
+    }
+
+    /**
+     * Get the capture mode for the given frontmost app, taking per-app overrides into account
+     */
+    pub fn capture_mode_for_app(&self, frontmost_app: &str) -> CaptureMode {
+        self.capture_mode_per_app
+            .get(frontmost_app)
+            .copied()
+            .unwrap_or(self.capture_mode)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.capture_interval_secs == 0 {
+            return Err("capture_interval_secs must be greater than 0".to_string());
+        }
+        if !(1..=100).contains(&self.frame_quality) {
+            return Err("frame_quality must be between 1 and 100".to_string());
+        }
+        if self.frame_format == FrameFormat::Avif && video::resolve_backend(self.video_backend) != VideoBackend::Avfoundation {
+            return Err("frame_format = \"avif\" needs the avfoundation video backend, since ffmpeg compaction can't read avif screenshots".to_string());
+        }
+        if self.pipeline_queue_capacity == 0 {
+            return Err("pipeline_queue_capacity must be greater than 0".to_string());
+        }
+        if self.compaction_max_image_files == 0 {
+            return Err("compaction_max_image_files must be greater than 0".to_string());
+        }
+        if self.compaction_max_segment_mb == 0 {
+            return Err("compaction_max_segment_mb must be greater than 0".to_string());
+        }
+        if self.video_backend == VideoBackend::Avfoundation && !cfg!(target_os = "macos") {
+            return Err("video_backend = \"avfoundation\" is only supported on macOS".to_string());
+        }
+        if self.video_backend != VideoBackend::Avfoundation && self.ffmpeg_path.is_empty() {
+            return Err("ffmpeg_path must not be empty".to_string());
+        }
+        if self.keyframe_interval == 0 {
+            return Err("keyframe_interval must be greater than 0".to_string());
+        }
+        if self.tiering_tier2_after_days <= self.tiering_tier1_after_days {
+            return Err("tiering_tier2_after_days must be greater than tiering_tier1_after_days".to_string());
+        }
+        if [self.ranking_text_weight, self.ranking_recency_weight, self.ranking_dwell_weight].iter().any(|weight| *weight < 0.0) {
+            return Err("ranking_text_weight, ranking_recency_weight and ranking_dwell_weight must not be negative".to_string());
+        }
+        if self.ranking_recency_half_life_days <= 0.0 {
+            return Err("ranking_recency_half_life_days must be greater than 0".to_string());
+        }
+        if !(0.0..1.0).contains(&self.embedding_min_similarity) {
+            return Err("embedding_min_similarity must be at least 0 and less than 1".to_string());
+        }
+        if self.ranking_candidates_per_result == 0 {
+            return Err("ranking_candidates_per_result must be greater than 0".to_string());
+        }
+        for pattern in &self.export_redact_patterns {
+            if let Err(e) = regex::Regex::new(pattern) {
+                return Err(format!("export_redact_patterns has an invalid regex {}: {}", pattern, e));
+            }
+        }
+        Ok(())
+    }
+
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use super::{CaptureMode, ScreentapConfig};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_load_missing_config_uses_defaults() {
+        let tmp_dir = tempdir().unwrap();
+        let config = ScreentapConfig::load(tmp_dir.path()).unwrap();
+        assert_eq!(config, ScreentapConfig::default());
+    }
+
+    #[test]
+    fn test_load_partial_config() {
+        let tmp_dir = tempdir().unwrap();
+        let config_path = ScreentapConfig::get_config_path(tmp_dir.path());
+        std::fs::write(config_path, "capture_interval_secs = 10\n").unwrap();
+
+        let config = ScreentapConfig::load(tmp_dir.path()).unwrap();
+        assert_eq!(config.capture_interval_secs, 10);
+        assert_eq!(config.compaction_max_image_files, ScreentapConfig::default().compaction_max_image_files);
+    }
+
+    #[test]
+    fn test_capture_mode_per_app() {
+        let config: ScreentapConfig = toml::from_str(r#"
+            capture_mode = "active_window"
+
+            [capture_mode_per_app]
+            "com.apple.finder" = "full_screen"
+        "#).unwrap();
+
+        assert_eq!(config.capture_mode_for_app("com.apple.finder"), CaptureMode::FullScreen);
+        assert_eq!(config.capture_mode_for_app("com.google.Chrome"), CaptureMode::ActiveWindow);
+    }
+
+    #[test]
+    fn test_load_invalid_config_is_error() {
+        let tmp_dir = tempdir().unwrap();
+        let config_path = ScreentapConfig::get_config_path(tmp_dir.path());
+        std::fs::write(config_path, "capture_interval_secs = 0\n").unwrap();
+
+        assert!(ScreentapConfig::load(tmp_dir.path()).is_err());
+    }
+
+}
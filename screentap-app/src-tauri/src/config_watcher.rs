@@ -0,0 +1,144 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use tauri::Manager;
+
+use crate::config::ScreentapConfig;
+use crate::plugins::focusguard::config::FocusGuardConfig;
+
+
+/**
+ * How often to check the config files for changes
+ */
+static CONFIG_POLL_INTERVAL_SECS: u64 = 2;
+
+/**
+ * Tauri events emitted to all windows when a config file changes
+ */
+pub static CONFIG_RELOADED_EVENT: &str = "config-reloaded-event";
+pub static CONFIG_ERROR_EVENT: &str = "config-error-event";
+
+
+/**
+ * A validated config that should be applied to the running app
+ */
+#[derive(Debug, Clone)]
+pub enum ConfigUpdate {
+    Core(ScreentapConfig),
+    FocusGuard(FocusGuardConfig),
+}
+
+/**
+ * Watches the core and plugin config files and sends validated updates over a channel.
+ *
+ * The files are polled by modification time rather than using filesystem notifications,
+ * since editors often replace a file on save rather than writing it in place.
+ */
+pub struct ConfigWatcher {
+    app_data_dir: PathBuf,
+    core_config_path: PathBuf,
+    focusguard_config_path: PathBuf,
+}
+
+impl ConfigWatcher {
+
+    pub fn new(app_data_dir: PathBuf) -> Self {
+        let core_config_path = ScreentapConfig::get_config_path(&app_data_dir);
+        let focusguard_config_path = FocusGuardConfig::get_config_path(&app_data_dir);
+        Self {
+            app_data_dir,
+            core_config_path,
+            focusguard_config_path,
+        }
+    }
+
+    /**
+     * Spawn the watcher thread.  Each changed file is loaded and validated, and only sent on
+     * the channel if valid.  On failure, the previous config stays in effect and an error
+     * event is emitted so the UI can show it.
+     */
+    pub fn spawn(self, app_handle: tauri::AppHandle, sender: Sender<ConfigUpdate>) {
+
+        thread::spawn(move || {
+
+            let mut last_core_mtime = get_mtime(&self.core_config_path);
+            let mut last_focusguard_mtime = get_mtime(&self.focusguard_config_path);
+
+            loop {
+
+                thread::sleep(Duration::from_secs(CONFIG_POLL_INTERVAL_SECS));
+
+                let core_mtime = get_mtime(&self.core_config_path);
+                if core_mtime != last_core_mtime {
+                    last_core_mtime = core_mtime;
+                    let result = ScreentapConfig::load(&self.app_data_dir)
+                        .map(ConfigUpdate::Core)
+                        .map_err(|e| e.to_string());
+                    if !self.handle_reload_result(&app_handle, &sender, &self.core_config_path, result) {
+                        // The receiving end is gone, so the capture loop has exited
+                        return;
+                    }
+                }
+
+                let focusguard_mtime = get_mtime(&self.focusguard_config_path);
+                if focusguard_mtime != last_focusguard_mtime {
+                    last_focusguard_mtime = focusguard_mtime;
+
+                    // A deleted plugin config is ignored rather than disabling the running plugin
+                    if focusguard_mtime.is_none() {
+                        continue;
+                    }
+
+                    let result = FocusGuardConfig::load(&self.app_data_dir).map(ConfigUpdate::FocusGuard);
+                    if !self.handle_reload_result(&app_handle, &sender, &self.focusguard_config_path, result) {
+                        return;
+                    }
+                }
+
+            }
+        });
+
+    }
+
+    /**
+     * Send a valid config update or emit an error event.  Returns false if the receiver has hung up.
+     */
+    fn handle_reload_result(&self, app_handle: &tauri::AppHandle, sender: &Sender<ConfigUpdate>, config_path: &Path, result: Result<ConfigUpdate, String>) -> bool {
+
+        match result {
+            Ok(update) => {
+                println!("Reloading config from {}", config_path.display());
+                if sender.send(update).is_err() {
+                    println!("Config update receiver hung up, stopping config watcher");
+                    return false;
+                }
+                emit_config_event(app_handle, CONFIG_RELOADED_EVENT, config_path, None);
+            },
+            Err(e) => {
+                println!("Ignoring invalid config {} and keeping previous config: {}", config_path.display(), e);
+                emit_config_event(app_handle, CONFIG_ERROR_EVENT, config_path, Some(&e));
+            }
+        }
+        true
+
+    }
+
+}
+
+fn get_mtime(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn emit_config_event(app_handle: &tauri::AppHandle, event_name: &str, config_path: &Path, error: Option<&str>) {
+
+    let payload = serde_json::json!({
+        "path": config_path.to_str().unwrap_or(""),
+        "error": error,
+    });
+
+    if let Err(e) = app_handle.emit_all(event_name, payload) {
+        eprintln!("Error emitting event {}: {}", event_name, e);
+    }
+
+}
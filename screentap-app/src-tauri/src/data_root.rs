@@ -0,0 +1,346 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use fs2::FileExt;
+use rusqlite::params;
+use sha2::{Digest, Sha256};
+
+use crate::db;
+use crate::error::ScreentapError;
+use crate::storage::StorageLayout;
+
+
+/**
+ * The file in the app data dir that holds the path of the data root, ie the dir with the
+ * screenshot DB and media.  If it doesn't exist, the data root is the app data dir itself.
+ */
+static DATA_ROOT_POINTER_FILENAME: &str = "data_root";
+
+/**
+ * The file in the app data dir that the running app and move_data_dir() hold an exclusive
+ * lock on, so that the data root is never moved while screenshots are being written to it.
+ */
+static LOCK_FILENAME: &str = "screentap.lock";
+
+
+/**
+ * Get the data root for the given app data dir.  Config files always stay in the app data
+ * dir, while the DB and media live in the data root, which can be moved with move_data_dir().
+ */
+pub fn resolve_data_root(app_data_dir: &Path) -> PathBuf {
+
+    let pointer_path = app_data_dir.join(DATA_ROOT_POINTER_FILENAME);
+    let data_root = match std::fs::read_to_string(&pointer_path) {
+        Ok(data_root) => data_root,
+        Err(_) => return app_data_dir.to_path_buf(),
+    };
+
+    let data_root = data_root.trim();
+    if data_root.is_empty() {
+        println!("Ignoring empty data root in {}", pointer_path.display());
+        return app_data_dir.to_path_buf();
+    }
+
+    PathBuf::from(data_root)
+}
+
+/**
+ * Point the app data dir at a new data root.  The pointer file is written to a temp file
+ * and renamed, so it always holds either the old or the new data root.
+ */
+pub fn set_data_root(app_data_dir: &Path, data_root: &Path) -> Result<(), ScreentapError> {
+
+    let data_root_str = data_root.to_str()
+        .ok_or_else(|| ScreentapError::Config(format!("Data root {} is not valid UTF-8", data_root.display())))?;
+
+    let pointer_path = app_data_dir.join(DATA_ROOT_POINTER_FILENAME);
+    let tmp_pointer_path = pointer_path.with_extension("tmp");
+
+    std::fs::write(&tmp_pointer_path, format!("{}\n", data_root_str))?;
+    File::open(&tmp_pointer_path)?.sync_all()?;
+    std::fs::rename(&tmp_pointer_path, &pointer_path)?;
+
+    Ok(())
+}
+
+/**
+ * An exclusive lock on the app data dir, which is released when this is dropped
+ */
+#[derive(Debug)]
+pub struct AppDataDirLock {
+    _lock_file: File,
+}
+
+/**
+ * Lock the app data dir, or fail right away if the app or a move of the data root holds it.
+ * The OS releases the lock if the process dies, so a crash never leaves it locked.
+ */
+pub fn lock_app_data_dir(app_data_dir: &Path) -> Result<AppDataDirLock, ScreentapError> {
+
+    let lock_file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(app_data_dir.join(LOCK_FILENAME))?;
+
+    match lock_file.try_lock_exclusive() {
+        Ok(()) => Ok(AppDataDirLock { _lock_file: lock_file }),
+        Err(e) if e.kind() == fs2::lock_contended_error().kind() => Err(ScreentapError::Config(
+            format!("{} is in use.  Quit screentap or wait for the data dir move to finish, then try again.", app_data_dir.display())
+        )),
+        Err(e) => Err(ScreentapError::Io(e)),
+    }
+}
+
+
+#[derive(Debug, Default)]
+pub struct MoveDataDirReport {
+    pub files_copied: usize,
+    pub bytes_copied: u64,
+    pub paths_rewritten: usize,
+}
+
+/**
+ * Move the DB and media from the current data root to new_data_root, then switch to it.
+ *
+ * 1. Lock the app data dir, which fails if the app is running
+ * 2. Bring the DB up to date, so that every path in it is relative to the data root
+ * 3. Checkpoint the DB's write-ahead log into the DB file, so copying the file copies every row
+ * 4. Copy the DB and the media dir to new_data_root
+ * 5. Verify the SHA-256 checksum of every copied file
+ * 6. Rewrite any absolute paths into the old data root in the copied DB
+ * 7. Atomically switch the data root pointer
+ *
+ * Nothing is deleted, so the old data root can be removed by hand once the app works
+ * from the new one.
+ */
+pub fn move_data_dir(app_data_dir: &Path, db_filename: &Path, new_data_root: &Path) -> Result<MoveDataDirReport, ScreentapError> {
+
+    let _lock = lock_app_data_dir(app_data_dir)?;
+
+    let old_data_root = resolve_data_root(app_data_dir);
+
+    if !new_data_root.is_absolute() {
+        return Err(ScreentapError::Config(format!("The new data root {} must be an absolute path", new_data_root.display())));
+    }
+    if new_data_root.starts_with(&old_data_root) || old_data_root.starts_with(new_data_root) {
+        return Err(ScreentapError::Config(format!("The new data root {} overlaps the current data root {}", new_data_root.display(), old_data_root.display())));
+    }
+    if new_data_root.exists() && std::fs::read_dir(new_data_root)?.next().is_some() {
+        return Err(ScreentapError::Config(format!("The new data root {} is not empty", new_data_root.display())));
+    }
+
+    // Migrate old DBs so that the media is under the media dir, and paths are relative
+    db::create_db(&old_data_root, db_filename)?;
+
+    // An interrupted compaction or tiering job references paths in the old data root, so let the app recover it first
+    let conn = db::get_db_conn(&old_data_root, db_filename)?;
+    let pending_compaction_jobs: i64 = conn.query_row("SELECT COUNT(*) FROM compaction_jobs", [], |row| row.get(0))?;
+    if pending_compaction_jobs > 0 {
+        return Err(ScreentapError::Config("There is an unfinished compaction.  Start screentap once to finish it, then try again.".to_string()));
+    }
+    let pending_tiering_jobs: i64 = conn.query_row("SELECT COUNT(*) FROM tiering_jobs", [], |row| row.get(0))?;
+    if pending_tiering_jobs > 0 {
+        return Err(ScreentapError::Config("There is an unfinished downsampling of old history.  Start screentap once to finish it, then try again.".to_string()));
+    }
+
+    // A no-op unless the DB is in WAL mode.  Nothing else can write while the lock is held.
+    let checkpoint_busy: i64 = conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |row| row.get(0))?;
+    if checkpoint_busy != 0 {
+        return Err(ScreentapError::Config("The DB is in use and its write-ahead log could not be checkpointed.  Quit screentap, then try again.".to_string()));
+    }
+    drop(conn);
+
+    let mut report = MoveDataDirReport::default();
+
+    let mut files_to_copy = vec![old_data_root.join(db_filename)];
+    files_to_copy.extend(StorageLayout::new(&old_data_root).list_media_files_recursive()?);
+
+    std::fs::create_dir_all(new_data_root)?;
+    for source in &files_to_copy {
+
+        let relative_path = source.strip_prefix(&old_data_root)
+            .map_err(|_| ScreentapError::Config(format!("{} is outside of the data root", source.display())))?;
+        let target = new_data_root.join(relative_path);
+        if let Some(target_dir) = target.parent() {
+            std::fs::create_dir_all(target_dir)?;
+        }
+
+        report.bytes_copied += std::fs::copy(source, &target)?;
+        File::open(&target)?.sync_all()?;
+
+        if sha256_file(source)? != sha256_file(&target)? {
+            return Err(ScreentapError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Checksum mismatch after copying {} to {}", source.display(), target.display())
+            )));
+        }
+
+        report.files_copied += 1;
+    }
+
+    report.paths_rewritten = rewrite_absolute_paths(new_data_root, db_filename, &old_data_root)?;
+
+    set_data_root(app_data_dir, new_data_root)?;
+
+    Ok(report)
+
+}
+
+/**
+ * Rewrite paths that point into the old data root to be relative, so they resolve against
+ * the new data root.  Returns the number of rows changed.
+ */
+fn rewrite_absolute_paths(data_root: &Path, db_filename: &Path, old_data_root: &Path) -> Result<usize, ScreentapError> {
+
+    let old_layout = StorageLayout::new(old_data_root);
+    let mut conn = db::get_db_conn(data_root, db_filename)?;
+
+    let rows = {
+        let mut stmt = conn.prepare("SELECT id, file_path, mp4_file_path FROM documents")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?))
+        })?.collect::<Result<Vec<_>, _>>()?;
+        rows
+    };
+
+    let tx = conn.transaction()?;
+    let mut paths_rewritten = 0;
+    for (id, file_path, mp4_file_path) in rows {
+        let new_file_path = old_layout.to_relative_string(Path::new(&file_path));
+        let new_mp4_file_path = old_layout.to_relative_string(Path::new(&mp4_file_path));
+        if new_file_path != file_path || new_mp4_file_path != mp4_file_path {
+            tx.execute(
+                "UPDATE documents SET file_path = ?1, mp4_file_path = ?2 WHERE id = ?3",
+                params![new_file_path, new_mp4_file_path, id],
+            )?;
+            paths_rewritten += 1;
+        }
+    }
+    tx.commit()?;
+
+    Ok(paths_rewritten)
+
+}
+
+fn sha256_file(path: &Path) -> std::io::Result<Vec<u8>> {
+    let mut hasher = Sha256::new();
+    let mut reader = BufReader::new(File::open(path)?);
+    loop {
+        let buffer = reader.fill_buf()?;
+        if buffer.is_empty() {
+            break;
+        }
+        hasher.update(buffer);
+        let len = buffer.len();
+        reader.consume(len);
+    }
+    Ok(hasher.finalize().to_vec())
+}
+
+
+#[derive(Debug, Default)]
+pub struct VerifyDataDirReport {
+    pub screenshots_checked: usize,
+
+    // (screenshot id, path) of every screenshot whose png or mp4 is missing
+    pub missing_files: Vec<(i64, PathBuf)>,
+}
+
+/**
+ * Check that the png or mp4 of every screenshot in the DB exists under the data root
+ */
+pub fn verify_data_dir(app_data_dir: &Path, db_filename: &Path) -> Result<VerifyDataDirReport, ScreentapError> {
+
+    let data_root = resolve_data_root(app_data_dir);
+    let layout = StorageLayout::new(&data_root);
+
+    if !data_root.join(db_filename).exists() {
+        return Err(ScreentapError::Config(format!("No screentap DB found in {}", data_root.display())));
+    }
+
+    let conn = db::get_db_conn(&data_root, db_filename)?;
+    let mut stmt = conn.prepare("SELECT id, file_path, mp4_file_path FROM documents ORDER BY id")?;
+    let rows = stmt.query_map([], |row| {
+        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?))
+    })?;
+
+    let mut report = VerifyDataDirReport::default();
+    for row in rows {
+        let (id, file_path, mp4_file_path) = row?;
+        let media_path = if mp4_file_path.is_empty() {
+            layout.to_absolute(&file_path)
+        } else {
+            layout.to_absolute(&mp4_file_path)
+        };
+        if !media_path.exists() {
+            report.missing_files.push((id, media_path));
+        }
+        report.screenshots_checked += 1;
+    }
+
+    Ok(report)
+
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use super::{lock_app_data_dir, move_data_dir, resolve_data_root, verify_data_dir};
+    use crate::db;
+    use crate::error::ScreentapError;
+    use chrono::NaiveDate;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_move_data_dir() {
+
+        let app_data_dir = tempdir().unwrap();
+        let external_volume = tempdir().unwrap();
+        let new_data_root = external_volume.path().join("screentap");
+        let db_filename = Path::new("test.db");
+
+        // A DB with one screenshot in the default data root, ie the app data dir
+        let layout = crate::storage::StorageLayout::new(app_data_dir.path());
+        let now = NaiveDate::from_ymd_opt(2024, 3, 7).unwrap().and_hms_opt(12, 30, 0).unwrap();
+        let png_path = layout.create_media_path(now, "a.png").unwrap();
+        std::fs::write(&png_path, b"png").unwrap();
+        db::create_db(app_data_dir.path(), db_filename).unwrap();
+
+        // The screenshot is only in the write-ahead log while another connection keeps it open
+        let wal_conn = db::get_db_conn(app_data_dir.path(), db_filename).unwrap();
+        wal_conn.pragma_update(None, "journal_mode", "WAL").unwrap();
+        db::save_screenshot_meta(&png_path, "text", app_data_dir.path(), db_filename, now, &db::CaptureMeta::default()).unwrap();
+
+        // Refused while the running app holds the lock
+        let app_lock = lock_app_data_dir(app_data_dir.path()).unwrap();
+        let result = move_data_dir(app_data_dir.path(), db_filename, &new_data_root);
+        assert!(matches!(result, Err(ScreentapError::Config(_))));
+        assert!(!new_data_root.exists());
+        drop(app_lock);
+
+        let report = move_data_dir(app_data_dir.path(), db_filename, &new_data_root).unwrap();
+        assert_eq!(report.files_copied, 2);
+
+        assert_eq!(resolve_data_root(app_data_dir.path()), new_data_root);
+        assert!(new_data_root.join("media/2024/03/07/a.png").exists());
+
+        // The copied DB has the screenshot without its write-ahead log
+        assert!(!new_data_root.join("test.db-wal").exists());
+        let conn = db::get_db_conn(&new_data_root, db_filename).unwrap();
+        let num_documents: i64 = conn.query_row("SELECT COUNT(*) FROM documents", [], |row| row.get(0)).unwrap();
+        assert_eq!(num_documents, 1);
+        drop(wal_conn);
+
+        let verify_report = verify_data_dir(app_data_dir.path(), db_filename).unwrap();
+        assert_eq!(verify_report.screenshots_checked, 1);
+        assert!(verify_report.missing_files.is_empty());
+
+        // The old data root is left in place, and moving into a non-empty dir is refused
+        assert!(png_path.exists());
+        assert!(move_data_dir(app_data_dir.path(), db_filename, &new_data_root).is_err());
+    }
+
+}
@@ -1,12 +1,53 @@
-extern crate screen_ocr_swift_rs;
-
-use rusqlite::{params, Connection, Result};
+use rusqlite::{params, Connection, OptionalExtension};
 use chrono::NaiveDateTime;
+#[cfg(test)]
+use chrono::NaiveDate;
 use std::{path::Path, collections::HashMap, path::PathBuf};
 use base64::engine::Engine as _;
 use base64::engine::general_purpose::STANDARD as BASE64;
 use backtrace::Backtrace;
+use crate::error::ScreentapError;
+use crate::frame_cache;
+use crate::frame_format::FrameFormat;
+use crate::native::{DisplayInfo, WindowRect};
+use crate::storage::{self, StorageLayout};
+use crate::video::FrameExtractor;
+
+
+/**
+ * The PRAGMA user_version of a DB whose OCR index is kept in sync with documents by triggers.
+ * Follows storage::RELATIVE_MEDIA_PATHS_DB_VERSION.
+ */
+pub const FTS_TRIGGERS_DB_VERSION: i32 = 2;
+
+/**
+ * The PRAGMA user_version of a DB that also has the trigram OCR index, see search::SearchMode
+ */
+pub const FTS_TRIGRAM_DB_VERSION: i32 = 3;
+
+/**
+ * The full-text indexes of the OCR text, which are all external content tables of documents
+ */
+static OCR_INDEXES: [&str; 2] = ["ocr_text_index", "ocr_trigram_index"];
+
 
+/**
+ * Struct to represent where a screenshot was captured from, which is saved
+ * alongside the screenshot in the DB
+ */
+#[derive(Debug, Clone, Default)]
+pub struct CaptureMeta {
+
+    // The display the screenshot was captured from
+    pub display: DisplayInfo,
+
+    // If the screenshot was cropped to the frontmost window, the window's visible bounds
+    // in global display coordinates.  None for full screen captures.
+    pub window_rect: Option<WindowRect>,
+
+    // The bundle identifier of the frontmost app at capture time
+    pub frontmost_app: String,
+}
 
 /**
  * Struct to represent screenshot records in the DB
@@ -32,23 +73,48 @@ pub struct ScreenshotRecord {
 
     // The screenshot image as a base64 string
     base64_image: String,
+
+    // The display this screenshot was captured from, and its geometry in global display coordinates
+    display_id: u32,
+    display_x: i32,
+    display_y: i32,
+    display_width: i32,
+    display_height: i32,
+
+    // The window the screenshot was cropped to, or all zeros for full screen captures
+    window_x: i32,
+    window_y: i32,
+    window_width: i32,
+    window_height: i32,
+
+    // The bundle identifier of the frontmost app at capture time
+    frontmost_app: String,
+
+    // The format of the screenshot file before it was compacted, see frame_format::FrameFormat
+    image_format: String,
 }
 
 impl ScreenshotRecord {
     
-    // Used by unit tests
-    #[allow(dead_code)]
+    pub fn get_id(&self) -> i32 {
+        self.id
+    }
+
+    pub fn get_timestamp(&self) -> i32 {
+        self.timestamp
+    }
+
+    pub fn get_ocr_text(&self) -> &str {
+        &self.ocr_text
+    }
+
     pub fn get_file_path(&self) -> &str {
         &self.file_path
     }
-    // Used by unit tests
-    #[allow(dead_code)]
     pub fn get_mp4_file_path(&self) -> &str {
         &self.mp4_file_path
     }
 
-    // Used by unit tests
-    #[allow(dead_code)]
     pub fn get_mp4_frame_id(&self) -> i32 {
         self.mp4_frame_id
     }
@@ -58,8 +124,13 @@ impl ScreenshotRecord {
         &self.base64_image
     }
 
+    pub fn get_display_id(&self) -> u32 {
+        self.display_id
+    }
 
-
+    pub fn get_frontmost_app(&self) -> &str {
+        &self.frontmost_app
+    }
 
 }
 
@@ -82,21 +153,44 @@ pub fn screenshot_record_to_hashmap(record: &ScreenshotRecord) -> HashMap<String
     map.insert("mp4_file_path".to_string(), record.mp4_file_path.clone());
     map.insert("mp4_frame_id".to_string(), record.mp4_frame_id.to_string());  // TODO: this should be an i32 rather than a String
     map.insert("base64_image".to_string(), record.base64_image.clone());
+    map.insert("display_id".to_string(), record.display_id.to_string());
+    map.insert("display_x".to_string(), record.display_x.to_string());
+    map.insert("display_y".to_string(), record.display_y.to_string());
+    map.insert("display_width".to_string(), record.display_width.to_string());
+    map.insert("display_height".to_string(), record.display_height.to_string());
+    map.insert("window_x".to_string(), record.window_x.to_string());
+    map.insert("window_y".to_string(), record.window_y.to_string());
+    map.insert("window_width".to_string(), record.window_width.to_string());
+    map.insert("window_height".to_string(), record.window_height.to_string());
+    map.insert("frontmost_app".to_string(), record.frontmost_app.clone());
+    map.insert("image_format".to_string(), record.image_format.clone());
+    map.insert("image_mime_type".to_string(), get_image_mime_type(record).to_string());
     map
 }
 
-pub fn get_db_conn(dataset_root: &Path, db_filename: &Path) -> Connection {
+/**
+ * The mime type of a record's base64 image.  Frames extracted from an mp4 are always PNG,
+ * whatever format the screenshot was in before it was compacted.
+ */
+fn get_image_mime_type(record: &ScreenshotRecord) -> &'static str {
+    if !record.mp4_file_path.is_empty() {
+        return FrameFormat::Png.mime_type();
+    }
+    FrameFormat::parse(&record.image_format).unwrap_or(FrameFormat::Png).mime_type()
+}
+
+pub fn get_db_conn(dataset_root: &Path, db_filename: &Path) -> Result<Connection, ScreentapError> {
     let db_filename_fq_path = dataset_root.join(db_filename);
-    let path_str = db_filename_fq_path.to_str().expect("Failed to get db_filename_fq_path");
-    Connection::open(path_str).expect("Failed to open db connection")
+    let conn = Connection::open(db_filename_fq_path)?;
+    Ok(conn)
 }
 
 /**
  * Helper function to create the DB if it doesn't exist
  */
-pub fn create_db(dataset_root: &Path, db_filename: &Path) -> Result<()> {
+pub fn create_db(dataset_root: &Path, db_filename: &Path) -> Result<(), ScreentapError> {
 
-    let conn = get_db_conn(dataset_root, db_filename);
+    let mut conn = get_db_conn(dataset_root, db_filename)?;
 
     // Create a table with the desired columns
     conn.execute(
@@ -120,12 +214,330 @@ pub fn create_db(dataset_root: &Path, db_filename: &Path) -> Result<()> {
         [],
     )?;
 
+    // Indexes every 3 character substring of the OCR text, so that words split or misread by
+    // OCR can still be found.  Needs sqlite 3.34 or later.
+    conn.execute(
+        "CREATE VIRTUAL TABLE IF NOT EXISTS ocr_trigram_index USING fts5(
+            content='documents',
+            ocr_text,
+            content_rowid='id',
+            tokenize='trigram'
+        );",
+        [],
+    )?;
+
+    // Keep the OCR indexes in sync with documents, since they're external content tables.  Updates
+    // only touch the indexes when the OCR text changes, not when compaction moves a screenshot.
+    for index in OCR_INDEXES {
+        create_ocr_index_triggers(&conn, index)?;
+    }
+
     // Create a UNIQUE index on the file_path column
     conn.execute(
         "CREATE UNIQUE INDEX IF NOT EXISTS file_path_index ON documents (file_path)",
         [],
     )?;
 
+    // Journal of in-progress compactions, see compaction::CompactionJobState
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS compaction_jobs (
+                id INTEGER PRIMARY KEY,
+                mp4_file_path TEXT NOT NULL,
+                png_files TEXT NOT NULL,
+                state TEXT NOT NULL,
+                created_at TIMESTAMP NOT NULL,
+                updated_at TIMESTAMP NOT NULL
+            )",
+        [],
+    )?;
+
+    // One row per compacted mp4, see compaction::CompactionPolicy.  The start and end are the
+    // timestamps of the first and last screenshot in the mp4, in seconds like documents.timestamp.
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS segments (
+                id INTEGER PRIMARY KEY,
+                mp4_file_path TEXT NOT NULL UNIQUE,
+                start_timestamp TIMESTAMP NOT NULL,
+                end_timestamp TIMESTAMP NOT NULL,
+                frame_count INTEGER NOT NULL,
+                size_bytes INTEGER NOT NULL,
+                created_at TIMESTAMP NOT NULL
+            )",
+        [],
+    )?;
+
+    // Journal of in-progress downsampling of segments, see tiering::TieringHelper
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS tiering_jobs (
+                id INTEGER PRIMARY KEY,
+                segment_id INTEGER NOT NULL,
+                old_mp4_file_path TEXT NOT NULL,
+                new_mp4_file_path TEXT NOT NULL,
+                state TEXT NOT NULL,
+                created_at TIMESTAMP NOT NULL,
+                updated_at TIMESTAMP NOT NULL
+            )",
+        [],
+    )?;
+
+    // One text embedding of the OCR text per screenshot, see embeddings::Embedder.  The vector is
+    // little-endian f32s, or empty if the screenshot had no OCR text.  Screenshots embedded by
+    // another model are embedded again.
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS embeddings (
+                document_id INTEGER PRIMARY KEY,
+                model TEXT NOT NULL,
+                vector BLOB NOT NULL
+            );
+        CREATE TRIGGER IF NOT EXISTS documents_embeddings_delete AFTER DELETE ON documents BEGIN
+            DELETE FROM embeddings WHERE document_id = old.id;
+        END;"
+    )?;
+
+    // The lines and words OCR found in each screenshot, encoded by ocr_layout::OcrLayout::encode()
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS ocr_layout (
+                document_id INTEGER PRIMARY KEY,
+                layout BLOB NOT NULL
+            );
+        CREATE TRIGGER IF NOT EXISTS documents_ocr_layout_delete AFTER DELETE ON documents BEGIN
+            DELETE FROM ocr_layout WHERE document_id = old.id;
+        END;"
+    )?;
+
+    // Searches saved by name, see saved_searches::SavedSearch.  Each new match of a watched
+    // search is recorded in alerts, which outlive the screenshot but not the saved search.
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS saved_searches (
+                id INTEGER PRIMARY KEY,
+                name TEXT NOT NULL UNIQUE,
+                term TEXT NOT NULL,
+                mode TEXT NOT NULL,
+                display_id INTEGER,
+                watch INTEGER NOT NULL DEFAULT 0,
+                created_at INTEGER NOT NULL,
+                last_matched_at INTEGER
+            );
+        CREATE TABLE IF NOT EXISTS alerts (
+                id INTEGER PRIMARY KEY,
+                saved_search_id INTEGER NOT NULL,
+                document_id INTEGER NOT NULL,
+                timestamp INTEGER NOT NULL
+            );
+        CREATE INDEX IF NOT EXISTS alerts_saved_search_id_index ON alerts (saved_search_id);
+        CREATE TRIGGER IF NOT EXISTS saved_searches_alerts_delete AFTER DELETE ON saved_searches BEGIN
+            DELETE FROM alerts WHERE saved_search_id = old.id;
+        END;"
+    )?;
+
+    // Tags and notes on screenshots, see annotations.rs.  Bookmarks are the bookmark tag.  Notes
+    // have their own full-text index, which is searched alongside the OCR text.
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS tags (
+                id INTEGER PRIMARY KEY,
+                name TEXT NOT NULL UNIQUE COLLATE NOCASE
+            );
+        CREATE TABLE IF NOT EXISTS document_tags (
+                document_id INTEGER NOT NULL,
+                tag_id INTEGER NOT NULL,
+                created_at INTEGER NOT NULL,
+                PRIMARY KEY (document_id, tag_id)
+            );
+        CREATE INDEX IF NOT EXISTS document_tags_tag_id_index ON document_tags (tag_id);
+        CREATE TABLE IF NOT EXISTS notes (
+                id INTEGER PRIMARY KEY,
+                document_id INTEGER NOT NULL,
+                text TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                updated_at INTEGER NOT NULL
+            );
+        CREATE INDEX IF NOT EXISTS notes_document_id_index ON notes (document_id);
+        CREATE VIRTUAL TABLE IF NOT EXISTS notes_index USING fts5(
+            content='notes',
+            text,
+            content_rowid='id'
+        );
+        CREATE TRIGGER IF NOT EXISTS notes_notes_index_insert AFTER INSERT ON notes BEGIN
+            INSERT INTO notes_index (rowid, text) VALUES (new.id, new.text);
+        END;
+        CREATE TRIGGER IF NOT EXISTS notes_notes_index_delete AFTER DELETE ON notes BEGIN
+            INSERT INTO notes_index (notes_index, rowid, text) VALUES ('delete', old.id, old.text);
+        END;
+        CREATE TRIGGER IF NOT EXISTS notes_notes_index_update AFTER UPDATE OF text ON notes BEGIN
+            INSERT INTO notes_index (notes_index, rowid, text) VALUES ('delete', old.id, old.text);
+            INSERT INTO notes_index (rowid, text) VALUES (new.id, new.text);
+        END;
+        CREATE TRIGGER IF NOT EXISTS documents_annotations_delete AFTER DELETE ON documents BEGIN
+            DELETE FROM document_tags WHERE document_id = old.id;
+            DELETE FROM notes WHERE document_id = old.id;
+        END;"
+    )?;
+
+    // Screenshots with a tag or a note are pinned: compaction leaves them loose and retention
+    // doesn't delete them, see annotations::pin_screenshot()
+    conn.execute(
+        "CREATE VIEW IF NOT EXISTS pinned_documents AS
+            SELECT document_id FROM document_tags UNION SELECT document_id FROM notes",
+        [],
+    )?;
+
+    // Columns added after the initial schema.  These are added to existing DBs on startup.
+    add_column_if_missing(&conn, "documents", "display_id", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(&conn, "documents", "display_x", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(&conn, "documents", "display_y", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(&conn, "documents", "display_width", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(&conn, "documents", "display_height", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(&conn, "documents", "window_x", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(&conn, "documents", "window_y", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(&conn, "documents", "window_width", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(&conn, "documents", "window_height", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(&conn, "documents", "frontmost_app", "TEXT NOT NULL DEFAULT ''")?;
+    add_column_if_missing(&conn, "documents", "image_format", "TEXT NOT NULL DEFAULT 'png'")?;
+    add_column_if_missing(&conn, "segments", "tier", "INTEGER NOT NULL DEFAULT 0")?;
+
+    // Move files from the old flat layout into the media dir
+    storage::migrate_to_relative_media_paths(&mut conn, dataset_root)?;
+
+    // Record mp4 files compacted before the segments table existed
+    backfill_segments(&conn, dataset_root)?;
+
+    // Fix OCR indexes written before the triggers, and fill in the trigram index
+    migrate_ocr_indexes(&conn)?;
+
+    Ok(())
+
+}
+
+/**
+ * Add a segments row for every mp4 that screenshots point to but that has no segment yet
+ */
+fn backfill_segments(conn: &Connection, dataset_root: &Path) -> Result<(), ScreentapError> {
+
+    let mut stmt = conn.prepare(
+        "SELECT mp4_file_path, MIN(timestamp), MAX(timestamp), COUNT(*) FROM documents
+            WHERE mp4_file_path != '' AND mp4_file_path NOT IN (SELECT mp4_file_path FROM segments)
+            GROUP BY mp4_file_path"
+    )?;
+    let missing_segments = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, i64>(1)?,
+            row.get::<_, i64>(2)?,
+            row.get::<_, i64>(3)?,
+        ))
+    })?.collect::<Result<Vec<_>, _>>()?;
+
+    let layout = StorageLayout::new(dataset_root);
+    for (mp4_file_path, start_timestamp, end_timestamp, frame_count) in missing_segments {
+        let size_bytes = layout.to_absolute(&mp4_file_path).metadata().map(|metadata| metadata.len()).unwrap_or(0);
+        conn.execute(
+            "INSERT INTO segments (mp4_file_path, start_timestamp, end_timestamp, frame_count, size_bytes, created_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![mp4_file_path, start_timestamp, end_timestamp, frame_count, size_bytes as i64, NaiveDateTime::from_timestamp_opt(end_timestamp, 0).unwrap_or_default()],
+        )?;
+    }
+
+    Ok(())
+
+}
+
+/**
+ * Helper function to create the triggers that keep an OCR index in sync with documents
+ */
+fn create_ocr_index_triggers(conn: &Connection, index: &str) -> Result<(), ScreentapError> {
+    conn.execute_batch(&format!(
+        "CREATE TRIGGER IF NOT EXISTS documents_{index}_insert AFTER INSERT ON documents BEGIN
+            INSERT INTO {index} (rowid, ocr_text) VALUES (new.id, new.ocr_text);
+        END;
+        CREATE TRIGGER IF NOT EXISTS documents_{index}_delete AFTER DELETE ON documents BEGIN
+            INSERT INTO {index} ({index}, rowid, ocr_text) VALUES ('delete', old.id, old.ocr_text);
+        END;
+        CREATE TRIGGER IF NOT EXISTS documents_{index}_update AFTER UPDATE OF ocr_text ON documents BEGIN
+            INSERT INTO {index} ({index}, rowid, ocr_text) VALUES ('delete', old.id, old.ocr_text);
+            INSERT INTO {index} (rowid, ocr_text) VALUES (new.id, new.ocr_text);
+        END;",
+        index = index
+    ))?;
+    Ok(())
+}
+
+/**
+ * Rebuild the OCR indexes of DBs from before FTS_TRIGRAM_DB_VERSION.  Before
+ * FTS_TRIGGERS_DB_VERSION, index rows were inserted without a rowid, so a failed insert or a
+ * deleted screenshot made searches return the wrong screenshot.  The trigram index is empty
+ * until it's rebuilt.
+ */
+fn migrate_ocr_indexes(conn: &Connection) -> Result<(), ScreentapError> {
+
+    let user_version: i32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if user_version >= FTS_TRIGRAM_DB_VERSION {
+        return Ok(());
+    }
+
+    println!("Rebuilding the OCR text indexes");
+    rebuild_ocr_indexes_with_conn(conn)?;
+    conn.execute_batch(&format!("PRAGMA user_version = {}", FTS_TRIGRAM_DB_VERSION))?;
+
+    Ok(())
+
+}
+
+/**
+ * Rebuild the OCR indexes from the documents table
+ */
+pub fn rebuild_ocr_indexes(dataset_root: &Path, db_filename: &Path) -> Result<(), ScreentapError> {
+    let conn = get_db_conn(dataset_root, db_filename)?;
+    rebuild_ocr_indexes_with_conn(&conn)
+}
+
+fn rebuild_ocr_indexes_with_conn(conn: &Connection) -> Result<(), ScreentapError> {
+    for index in OCR_INDEXES {
+        conn.execute(&format!("INSERT INTO {index} ({index}) VALUES ('rebuild')", index = index), [])?;
+    }
+    Ok(())
+}
+
+/**
+ * Check that the OCR indexes match the documents table.  Returns Ok(false) if they don't,
+ * which rebuild_ocr_indexes() fixes.
+ */
+pub fn check_ocr_indexes(dataset_root: &Path, db_filename: &Path) -> Result<bool, ScreentapError> {
+
+    let conn = get_db_conn(dataset_root, db_filename)?;
+
+    for index in OCR_INDEXES {
+        // A rank of 1 also compares the index against the contents of documents
+        match conn.execute(&format!("INSERT INTO {index} ({index}, rank) VALUES ('integrity-check', 1)", index = index), []) {
+            Ok(_) => {},
+            Err(rusqlite::Error::SqliteFailure(e, _)) if e.code == rusqlite::ErrorCode::DatabaseCorrupt => {
+                println!("The {} OCR index doesn't match the screenshots", index);
+                return Ok(false);
+            },
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(true)
+
+}
+
+/**
+ * Helper function to add a column to an existing table, since sqlite has no
+ * ALTER TABLE .. ADD COLUMN IF NOT EXISTS
+ */
+pub fn add_column_if_missing(conn: &Connection, table: &str, column: &str, column_def: &str) -> Result<(), ScreentapError> {
+
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let column_exists = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<_>, _>>()?
+        .iter()
+        .any(|name| name == column);
+
+    if !column_exists {
+        conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, column_def), [])?;
+    }
+
     Ok(())
 
 }
@@ -135,102 +547,252 @@ pub fn create_db(dataset_root: &Path, db_filename: &Path) -> Result<()> {
  * 
  * Returns a Result with the screenshot_id (primary key)
  */
-pub fn save_screenshot_meta(screenshot_file_path: &Path, ocr_text: &str, dataset_root: &Path, db_filename: &Path, now: NaiveDateTime) -> Result<i64> {
+pub fn save_screenshot_meta(screenshot_file_path: &Path, ocr_text: &str, dataset_root: &Path, db_filename: &Path, now: NaiveDateTime, capture_meta: &CaptureMeta) -> Result<i64, ScreentapError> {
+
+    let conn = get_db_conn(dataset_root, db_filename)?;
 
-    let conn = get_db_conn(dataset_root, db_filename);
+    // Store the path relative to the dataset root, so that the dataset root can be moved
+    let relative_file_path = StorageLayout::new(dataset_root).to_relative(screenshot_file_path);
+    let screenshot_file_path_str = relative_file_path.to_str()
+        .ok_or_else(|| ScreentapError::Io(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Screenshot path is not valid UTF-8")))?;
 
-    let screenshot_file_path_str = screenshot_file_path.to_str().expect("Failed to get screenshot_file_path_str");
+    let display = &capture_meta.display;
+    let window_rect = capture_meta.window_rect.clone().unwrap_or_default();
 
     // TODO: change table name to 'screenshots'
-    // TODO: record frontmost app and browser url (if in a browser)
+    // TODO: record browser url (if in a browser)
     conn.execute(
-        "INSERT INTO documents (timestamp, ocr_text, file_path, mp4_file_path) VALUES (?1, ?2, ?3, ?4)",
-        params![now.timestamp(), ocr_text, screenshot_file_path_str, ""],
+        "INSERT INTO documents (timestamp, ocr_text, file_path, mp4_file_path, display_id, display_x, display_y, display_width, display_height, window_x, window_y, window_width, window_height, frontmost_app, image_format) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)",
+        params![now.timestamp(), ocr_text, screenshot_file_path_str, "", display.display_id, display.x, display.y, display.width, display.height, window_rect.x, window_rect.y, window_rect.width, window_rect.height, capture_meta.frontmost_app, FrameFormat::from_path(screenshot_file_path).as_str()],
     )?;
 
-    let last_id = conn.last_insert_rowid();
+    // The OCR indexes are updated by the documents_*_insert triggers
+    Ok(conn.last_insert_rowid())
 
-    // Insert the OCR text into the full-text search index
-    conn.execute(
-        "INSERT INTO ocr_text_index (ocr_text) VALUES (?1)",
-        [ocr_text],
-    )?;
+}
 
-    Ok(last_id)
+/**
+ * The time of a test capture taken seconds after 2024-03-07 10:00:00, see insert_test_screenshots()
+ */
+#[cfg(test)]
+pub fn test_capture_time(seconds: i64) -> NaiveDateTime {
+    NaiveDate::from_ymd_opt(2024, 3, 7).unwrap().and_hms_opt(10, 0, 0).unwrap() + chrono::Duration::seconds(seconds)
+}
+
+/**
+ * Save a screenshot for each (seconds, OCR text) capture, as taken at test_capture_time(seconds).
+ * The image paths are <seconds>.png in the dataset root, which aren't written.  Returns the
+ * screenshot ids in the order of captures.
+ */
+#[cfg(test)]
+pub fn insert_test_screenshots(dataset_root: &Path, db_filename: &Path, captures: &[(i64, &str)]) -> Vec<i64> {
+    captures.iter()
+        .map(|(seconds, ocr_text)| {
+            let png_path = dataset_root.join(format!("{}.png", seconds));
+            save_screenshot_meta(&png_path, ocr_text, dataset_root, db_filename, test_capture_time(*seconds), &CaptureMeta::default()).unwrap()
+        })
+        .collect()
+}
+
+/**
+ * The columns selected from the documents table (aliased as d) by the screenshot queries,
+ * in the order expected by row_to_screenshot_record()
+ */
+static SCREENSHOT_COLUMNS: &str = "d.id, d.timestamp, d.ocr_text, d.file_path, d.mp4_file_path, d.mp4_frame_id, d.display_id, d.display_x, d.display_y, d.display_width, d.display_height, d.window_x, d.window_y, d.window_width, d.window_height, d.frontmost_app, d.image_format";
+
+/**
+ * Helper function to convert a row selected with SCREENSHOT_COLUMNS into a ScreenshotRecord.
+ * The image is loaded afterwards by load_base64_images(), so that frames in the same mp4
+ * can be extracted together.
+ */
+fn row_to_screenshot_record(row: &rusqlite::Row, dataset_root: &Path) -> rusqlite::Result<ScreenshotRecord> {
+
+    // Paths are stored relative to the dataset root
+    let layout = StorageLayout::new(dataset_root);
+    let file_path_str = layout.to_absolute_string(&row.get::<_, String>(3)?);
+    let mp4_file_path_str = layout.to_absolute_string(&row.get::<_, String>(4)?);
+
+    Ok(ScreenshotRecord {
+        id: row.get(0)?,
+        timestamp: row.get(1)?,
+        ocr_text: row.get(2)?,
+        file_path: file_path_str,
+        mp4_file_path: mp4_file_path_str,
+        mp4_frame_id: row.get(5)?,
+        base64_image: String::new(),
+        display_id: row.get(6)?,
+        display_x: row.get(7)?,
+        display_y: row.get(8)?,
+        display_width: row.get(9)?,
+        display_height: row.get(10)?,
+        window_x: row.get(11)?,
+        window_y: row.get(12)?,
+        window_width: row.get(13)?,
+        window_height: row.get(14)?,
+        frontmost_app: row.get(15)?,
+        image_format: row.get(16)?,
+    })
 
 }
 
 /**
  * Helper function to get a screenshot from the DB by ID
  */
-pub fn get_screenshot_by_id(dataset_root: &Path, db_filename: &Path, target_id: i32) -> Result<Vec<ScreenshotRecord>, rusqlite::Error> {
+pub fn get_screenshot_by_id(dataset_root: &Path, db_filename: &Path, target_id: i32, frame_extractor: &dyn FrameExtractor) -> Result<Vec<ScreenshotRecord>, ScreentapError> {
 
-    let conn = get_db_conn(dataset_root, db_filename);
+    let conn = get_db_conn(dataset_root, db_filename)?;
 
-    let mut stmt = conn.prepare("SELECT id, timestamp, ocr_text, file_path, mp4_file_path, mp4_frame_id FROM documents WHERE id = ? ORDER BY timestamp DESC")?;
-    let screenshots = stmt.query_map(params![target_id], |row| {
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM documents d WHERE d.id = ?", SCREENSHOT_COLUMNS))?;
+    let mut screenshots = stmt.query_map(params![target_id], |row| {
+        row_to_screenshot_record(row, dataset_root)
+    })?
+    .collect::<Result<Vec<_>, _>>()?;
 
-        // open the file_path and convert to base64
-        let file_path_str: String = row.get(3).expect("Failed to get file_path");
-        let mp4_file_path_str: String = row.get(4).expect("Failed to get mp4_file_path");
-        let mp4_frame_id: i32 = row.get(5).expect("Failed to get mp4_frame_id");
+    load_base64_images(&mut screenshots, frame_extractor);
 
-        let fully_qualified_file_path = dataset_root.join(file_path_str.clone());
+    Ok(screenshots)
 
-        let base64_image: String = get_screenshot_as_base64_string(
-            fully_qualified_file_path.to_str().unwrap(), 
-            &mp4_file_path_str, 
-            mp4_frame_id
-        );
+}
 
-        Ok(ScreenshotRecord {
-            id: row.get(0)?,
-            timestamp: row.get(1)?,
-            ocr_text: row.get(2)?,
-            file_path: file_path_str,
-            mp4_file_path: mp4_file_path_str,
-            mp4_frame_id,
-            base64_image,
-        })
+/**
+ * Helper function to get the screenshot before or after cur_id, optionally restricted to a single display
+ */
+pub fn get_adjacent_screenshot(dataset_root: &Path, db_filename: &Path, cur_id: i32, forward: bool, display_id: Option<u32>, frame_extractor: &dyn FrameExtractor) -> Result<Vec<ScreenshotRecord>, ScreentapError> {
+
+    let conn = get_db_conn(dataset_root, db_filename)?;
+
+    let query = if forward {
+        format!("SELECT {} FROM documents d WHERE d.id > ?1 AND (?2 IS NULL OR d.display_id = ?2) ORDER BY d.id ASC LIMIT 1", SCREENSHOT_COLUMNS)
+    } else {
+        format!("SELECT {} FROM documents d WHERE d.id < ?1 AND (?2 IS NULL OR d.display_id = ?2) ORDER BY d.id DESC LIMIT 1", SCREENSHOT_COLUMNS)
+    };
+
+    let mut stmt = conn.prepare(&query)?;
+    let mut screenshots = stmt.query_map(params![cur_id, display_id], |row| {
+        row_to_screenshot_record(row, dataset_root)
     })?
     .collect::<Result<Vec<_>, _>>()?;
 
+    load_base64_images(&mut screenshots, frame_extractor);
+
     Ok(screenshots)
 
 }
 
 /**
- * Helper function to get all screenshots from the DB
+ * Helper function to get all screenshots from the DB, optionally restricted to a single display
  */
-pub fn get_all_screenshots(dataset_root: &Path, db_filename: &Path, limit: i32) -> Result<Vec<ScreenshotRecord>, rusqlite::Error> {
+pub fn get_all_screenshots(dataset_root: &Path, db_filename: &Path, limit: i32, display_id: Option<u32>, frame_extractor: &dyn FrameExtractor) -> Result<Vec<ScreenshotRecord>, ScreentapError> {
 
-    let conn = get_db_conn(dataset_root, db_filename);
+    let conn = get_db_conn(dataset_root, db_filename)?;
 
-    let mut stmt = conn.prepare("SELECT id, timestamp, ocr_text, file_path, mp4_file_path, mp4_frame_id FROM documents ORDER BY timestamp DESC LIMIT ?")?;
-    let screenshots = stmt.query_map(params![limit], |row| {
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM documents d WHERE (?2 IS NULL OR d.display_id = ?2) ORDER BY d.timestamp DESC LIMIT ?1", SCREENSHOT_COLUMNS))?;
+    let mut screenshots = stmt.query_map(params![limit, display_id], |row| {
+        row_to_screenshot_record(row, dataset_root)
+    })?
+    .collect::<Result<Vec<_>, _>>()?;
 
-        // open the file_path and convert to base64
-        let file_path_str: String = row.get(3).expect("Failed to get file_path");
-        let mp4_file_path_str: String = row.get(4).expect("Failed to get mp4_file_path");
-        let mp4_frame_id: i32 = row.get(5).expect("Failed to get mp4_frame_id");
+    load_base64_images(&mut screenshots, frame_extractor);
 
-        let fully_qualified_file_path = dataset_root.join(file_path_str.clone());
+    Ok(screenshots)
 
-        let base64_image: String = get_screenshot_as_base64_string(
-            fully_qualified_file_path.to_str().unwrap(), 
-            &mp4_file_path_str, 
-            mp4_frame_id
-        );
+}
 
-        Ok(ScreenshotRecord {
-            id: row.get(0)?,
-            timestamp: row.get(1)?,
-            ocr_text: row.get(2)?,
-            file_path: file_path_str,
-            mp4_file_path: mp4_file_path_str,
-            mp4_frame_id,
-            base64_image,
-        })
+/**
+ * Does the OCR text of a screenshot match the FTS5 query term?
+ */
+pub fn ocr_text_matches(term: &str, document_id: i64, dataset_root: &Path, db_filename: &Path) -> Result<bool, ScreentapError> {
+
+    let conn = get_db_conn(dataset_root, db_filename)?;
+
+    let matches: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM ocr_text_index WHERE ocr_text_index.ocr_text MATCH ?1 AND rowid = ?2)",
+        params![term, document_id],
+        |row| row.get(0),
+    )?;
+
+    Ok(matches)
+
+}
+
+/**
+ * Is term a valid FTS5 query for the OCR index?  A term with invalid syntax, eg an unbalanced
+ * parenthesis, fails every exact search, which is only reported once the search runs.
+ */
+pub fn is_valid_match_query(term: &str, dataset_root: &Path, db_filename: &Path) -> Result<bool, ScreentapError> {
+
+    let conn = get_db_conn(dataset_root, db_filename)?;
+
+    // The query is parsed even though no row can match
+    let result = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM ocr_text_index WHERE ocr_text_index.ocr_text MATCH ?1 AND rowid = 0)",
+        params![term],
+        |row| row.get::<_, bool>(0),
+    );
+
+    match result {
+        Ok(_) => Ok(true),
+        Err(rusqlite::Error::SqliteFailure(_, Some(msg))) if msg.starts_with("fts5:") => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+
+}
+
+/**
+ * Find screenshots whose OCR text shares 3 character substrings with match_query, best
+ * matches first.  match_query is an FTS5 query of quoted trigrams, see search::SearchMode::Fuzzy.
+ * The images are not loaded.
+ */
+pub fn find_screenshots_trigram(match_query: &str, dataset_root: &Path, db_filename: &Path, limit: i32, display_id: Option<u32>) -> Result<Vec<ScreenshotRecord>, ScreentapError> {
+    find_screenshots_in_index("ocr_trigram_index", match_query, dataset_root, db_filename, limit, display_id)
+}
+
+/**
+ * Helper function to find screenshots in the db matching ocr term, optionally restricted to a
+ * single display.  Returns the bm25 score of each screenshot, computed with the given weights
+ * for the columns of the OCR text index, best matches first.  Lower scores are better matches.
+ * The images are not loaded, see search::search_screenshots().
+ */
+pub fn find_screenshots_ocr_bm25(term: &str, column_weights: &[f64], dataset_root: &Path, db_filename: &Path, limit: i32, display_id: Option<u32>) -> Result<Vec<(ScreenshotRecord, f64)>, ScreentapError> {
+
+    let conn = get_db_conn(dataset_root, db_filename)?;
+
+    let weights = column_weights.iter().map(|weight| weight.to_string()).collect::<Vec<_>>().join(", ");
+    let mut stmt = conn.prepare(&format!(r#"
+        SELECT {columns}, bm25(ocr_text_index, {weights}) AS bm25
+        FROM ocr_text_index
+        JOIN documents d on d.id = ocr_text_index.rowid
+        WHERE ocr_text_index.ocr_text MATCH ?1
+        AND (?3 IS NULL OR d.display_id = ?3)
+        ORDER BY bm25, d.timestamp DESC
+        LIMIT ?2
+    "#, columns = SCREENSHOT_COLUMNS, weights = weights))?;
+
+    let screenshots = stmt.query_map(params![term, limit, display_id], |row| {
+        Ok((row_to_screenshot_record(row, dataset_root)?, row.get::<_, f64>("bm25")?))
+    })?
+    .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(screenshots)
+
+}
+
+fn find_screenshots_in_index(index: &str, match_query: &str, dataset_root: &Path, db_filename: &Path, limit: i32, display_id: Option<u32>) -> Result<Vec<ScreenshotRecord>, ScreentapError> {
+
+    let conn = get_db_conn(dataset_root, db_filename)?;
+
+    let mut stmt = conn.prepare(&format!(r#"
+        SELECT {columns}
+        FROM {index} 
+        JOIN documents d on d.id = {index}.rowid 
+        WHERE {index}.ocr_text MATCH ?1
+        AND (?3 IS NULL OR d.display_id = ?3)
+        ORDER BY rank, d.timestamp DESC
+        LIMIT ?2
+    "#, columns = SCREENSHOT_COLUMNS, index = index))?;
+
+    let screenshots = stmt.query_map(params![match_query, limit, display_id], |row| {
+        row_to_screenshot_record(row, dataset_root)
     })?
     .collect::<Result<Vec<_>, _>>()?;
 
@@ -239,46 +801,51 @@ pub fn get_all_screenshots(dataset_root: &Path, db_filename: &Path, limit: i32)
 }
 
 /**
- * Helper function to search screenshots in the db matching ocr term
+ * Find screenshots with a note matching the FTS5 query term, best matches first.  A screenshot
+ * with several matching notes is returned once for each.  The images are not loaded.
  */
-pub fn search_screenshots_ocr(term: &str, dataset_root: &Path, db_filename: &Path, limit: i32) -> Result<Vec<ScreenshotRecord>, rusqlite::Error> {
+pub fn find_screenshots_notes(term: &str, dataset_root: &Path, db_filename: &Path, limit: i32, display_id: Option<u32>) -> Result<Vec<ScreenshotRecord>, ScreentapError> {
 
-    let conn = get_db_conn(dataset_root, db_filename);
+    let conn = get_db_conn(dataset_root, db_filename)?;
 
-    let mut stmt = conn.prepare(r#"
-        SELECT ocr_text_index.rowid, d.timestamp, d.ocr_text, d.file_path, d.mp4_file_path, d.mp4_frame_id
-        FROM ocr_text_index 
-        JOIN documents d on d.id = ocr_text_index.rowid 
-        WHERE ocr_text_index.ocr_text MATCH ?
+    let mut stmt = conn.prepare(&format!(r#"
+        SELECT {columns}
+        FROM notes_index
+        JOIN notes n on n.id = notes_index.rowid
+        JOIN documents d on d.id = n.document_id
+        WHERE notes_index.text MATCH ?1
+        AND (?3 IS NULL OR d.display_id = ?3)
         ORDER BY rank, d.timestamp DESC
-        LIMIT ?
-    "#)?;
+        LIMIT ?2
+    "#, columns = SCREENSHOT_COLUMNS))?;
+
+    let screenshots = stmt.query_map(params![term, limit, display_id], |row| {
+        row_to_screenshot_record(row, dataset_root)
+    })?
+    .collect::<Result<Vec<_>, _>>()?;
 
-    let screenshots = stmt.query_map(params![term, limit], |row| {
+    Ok(screenshots)
 
-        // open the file_path and convert to base64
-        let file_path_str: String = row.get(3).expect("Failed to get file_path");
-        let mp4_file_path_str: String = row.get(4).expect("Failed to get mp4_file_path");
-        let mp4_frame_id: i32 = row.get(5).expect("Failed to get mp4_frame_id");
+}
 
-        let fully_qualified_file_path = dataset_root.join(file_path_str.clone());
+/**
+ * Get the screenshots taken between two timestamps, inclusive, in the order they were taken.
+ * The images are not loaded.
+ */
+pub fn find_screenshots_in_range(dataset_root: &Path, db_filename: &Path, start_timestamp: i64, end_timestamp: i64, display_id: Option<u32>) -> Result<Vec<ScreenshotRecord>, ScreentapError> {
 
-        let base64_image = get_screenshot_as_base64_string(
-            fully_qualified_file_path.to_str().unwrap(), 
-            &mp4_file_path_str, 
-            mp4_frame_id
-        );
+    let conn = get_db_conn(dataset_root, db_filename)?;
 
+    let mut stmt = conn.prepare(&format!(r#"
+        SELECT {columns}
+        FROM documents d
+        WHERE d.timestamp BETWEEN ?1 AND ?2
+        AND (?3 IS NULL OR d.display_id = ?3)
+        ORDER BY d.timestamp, d.id
+    "#, columns = SCREENSHOT_COLUMNS))?;
 
-        Ok(ScreenshotRecord {
-            id: row.get(0)?,
-            timestamp: row.get(1)?,
-            ocr_text: row.get(2)?,
-            file_path: file_path_str,
-            mp4_file_path: mp4_file_path_str,
-            mp4_frame_id,
-            base64_image,
-        })
+    let screenshots = stmt.query_map(params![start_timestamp, end_timestamp, display_id], |row| {
+        row_to_screenshot_record(row, dataset_root)
     })?
     .collect::<Result<Vec<_>, _>>()?;
 
@@ -286,46 +853,244 @@ pub fn search_screenshots_ocr(term: &str, dataset_root: &Path, db_filename: &Pat
 
 }
 
-pub fn get_screenshot_as_base64_string(file_path: &str, mp4_file_path: &str, mp4_frame_id: i32) -> String {
+/**
+ * Get screenshots by id, in the order of the ids and skipping any that no longer exist.  The
+ * images are not loaded.
+ */
+pub fn find_screenshots_by_ids(dataset_root: &Path, db_filename: &Path, ids: &[i64]) -> Result<Vec<ScreenshotRecord>, ScreentapError> {
+
+    let conn = get_db_conn(dataset_root, db_filename)?;
+
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM documents d WHERE d.id = ?1", SCREENSHOT_COLUMNS))?;
+    let mut screenshots = vec![];
+    for id in ids {
+        if let Some(screenshot) = stmt.query_row(params![id], |row| row_to_screenshot_record(row, dataset_root)).optional()? {
+            screenshots.push(screenshot);
+        }
+    }
+
+    Ok(screenshots)
+
+}
+
+/**
+ * Get the image of a screenshot as it's stored: the loose file in its frame format, or the PNG
+ * of its mp4 frame
+ */
+pub fn get_screenshot_image_data(dataset_root: &Path, db_filename: &Path, id: i64, frame_extractor: &dyn FrameExtractor) -> Result<Vec<u8>, ScreentapError> {
+
+    let record = find_screenshots_by_ids(dataset_root, db_filename, &[id])?
+        .into_iter()
+        .next()
+        .ok_or_else(|| ScreentapError::Io(std::io::Error::new(std::io::ErrorKind::NotFound, format!("No screenshot #{}", id))))?;
+
+    if record.mp4_file_path.is_empty() {
+        return Ok(std::fs::read(&record.file_path)?);
+    }
+
+    let frames = frame_cache::get_frames(Path::new(&record.mp4_file_path), &[record.mp4_frame_id], frame_extractor)?;
+    frames.get(&record.mp4_frame_id)
+        .map(|png_data| png_data.to_vec())
+        .ok_or_else(|| ScreentapError::Encode(format!("Could not extract frame {} from {}", record.mp4_frame_id, record.mp4_file_path)))
+
+}
+
+/**
+ * Move a compacted screenshot out of its mp4, into a loose PNG next to where it was captured.
+ * The mp4 keeps the frame, but nothing points to it anymore, so later compaction, tiering and
+ * retention of the segment don't touch the screenshot.  Returns false if it was already loose.
+ */
+pub fn unpack_screenshot(dataset_root: &Path, db_filename: &Path, id: i64, frame_extractor: &dyn FrameExtractor) -> Result<bool, ScreentapError> {
+
+    let record = find_screenshots_by_ids(dataset_root, db_filename, &[id])?
+        .into_iter()
+        .next()
+        .ok_or_else(|| ScreentapError::Io(std::io::Error::new(std::io::ErrorKind::NotFound, format!("No screenshot #{}", id))))?;
+
+    if record.mp4_file_path.is_empty() {
+        return Ok(false);
+    }
+
+    // Frames are extracted from the mp4 as PNG, whatever format they were captured in
+    let png_data = get_screenshot_image_data(dataset_root, db_filename, id, frame_extractor)?;
+    let png_path = Path::new(&record.file_path).with_extension(FrameFormat::Png.extension());
+    if let Some(parent_dir) = png_path.parent() {
+        std::fs::create_dir_all(parent_dir)?;
+    }
+    std::fs::write(&png_path, png_data)?;
+
+    let conn = get_db_conn(dataset_root, db_filename)?;
+    conn.execute(
+        "UPDATE documents SET file_path = ?1, mp4_file_path = '', mp4_frame_id = -1, image_format = ?2 WHERE id = ?3",
+        params![StorageLayout::new(dataset_root).to_relative_string(&png_path), FrameFormat::Png.as_str(), id],
+    )?;
+
+    Ok(true)
+
+}
+
+/**
+ * Helper function to get the ids of all displays that have screenshots in the DB
+ */
+pub fn get_display_ids(dataset_root: &Path, db_filename: &Path) -> Result<Vec<u32>, ScreentapError> {
+
+    let conn = get_db_conn(dataset_root, db_filename)?;
+
+    let mut stmt = conn.prepare("SELECT DISTINCT display_id FROM documents ORDER BY display_id")?;
+    let display_ids = stmt.query_map([], |row| row.get(0))?
+        .collect::<Result<Vec<u32>, _>>()?;
+
+    Ok(display_ids)
+
+}
+
+/**
+ * Fill in the base64 image of each record.  Screenshots compacted into the same mp4 are
+ * extracted in one batch, and frames that were shown recently come from the frame cache.
+ */
+pub fn load_base64_images(records: &mut [ScreenshotRecord], frame_extractor: &dyn FrameExtractor) {
+
+    let mut frame_ids_by_mp4: HashMap<String, Vec<i32>> = HashMap::new();
+    for record in records.iter().filter(|record| !record.mp4_file_path.is_empty()) {
+        frame_ids_by_mp4.entry(record.mp4_file_path.clone()).or_default().push(record.mp4_frame_id);
+    }
+
+    let mut frames_by_mp4 = HashMap::new();
+    for (mp4_file_path, frame_ids) in frame_ids_by_mp4 {
+        let mp4_path = Path::new(&mp4_file_path);
+        let frames = match frame_cache::get_frames(mp4_path, &frame_ids, frame_extractor) {
+            Ok(frames) => frames,
+            Err(e) => {
+                // Retry frame by frame, so that one bad frame doesn't blank out the whole mp4
+                println!("Error: could not extract frames from {}: {}.  Retrying frame by frame.", mp4_file_path, e);
+                frame_ids.iter()
+                    .filter_map(|&frame_id| frame_cache::get_frames(mp4_path, &[frame_id], frame_extractor).ok())
+                    .flatten()
+                    .collect()
+            }
+        };
+        frames_by_mp4.insert(mp4_file_path, frames);
+    }
+
+    for record in records.iter_mut() {
+        record.base64_image = if record.mp4_file_path.is_empty() {
+            get_screenshot_as_base64_string(&record.file_path, "", -1, frame_extractor)
+        } else {
+            match frames_by_mp4.get(&record.mp4_file_path).and_then(|frames| frames.get(&record.mp4_frame_id)) {
+                Some(png_data) => BASE64.encode(png_data.as_slice()),
+                None => {
+                    println!("Error: could not extract frame {} from {}.  Returning empty data for frame.", record.mp4_frame_id, record.mp4_file_path);
+                    String::from("")
+                }
+            }
+        };
+    }
+
+}
+
+pub fn get_screenshot_as_base64_string(file_path: &str, mp4_file_path: &str, mp4_frame_id: i32, frame_extractor: &dyn FrameExtractor) -> String {
 
     // If there is a non-empty mp4_file_path, then the screenshot has been compacted into an mp4
     if !mp4_file_path.is_empty() {
-        get_screenshot_base64_from_mp4(mp4_file_path, mp4_frame_id)
+        get_screenshot_base64_from_mp4(mp4_file_path, mp4_frame_id, frame_extractor)
     } else {
 
         // Does the file_path exists?
         let file_path_check = PathBuf::from(file_path);
         if !file_path_check.exists() {
-            let bt = Backtrace::new();
-
-            // If this happens, the screenshot file will not be shown in the UI
-            println!("Error: get_screenshot_as_base64_string() called with non-existent file.  Returning empty data for file.  Stack trace:\n{:?}", bt);
+            // If this happens, the screenshot file will not be shown in the UI.  Usually the data
+            // dir is on a volume that isn't mounted, or files were moved outside of move-data-dir.
+            println!("Error: screenshot file {} does not exist.  Returning empty data for file.  Run `screentap-app verify-data-dir` to check for other missing files.", file_path);
 
             return String::from("");
         }
         
-        let file_contents = std::fs::read(file_path).unwrap();
-        BASE64.encode(file_contents)    
+        match std::fs::read(file_path) {
+            Ok(file_contents) => BASE64.encode(file_contents),
+            Err(e) => {
+                println!("Error: get_screenshot_as_base64_string() could not read {}: {}.  Returning empty data for file.", file_path, e);
+                String::from("")
+            }
+        }
     }
 }
 
-fn get_screenshot_base64_from_mp4(mp4_file_path: &str, mp4_frame_id: i32) -> String {
+fn get_screenshot_base64_from_mp4(mp4_file_path: &str, mp4_frame_id: i32, frame_extractor: &dyn FrameExtractor) -> String {
 
-    let frame_data_option = screen_ocr_swift_rs::extract_frame_from_mp4(
-        mp4_file_path, 
-        mp4_frame_id as isize
+    let frame_data_result = frame_cache::get_frames(
+        Path::new(mp4_file_path), 
+        &[mp4_frame_id],
+        frame_extractor
     );
 
-    match frame_data_option {
-        Some(frame_data) => {
-            BASE64.encode(frame_data)
+    match frame_data_result {
+        Ok(frames) if frames.contains_key(&mp4_frame_id) => {
+            BASE64.encode(frames[&mp4_frame_id].as_slice())
+        },
+        Ok(_) => {
+            println!("Error: get_screenshot_base64_from_mp4() did not get frame {} from {}.  Returning empty data for frame.", mp4_frame_id, mp4_file_path);
+            String::from("")
         },
-        None => {
+        Err(e) => {
             let bt = Backtrace::new();
-            println!("Error: get_screenshot_base64_from_mp4() called with non-existent frame.  Returning empty data for frame.  Stack trace:\n{:?}", bt);
+            println!("Error: get_screenshot_base64_from_mp4() could not extract frame: {}.  Returning empty data for frame.  Stack trace:\n{:?}", e, bt);
             String::from("")
         }
     }
 
 }
 
+
+
+#[cfg(test)]
+mod test {
+
+    use super::{check_ocr_indexes, create_db, get_db_conn};
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_ocr_text_index_migration() {
+
+        let tmp_dir = tempdir().unwrap();
+        let data_root = tmp_dir.path();
+        let db_filename = Path::new("test.db");
+        create_db(data_root, db_filename).unwrap();
+
+        // Recreate an index from before the triggers, whose rowids are off by one
+        let conn = get_db_conn(data_root, db_filename).unwrap();
+        conn.execute_batch(
+            "DROP TRIGGER documents_ocr_text_index_insert;
+            DROP TRIGGER documents_ocr_text_index_delete;
+            DROP TRIGGER documents_ocr_text_index_update;
+            PRAGMA user_version = 1;
+            INSERT INTO documents (id, timestamp, ocr_text, file_path) VALUES (2, 0, 'alpha', 'a.png'), (3, 0, 'beta', 'b.png');
+            INSERT INTO ocr_text_index (ocr_text) VALUES ('alpha'), ('beta');"
+        ).unwrap();
+
+        let search = |term: &str| {
+            get_db_conn(data_root, db_filename).unwrap().query_row(
+                "SELECT d.id FROM ocr_text_index JOIN documents d ON d.id = ocr_text_index.rowid WHERE ocr_text_index.ocr_text MATCH ?1",
+                [term],
+                |row| row.get::<_, i64>(0),
+            ).unwrap()
+        };
+        assert_eq!(search("beta"), 2);
+        assert!(!check_ocr_indexes(data_root, db_filename).unwrap());
+
+        create_db(data_root, db_filename).unwrap();
+        assert_eq!(search("beta"), 3);
+        assert!(check_ocr_indexes(data_root, db_filename).unwrap());
+
+        // The triggers keep it in sync from then on
+        conn.execute_batch(
+            "DELETE FROM documents WHERE id = 2;
+            UPDATE documents SET ocr_text = 'gamma' WHERE id = 3;
+            INSERT INTO documents (timestamp, ocr_text, file_path) VALUES (0, 'delta', 'c.png');"
+        ).unwrap();
+        assert_eq!(search("gamma"), 3);
+        assert_eq!(search("delta"), 4);
+        assert!(check_ocr_indexes(data_root, db_filename).unwrap());
+    }
+
+}
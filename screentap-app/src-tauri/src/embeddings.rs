@@ -0,0 +1,302 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use candle_core::{Device, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::bert::{BertModel, Config, DTYPE};
+use rusqlite::params;
+use tokenizers::{Tokenizer, TruncationParams};
+
+use crate::config::ScreentapConfig;
+use crate::db;
+use crate::error::ScreentapError;
+
+
+/**
+ * The model used when embedding_model_dir is empty, looked up in the models dir of the app
+ * data dir.  Download config.json, tokenizer.json and model.safetensors from
+ * https://huggingface.co/sentence-transformers/all-MiniLM-L6-v2 into it.
+ */
+pub static DEFAULT_EMBEDDING_MODEL: &str = "all-MiniLM-L6-v2";
+
+// How many screenshots are embedded per batch, so that a backlog doesn't hold up the persist stage
+pub static EMBEDDING_BATCH_SIZE: i32 = 32;
+
+// The longest OCR text embedded, in tokens.  The rest of the text is ignored.
+static MAX_EMBEDDING_TOKENS: usize = 256;
+
+// The model and its tokenizer, loaded once and shared by the persist stage and searches
+static EMBEDDER: OnceLock<Mutex<Option<(PathBuf, Arc<dyn Embedder>)>>> = OnceLock::new();
+
+
+/**
+ * Computes a vector from text, such that texts with similar meanings have vectors with a high
+ * cosine similarity
+ */
+pub trait Embedder: Send + Sync {
+
+    // Stored with each vector, since vectors from different models can't be compared
+    fn model_name(&self) -> &str;
+
+    fn embed(&self, text: &str) -> Result<Vec<f32>, ScreentapError>;
+
+}
+
+/**
+ * A BERT sentence embedding model such as all-MiniLM-L6-v2, run on the CPU with candle.  The model
+ * dir needs config.json, tokenizer.json and model.safetensors.
+ */
+pub struct BertEmbedder {
+    model: BertModel,
+    tokenizer: Tokenizer,
+    device: Device,
+    model_name: String,
+}
+
+fn embedding_error<E: std::fmt::Display>(e: E) -> ScreentapError {
+    ScreentapError::Embedding(e.to_string())
+}
+
+impl BertEmbedder {
+
+    pub fn load(model_dir: &Path) -> Result<Self, ScreentapError> {
+
+        let device = Device::Cpu;
+
+        let config_str = std::fs::read_to_string(model_dir.join("config.json"))?;
+        let config: Config = serde_json::from_str(&config_str).map_err(embedding_error)?;
+
+        let mut tokenizer = Tokenizer::from_file(model_dir.join("tokenizer.json")).map_err(embedding_error)?;
+        tokenizer
+            .with_truncation(Some(TruncationParams { max_length: MAX_EMBEDDING_TOKENS, ..Default::default() }))
+            .map_err(embedding_error)?;
+
+        // Safe as long as the model file isn't modified while it's loaded
+        let vb = unsafe {
+            VarBuilder::from_mmaped_safetensors(&[model_dir.join("model.safetensors")], DTYPE, &device).map_err(embedding_error)?
+        };
+        let model = BertModel::load(vb, &config).map_err(embedding_error)?;
+
+        let model_name = model_dir.file_name()
+            .and_then(|model_name| model_name.to_str())
+            .unwrap_or(DEFAULT_EMBEDDING_MODEL)
+            .to_string();
+
+        Ok(BertEmbedder { model, tokenizer, device, model_name })
+    }
+
+}
+
+impl Embedder for BertEmbedder {
+
+    fn model_name(&self) -> &str {
+        &self.model_name
+    }
+
+    /**
+     * Mean pool the token embeddings and normalize the result, like sentence-transformers does
+     */
+    fn embed(&self, text: &str) -> Result<Vec<f32>, ScreentapError> {
+
+        let encoding = self.tokenizer.encode(text, true).map_err(embedding_error)?;
+        let token_ids = Tensor::new(encoding.get_ids(), &self.device)
+            .and_then(|token_ids| token_ids.unsqueeze(0))
+            .map_err(embedding_error)?;
+        let token_type_ids = token_ids.zeros_like().map_err(embedding_error)?;
+
+        let token_embeddings = self.model.forward(&token_ids, &token_type_ids).map_err(embedding_error)?;
+        let (_, token_count, _) = token_embeddings.dims3().map_err(embedding_error)?;
+        let mean_embedding = (token_embeddings.sum(1).map_err(embedding_error)? / token_count as f64)
+            .and_then(|mean_embedding| mean_embedding.squeeze(0))
+            .and_then(|mean_embedding| mean_embedding.to_vec1::<f32>())
+            .map_err(embedding_error)?;
+
+        Ok(normalize(mean_embedding))
+    }
+
+}
+
+
+/**
+ * Get the embedding model dir, see ScreentapConfig::embedding_model_dir
+ */
+pub fn get_model_dir(app_data_dir: &Path, config: &ScreentapConfig) -> PathBuf {
+    if config.embedding_model_dir.is_empty() {
+        app_data_dir.join("models").join(DEFAULT_EMBEDDING_MODEL)
+    } else {
+        PathBuf::from(&config.embedding_model_dir)
+    }
+}
+
+/**
+ * Get the embedder for the model in model_dir, loading it on first use or if the model dir changed
+ */
+pub fn get_embedder(model_dir: &Path) -> Result<Arc<dyn Embedder>, ScreentapError> {
+
+    // A panic while loading a model poisons the lock, but the loaded embedder is only replaced
+    // after a successful load, so it's still consistent and later calls can use it
+    let mut embedder = EMBEDDER.get_or_init(|| Mutex::new(None)).lock().unwrap_or_else(PoisonError::into_inner);
+
+    if let Some((loaded_model_dir, loaded_embedder)) = embedder.as_ref() {
+        if loaded_model_dir == model_dir {
+            return Ok(loaded_embedder.clone());
+        }
+    }
+
+    println!("Loading embedding model from {}", model_dir.display());
+    let loaded_embedder: Arc<dyn Embedder> = Arc::new(BertEmbedder::load(model_dir)?);
+    *embedder = Some((model_dir.to_path_buf(), loaded_embedder.clone()));
+
+    Ok(loaded_embedder)
+
+}
+
+/**
+ * Embed up to limit screenshots that have no embedding from this model yet, newest first so that
+ * recent history is searchable first.  Returns how many were embedded.
+ */
+pub fn embed_pending_screenshots(dataset_root: &Path, db_filename: &Path, embedder: &dyn Embedder, limit: i32) -> Result<usize, ScreentapError> {
+
+    let conn = db::get_db_conn(dataset_root, db_filename)?;
+
+    let pending = {
+        let mut stmt = conn.prepare(
+            "SELECT d.id, d.ocr_text FROM documents d
+                LEFT JOIN embeddings e ON e.document_id = d.id
+                WHERE e.document_id IS NULL OR e.model != ?1
+                ORDER BY d.id DESC
+                LIMIT ?2"
+        )?;
+        let pending = stmt.query_map(params![embedder.model_name(), limit], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        pending
+    };
+
+    for (document_id, ocr_text) in &pending {
+        // Screenshots without text are stored with an empty vector, so they aren't picked up again
+        let vector = if ocr_text.trim().is_empty() { vec![] } else { embedder.embed(ocr_text)? };
+        conn.execute(
+            "INSERT OR REPLACE INTO embeddings (document_id, model, vector) VALUES (?1, ?2, ?3)",
+            params![document_id, embedder.model_name(), vector_to_blob(&vector)],
+        )?;
+    }
+
+    Ok(pending.len())
+
+}
+
+/**
+ * Find the screenshots whose embeddings are most similar to the query's, optionally restricted to
+ * a single display.  Only screenshots more similar than min_similarity are returned, so there
+ * are no hits when nothing is related.  Returns the screenshot ids with their cosine similarity,
+ * most similar first.
+ *
+ * This compares every embedding, which is fast enough for a few hundred thousand screenshots.
+ */
+pub fn find_similar_screenshots(query: &str, embedder: &dyn Embedder, dataset_root: &Path, db_filename: &Path, limit: i32, display_id: Option<u32>, min_similarity: f32) -> Result<Vec<(i64, f32)>, ScreentapError> {
+
+    let query_vector = embedder.embed(query)?;
+
+    let conn = db::get_db_conn(dataset_root, db_filename)?;
+    let mut stmt = conn.prepare(
+        "SELECT e.document_id, e.vector FROM embeddings e
+            JOIN documents d ON d.id = e.document_id
+            WHERE e.model = ?1 AND (?2 IS NULL OR d.display_id = ?2)"
+    )?;
+
+    let mut similarities = vec![];
+    let mut rows = stmt.query(params![embedder.model_name(), display_id])?;
+    while let Some(row) = rows.next()? {
+        let vector = blob_to_vector(&row.get::<_, Vec<u8>>(1)?);
+        if vector.len() != query_vector.len() {
+            continue;
+        }
+        let similarity = cosine_similarity(&query_vector, &vector);
+        if similarity > min_similarity {
+            similarities.push((row.get::<_, i64>(0)?, similarity));
+        }
+    }
+
+    similarities.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    similarities.truncate(limit.max(0) as usize);
+
+    Ok(similarities)
+
+}
+
+fn normalize(vector: Vec<f32>) -> Vec<f32> {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm == 0.0 {
+        return vector;
+    }
+    vector.into_iter().map(|x| x / norm).collect()
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norms = a.iter().map(|x| x * x).sum::<f32>().sqrt() * b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norms == 0.0 {
+        return 0.0;
+    }
+    dot / norms
+}
+
+fn vector_to_blob(vector: &[f32]) -> Vec<u8> {
+    vector.iter().flat_map(|x| x.to_le_bytes()).collect()
+}
+
+fn blob_to_vector(blob: &[u8]) -> Vec<f32> {
+    blob.chunks_exact(4).map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])).collect()
+}
+
+
+#[cfg(test)]
+pub mod test {
+
+    use super::{blob_to_vector, embed_pending_screenshots, find_similar_screenshots, vector_to_blob, Embedder};
+    use crate::db;
+    use crate::error::ScreentapError;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    /**
+     * Embeds text by counting a few keywords, so that tests don't need a model on disk
+     */
+    pub struct KeywordEmbedder;
+
+    impl Embedder for KeywordEmbedder {
+        fn model_name(&self) -> &str {
+            "keywords"
+        }
+        fn embed(&self, text: &str) -> Result<Vec<f32>, ScreentapError> {
+            let text = text.to_lowercase();
+            Ok(["invoice", "flight", "recipe"].iter().map(|keyword| text.matches(keyword).count() as f32).collect())
+        }
+    }
+
+    #[test]
+    fn test_embed_and_find_similar() {
+
+        let tmp_dir = tempdir().unwrap();
+        let data_root = tmp_dir.path();
+        let db_filename = Path::new("test.db");
+        db::create_db(data_root, db_filename).unwrap();
+
+        db::insert_test_screenshots(data_root, db_filename, &[(0, "Invoice #42 due"), (1, "Flight to Lisbon"), (2, ""), (3, "Recipe: flight of tacos")]);
+
+        assert_eq!(embed_pending_screenshots(data_root, db_filename, &KeywordEmbedder, 3).unwrap(), 3);
+        assert_eq!(embed_pending_screenshots(data_root, db_filename, &KeywordEmbedder, 3).unwrap(), 1);
+        assert_eq!(embed_pending_screenshots(data_root, db_filename, &KeywordEmbedder, 3).unwrap(), 0);
+
+        // Neither the screenshot without text nor the unrelated one is a match
+        let similar = find_similar_screenshots("flight", &KeywordEmbedder, data_root, db_filename, 10, None, 0.0).unwrap();
+        let ids: Vec<i64> = similar.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, vec![2, 4]);
+        assert!((similar[0].1 - 1.0).abs() < 1e-6);
+
+        let similar = find_similar_screenshots("flight", &KeywordEmbedder, data_root, db_filename, 10, None, 0.8).unwrap();
+        assert_eq!(similar.len(), 1);
+
+        assert_eq!(blob_to_vector(&vector_to_blob(&[1.5, -2.0])), vec![1.5, -2.0]);
+    }
+
+}
@@ -0,0 +1,76 @@
+use std::fmt;
+
+
+/**
+ * Errors from the core screentap modules (db, compaction, capture, config and embeddings)
+ */
+#[derive(Debug)]
+pub enum ScreentapError {
+
+    // Reading or writing files in the app data dir
+    Io(std::io::Error),
+
+    // Any sqlite error
+    Db(rusqlite::Error),
+
+    // The OCR step did not return any text
+    Ocr(String),
+
+    // Capturing a display or window failed
+    Capture(String),
+
+    // Encoding or decoding images or video
+    Encode(String),
+
+    // Invalid or unreadable config
+    Config(String),
+
+    // Invalid arguments from the UI or cli, eg an empty tag name
+    InvalidInput(String),
+
+    // Loading the embedding model or computing text embeddings
+    Embedding(String),
+}
+
+impl fmt::Display for ScreentapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScreentapError::Io(e) => write!(f, "IO error: {}", e),
+            ScreentapError::Db(e) => write!(f, "DB error: {}", e),
+            ScreentapError::Ocr(msg) => write!(f, "OCR error: {}", msg),
+            ScreentapError::Capture(msg) => write!(f, "Capture error: {}", msg),
+            ScreentapError::Encode(msg) => write!(f, "Encode error: {}", msg),
+            ScreentapError::Config(msg) => write!(f, "Config error: {}", msg),
+            ScreentapError::InvalidInput(msg) => write!(f, "Invalid input: {}", msg),
+            ScreentapError::Embedding(msg) => write!(f, "Embedding error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ScreentapError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ScreentapError::Io(e) => Some(e),
+            ScreentapError::Db(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ScreentapError {
+    fn from(e: std::io::Error) -> Self {
+        ScreentapError::Io(e)
+    }
+}
+
+impl From<rusqlite::Error> for ScreentapError {
+    fn from(e: rusqlite::Error) -> Self {
+        ScreentapError::Db(e)
+    }
+}
+
+impl From<image::ImageError> for ScreentapError {
+    fn from(e: image::ImageError) -> Self {
+        ScreentapError::Encode(e.to_string())
+    }
+}
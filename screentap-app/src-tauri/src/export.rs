@@ -0,0 +1,634 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Cursor, Write};
+use std::path::{Path, PathBuf};
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::imageops::{self, FilterType};
+use image::{Delay, DynamicImage, Frame, ImageFormat, Rgba, RgbaImage};
+use regex::Regex;
+use rusqlite::params;
+use serde::Serialize;
+
+use crate::config::ScreentapConfig;
+use crate::db::{self, ScreenshotRecord};
+use crate::error::ScreentapError;
+use crate::ocr_layout::{self, OcrRect};
+use crate::region_ocr;
+use crate::storage::StorageLayout;
+use crate::video::{self, FrameExtractor, VideoEncoder};
+
+
+// The most screenshots in a single export, about 8 hours at the default capture interval
+static EXPORT_MAX_FRAMES: usize = 1000;
+
+// How many screenshots are loaded at once, so frames of the same mp4 are extracted together
+// without holding a whole export in memory
+static EXPORT_BATCH_SIZE: usize = 32;
+
+// GIF frames are scaled down to this width, since GIFs of full resolution screenshots are huge
+static GIF_MAX_WIDTH: u32 = 1280;
+
+// How long each GIF frame is shown, like a frame of the 1 fps mp4 files
+static GIF_FRAME_DELAY_MS: u32 = 1000;
+
+// From 1 to 30, where 1 gives the best GIF colors and 30 encodes fastest
+static GIF_ENCODE_SPEED: i32 = 10;
+
+// Replaces redacted text in the sidecar
+static REDACTED_TEXT: &str = "[redacted]";
+
+
+/**
+ * What an export is written as
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+
+    // A 1 fps video, like the compacted segments
+    Mp4,
+
+    // An animated GIF, scaled down to GIF_MAX_WIDTH
+    Gif,
+
+    // A zip of one full resolution PNG per screenshot
+    Zip,
+}
+
+impl ExportFormat {
+
+    pub fn parse(format: &str) -> Option<ExportFormat> {
+        match format {
+            "mp4" => Some(ExportFormat::Mp4),
+            "gif" => Some(ExportFormat::Gif),
+            "zip" => Some(ExportFormat::Zip),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Mp4 => "mp4",
+            ExportFormat::Gif => "gif",
+            ExportFormat::Zip => "zip",
+        }
+    }
+
+}
+
+/**
+ * Which screenshots are exported.  They're always exported in the order they were taken.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExportSelection {
+
+    // The screenshots taken between two timestamps, inclusive, optionally of a single display
+    TimeRange { start_timestamp: i64, end_timestamp: i64, display_id: Option<u32> },
+
+    // The screenshots with these ids
+    Ids(Vec<i64>),
+}
+
+/**
+ * What's hidden in exports, see export_redact_patterns and export_redact_apps in
+ * config_sample.toml
+ */
+#[derive(Debug, Clone, Default)]
+pub struct RedactionRules {
+    patterns: Vec<Regex>,
+    apps: Vec<String>,
+}
+
+impl RedactionRules {
+
+    pub fn from_config(config: &ScreentapConfig) -> Result<Self, ScreentapError> {
+        let patterns = config.export_redact_patterns.iter()
+            .map(|pattern| Regex::new(pattern).map_err(|e| ScreentapError::Config(format!("Invalid redaction regex {}: {}", pattern, e))))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(RedactionRules { patterns, apps: config.export_redact_apps.clone() })
+    }
+
+    fn hides_screenshot(&self, record: &ScreenshotRecord) -> bool {
+        self.apps.iter().any(|app| app == record.get_frontmost_app())
+    }
+
+    /**
+     * Does anything in the screenshot need to be hidden?  The OCR layout has the same text as
+     * the OCR text, so this doesn't need the image or the layout.
+     */
+    fn applies_to(&self, record: &ScreenshotRecord) -> bool {
+        self.hides_screenshot(record) || self.patterns.iter().any(|pattern| pattern.is_match(record.get_ocr_text()))
+    }
+
+    fn redact_text(&self, text: &str) -> String {
+        self.patterns.iter().fold(text.to_string(), |text, pattern| pattern.replace_all(&text, REDACTED_TEXT).into_owned())
+    }
+
+}
+
+/**
+ * One screenshot in the sidecar JSON of an export
+ */
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ExportedFrame {
+
+    // The frame of the mp4 or GIF, or the position in the zip
+    pub frame_index: usize,
+
+    pub screenshot_id: i64,
+    pub timestamp: i64,
+    pub display_id: u32,
+    pub frontmost_app: String,
+
+    // With the text matching the redaction patterns replaced
+    pub ocr_text: String,
+
+    pub redacted: bool,
+
+    // The name of the PNG in the zip
+    pub file_name: Option<String>,
+}
+
+/**
+ * The sidecar JSON written next to each export
+ */
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ExportSidecar {
+    pub format: String,
+    pub start_timestamp: i64,
+    pub end_timestamp: i64,
+    pub frames: Vec<ExportedFrame>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ExportReport {
+    pub output_path: PathBuf,
+    pub sidecar_path: PathBuf,
+    pub frame_count: usize,
+    pub redacted_frame_count: usize,
+
+    // The export is a copy of an existing segment's mp4, which is much faster than encoding
+    pub copied_segment: bool,
+}
+
+
+/**
+ * Exports screenshots as a video, a GIF or a zip of PNGs, with a sidecar JSON of their metadata
+ * and OCR text.  Frames are read from the loose screenshots and the compacted segments.
+ */
+pub struct Exporter {
+    data_root: PathBuf,
+    db_filename: PathBuf,
+    video_encoder: Box<dyn VideoEncoder>,
+    frame_extractor: Box<dyn FrameExtractor>,
+    redaction: RedactionRules,
+}
+
+impl Exporter {
+
+    pub fn new(data_root: PathBuf, db_filename: PathBuf, video_encoder: Box<dyn VideoEncoder>, frame_extractor: Box<dyn FrameExtractor>, redaction: RedactionRules) -> Self {
+        Exporter { data_root, db_filename, video_encoder, frame_extractor, redaction }
+    }
+
+    pub fn from_config(data_root: PathBuf, db_filename: PathBuf, config: &ScreentapConfig) -> Result<Self, ScreentapError> {
+        let redaction = RedactionRules::from_config(config)?;
+        Ok(Exporter::new(data_root, db_filename, video::create_encoder(config), video::create_frame_extractor(config), redaction))
+    }
+
+    /**
+     * Export the selected screenshots to output_path, and their sidecar JSON to output_path
+     * with a .json extension.  An mp4 export of exactly the screenshots of one segment, with
+     * nothing to redact, is a copy of the segment's mp4.
+     */
+    pub fn export(&self, selection: &ExportSelection, format: ExportFormat, output_path: &Path) -> Result<ExportReport, ScreentapError> {
+
+        let records = self.get_selected_screenshots(selection)?;
+        if records.is_empty() {
+            return Err(ScreentapError::InvalidInput("No screenshots to export".to_string()));
+        }
+        if records.len() > EXPORT_MAX_FRAMES {
+            return Err(ScreentapError::InvalidInput(format!("Can't export {} screenshots, the most is {}", records.len(), EXPORT_MAX_FRAMES)));
+        }
+
+        if let Some(parent_dir) = output_path.parent() {
+            std::fs::create_dir_all(parent_dir)?;
+        }
+
+        let copied_segment = format == ExportFormat::Mp4 && self.copy_whole_segment(&records, output_path)?;
+
+        let frames = if copied_segment {
+            records.iter()
+                .map(|record| self.get_exported_frame(record, record.get_mp4_frame_id().max(0) as usize, None))
+                .collect()
+        } else {
+            match format {
+                ExportFormat::Mp4 => self.write_mp4(&records, output_path)?,
+                ExportFormat::Gif => self.write_gif(&records, output_path)?,
+                ExportFormat::Zip => self.write_zip(&records, output_path)?,
+            }
+        };
+
+        let sidecar = ExportSidecar {
+            format: format.extension().to_string(),
+            start_timestamp: records.first().map(|record| record.get_timestamp() as i64).unwrap_or_default(),
+            end_timestamp: records.last().map(|record| record.get_timestamp() as i64).unwrap_or_default(),
+            frames,
+        };
+        let sidecar_path = output_path.with_extension("json");
+        let sidecar_json = serde_json::to_string_pretty(&sidecar)
+            .map_err(|e| ScreentapError::Encode(format!("Failed to write the sidecar of {}: {}", output_path.display(), e)))?;
+        std::fs::write(&sidecar_path, sidecar_json)?;
+
+        let report = ExportReport {
+            output_path: output_path.to_path_buf(),
+            sidecar_path,
+            frame_count: sidecar.frames.len(),
+            redacted_frame_count: sidecar.frames.iter().filter(|frame| frame.redacted).count(),
+            copied_segment,
+        };
+        println!("Exported {} screenshots to {}", report.frame_count, output_path.display());
+
+        Ok(report)
+
+    }
+
+    fn get_selected_screenshots(&self, selection: &ExportSelection) -> Result<Vec<ScreenshotRecord>, ScreentapError> {
+        match selection {
+            ExportSelection::TimeRange { start_timestamp, end_timestamp, display_id } => {
+                db::find_screenshots_in_range(&self.data_root, &self.db_filename, *start_timestamp, *end_timestamp, *display_id)
+            },
+            ExportSelection::Ids(ids) => {
+                let mut records = db::find_screenshots_by_ids(&self.data_root, &self.db_filename, ids)?;
+                records.sort_by_key(|record| (record.get_timestamp(), record.get_id()));
+                records.dedup_by_key(|record| record.get_id());
+                Ok(records)
+            },
+        }
+    }
+
+    /**
+     * Copy the mp4 of a segment if the screenshots are all of the screenshots in it, and none
+     * of them need to be redacted.  Returns false if the export has to be encoded.
+     */
+    fn copy_whole_segment(&self, records: &[ScreenshotRecord], output_path: &Path) -> Result<bool, ScreentapError> {
+
+        let mp4_file_path = records[0].get_mp4_file_path();
+        if mp4_file_path.is_empty()
+            || records.iter().any(|record| record.get_mp4_file_path() != mp4_file_path || self.redaction.applies_to(record)) {
+            return Ok(false);
+        }
+
+        let conn = db::get_db_conn(&self.data_root, &self.db_filename)?;
+        let segment_screenshots: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM documents WHERE mp4_file_path = ?1",
+            params![StorageLayout::new(&self.data_root).to_relative_string(Path::new(mp4_file_path))],
+            |row| row.get(0),
+        )?;
+        if segment_screenshots != records.len() as i64 {
+            return Ok(false);
+        }
+
+        std::fs::copy(mp4_file_path, output_path)?;
+        Ok(true)
+
+    }
+
+    fn write_mp4(&self, records: &[ScreenshotRecord], output_path: &Path) -> Result<Vec<ExportedFrame>, ScreentapError> {
+
+        let work_dir = tempfile::tempdir()?;
+        let mut png_files = vec![];
+        let mut frames = vec![];
+
+        // Screenshots of other displays are letterboxed to the size of the first, rather than
+        // leaving it to the video backend to stretch them
+        let mut mp4_size: Option<(u32, u32)> = None;
+
+        self.for_each_frame(records, |frame_index, record, png_data| {
+            let png_file = work_dir.path().join(format!("{:06}.png", frame_index));
+            let size = image::io::Reader::new(Cursor::new(png_data)).with_guessed_format()?.into_dimensions()?;
+            let (width, height) = *mp4_size.get_or_insert(size);
+            if size == (width, height) {
+                std::fs::write(&png_file, png_data)?;
+            } else {
+                letterbox(&image::load_from_memory(png_data)?, width, height).save(&png_file)?;
+            }
+            png_files.push(png_file);
+            frames.push(self.get_exported_frame(record, frame_index, None));
+            Ok(())
+        })?;
+
+        self.video_encoder.encode(&png_files, output_path)?;
+        Ok(frames)
+
+    }
+
+    fn write_gif(&self, records: &[ScreenshotRecord], output_path: &Path) -> Result<Vec<ExportedFrame>, ScreentapError> {
+
+        let mut encoder = GifEncoder::new_with_speed(BufWriter::new(File::create(output_path)?), GIF_ENCODE_SPEED);
+        encoder.set_repeat(Repeat::Infinite)?;
+
+        // Every frame is letterboxed to the size of the first, since a GIF has a single size
+        let mut gif_size: Option<(u32, u32)> = None;
+        let mut frames = vec![];
+
+        self.for_each_frame(records, |frame_index, record, png_data| {
+            let image = image::load_from_memory(png_data)?;
+            let (width, height) = *gif_size.get_or_insert_with(|| {
+                let width = image.width().min(GIF_MAX_WIDTH).max(1);
+                (width, ((image.height() as u64 * width as u64) / image.width().max(1) as u64).max(1) as u32)
+            });
+            let image = letterbox(&image, width, height);
+            encoder.encode_frame(Frame::from_parts(image, 0, 0, Delay::from_numer_denom_ms(GIF_FRAME_DELAY_MS, 1)))?;
+            frames.push(self.get_exported_frame(record, frame_index, None));
+            Ok(())
+        })?;
+
+        Ok(frames)
+
+    }
+
+    fn write_zip(&self, records: &[ScreenshotRecord], output_path: &Path) -> Result<Vec<ExportedFrame>, ScreentapError> {
+
+        let mut zip = zip::ZipWriter::new(File::create(output_path)?);
+
+        // PNGs are already compressed
+        let options = zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        let mut frames = vec![];
+
+        self.for_each_frame(records, |frame_index, record, png_data| {
+            let file_name = format!("{:06}_{}.png", frame_index, record.get_id());
+            zip.start_file(file_name.as_str(), options).map_err(zip_error)?;
+            zip.write_all(png_data)?;
+            frames.push(self.get_exported_frame(record, frame_index, Some(file_name)));
+            Ok(())
+        })?;
+
+        zip.finish().map_err(zip_error)?;
+        Ok(frames)
+
+    }
+
+    /**
+     * Call f with each screenshot as a redacted PNG, in order.  Frames of the same mp4 are
+     * extracted in a single pass per batch, and PNGs that don't need redacting are passed on
+     * as they were read, without decoding them.
+     */
+    fn for_each_frame<F>(&self, records: &[ScreenshotRecord], mut f: F) -> Result<(), ScreentapError>
+    where F: FnMut(usize, &ScreenshotRecord, &[u8]) -> Result<(), ScreentapError> {
+
+        for (batch_index, batch) in records.chunks(EXPORT_BATCH_SIZE).enumerate() {
+
+            let image_data = self.load_image_data(batch)?;
+
+            for (index_in_batch, (record, image_data)) in batch.iter().zip(image_data).enumerate() {
+                let png_data = self.redact_to_png(record, image_data)?;
+                f(batch_index * EXPORT_BATCH_SIZE + index_in_batch, record, &png_data)?;
+            }
+        }
+
+        Ok(())
+
+    }
+
+    /**
+     * The stored image of each screenshot, in order: the loose file, or the PNG of its mp4 frame
+     */
+    fn load_image_data(&self, records: &[ScreenshotRecord]) -> Result<Vec<Vec<u8>>, ScreentapError> {
+
+        // Tiering can point several screenshots at the same frame
+        let mut frame_ids_by_mp4: HashMap<&str, Vec<i32>> = HashMap::new();
+        for record in records.iter().filter(|record| !record.get_mp4_file_path().is_empty()) {
+            let frame_ids = frame_ids_by_mp4.entry(record.get_mp4_file_path()).or_default();
+            if !frame_ids.contains(&record.get_mp4_frame_id()) {
+                frame_ids.push(record.get_mp4_frame_id());
+            }
+        }
+
+        // Bypass the frame cache, so that an export doesn't evict the frames of recent searches
+        let mut frames_by_mp4: HashMap<&str, HashMap<i32, Vec<u8>>> = HashMap::new();
+        for (mp4_file_path, frame_ids) in frame_ids_by_mp4 {
+            let frames = self.frame_extractor.extract_frames(Path::new(mp4_file_path), &frame_ids)?;
+            frames_by_mp4.insert(mp4_file_path, frame_ids.into_iter().zip(frames).collect());
+        }
+
+        records.iter()
+            .map(|record| {
+                if record.get_mp4_file_path().is_empty() {
+                    return Ok(std::fs::read(record.get_file_path())?);
+                }
+                frames_by_mp4.get(record.get_mp4_file_path())
+                    .and_then(|frames| frames.get(&record.get_mp4_frame_id()))
+                    .cloned()
+                    .ok_or_else(|| ScreentapError::Encode(format!("Could not extract frame {} from {}", record.get_mp4_frame_id(), record.get_mp4_file_path())))
+            })
+            .collect()
+
+    }
+
+    /**
+     * Black out the redacted text of a screenshot, or all of it if its app is redacted, and
+     * return it as a PNG.  A screenshot without an OCR layout is blacked out whole if its text
+     * matches, since the matches can't be located.
+     */
+    fn redact_to_png(&self, record: &ScreenshotRecord, image_data: Vec<u8>) -> Result<Vec<u8>, ScreentapError> {
+
+        let needs_redaction = self.redaction.applies_to(record);
+        if !needs_redaction && image::guess_format(&image_data).ok() == Some(ImageFormat::Png) {
+            return Ok(image_data);
+        }
+
+        let mut image = region_ocr::decode_image(&image_data)?.to_rgba8();
+
+        if needs_redaction {
+            let full_frame = OcrRect { x: 0.0, y: 0.0, width: 1.0, height: 1.0 };
+            let rects = if self.redaction.hides_screenshot(record) {
+                vec![full_frame]
+            } else {
+                match ocr_layout::get_ocr_layout(&self.data_root, &self.db_filename, record.get_id() as i64)? {
+                    Some(layout) => self.redaction.patterns.iter().flat_map(|pattern| layout.find_matches(pattern)).collect(),
+                    None => vec![full_frame],
+                }
+            };
+            for rect in &rects {
+                black_out(&mut image, rect);
+            }
+        }
+
+        let mut png_data = vec![];
+        DynamicImage::ImageRgba8(image).write_to(&mut Cursor::new(&mut png_data), ImageFormat::Png)?;
+        Ok(png_data)
+
+    }
+
+    fn get_exported_frame(&self, record: &ScreenshotRecord, frame_index: usize, file_name: Option<String>) -> ExportedFrame {
+        let hidden = self.redaction.hides_screenshot(record);
+        ExportedFrame {
+            frame_index,
+            screenshot_id: record.get_id() as i64,
+            timestamp: record.get_timestamp() as i64,
+            display_id: record.get_display_id(),
+            frontmost_app: record.get_frontmost_app().to_string(),
+            ocr_text: if hidden { String::new() } else { self.redaction.redact_text(record.get_ocr_text()) },
+            redacted: self.redaction.applies_to(record),
+            file_name,
+        }
+    }
+
+}
+
+/**
+ * Fill a normalized rect of the image with black, rounding outwards so no edge of the text
+ * is left
+ */
+fn black_out(image: &mut RgbaImage, rect: &OcrRect) {
+
+    let (width, height) = (image.width() as f32, image.height() as f32);
+    let left = (rect.x.clamp(0.0, 1.0) * width).floor() as u32;
+    let top = (rect.y.clamp(0.0, 1.0) * height).floor() as u32;
+    let right = ((rect.x + rect.width).clamp(0.0, 1.0) * width).ceil() as u32;
+    let bottom = ((rect.y + rect.height).clamp(0.0, 1.0) * height).ceil() as u32;
+
+    for y in top..bottom {
+        for x in left..right {
+            image.put_pixel(x, y, Rgba([0, 0, 0, 255]));
+        }
+    }
+
+}
+
+/**
+ * Scale an image to fit in width x height, keeping its aspect ratio, and center it on black
+ */
+fn letterbox(image: &DynamicImage, width: u32, height: u32) -> RgbaImage {
+
+    if image.width() == width && image.height() == height {
+        return image.to_rgba8();
+    }
+
+    let resized = image.resize(width, height, FilterType::Triangle).to_rgba8();
+    let mut canvas = RgbaImage::from_pixel(width, height, Rgba([0, 0, 0, 255]));
+    let x = (width - resized.width().min(width)) / 2;
+    let y = (height - resized.height().min(height)) / 2;
+    imageops::overlay(&mut canvas, &resized, x as i64, y as i64);
+    canvas
+
+}
+
+fn zip_error(e: zip::result::ZipError) -> ScreentapError {
+    ScreentapError::Encode(format!("Failed to write zip: {}", e))
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use super::{ExportFormat, ExportSelection, Exporter, RedactionRules};
+    use crate::config::ScreentapConfig;
+    use crate::db;
+    use crate::error::ScreentapError;
+    use crate::ocr_layout::{self, OcrLayout, OcrLine, OcrRect, OcrWord};
+    use crate::video::{FrameExtractor, VideoEncoder};
+    use chrono::{Duration, NaiveDate};
+    use image::codecs::gif::GifDecoder;
+    use image::{AnimationDecoder, Rgba, RgbaImage};
+    use std::io::Read;
+    use std::path::{Path, PathBuf};
+    use tempfile::tempdir;
+
+    struct NoVideo;
+
+    impl VideoEncoder for NoVideo {
+        fn encode(&self, _frames: &[PathBuf], _target_mp4: &Path) -> Result<(), ScreentapError> {
+            Err(ScreentapError::Encode("No video in this test".to_string()))
+        }
+    }
+
+    impl FrameExtractor for NoVideo {
+        fn extract_frame(&self, _mp4_path: &Path, _frame_id: i32) -> Result<Vec<u8>, ScreentapError> {
+            Err(ScreentapError::Encode("No video in this test".to_string()))
+        }
+        fn count_frames(&self, _mp4_path: &Path) -> Result<usize, ScreentapError> {
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn test_export_zip_and_gif_with_redaction() {
+
+        let tmp_dir = tempdir().unwrap();
+        let data_root = tmp_dir.path();
+        let db_filename = Path::new("test.db");
+        db::create_db(data_root, db_filename).unwrap();
+
+        // A white screenshot with an email address in its top half, one of a password manager,
+        // and a narrower one of another display
+        let start = NaiveDate::from_ymd_opt(2024, 3, 7).unwrap().and_hms_opt(10, 0, 0).unwrap();
+        let captures = [("Mail from bob@example.com", "com.apple.mail"), ("Vault", "com.1password.1password"), ("Later", "com.apple.mail")];
+        for (second, (ocr_text, frontmost_app)) in captures.iter().enumerate() {
+            let png_path = data_root.join(format!("{}.png", second));
+            let width = if second == 2 { 50 } else { 100 };
+            RgbaImage::from_pixel(width, 40, Rgba([255, 255, 255, 255])).save(&png_path).unwrap();
+            let now = start + Duration::seconds(30 * second as i64);
+            let capture_meta = db::CaptureMeta { frontmost_app: frontmost_app.to_string(), ..db::CaptureMeta::default() };
+            db::save_screenshot_meta(&png_path, ocr_text, data_root, db_filename, now, &capture_meta).unwrap();
+        }
+        let email_bounds = OcrRect { x: 0.5, y: 0.0, width: 0.5, height: 0.5 };
+        let layout = OcrLayout { lines: vec![OcrLine {
+            text: "Mail from bob@example.com".to_string(),
+            confidence: 1.0,
+            bounds: OcrRect { x: 0.0, y: 0.0, width: 1.0, height: 0.5 },
+            words: vec![
+                OcrWord { text: "Mail".to_string(), confidence: 1.0, bounds: OcrRect { x: 0.0, y: 0.0, width: 0.2, height: 0.5 } },
+                OcrWord { text: "from".to_string(), confidence: 1.0, bounds: OcrRect { x: 0.25, y: 0.0, width: 0.2, height: 0.5 } },
+                OcrWord { text: "bob@example.com".to_string(), confidence: 1.0, bounds: email_bounds },
+            ],
+        }] };
+        ocr_layout::save_ocr_layout(data_root, db_filename, 1, &layout).unwrap();
+
+        let config = ScreentapConfig {
+            export_redact_patterns: vec![r"[\w.+-]+@[\w-]+\.[\w.]+".to_string()],
+            export_redact_apps: vec!["com.1password.1password".to_string()],
+            ..ScreentapConfig::default()
+        };
+        let exporter = Exporter::new(data_root.to_path_buf(), db_filename.to_path_buf(), Box::new(NoVideo), Box::new(NoVideo), RedactionRules::from_config(&config).unwrap());
+
+        // The first two screenshots, by time range
+        let selection = ExportSelection::TimeRange {
+            start_timestamp: start.timestamp(),
+            end_timestamp: (start + Duration::seconds(30)).timestamp(),
+            display_id: None,
+        };
+        let zip_path = data_root.join("exports").join("export.zip");
+        let report = exporter.export(&selection, ExportFormat::Zip, &zip_path).unwrap();
+        assert_eq!((report.frame_count, report.redacted_frame_count, report.copied_segment), (2, 2, false));
+
+        let mut zip = zip::ZipArchive::new(std::fs::File::open(&zip_path).unwrap()).unwrap();
+        let mut png_data = vec![];
+        zip.by_name("000000_1.png").unwrap().read_to_end(&mut png_data).unwrap();
+        let image = image::load_from_memory(&png_data).unwrap().to_rgba8();
+        assert_eq!(*image.get_pixel(75, 10), Rgba([0, 0, 0, 255]));
+        assert_eq!(*image.get_pixel(10, 10), Rgba([255, 255, 255, 255]));
+        assert_eq!(*image.get_pixel(75, 30), Rgba([255, 255, 255, 255]));
+        png_data.clear();
+        zip.by_name("000001_2.png").unwrap().read_to_end(&mut png_data).unwrap();
+        assert_eq!(*image::load_from_memory(&png_data).unwrap().to_rgba8().get_pixel(10, 30), Rgba([0, 0, 0, 255]));
+
+        let sidecar: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&report.sidecar_path).unwrap()).unwrap();
+        assert_eq!(sidecar["frames"][0]["ocr_text"], "Mail from [redacted]");
+        assert_eq!(sidecar["frames"][1]["ocr_text"], "");
+        assert_eq!(sidecar["frames"][1]["file_name"], "000001_2.png");
+
+        // By ids, in the order they were taken
+        let gif_path = data_root.join("export.gif");
+        let report = exporter.export(&ExportSelection::Ids(vec![3, 1]), ExportFormat::Gif, &gif_path).unwrap();
+        assert_eq!(report.frame_count, 2);
+        assert_eq!(image::image_dimensions(&gif_path).unwrap(), (100, 40));
+
+        // The narrower screenshot is letterboxed, not stretched
+        let gif_frames = GifDecoder::new(std::fs::File::open(&gif_path).unwrap()).unwrap().into_frames().collect_frames().unwrap();
+        assert_eq!(gif_frames[1].buffer().get_pixel(10, 20)[0], 0);
+        assert!(gif_frames[1].buffer().get_pixel(50, 20)[0] > 200);
+
+        assert!(matches!(exporter.export(&ExportSelection::Ids(vec![]), ExportFormat::Gif, &gif_path), Err(ScreentapError::InvalidInput(_))));
+    }
+
+}
@@ -0,0 +1,267 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use crate::error::ScreentapError;
+use crate::video::FrameExtractor;
+
+
+/**
+ * Default memory budget of the frame cache, see frame_cache_max_mb in the config
+ */
+pub const DEFAULT_MAX_MB: u64 = 256;
+
+/**
+ * The cache shared by every DB query that returns screenshots
+ */
+static FRAME_CACHE: OnceLock<Mutex<FrameCache>> = OnceLock::new();
+
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct FrameKey {
+    mp4_path: PathBuf,
+    frame_id: i32,
+}
+
+struct CacheEntry {
+    png_data: Arc<Vec<u8>>,
+    last_used: u64,
+}
+
+/**
+ * An LRU cache of frames extracted from mp4 files, bounded by the total size of the
+ * PNG data rather than the number of frames, since frames from different displays
+ * can differ in size by an order of magnitude.
+ */
+pub struct FrameCache {
+    max_bytes: u64,
+    used_bytes: u64,
+    entries: HashMap<FrameKey, CacheEntry>,
+
+    // Keyed by CacheEntry::last_used, so the first entry is the least recently used frame
+    usage_order: BTreeMap<u64, FrameKey>,
+    usage_counter: u64,
+}
+
+impl FrameCache {
+
+    pub fn new(max_bytes: u64) -> Self {
+        Self {
+            max_bytes,
+            used_bytes: 0,
+            entries: HashMap::new(),
+            usage_order: BTreeMap::new(),
+            usage_counter: 0,
+        }
+    }
+
+    pub fn set_max_bytes(&mut self, max_bytes: u64) {
+        self.max_bytes = max_bytes;
+        self.evict();
+    }
+
+    pub fn used_bytes(&self) -> u64 {
+        self.used_bytes
+    }
+
+    pub fn get(&mut self, mp4_path: &Path, frame_id: i32) -> Option<Arc<Vec<u8>>> {
+        let key = FrameKey { mp4_path: mp4_path.to_path_buf(), frame_id };
+        let entry = self.entries.get_mut(&key)?;
+
+        self.usage_counter += 1;
+        self.usage_order.remove(&entry.last_used);
+        self.usage_order.insert(self.usage_counter, key);
+        entry.last_used = self.usage_counter;
+
+        Some(entry.png_data.clone())
+    }
+
+    /**
+     * Add a frame, evicting the least recently used frames to stay within max_bytes.  A frame
+     * bigger than the whole cache is returned without being cached.
+     */
+    pub fn insert(&mut self, mp4_path: &Path, frame_id: i32, png_data: Vec<u8>) -> Arc<Vec<u8>> {
+        let png_data = Arc::new(png_data);
+        let size = png_data.len() as u64;
+        if size > self.max_bytes {
+            return png_data;
+        }
+
+        let key = FrameKey { mp4_path: mp4_path.to_path_buf(), frame_id };
+        self.remove(&key);
+
+        self.usage_counter += 1;
+        self.usage_order.insert(self.usage_counter, key.clone());
+        self.entries.insert(key, CacheEntry { png_data: png_data.clone(), last_used: self.usage_counter });
+        self.used_bytes += size;
+
+        self.evict();
+        png_data
+    }
+
+    fn remove(&mut self, key: &FrameKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.usage_order.remove(&entry.last_used);
+            self.used_bytes -= entry.png_data.len() as u64;
+        }
+    }
+
+    fn evict(&mut self) {
+        while self.used_bytes > self.max_bytes {
+            let Some((_, key)) = self.usage_order.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&key) {
+                self.used_bytes -= entry.png_data.len() as u64;
+            }
+        }
+    }
+
+}
+
+
+fn global_cache() -> &'static Mutex<FrameCache> {
+    FRAME_CACHE.get_or_init(|| Mutex::new(FrameCache::new(DEFAULT_MAX_MB * 1024 * 1024)))
+}
+
+/**
+ * Resize the shared cache, eg after the config was reloaded
+ */
+pub fn set_max_mb(max_mb: u64) {
+    global_cache().lock().unwrap().set_max_bytes(max_mb * 1024 * 1024);
+}
+
+/**
+ * Get frames of one mp4 from the shared cache, extracting the ones that aren't cached
+ * in a single batch.  See get_frames_with_cache().
+ */
+pub fn get_frames(mp4_path: &Path, frame_ids: &[i32], frame_extractor: &dyn FrameExtractor) -> Result<HashMap<i32, Arc<Vec<u8>>>, ScreentapError> {
+    get_frames_with_cache(global_cache(), mp4_path, frame_ids, frame_extractor)
+}
+
+/**
+ * Get frames of one mp4 keyed by frame id.  The lock is not held while extracting, so
+ * a slow extraction doesn't block reads of frames that are already cached.
+ */
+pub fn get_frames_with_cache(cache: &Mutex<FrameCache>, mp4_path: &Path, frame_ids: &[i32], frame_extractor: &dyn FrameExtractor) -> Result<HashMap<i32, Arc<Vec<u8>>>, ScreentapError> {
+
+    let mut frames = HashMap::new();
+    let mut missing_frame_ids = vec![];
+    {
+        let mut cache = cache.lock().unwrap();
+        for &frame_id in frame_ids {
+            if frames.contains_key(&frame_id) || missing_frame_ids.contains(&frame_id) {
+                continue;
+            }
+            match cache.get(mp4_path, frame_id) {
+                Some(png_data) => { frames.insert(frame_id, png_data); },
+                None => missing_frame_ids.push(frame_id),
+            }
+        }
+    }
+
+    if missing_frame_ids.is_empty() {
+        return Ok(frames);
+    }
+
+    let extracted_frames = frame_extractor.extract_frames(mp4_path, &missing_frame_ids)?;
+
+    let mut cache = cache.lock().unwrap();
+    for (frame_id, png_data) in missing_frame_ids.into_iter().zip(extracted_frames) {
+        frames.insert(frame_id, cache.insert(mp4_path, frame_id, png_data));
+    }
+
+    Ok(frames)
+
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use super::{get_frames_with_cache, FrameCache};
+    use crate::error::ScreentapError;
+    use crate::video::FrameExtractor;
+    use std::path::Path;
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /**
+     * Returns the frame id as a single byte, and counts the extracted frames
+     */
+    #[derive(Default)]
+    struct CountingFrameExtractor {
+        frames_extracted: AtomicUsize,
+        batches: AtomicUsize,
+    }
+
+    impl FrameExtractor for CountingFrameExtractor {
+        fn extract_frame(&self, _mp4_path: &Path, frame_id: i32) -> Result<Vec<u8>, ScreentapError> {
+            self.frames_extracted.fetch_add(1, Ordering::SeqCst);
+            Ok(vec![frame_id as u8])
+        }
+
+        fn extract_frames(&self, mp4_path: &Path, frame_ids: &[i32]) -> Result<Vec<Vec<u8>>, ScreentapError> {
+            self.batches.fetch_add(1, Ordering::SeqCst);
+            frame_ids.iter().map(|&frame_id| self.extract_frame(mp4_path, frame_id)).collect()
+        }
+
+        fn count_frames(&self, _mp4_path: &Path) -> Result<usize, ScreentapError> {
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used_by_size() {
+
+        let mp4_path = Path::new("a.mp4");
+        let mut cache = FrameCache::new(10);
+
+        cache.insert(mp4_path, 0, vec![0; 4]);
+        cache.insert(mp4_path, 1, vec![1; 4]);
+
+        // Touch frame 0, so frame 1 is the least recently used
+        assert!(cache.get(mp4_path, 0).is_some());
+
+        cache.insert(mp4_path, 2, vec![2; 4]);
+        assert_eq!(cache.used_bytes(), 8);
+        assert!(cache.get(mp4_path, 1).is_none());
+        assert!(cache.get(mp4_path, 0).is_some());
+        assert!(cache.get(mp4_path, 2).is_some());
+
+        // Too big to cache at all
+        assert_eq!(cache.insert(mp4_path, 3, vec![3; 11]).len(), 11);
+        assert!(cache.get(mp4_path, 3).is_none());
+        assert_eq!(cache.used_bytes(), 8);
+
+        // Shrinking the cache evicts right away
+        cache.set_max_bytes(4);
+        assert_eq!(cache.used_bytes(), 4);
+        assert!(cache.get(mp4_path, 2).is_some());
+    }
+
+    #[test]
+    fn test_get_frames_extracts_misses_in_one_batch() {
+
+        let mp4_path = Path::new("a.mp4");
+        let cache = Mutex::new(FrameCache::new(1024));
+        let frame_extractor = CountingFrameExtractor::default();
+
+        let frames = get_frames_with_cache(&cache, mp4_path, &[3, 1, 3], &frame_extractor).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(*frames[&3], vec![3]);
+        assert_eq!(frame_extractor.batches.load(Ordering::SeqCst), 1);
+        assert_eq!(frame_extractor.frames_extracted.load(Ordering::SeqCst), 2);
+
+        // Only frame 2 is new
+        let frames = get_frames_with_cache(&cache, mp4_path, &[1, 2, 3], &frame_extractor).unwrap();
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frame_extractor.batches.load(Ordering::SeqCst), 2);
+        assert_eq!(frame_extractor.frames_extracted.load(Ordering::SeqCst), 3);
+
+        // Everything is cached
+        get_frames_with_cache(&cache, mp4_path, &[1, 2], &frame_extractor).unwrap();
+        assert_eq!(frame_extractor.batches.load(Ordering::SeqCst), 2);
+    }
+
+}
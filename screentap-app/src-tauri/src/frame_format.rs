@@ -0,0 +1,174 @@
+use std::io::Cursor;
+use std::path::Path;
+use image::codecs::avif::AvifEncoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::{ColorType, ImageEncoder};
+use serde::Deserialize;
+
+use crate::error::ScreentapError;
+
+
+// The quality of lossy formats, from 1 to 100
+pub const DEFAULT_FRAME_QUALITY: u8 = 80;
+
+/**
+ * AVIF encoding speed from 1 (slowest, smallest) to 10 (fastest).  Captures are encoded on
+ * the capture thread, so this leans towards fast.
+ */
+const AVIF_SPEED: u8 = 8;
+
+
+/**
+ * The on-disk format of loose screenshots, until they are compacted into an mp4
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FrameFormat {
+
+    // Lossless and the largest, as captured
+    Png,
+
+    // Lossless, typically a third smaller than PNG for screenshots
+    Webp,
+
+    // Lossy, see frame_quality.  Blurs small text at low quality.
+    Jpeg,
+
+    // Lossy, see frame_quality.  The smallest, but the slowest to encode.
+    Avif,
+}
+
+impl FrameFormat {
+
+    pub const ALL: [FrameFormat; 4] = [FrameFormat::Png, FrameFormat::Webp, FrameFormat::Jpeg, FrameFormat::Avif];
+
+    /**
+     * The name stored in the image_format column
+     */
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FrameFormat::Png => "png",
+            FrameFormat::Webp => "webp",
+            FrameFormat::Jpeg => "jpeg",
+            FrameFormat::Avif => "avif",
+        }
+    }
+
+    pub fn parse(format: &str) -> Option<FrameFormat> {
+        match format {
+            "png" => Some(FrameFormat::Png),
+            "webp" => Some(FrameFormat::Webp),
+            "jpeg" | "jpg" => Some(FrameFormat::Jpeg),
+            "avif" => Some(FrameFormat::Avif),
+            _ => None,
+        }
+    }
+
+    /**
+     * Get the format of a screenshot file from its extension.  Files written before the
+     * format was configurable are all PNG.
+     */
+    pub fn from_path(path: &Path) -> FrameFormat {
+        path.extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(|extension| FrameFormat::parse(&extension.to_ascii_lowercase()))
+            .unwrap_or(FrameFormat::Png)
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            FrameFormat::Jpeg => "jpg",
+            _ => self.as_str(),
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            FrameFormat::Png => "image/png",
+            FrameFormat::Webp => "image/webp",
+            FrameFormat::Jpeg => "image/jpeg",
+            FrameFormat::Avif => "image/avif",
+        }
+    }
+
+}
+
+
+/**
+ * Re-encode a captured PNG in the given format.  PNG data is returned as is.  quality only
+ * applies to JPEG and AVIF, since WebP is always written lossless.
+ */
+pub fn encode_frame(png_data: &[u8], format: FrameFormat, quality: u8) -> Result<Vec<u8>, ScreentapError> {
+
+    let quality = quality.clamp(1, 100);
+    let mut encoded = Cursor::new(Vec::new());
+
+    match format {
+        FrameFormat::Png => return Ok(png_data.to_vec()),
+        FrameFormat::Webp => {
+            let rgba = image::load_from_memory(png_data)?.to_rgba8();
+            WebPEncoder::new_lossless(&mut encoded).write_image(rgba.as_raw(), rgba.width(), rgba.height(), ColorType::Rgba8)?;
+        },
+        FrameFormat::Jpeg => {
+            // JPEG has no alpha channel
+            let rgb = image::load_from_memory(png_data)?.to_rgb8();
+            JpegEncoder::new_with_quality(&mut encoded, quality).write_image(rgb.as_raw(), rgb.width(), rgb.height(), ColorType::Rgb8)?;
+        },
+        FrameFormat::Avif => {
+            let rgba = image::load_from_memory(png_data)?.to_rgba8();
+            AvifEncoder::new_with_speed_quality(&mut encoded, AVIF_SPEED, quality).write_image(rgba.as_raw(), rgba.width(), rgba.height(), ColorType::Rgba8)?;
+        },
+    }
+
+    Ok(encoded.into_inner())
+
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use super::{encode_frame, FrameFormat};
+    use image::{ImageBuffer, ImageFormat, Rgba};
+    use std::path::Path;
+
+    fn png_frame() -> Vec<u8> {
+        let img = ImageBuffer::from_fn(64, 48, |x, y| Rgba([(x * 4) as u8, (y * 5) as u8, 128, 255]));
+        let mut png_data = vec![];
+        image::DynamicImage::ImageRgba8(img)
+            .write_to(&mut std::io::Cursor::new(&mut png_data), image::ImageOutputFormat::Png)
+            .unwrap();
+        png_data
+    }
+
+    #[test]
+    fn test_encode_frame() {
+
+        let png_data = png_frame();
+        assert_eq!(encode_frame(&png_data, FrameFormat::Png, 80).unwrap(), png_data);
+
+        // WebP is lossless, so it decodes to the same pixels
+        let webp_data = encode_frame(&png_data, FrameFormat::Webp, 80).unwrap();
+        assert_eq!(image::guess_format(&webp_data).unwrap(), ImageFormat::WebP);
+        let original = image::load_from_memory(&png_data).unwrap().to_rgba8();
+        assert_eq!(image::load_from_memory(&webp_data).unwrap().to_rgba8(), original);
+
+        let jpeg_data = encode_frame(&png_data, FrameFormat::Jpeg, 80).unwrap();
+        assert_eq!(image::guess_format(&jpeg_data).unwrap(), ImageFormat::Jpeg);
+        assert_eq!(image::load_from_memory(&jpeg_data).unwrap().width(), 64);
+
+        // AVIF files start with an ftyp box of brand avif
+        let avif_data = encode_frame(&png_data, FrameFormat::Avif, 80).unwrap();
+        assert_eq!(&avif_data[4..12], b"ftypavif");
+    }
+
+    #[test]
+    fn test_from_path() {
+        assert_eq!(FrameFormat::from_path(Path::new("media/2024/03/07/a.webp")), FrameFormat::Webp);
+        assert_eq!(FrameFormat::from_path(Path::new("a.JPG")), FrameFormat::Jpeg);
+        assert_eq!(FrameFormat::from_path(Path::new("a.png")), FrameFormat::Png);
+        assert_eq!(FrameFormat::from_path(Path::new("a")), FrameFormat::Png);
+    }
+
+}
@@ -1,23 +1,45 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-extern crate screen_ocr_swift_rs;
-
 use tauri::{Manager, SystemTray, SystemTrayEvent, SystemTrayMenu, CustomMenuItem, SystemTrayMenuItem};
 
 use std::collections::HashMap;
-use std::thread;
 use std::env;
-use std::time::Duration;
 use std::path::Path;
 use std::path::PathBuf;
-use chrono::Local;
+use std::sync::{Arc, RwLock};
+use std::sync::mpsc;
 use crate::plugins::focusguard;
+use crate::config_watcher::ConfigUpdate;
+use crate::pipeline::{PipelineMetrics, StageMetricsSnapshot};
 
 mod db;
+mod error;
 mod utils; 
 mod screenshot;
 mod compaction;
+mod tiering;
+mod config;
+mod config_watcher;
+mod pipeline;
+mod video;
+mod frame_cache;
+mod frame_format;
+mod native;
+mod ocr_layout;
+mod region_ocr;
+mod storage;
+mod storage_stats;
+mod retention;
+mod search;
+mod cluster;
+mod ranking;
+mod saved_searches;
+mod annotations;
+mod export;
+mod embeddings;
+mod data_root;
+mod cli;
 mod plugins;
 
 
@@ -25,30 +47,50 @@ static DATABASE_FILENAME: &str = "screentap.db";
 
 
 /**
- * Capture screenshots on a fixed schedule 
+ * Search screenshots by their OCR text and notes, optionally only those with a tag.  Unless
+ * group_similar is false, near-identical hits are grouped, and each result is a cluster's
+ * representative with its size, time span and the ids of its screenshots under the cluster_
+ * keys.  See expand_cluster().  The tags of each result are a JSON array under "tags".
  */
-static DURATION_BETWEEN_SCREEN_CAPTURES_CHECKS: i64 = 30;
-
-
 #[tauri::command]
-fn search_screenshots(app_handle: tauri::AppHandle, term: &str) -> Vec<HashMap<String, String>> {
+fn search_screenshots(app_handle: tauri::AppHandle, term: &str, display_id: Option<u32>, mode: Option<&str>, group_similar: Option<bool>, tag: Option<&str>) -> Vec<HashMap<String, String>> {
 
-    let app_data_dir = get_effective_app_dir(app_handle);
+    let app_data_dir = get_effective_app_dir(app_handle.clone());
+    let screentap_config = get_config(app_handle.clone());
+    let data_root = get_data_root(app_handle);
 
     let db_filename_path = Path::new(DATABASE_FILENAME);
+    let frame_extractor = video::create_frame_extractor(&screentap_config);
 
     // Cap the max results until we implement techniques to reduce memory footprint
     let max_results: i32 = 25;
 
-    let screenshot_records_result = if term.is_empty() {
-        db::get_all_screenshots(app_data_dir.as_path(), db_filename_path, max_results)
+    // An empty tag is no tag, eg when the tag filter is cleared
+    let tag = tag.filter(|tag| !tag.trim().is_empty());
+
+    let screenshots_result = if term.is_empty() && tag.is_none() {
+        db::get_all_screenshots(data_root.as_path(), db_filename_path, max_results, display_id, frame_extractor.as_ref())
+            .map(|screenshot_records| db::create_hashmap_vector(screenshot_records.as_slice()))
     } else {
-        db::search_screenshots_ocr(term, app_data_dir.as_path(), db_filename_path, max_results)
+        let search_mode = mode.and_then(search::SearchMode::parse).unwrap_or_default();
+        let embedder = get_embedder(&app_data_dir, &screentap_config, search_mode);
+        let ranking = ranking::RankingModel::from_config(&screentap_config);
+        let query = search::SearchQuery { display_id, tag, ..search::SearchQuery::new(term, search_mode, max_results) };
+        let context = search::SearchContext { embedder: embedder.as_deref(), min_similarity: screentap_config.embedding_min_similarity, ranking: &ranking };
+        if group_similar.unwrap_or(true) {
+            search::search_screenshot_clusters(&query, &context, data_root.as_path(), db_filename_path, frame_extractor.as_ref())
+                .map(|clusters| clusters.iter().map(cluster_to_hashmap).collect())
+        } else {
+            search::search_screenshots(&query, &context, data_root.as_path(), db_filename_path, frame_extractor.as_ref())
+                .map(|screenshot_records| db::create_hashmap_vector(screenshot_records.as_slice()))
+        }
     };
 
-    match screenshot_records_result {
-        Ok(screenshot_records) => {
-            db::create_hashmap_vector(screenshot_records.as_slice())
+    match screenshots_result {
+        Ok(mut screenshots) => {
+            add_highlight_boxes(&mut screenshots, term, data_root.as_path(), db_filename_path);
+            add_tags(&mut screenshots, data_root.as_path(), db_filename_path);
+            screenshots
         },
         Err(e) => {
             println!("Error searching screenshots: {}.  Returning empty result", e);
@@ -57,12 +99,287 @@ fn search_screenshots(app_handle: tauri::AppHandle, term: &str) -> Vec<HashMap<S
     }
 }
 
+fn cluster_to_hashmap(cluster: &cluster::ScreenshotCluster) -> HashMap<String, String> {
+    let mut map = db::screenshot_record_to_hashmap(&cluster.representative);
+    map.insert("cluster_size".to_string(), cluster.size().to_string());
+    map.insert("cluster_first_timestamp".to_string(), cluster.first_timestamp.to_string());
+    map.insert("cluster_last_timestamp".to_string(), cluster.last_timestamp.to_string());
+    map.insert("cluster_screenshot_ids".to_string(), serde_json::to_string(&cluster.screenshot_ids).unwrap_or_default());
+    map
+}
+
+/**
+ * Get all screenshots of a search result cluster, oldest first, with the search term highlighted
+ */
+#[tauri::command]
+fn expand_cluster(app_handle: tauri::AppHandle, screenshot_ids: Vec<i64>, term: &str) -> Vec<HashMap<String, String>> {
+
+    let frame_extractor = video::create_frame_extractor(&get_config(app_handle.clone()));
+    let data_root = get_data_root(app_handle);
+    let db_filename_path = Path::new(DATABASE_FILENAME);
+
+    match db::find_screenshots_by_ids(data_root.as_path(), db_filename_path, &screenshot_ids) {
+        Ok(mut screenshot_records) => {
+            db::load_base64_images(&mut screenshot_records, frame_extractor.as_ref());
+            let mut screenshots = db::create_hashmap_vector(screenshot_records.as_slice());
+            add_highlight_boxes(&mut screenshots, term, data_root.as_path(), db_filename_path);
+            add_tags(&mut screenshots, data_root.as_path(), db_filename_path);
+            screenshots
+        },
+        Err(e) => {
+            println!("Error expanding cluster: {}.  Returning empty result", e);
+            vec![]
+        },
+    }
+}
+
+/**
+ * Add the bounding boxes of the words matching the search term to each screenshot, as a JSON
+ * array under "highlight_boxes".  Screenshots without an OCR layout get no highlights.
+ */
+fn add_highlight_boxes(screenshots: &mut [HashMap<String, String>], term: &str, data_root: &Path, db_filename_path: &Path) {
+
+    if term.is_empty() {
+        return;
+    }
+
+    for screenshot in screenshots.iter_mut() {
+        let Some(screenshot_id) = screenshot.get("id").and_then(|id| id.parse::<i64>().ok()) else {
+            continue;
+        };
+        let highlight_boxes = match ocr_layout::get_ocr_layout(data_root, db_filename_path, screenshot_id) {
+            Ok(Some(layout)) => layout.find_words(term),
+            Ok(None) => vec![],
+            Err(e) => {
+                println!("Error loading the OCR layout of screenshot #{}: {}", screenshot_id, e);
+                vec![]
+            }
+        };
+        screenshot.insert("highlight_boxes".to_string(), serde_json::to_string(&highlight_boxes).unwrap_or_default());
+    }
+
+}
+
+/**
+ * Add the names of the tags of each screenshot, as a JSON array under "tags"
+ */
+fn add_tags(screenshots: &mut [HashMap<String, String>], data_root: &Path, db_filename_path: &Path) {
+
+    for screenshot in screenshots.iter_mut() {
+        let Some(screenshot_id) = screenshot.get("id").and_then(|id| id.parse::<i64>().ok()) else {
+            continue;
+        };
+        let tags = annotations::list_screenshot_tags(data_root, db_filename_path, screenshot_id).unwrap_or_else(|e| {
+            println!("Error loading the tags of screenshot #{}: {}", screenshot_id, e);
+            vec![]
+        });
+        screenshot.insert("tags".to_string(), serde_json::to_string(&tags).unwrap_or_default());
+    }
+
+}
+
+/**
+ * Tag a screenshot, which pins it, see annotations::pin_screenshot().  Bookmarks are the
+ * bookmark tag.
+ */
+#[tauri::command]
+fn add_screenshot_tag(app_handle: tauri::AppHandle, screenshot_id: i64, tag: &str) -> Result<bool, String> {
+    let frame_extractor = video::create_frame_extractor(&get_config(app_handle.clone()));
+    let data_root = get_data_root(app_handle);
+    let now = chrono::Local::now().naive_utc().timestamp();
+    annotations::add_tag(data_root.as_path(), Path::new(DATABASE_FILENAME), screenshot_id, tag, now, frame_extractor.as_ref())
+        .map_err(|e| format!("Error tagging screenshot #{} with {}: {}", screenshot_id, tag, e))
+}
+
+#[tauri::command]
+fn remove_screenshot_tag(app_handle: tauri::AppHandle, screenshot_id: i64, tag: &str) -> Result<bool, String> {
+    let data_root = get_data_root(app_handle);
+    annotations::remove_tag(data_root.as_path(), Path::new(DATABASE_FILENAME), screenshot_id, tag)
+        .map_err(|e| format!("Error removing tag {} from screenshot #{}: {}", tag, screenshot_id, e))
+}
+
+#[tauri::command]
+fn list_screenshot_tags(app_handle: tauri::AppHandle, screenshot_id: i64) -> Result<Vec<String>, String> {
+    let data_root = get_data_root(app_handle);
+    annotations::list_screenshot_tags(data_root.as_path(), Path::new(DATABASE_FILENAME), screenshot_id)
+        .map_err(|e| format!("Error loading the tags of screenshot #{}: {}", screenshot_id, e))
+}
+
+#[tauri::command]
+fn list_tags(app_handle: tauri::AppHandle) -> Result<Vec<annotations::Tag>, String> {
+    let data_root = get_data_root(app_handle);
+    annotations::list_tags(data_root.as_path(), Path::new(DATABASE_FILENAME))
+        .map_err(|e| format!("Error loading tags: {}", e))
+}
+
+/**
+ * Add a note to a screenshot, which pins it like a tag.  Returns the id of the note.
+ */
+#[tauri::command]
+fn add_note(app_handle: tauri::AppHandle, screenshot_id: i64, text: &str) -> Result<i64, String> {
+    let frame_extractor = video::create_frame_extractor(&get_config(app_handle.clone()));
+    let data_root = get_data_root(app_handle);
+    let now = chrono::Local::now().naive_utc().timestamp();
+    annotations::add_note(data_root.as_path(), Path::new(DATABASE_FILENAME), screenshot_id, text, now, frame_extractor.as_ref())
+        .map_err(|e| format!("Error adding a note to screenshot #{}: {}", screenshot_id, e))
+}
+
+#[tauri::command]
+fn update_note(app_handle: tauri::AppHandle, id: i64, text: &str) -> Result<bool, String> {
+    let data_root = get_data_root(app_handle);
+    let now = chrono::Local::now().naive_utc().timestamp();
+    annotations::update_note(data_root.as_path(), Path::new(DATABASE_FILENAME), id, text, now)
+        .map_err(|e| format!("Error updating note #{}: {}", id, e))
+}
+
+#[tauri::command]
+fn delete_note(app_handle: tauri::AppHandle, id: i64) -> Result<bool, String> {
+    let data_root = get_data_root(app_handle);
+    annotations::delete_note(data_root.as_path(), Path::new(DATABASE_FILENAME), id)
+        .map_err(|e| format!("Error deleting note #{}: {}", id, e))
+}
+
+#[tauri::command]
+fn list_notes(app_handle: tauri::AppHandle, screenshot_id: i64) -> Result<Vec<annotations::Note>, String> {
+    let data_root = get_data_root(app_handle);
+    annotations::list_notes(data_root.as_path(), Path::new(DATABASE_FILENAME), screenshot_id)
+        .map_err(|e| format!("Error loading the notes of screenshot #{}: {}", screenshot_id, e))
+}
+
+#[tauri::command]
+fn list_saved_searches(app_handle: tauri::AppHandle) -> Result<Vec<saved_searches::SavedSearch>, String> {
+    let data_root = get_data_root(app_handle);
+    saved_searches::list_saved_searches(data_root.as_path(), Path::new(DATABASE_FILENAME))
+        .map_err(|e| format!("Error loading saved searches: {}", e))
+}
+
+/**
+ * Save a search by name, or update the saved search with that name.  Watched searches alert
+ * when a new screenshot matches them.
+ */
+#[tauri::command]
+fn save_search(app_handle: tauri::AppHandle, name: &str, term: &str, mode: Option<&str>, display_id: Option<u32>, watch: bool) -> Result<i64, String> {
+    let data_root = get_data_root(app_handle);
+    let search_mode = mode.and_then(search::SearchMode::parse).unwrap_or_default();
+    let now = chrono::Local::now().naive_utc().timestamp();
+    let query = search::SearchQuery { display_id, ..search::SearchQuery::new(term, search_mode, 0) };
+    saved_searches::save_search(data_root.as_path(), Path::new(DATABASE_FILENAME), name, &query, watch, now)
+        .map_err(|e| format!("Error saving search {}: {}", name, e))
+}
+
 #[tauri::command]
-fn browse_screenshots(app_handle: tauri::AppHandle, cur_id: i32, direction: &str) -> Vec<HashMap<String, String>> {
+fn delete_saved_search(app_handle: tauri::AppHandle, id: i64) -> Result<bool, String> {
+    let data_root = get_data_root(app_handle);
+    saved_searches::delete_saved_search(data_root.as_path(), Path::new(DATABASE_FILENAME), id)
+        .map_err(|e| format!("Error deleting saved search #{}: {}", id, e))
+}
 
-    println!("browse_screenshots: cur_id: {}, direction: {}", cur_id, direction);
+#[tauri::command]
+fn set_saved_search_watch(app_handle: tauri::AppHandle, id: i64, watch: bool) -> Result<bool, String> {
+    let data_root = get_data_root(app_handle);
+    saved_searches::set_watch(data_root.as_path(), Path::new(DATABASE_FILENAME), id, watch)
+        .map_err(|e| format!("Error updating saved search #{}: {}", id, e))
+}
 
-    let app_data_dir: PathBuf = get_effective_app_dir(app_handle);
+/**
+ * Run a saved search, like search_screenshots() with the saved term, mode and display
+ */
+#[tauri::command]
+fn run_saved_search(app_handle: tauri::AppHandle, id: i64, group_similar: Option<bool>) -> Vec<HashMap<String, String>> {
+
+    let data_root = get_data_root(app_handle.clone());
+
+    match saved_searches::get_saved_search(data_root.as_path(), Path::new(DATABASE_FILENAME), &id.to_string()) {
+        Ok(Some(saved_search)) => search_screenshots(app_handle, &saved_search.term, saved_search.display_id, Some(&saved_search.mode), group_similar, None),
+        Ok(None) => {
+            println!("No saved search #{}.  Returning empty result", id);
+            vec![]
+        },
+        Err(e) => {
+            println!("Error loading saved search #{}: {}.  Returning empty result", id, e);
+            vec![]
+        },
+    }
+}
+
+#[tauri::command]
+fn list_alerts(app_handle: tauri::AppHandle, limit: Option<i32>) -> Result<Vec<saved_searches::Alert>, String> {
+    let data_root = get_data_root(app_handle);
+    saved_searches::list_alerts(data_root.as_path(), Path::new(DATABASE_FILENAME), limit.unwrap_or(50))
+        .map_err(|e| format!("Error loading alerts: {}", e))
+}
+
+#[tauri::command]
+fn get_ocr_layout(app_handle: tauri::AppHandle, screenshot_id: i64) -> Result<Option<ocr_layout::OcrLayout>, String> {
+    let data_root = get_data_root(app_handle);
+    ocr_layout::get_ocr_layout(data_root.as_path(), Path::new(DATABASE_FILENAME), screenshot_id)
+        .map_err(|e| format!("Error loading the OCR layout of screenshot #{}: {}", screenshot_id, e))
+}
+
+/**
+ * Get the text of the words inside a region of a screenshot, normalized to 0..1 from the top
+ * left, from its stored OCR layout
+ */
+#[tauri::command]
+fn get_region_text(app_handle: tauri::AppHandle, screenshot_id: i64, region: ocr_layout::OcrRect) -> Result<String, String> {
+    load_ocr_layout(app_handle, screenshot_id).map(|layout| layout.region_text(&region))
+}
+
+/**
+ * Get the text of the words of a screenshot with the given ids, see OcrLayout::words_text
+ */
+#[tauri::command]
+fn get_words_text(app_handle: tauri::AppHandle, screenshot_id: i64, word_ids: Vec<usize>) -> Result<String, String> {
+    load_ocr_layout(app_handle, screenshot_id).map(|layout| layout.words_text(&word_ids))
+}
+
+/**
+ * OCR a region of a screenshot again at a higher resolution, for text that the stored OCR
+ * text got wrong
+ */
+#[tauri::command]
+fn reocr_region(app_handle: tauri::AppHandle, screenshot_id: i64, region: ocr_layout::OcrRect) -> Result<String, String> {
+    let frame_extractor = video::create_frame_extractor(&get_config(app_handle.clone()));
+    let data_root = get_data_root(app_handle);
+    region_ocr::reocr_region(data_root.as_path(), Path::new(DATABASE_FILENAME), screenshot_id, &region, frame_extractor.as_ref())
+        .map_err(|e| format!("Error running OCR on a region of screenshot #{}: {}", screenshot_id, e))
+}
+
+fn load_ocr_layout(app_handle: tauri::AppHandle, screenshot_id: i64) -> Result<ocr_layout::OcrLayout, String> {
+    get_ocr_layout(app_handle, screenshot_id)?
+        .ok_or_else(|| format!("Screenshot #{} has no OCR layout", screenshot_id))
+}
+
+/**
+ * Get the embedder for semantic and hybrid searches, if embeddings are enabled
+ */
+fn get_embedder(app_data_dir: &Path, screentap_config: &config::ScreentapConfig, search_mode: search::SearchMode) -> Option<Arc<dyn embeddings::Embedder>> {
+
+    if !matches!(search_mode, search::SearchMode::Semantic | search::SearchMode::Hybrid) {
+        return None;
+    }
+
+    if !screentap_config.embeddings_enabled {
+        return None;
+    }
+
+    match embeddings::get_embedder(&embeddings::get_model_dir(app_data_dir, screentap_config)) {
+        Ok(embedder) => Some(embedder),
+        Err(e) => {
+            println!("Error loading the embedding model: {}", e);
+            None
+        }
+    }
+
+}
+
+#[tauri::command]
+fn browse_screenshots(app_handle: tauri::AppHandle, cur_id: i32, direction: &str, display_id: Option<u32>) -> Vec<HashMap<String, String>> {
+
+    println!("browse_screenshots: cur_id: {}, direction: {}, display_id: {:?}", cur_id, direction, display_id);
+
+    let frame_extractor = video::create_frame_extractor(&get_config(app_handle.clone()));
+    let data_root: PathBuf = get_data_root(app_handle);
 
     let db_filename_path = Path::new(DATABASE_FILENAME);
 
@@ -70,24 +387,31 @@ fn browse_screenshots(app_handle: tauri::AppHandle, cur_id: i32, direction: &str
         0 => {
             // If the user passed 0 as the cur_id, get the most recent screenshot in the DB
             db::get_all_screenshots(
-                app_data_dir.as_path(), 
+                data_root.as_path(), 
                 db_filename_path, 
-                1
+                1,
+                display_id,
+                frame_extractor.as_ref()
             )
         },
         _ => {
-            // Otherwise, get the next screenshot by id, depending on direction
-            let target_id = match direction {
-                "forward" => cur_id + 1,
-                "backward" => cur_id - 1,
-                "exact" => cur_id,
-                _ => cur_id,
-            };
-            db::get_screenshot_by_id(
-                app_data_dir.as_path(), 
-                db_filename_path, 
-                target_id
-            )
+            // Otherwise, get the next screenshot by id (on the same display if filtered), depending on direction
+            match direction {
+                "forward" | "backward" => db::get_adjacent_screenshot(
+                    data_root.as_path(), 
+                    db_filename_path, 
+                    cur_id,
+                    direction == "forward",
+                    display_id,
+                    frame_extractor.as_ref()
+                ),
+                _ => db::get_screenshot_by_id(
+                    data_root.as_path(), 
+                    db_filename_path, 
+                    cur_id,
+                    frame_extractor.as_ref()
+                ),
+            }
         }
     };
 
@@ -102,6 +426,69 @@ fn browse_screenshots(app_handle: tauri::AppHandle, cur_id: i32, direction: &str
     }
 }
 
+#[tauri::command]
+fn list_displays(app_handle: tauri::AppHandle) -> Vec<u32> {
+
+    let data_root = get_data_root(app_handle);
+
+    match db::get_display_ids(data_root.as_path(), Path::new(DATABASE_FILENAME)) {
+        Ok(display_ids) => display_ids,
+        Err(e) => {
+            println!("Error listing displays: {}.  Returning empty result", e);
+            vec![]
+        },
+    }
+}
+
+#[tauri::command]
+fn get_pipeline_metrics(pipeline_metrics: tauri::State<Arc<PipelineMetrics>>) -> HashMap<String, StageMetricsSnapshot> {
+    pipeline_metrics.snapshot()
+}
+
+#[tauri::command]
+fn get_storage_stats(app_handle: tauri::AppHandle) -> Result<storage_stats::StorageStats, String> {
+
+    let app_data_dir = get_effective_app_dir(app_handle.clone());
+    let data_root = get_data_root(app_handle.clone());
+
+    // The limits the pipeline is enforcing, which are the defaults if the config on disk is invalid
+    let screentap_config = get_config(app_handle);
+
+    storage_stats::collect_storage_stats(&app_data_dir, &data_root, Path::new(DATABASE_FILENAME), &screentap_config)
+        .map_err(|e| format!("Error collecting storage stats: {}", e))
+}
+
+/**
+ * Export a time range or a list of screenshots as an mp4, gif or zip of PNGs to the downloads
+ * dir, with a sidecar JSON of their metadata and OCR text.  Runs off the main thread, since
+ * encoding a long range takes a while.
+ */
+#[tauri::command(async)]
+fn export_screenshots(app_handle: tauri::AppHandle, format: String, start_timestamp: Option<i64>, end_timestamp: Option<i64>, screenshot_ids: Option<Vec<i64>>, display_id: Option<u32>) -> Result<export::ExportReport, String> {
+
+    let app_data_dir = get_effective_app_dir(app_handle.clone());
+    let data_root = get_data_root(app_handle);
+
+    let export_format = export::ExportFormat::parse(&format).ok_or_else(|| format!("Unknown export format {}", format))?;
+    let selection = match (screenshot_ids, start_timestamp, end_timestamp) {
+        (Some(ids), _, _) => export::ExportSelection::Ids(ids),
+        (None, Some(start_timestamp), Some(end_timestamp)) => export::ExportSelection::TimeRange { start_timestamp, end_timestamp, display_id },
+        _ => return Err("Export needs either screenshot ids or a start and end time".to_string()),
+    };
+
+    // Unlike elsewhere, an invalid config doesn't fall back to defaults, which would skip redaction
+    let screentap_config = config::ScreentapConfig::load(app_data_dir.as_path())
+        .map_err(|e| format!("Error loading config for export: {}", e))?;
+
+    let export_dir = tauri::api::path::download_dir().unwrap_or_else(|| data_root.join("exports"));
+    let now = chrono::Local::now().naive_utc();
+    let output_path = export_dir.join(format!("screentap_{}", utils::generate_filename(now, export_format.extension()).display()));
+
+    export::Exporter::from_config(data_root, PathBuf::from(DATABASE_FILENAME), &screentap_config)
+        .and_then(|exporter| exporter.export(&selection, export_format, &output_path))
+        .map_err(|e| format!("Error exporting screenshots: {}", e))
+}
+
 
 fn get_effective_app_dir(app_handle: tauri::AppHandle) -> PathBuf {
     // Attempt to get the "screentap_app_data_dir" environment variable
@@ -118,6 +505,22 @@ fn get_effective_app_dir(app_handle: tauri::AppHandle) -> PathBuf {
     app_data_dir
 }
 
+/**
+ * The current core config, as reloaded by the config watcher.  An invalid config at startup is
+ * the default config.
+ */
+fn get_config(app_handle: tauri::AppHandle) -> config::ScreentapConfig {
+    app_handle.state::<Arc<RwLock<config::ScreentapConfig>>>().read().unwrap().clone()
+}
+
+/**
+ * The dir with the screenshot DB and media, which can be moved out of the app data dir
+ * with the move-data-dir command
+ */
+fn get_data_root(app_handle: tauri::AppHandle) -> PathBuf {
+    data_root::resolve_data_root(&get_effective_app_dir(app_handle))
+}
+
 fn setup_handler(app: &mut tauri::App) -> Result<(), Box<dyn std::error::Error + 'static>> {
 
     let app_handle = app.handle();
@@ -133,32 +536,37 @@ fn setup_handler(app: &mut tauri::App) -> Result<(), Box<dyn std::error::Error +
         println!("Found existing app_data_dir: {}", app_data_dir.as_path().to_str().unwrap());
     }
 
+    // Held until the app exits, so that the data dir can't be moved while screenshots are captured
+    app.manage(data_root::lock_app_data_dir(&app_data_dir)?);
+
+    // The DB and media can live outside the app data dir, eg on an external volume.  If that
+    // is missing, don't create an empty one in its place, since the volume may just be unmounted.
+    let data_root = data_root::resolve_data_root(&app_data_dir);
+    if data_root != app_data_dir && !data_root.is_dir() {
+        return Err(format!("Data dir {} not found.  Is the volume mounted?", data_root.display()).into());
+    }
+    println!("Using data dir: {}", data_root.display());
+
     // Create the database if it doesn't exist
-    match db::create_db(app_data_dir.as_path(), db_filename_path) {
+    match db::create_db(data_root.as_path(), db_filename_path) {
         Ok(()) => (),
         Err(e) => eprintln!("Failed to create db: {}", e),
     }
 
-    // Save one screenshot on startup so we never have an empty screen
-    let screenshot_result = screenshot::save_screenshot(app_data_dir.as_path(), db_filename_path);
-    match screenshot_result {
-        Ok(_) => {},
+    // Load the core config.  An invalid config at startup falls back to defaults
+    // rather than preventing the app from starting.
+    let screentap_config = match config::ScreentapConfig::load(app_data_dir.as_path()) {
+        Ok(screentap_config) => screentap_config,
         Err(e) => {
-            println!("Error saving screenshot on startup: {}", e);
+            println!("Invalid config, using defaults: {}", e);
+            config::ScreentapConfig::default()
         }
-    }
-    let (mut last_frontmost_app, mut last_browser_tab) = utils::get_frontmost_app_via_applescript();
-
-    // Create a compaction helper
-    let compaction_helper = compaction::CompactionHelper::new(
-        app_data_dir.clone(), 
-        db_filename_path.to_path_buf(),
-        compaction::DEFAULT_MAX_IMAGE_FILES,
-    );
+    };
+    frame_cache::set_max_mb(screentap_config.frame_cache_max_mb);
 
     // Create a focusguard instance
-    let screentap_db_filename_fq_path = app_data_dir.join(db_filename_path);
-    let mut focus_guard_option = focusguard::FocusGuard::new_from_config(
+    let screentap_db_filename_fq_path = data_root.join(db_filename_path);
+    let focus_guard_option = focusguard::FocusGuard::new_from_config(
         // Clone app_data_dir so focusguard can own the app data dir path instance
         // and we avoid reference lifetime issues
         // TODO: review this, it feels a bit overcomplicated
@@ -172,72 +580,31 @@ fn setup_handler(app: &mut tauri::App) -> Result<(), Box<dyn std::error::Error +
         // Put a copy of the focusguard instance into the app managed state,
         // so we can at least access the configuration from handlers.
         // Why a clone?  If the original focusguard is moved into the managed
-        // state, then the closure below passed to the thread will no longer work        
+        // state, then the pipeline's plugin stage will no longer be able to use it
         app.manage(focus_guard_option.clone());
     }
 
     // Get an app handle from the app since this can be moved to threads
     let app_handle = app.app_handle();
 
-    // Spawn a thread to save screenshots in the background.
-    // The move keyword is necessary to move app_data_dir into the thread.
-    thread::spawn(move || {
-
-        loop {
-
-            let now = Local::now().naive_utc();
-
-            // Compact screenshots to mp4 if necessary
-            if compaction_helper.should_compact_screenshots() {
-                let timestamp_mp4_filename = utils::generate_filename(now, "mp4");
-                let timestamp_mp4_filename_fq = app_data_dir.join(timestamp_mp4_filename);
-
-                compaction_helper.compact_screenshots_to_mp4(
-                    timestamp_mp4_filename_fq, 
-                    // TODO: make the use_bitrate_key a setting, otherwise it will
-                    // crash on certain machines
-                    false
-                );
-            }
-
-            // Get the name of the frontmost app and browser tab (if applicable)
-            let (cur_frontmost_app, cur_browser_tab) = utils::get_frontmost_app_via_applescript();
-            let frontmost_app_or_tab_changed = utils::frontmost_app_or_browser_tab_changed(&cur_frontmost_app, &last_frontmost_app, &cur_browser_tab, &last_browser_tab);
-            println!("Capturing screenshot.  cur_frontmost_app: {} last_frontmost_app: {} cur_browser_tab: {}, last_browser_tab: {} frontmost_app_or_tab_changed: {} ", &cur_frontmost_app, last_frontmost_app, cur_browser_tab, last_browser_tab, frontmost_app_or_tab_changed);
-            
-            // Capture a screenshot, OCR and save it to DB
-            let screenshot_result = screenshot::save_screenshot(app_data_dir.as_path(), db_filename_path);
-            match screenshot_result {
-                Ok(screenshot::ScreenshotSaveResult { png_data, ocr_text, png_image_path, screenshot_id}) => {
-
-                    // Invoke plugins
-                    // TODO: any way to avoid this confusing "ref mut" stuff?
-                    if let Some(ref mut focus_guard) = focus_guard_option {
-                        focus_guard.handle_screentap_event(
-                            &app_handle,
-                            png_data,
-                            png_image_path.as_path(),
-                            screenshot_id,
-                            ocr_text,
-                            &cur_frontmost_app,
-                            &cur_browser_tab,
-                            frontmost_app_or_tab_changed
-                        );        
-                    }
-                },
-                Err(e) => {
-                    println!("Error saving screenshot: {}", e);
-                }
-            }
-
-            // Update the last_ tracking variables to the current values
-            last_frontmost_app = cur_frontmost_app;
-            last_browser_tab = cur_browser_tab;
-
-            thread::sleep(Duration::from_secs(DURATION_BETWEEN_SCREEN_CAPTURES_CHECKS as u64));
+    // Watch the config files so that changes are applied without restarting the app
+    let (config_update_sender, config_update_receiver) = mpsc::channel::<ConfigUpdate>();
+    config_watcher::ConfigWatcher::new(app_data_dir.clone()).spawn(
+        app_handle.clone(),
+        config_update_sender
+    );
 
-        }
-    });
+    // Start capturing screenshots in the background.  The first capture happens
+    // immediately, so we never have an empty screen.
+    let capture_pipeline = pipeline::CapturePipeline::new(
+        app_data_dir.clone(),
+        data_root,
+        db_filename_path.to_path_buf(),
+        screentap_config,
+    );
+    app.manage(capture_pipeline.metrics());
+    app.manage(capture_pipeline.config());
+    capture_pipeline.spawn(app_handle, focus_guard_option, config_update_receiver)?;
 
     // Maximize the main window
     match app.get_window("main") {
@@ -317,6 +684,18 @@ fn handle_system_tray_event(app: &tauri::AppHandle, event: tauri::SystemTrayEven
 
 fn main() {
 
+    let context = tauri::generate_context!();
+
+    // Maintenance commands run instead of the app
+    let args: Vec<String> = env::args().skip(1).collect();
+    if cli::is_cli_command(&args) {
+        let app_data_dir = match env::var("SCREENTAP_APP_DATA_DIR") {
+            Ok(value) => PathBuf::from(value),
+            Err(_) => tauri::api::path::app_data_dir(context.config()).expect("Failed to get app_data_dir"),
+        };
+        std::process::exit(cli::run(&args, &app_data_dir, Path::new(DATABASE_FILENAME)));
+    }
+
     println!("Starting screentap...");
 
     let quit = CustomMenuItem::new("quit".to_string(), "Quit").accelerator("Cmd+Q");
@@ -337,10 +716,33 @@ fn main() {
     .on_system_tray_event(handle_system_tray_event)
     .invoke_handler(tauri::generate_handler![
         search_screenshots, 
+        expand_cluster,
+        list_saved_searches,
+        save_search,
+        delete_saved_search,
+        set_saved_search_watch,
+        run_saved_search,
+        list_alerts,
+        add_screenshot_tag,
+        remove_screenshot_tag,
+        list_screenshot_tags,
+        list_tags,
+        add_note,
+        update_note,
+        delete_note,
+        list_notes,
         browse_screenshots,
+        list_displays,
+        get_pipeline_metrics,
+        get_storage_stats,
+        export_screenshots,
+        get_ocr_layout,
+        get_region_text,
+        get_words_text,
+        reocr_region,
         focusguard::handlers::distraction_alert_rating]
     )
-    .run(tauri::generate_context!())
+    .run(context)
     .expect("Error while starting screentap");
     
 }
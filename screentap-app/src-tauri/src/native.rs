@@ -0,0 +1,118 @@
+/**
+ * The native capture, OCR and image functions of screen-ocr-swift-rs, which only builds on
+ * macOS.  Elsewhere, eg on Linux with the ffmpeg video backend, these are stand-ins with the
+ * same signatures that find no displays, capture nothing and return no OCR text, so the rest
+ * of the app builds and the stored screenshots can still be searched, exported and compacted.
+ */
+
+#[cfg(target_os = "macos")]
+pub use screen_ocr_swift_rs::{
+    extract_layout_from_image_data,
+    get_displays,
+    get_frontmost_window_bounds,
+    resize_image,
+    screen_capture_display,
+    DisplayInfo,
+    OcrLine,
+    OcrRect,
+    WindowRect,
+};
+
+#[cfg(not(target_os = "macos"))]
+pub use self::unsupported::*;
+
+
+#[cfg(not(target_os = "macos"))]
+mod unsupported {
+
+    /**
+     * A connected display and its geometry in global display coordinates (points)
+     */
+    #[derive(Debug, Clone, Default, PartialEq)]
+    pub struct DisplayInfo {
+        pub display_id: u32,
+        pub x: i32,
+        pub y: i32,
+        pub width: i32,
+        pub height: i32,
+        pub is_main: bool,
+    }
+
+    /**
+     * A window's bounds in global display coordinates (points)
+     */
+    #[derive(Debug, Clone, Default, PartialEq)]
+    pub struct WindowRect {
+        pub x: i32,
+        pub y: i32,
+        pub width: i32,
+        pub height: i32,
+    }
+
+    impl WindowRect {
+
+        /**
+         * Get the overlapping part of this rect and another rect, if any
+         */
+        pub fn intersect(&self, other: &WindowRect) -> Option<WindowRect> {
+            let x = self.x.max(other.x);
+            let y = self.y.max(other.y);
+            let right = (self.x + self.width).min(other.x + other.width);
+            let bottom = (self.y + self.height).min(other.y + other.height);
+            if right <= x || bottom <= y {
+                return None;
+            }
+            Some(WindowRect { x, y, width: right - x, height: bottom - y })
+        }
+    }
+
+    impl From<&DisplayInfo> for WindowRect {
+        fn from(display: &DisplayInfo) -> Self {
+            WindowRect { x: display.x, y: display.y, width: display.width, height: display.height }
+        }
+    }
+
+    #[derive(Debug, Clone, Default, PartialEq)]
+    pub struct OcrRect {
+        pub x: f32,
+        pub y: f32,
+        pub width: f32,
+        pub height: f32,
+    }
+
+    #[derive(Debug, Clone, Default, PartialEq)]
+    pub struct OcrWord {
+        pub text: String,
+        pub confidence: f32,
+        pub bounds: OcrRect,
+    }
+
+    #[derive(Debug, Clone, Default, PartialEq)]
+    pub struct OcrLine {
+        pub text: String,
+        pub confidence: f32,
+        pub bounds: OcrRect,
+        pub words: Vec<OcrWord>,
+    }
+
+    pub fn get_displays() -> Vec<DisplayInfo> {
+        vec![]
+    }
+
+    pub fn get_frontmost_window_bounds() -> Option<WindowRect> {
+        None
+    }
+
+    pub fn screen_capture_display(_display_id: u32) -> Option<Vec<u8>> {
+        None
+    }
+
+    pub fn extract_layout_from_image_data(_image_data: &[u8]) -> Option<Vec<OcrLine>> {
+        None
+    }
+
+    pub fn resize_image(_png_data: &[u8], _scale: f32) -> Option<Vec<u8>> {
+        None
+    }
+
+}
@@ -0,0 +1,548 @@
+use std::path::Path;
+use regex::Regex;
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::db;
+use crate::error::ScreentapError;
+use crate::native;
+
+
+// The first byte of an encoded layout, so the encoding can change without migrating old rows
+static LAYOUT_ENCODING_VERSION: u8 = 1;
+
+// Lines whose horizontal extents overlap by at least this fraction of the narrower line are in the same column
+static COLUMN_OVERLAP_FRACTION: f32 = 0.5;
+
+
+/**
+ * A bounding box normalized to 0..1, with the origin at the top left of the screenshot
+ */
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct OcrRect {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct OcrWord {
+    pub text: String,
+    pub confidence: f32,
+    pub bounds: OcrRect,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct OcrLine {
+    pub text: String,
+    pub confidence: f32,
+    pub bounds: OcrRect,
+    pub words: Vec<OcrWord>,
+}
+
+/**
+ * The lines and words OCR found in a screenshot, with their bounding boxes.  Stored per
+ * screenshot in the ocr_layout table, so search hits can be highlighted and text can be
+ * copied from a region of the screenshot.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct OcrLayout {
+    pub lines: Vec<OcrLine>,
+}
+
+impl From<&native::OcrRect> for OcrRect {
+    fn from(rect: &native::OcrRect) -> Self {
+        OcrRect { x: rect.x, y: rect.y, width: rect.width, height: rect.height }
+    }
+}
+
+impl From<Vec<native::OcrLine>> for OcrLayout {
+    fn from(lines: Vec<native::OcrLine>) -> Self {
+        let lines = lines.iter().map(|line| OcrLine {
+            text: line.text.clone(),
+            confidence: line.confidence,
+            bounds: OcrRect::from(&line.bounds),
+            words: line.words.iter().map(|word| OcrWord {
+                text: word.text.clone(),
+                confidence: word.confidence,
+                bounds: OcrRect::from(&word.bounds),
+            }).collect(),
+        }).collect();
+        OcrLayout { lines }
+    }
+}
+
+impl OcrRect {
+
+    fn right(&self) -> f32 {
+        self.x + self.width
+    }
+
+    fn bottom(&self) -> f32 {
+        self.y + self.height
+    }
+
+    /**
+     * Is the center of the other rect inside this one?  Used to select words with a rectangle,
+     * so a word that's mostly inside the selection counts.
+     */
+    pub fn contains_center_of(&self, other: &OcrRect) -> bool {
+        let center_x = other.x + other.width / 2.0;
+        let center_y = other.y + other.height / 2.0;
+        center_x >= self.x && center_x <= self.right() && center_y >= self.y && center_y <= self.bottom()
+    }
+
+    /**
+     * How much of the narrower of the two rects overlaps the other horizontally, from 0 to 1
+     */
+    fn horizontal_overlap(&self, other: &OcrRect) -> f32 {
+        let overlap = self.right().min(other.right()) - self.x.max(other.x);
+        let narrower = self.width.min(other.width);
+        if narrower <= 0.0 {
+            return 0.0;
+        }
+        (overlap / narrower).max(0.0)
+    }
+
+}
+
+impl OcrLayout {
+
+    /**
+     * The text of all lines in reading order, one line per line
+     */
+    pub fn text(&self) -> String {
+        self.reading_order().iter().map(|line| line.text.as_str()).collect::<Vec<_>>().join("\n")
+    }
+
+    /**
+     * The lines in column-aware reading order: columns left to right, and each column top to
+     * bottom.  A line belongs to the first column it overlaps horizontally, so a sidebar isn't
+     * interleaved with the main text next to it.
+     */
+    pub fn reading_order(&self) -> Vec<&OcrLine> {
+        self.reading_order_indices().into_iter().map(|line_index| &self.lines[line_index]).collect()
+    }
+
+    fn reading_order_indices(&self) -> Vec<usize> {
+
+        let mut lines_by_y: Vec<usize> = (0..self.lines.len()).collect();
+        lines_by_y.sort_by(|&a, &b| self.lines[a].bounds.y.total_cmp(&self.lines[b].bounds.y));
+
+        // Each column is its horizontal extent and its lines, top to bottom
+        let mut columns: Vec<(OcrRect, Vec<usize>)> = vec![];
+        for line_index in lines_by_y {
+            let bounds = &self.lines[line_index].bounds;
+            match columns.iter_mut().find(|(extent, _)| extent.horizontal_overlap(bounds) >= COLUMN_OVERLAP_FRACTION) {
+                Some((extent, column_lines)) => {
+                    let x = extent.x.min(bounds.x);
+                    extent.width = extent.right().max(bounds.right()) - x;
+                    extent.x = x;
+                    column_lines.push(line_index);
+                },
+                None => columns.push((bounds.clone(), vec![line_index])),
+            }
+        }
+
+        columns.sort_by(|(a, _), (b, _)| a.x.total_cmp(&b.x));
+        columns.into_iter().flat_map(|(_, column_lines)| column_lines).collect()
+    }
+
+    /**
+     * The text of the words whose centers are inside the region, in reading order
+     */
+    pub fn region_text(&self, region: &OcrRect) -> String {
+        self.selected_text(|_, word| region.contains_center_of(&word.bounds))
+    }
+
+    /**
+     * The text of the words with the given ids, in reading order.  A word's id is its index
+     * among the words of all lines, in the order get_ocr_layout returns them.
+     */
+    pub fn words_text(&self, word_ids: &[usize]) -> String {
+        self.selected_text(|word_id, _| word_ids.contains(&word_id))
+    }
+
+    /**
+     * The text of the selected words, one line per line.  The text between the first and last
+     * selected word of a line is kept as OCR'd, since words don't include punctuation.  Words
+     * aren't always in the order of the text, so this spans all of the selected words.
+     */
+    fn selected_text<F: Fn(usize, &OcrWord) -> bool>(&self, is_selected: F) -> String {
+
+        // The id of the first word of each line
+        let mut first_word_ids = vec![];
+        let mut word_count = 0;
+        for line in &self.lines {
+            first_word_ids.push(word_count);
+            word_count += line.words.len();
+        }
+
+        let mut selected_lines = vec![];
+        for line_index in self.reading_order_indices() {
+            let line = &self.lines[line_index];
+            let selected_ranges: Vec<(usize, usize)> = get_word_ranges(line).into_iter()
+                .filter(|&(word_index, _)| is_selected(first_word_ids[line_index] + word_index, &line.words[word_index]))
+                .map(|(_, range)| range)
+                .collect();
+
+            let Some(start) = selected_ranges.iter().map(|&(start, _)| start).min() else {
+                continue;
+            };
+            let end = selected_ranges.iter().map(|&(_, end)| end).max().unwrap_or(start);
+            if selected_ranges.len() == line.words.len() {
+                selected_lines.push(line.text.as_str());
+            } else if let Some(text) = line.text.get(start..end) {
+                selected_lines.push(text);
+            }
+        }
+
+        selected_lines.join("\n")
+    }
+
+    /**
+     * The bounding boxes of the words that contain any of the words of term, ignoring case.
+     * Used to highlight search hits on the screenshot.
+     */
+    pub fn find_words(&self, term: &str) -> Vec<OcrRect> {
+
+        let term_words: Vec<String> = term.split_whitespace()
+            .map(|term_word| term_word.trim_matches(|c: char| !c.is_alphanumeric()).to_lowercase())
+            .filter(|term_word| !term_word.is_empty())
+            .collect();
+
+        self.lines.iter()
+            .flat_map(|line| line.words.iter())
+            .filter(|word| {
+                let word_text = word.text.to_lowercase();
+                term_words.iter().any(|term_word| word_text.contains(term_word.as_str()))
+            })
+            .map(|word| word.bounds.clone())
+            .collect()
+    }
+
+    /**
+     * The bounding boxes of the words that overlap a match of pattern in their line's text, or
+     * of the whole line if none of its words do.  Used to redact exports, see export.rs.
+     */
+    pub fn find_matches(&self, pattern: &Regex) -> Vec<OcrRect> {
+
+        let mut rects = vec![];
+        for line in &self.lines {
+
+            let matches: Vec<(usize, usize)> = pattern.find_iter(&line.text).map(|m| (m.start(), m.end())).collect();
+            if matches.is_empty() {
+                continue;
+            }
+
+            let matched_words: Vec<OcrRect> = get_word_ranges(line).into_iter()
+                .filter(|(_, (start, end))| matches.iter().any(|(match_start, match_end)| start < match_end && match_start < end))
+                .map(|(word_index, _)| line.words[word_index].bounds.clone())
+                .collect();
+
+            if matched_words.is_empty() {
+                rects.push(line.bounds.clone());
+            } else {
+                rects.extend(matched_words);
+            }
+        }
+
+        rects
+    }
+
+    /**
+     * Encode the layout compactly: coordinates as u16 fractions, confidences as u8, and words as
+     * byte ranges of their line's text rather than copies of it
+     */
+    pub fn encode(&self) -> Vec<u8> {
+
+        let mut data = vec![LAYOUT_ENCODING_VERSION];
+        data.extend_from_slice(&(self.lines.len() as u32).to_le_bytes());
+
+        for line in &self.lines {
+            encode_rect(&mut data, &line.bounds);
+            data.push(encode_confidence(line.confidence));
+            data.extend_from_slice(&(line.text.len() as u32).to_le_bytes());
+            data.extend_from_slice(line.text.as_bytes());
+
+            // Words that aren't part of the line's text can't be encoded, and are dropped
+            let word_ranges = get_word_ranges(line);
+            data.extend_from_slice(&(word_ranges.len() as u32).to_le_bytes());
+            for (word_index, (start, end)) in word_ranges {
+                let word = &line.words[word_index];
+                encode_rect(&mut data, &word.bounds);
+                data.push(encode_confidence(word.confidence));
+                data.extend_from_slice(&(start as u32).to_le_bytes());
+                data.extend_from_slice(&((end - start) as u32).to_le_bytes());
+            }
+        }
+
+        data
+    }
+
+    pub fn decode(data: &[u8]) -> Result<OcrLayout, ScreentapError> {
+
+        let mut reader = LayoutReader { data, pos: 0 };
+
+        let version = reader.read_u8()?;
+        if version != LAYOUT_ENCODING_VERSION {
+            return Err(ScreentapError::Ocr(format!("Unknown OCR layout encoding version {}", version)));
+        }
+
+        let line_count = reader.read_u32()?;
+        let mut lines = vec![];
+        for _ in 0..line_count {
+            let bounds = reader.read_rect()?;
+            let confidence = reader.read_confidence()?;
+            let text_len = reader.read_u32()? as usize;
+            let text = String::from_utf8(reader.read_bytes(text_len)?.to_vec())
+                .map_err(|e| ScreentapError::Ocr(format!("Invalid OCR layout text: {}", e)))?;
+
+            let word_count = reader.read_u32()?;
+            let mut words = vec![];
+            for _ in 0..word_count {
+                let bounds = reader.read_rect()?;
+                let confidence = reader.read_confidence()?;
+                let start = reader.read_u32()? as usize;
+                let len = reader.read_u32()? as usize;
+                let word_text = text.get(start..start + len)
+                    .ok_or_else(|| ScreentapError::Ocr("Invalid OCR layout word range".to_string()))?;
+                words.push(OcrWord { text: word_text.to_string(), confidence, bounds });
+            }
+
+            lines.push(OcrLine { text, confidence, bounds, words });
+        }
+
+        Ok(OcrLayout { lines })
+    }
+
+}
+
+/**
+ * Find the byte range of each word in its line's text, searching from the end of the previous
+ * word.  Returns (word index, (start, end)), skipping words that aren't in the text.
+ */
+fn get_word_ranges(line: &OcrLine) -> Vec<(usize, (usize, usize))> {
+
+    let mut search_from = 0;
+    let mut word_ranges = vec![];
+    for (word_index, word) in line.words.iter().enumerate() {
+        let start = match line.text[search_from..].find(&word.text) {
+            Some(offset) => search_from + offset,
+            None => match line.text.find(&word.text) {
+                Some(start) => start,
+                None => continue,
+            },
+        };
+        search_from = start + word.text.len();
+        word_ranges.push((word_index, (start, search_from)));
+    }
+
+    word_ranges
+}
+
+fn encode_fraction(value: f32) -> u16 {
+    (value.clamp(0.0, 1.0) * u16::MAX as f32).round() as u16
+}
+
+fn encode_confidence(confidence: f32) -> u8 {
+    (confidence.clamp(0.0, 1.0) * u8::MAX as f32).round() as u8
+}
+
+fn encode_rect(data: &mut Vec<u8>, rect: &OcrRect) {
+    for value in [rect.x, rect.y, rect.width, rect.height] {
+        data.extend_from_slice(&encode_fraction(value).to_le_bytes());
+    }
+}
+
+struct LayoutReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> LayoutReader<'a> {
+
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], ScreentapError> {
+        let bytes = self.data.get(self.pos..self.pos + len)
+            .ok_or_else(|| ScreentapError::Ocr("Truncated OCR layout".to_string()))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, ScreentapError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_u32(&mut self) -> Result<u32, ScreentapError> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn read_fraction(&mut self) -> Result<f32, ScreentapError> {
+        let bytes = self.read_bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]) as f32 / u16::MAX as f32)
+    }
+
+    fn read_confidence(&mut self) -> Result<f32, ScreentapError> {
+        Ok(self.read_u8()? as f32 / u8::MAX as f32)
+    }
+
+    fn read_rect(&mut self) -> Result<OcrRect, ScreentapError> {
+        Ok(OcrRect {
+            x: self.read_fraction()?,
+            y: self.read_fraction()?,
+            width: self.read_fraction()?,
+            height: self.read_fraction()?,
+        })
+    }
+
+}
+
+
+/**
+ * Save the OCR layout of a screenshot, replacing any previous layout
+ */
+pub fn save_ocr_layout(dataset_root: &Path, db_filename: &Path, document_id: i64, layout: &OcrLayout) -> Result<(), ScreentapError> {
+    let conn = db::get_db_conn(dataset_root, db_filename)?;
+    conn.execute(
+        "INSERT OR REPLACE INTO ocr_layout (document_id, layout) VALUES (?1, ?2)",
+        params![document_id, layout.encode()],
+    )?;
+    Ok(())
+}
+
+/**
+ * Get the OCR layout of a screenshot.  Screenshots from before layouts were stored have none.
+ */
+pub fn get_ocr_layout(dataset_root: &Path, db_filename: &Path, document_id: i64) -> Result<Option<OcrLayout>, ScreentapError> {
+    let conn = db::get_db_conn(dataset_root, db_filename)?;
+    let layout_data = conn.query_row(
+        "SELECT layout FROM ocr_layout WHERE document_id = ?1",
+        params![document_id],
+        |row| row.get::<_, Vec<u8>>(0),
+    ).optional()?;
+    layout_data.map(|layout_data| OcrLayout::decode(&layout_data)).transpose()
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use super::{get_ocr_layout, save_ocr_layout, OcrLayout, OcrLine, OcrRect, OcrWord};
+    use crate::db;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    fn line(text: &str, x: f32, y: f32, width: f32) -> OcrLine {
+        let mut word_x = x;
+        let words = text.split(' ').map(|word_text| {
+            let word_width = width * word_text.len() as f32 / text.len() as f32;
+            let word = OcrWord { text: word_text.to_string(), confidence: 1.0, bounds: OcrRect { x: word_x, y, width: word_width, height: 0.02 } };
+            word_x += word_width;
+            word
+        }).collect();
+        OcrLine { text: text.to_string(), confidence: 0.5, bounds: OcrRect { x, y, width, height: 0.02 }, words }
+    }
+
+    /**
+     * A sidebar on the left and two paragraphs on the right, in the order Vision might find them
+     */
+    fn two_column_layout() -> OcrLayout {
+        OcrLayout {
+            lines: vec![
+                line("Inbox", 0.02, 0.10, 0.15),
+                line("Dear team, the", 0.30, 0.10, 0.60),
+                line("Drafts", 0.02, 0.13, 0.15),
+                line("invoice is attached", 0.30, 0.13, 0.55),
+                line("Sent", 0.02, 0.16, 0.10),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_reading_order() {
+        assert_eq!(two_column_layout().text(), "Inbox\nDrafts\nSent\nDear team, the\ninvoice is attached");
+    }
+
+    #[test]
+    fn test_region_and_words_text() {
+
+        let layout = two_column_layout();
+
+        let region = OcrRect { x: 0.25, y: 0.09, width: 0.70, height: 0.06 };
+        assert_eq!(layout.region_text(&region), "Dear team, the\ninvoice is attached");
+
+        let region = OcrRect { x: 0.30, y: 0.12, width: 0.20, height: 0.03 };
+        assert_eq!(layout.region_text(&region), "invoice");
+
+        // Word ids count the words of all lines in stored order
+        assert_eq!(layout.words_text(&[7, 2, 6]), "team,\nis attached");
+        assert_eq!(layout.words_text(&[0, 8]), "Inbox\nSent");
+        assert_eq!(layout.words_text(&[]), "");
+
+        // Words out of the order of the text, and one that's not in it
+        let mut shuffled_line = line("foo bar", 0.0, 0.0, 0.5);
+        shuffled_line.words.reverse();
+        shuffled_line.words.push(OcrWord { text: "baz".to_string(), ..shuffled_line.words[0].clone() });
+        let layout = OcrLayout { lines: vec![shuffled_line] };
+        assert_eq!(layout.words_text(&[0, 1]), "foo bar");
+        assert_eq!(layout.words_text(&[1]), "foo");
+    }
+
+    #[test]
+    fn test_find_matches() {
+
+        let layout = two_column_layout();
+
+        let pattern = regex::Regex::new(r"team, the|Sent").unwrap();
+        let rects = layout.find_matches(&pattern);
+        assert_eq!(rects.len(), 3);
+        assert_eq!(rects[0], layout.lines[1].words[1].bounds);
+        assert_eq!(rects[2], layout.lines[4].words[0].bounds);
+
+        // A match inside a word covers the whole word
+        let pattern = regex::Regex::new(r"nvoi").unwrap();
+        assert_eq!(layout.find_matches(&pattern), vec![layout.lines[3].words[0].bounds.clone()]);
+    }
+
+    #[test]
+    fn test_encode_decode() {
+
+        let layout = two_column_layout();
+        let decoded = OcrLayout::decode(&layout.encode()).unwrap();
+
+        assert_eq!(decoded.lines.len(), layout.lines.len());
+        for (decoded_line, line) in decoded.lines.iter().zip(&layout.lines) {
+            assert_eq!(decoded_line.text, line.text);
+            assert_eq!(decoded_line.words.iter().map(|word| &word.text).collect::<Vec<_>>(), line.words.iter().map(|word| &word.text).collect::<Vec<_>>());
+            assert!((decoded_line.bounds.x - line.bounds.x).abs() < 1e-4);
+            assert!((decoded_line.confidence - line.confidence).abs() < 1e-2);
+        }
+
+        assert!(OcrLayout::decode(&layout.encode()[..20]).is_err());
+    }
+
+    #[test]
+    fn test_find_words_and_storage() {
+
+        let layout = two_column_layout();
+        let boxes = layout.find_words("Invoice!");
+        assert_eq!(boxes.len(), 1);
+        assert_eq!(boxes[0].x, 0.30);
+
+        let tmp_dir = tempdir().unwrap();
+        let data_root = tmp_dir.path();
+        let db_filename = Path::new("test.db");
+        db::create_db(data_root, db_filename).unwrap();
+
+        let id = db::insert_test_screenshots(data_root, db_filename, &[(0, layout.text().as_str())])[0];
+        save_ocr_layout(data_root, db_filename, id, &layout).unwrap();
+        assert_eq!(get_ocr_layout(data_root, db_filename, id).unwrap().unwrap().lines.len(), 5);
+
+        // The layout is deleted with its screenshot
+        db::get_db_conn(data_root, db_filename).unwrap().execute("DELETE FROM documents WHERE id = ?1", [id]).unwrap();
+        assert_eq!(get_ocr_layout(data_root, db_filename, id).unwrap(), None);
+    }
+
+}
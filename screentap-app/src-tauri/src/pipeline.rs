@@ -0,0 +1,753 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use serde::Serialize;
+
+use crate::compaction;
+use crate::config::{BackpressurePolicy, ScreentapConfig, StorageQuotaAction};
+use crate::config_watcher::ConfigUpdate;
+use crate::embeddings;
+use crate::frame_cache;
+use crate::ocr_layout::OcrLayout;
+use crate::plugins::focusguard;
+use crate::plugins::focusguard::config::FocusGuardConfig;
+use crate::screenshot;
+use crate::retention;
+use crate::saved_searches;
+use crate::screenshot::{CapturedFrame, ScreenshotSaveResult};
+use crate::storage_stats;
+use crate::tiering;
+use crate::utils;
+use crate::video;
+
+
+/**
+ * Captured frames are written here until they have been OCR'd and saved to the DB,
+ * so that compaction never sees a frame that isn't in the DB yet
+ */
+pub(crate) static INCOMING_DIR_NAME: &str = "incoming";
+
+/**
+ * Log the pipeline metrics every this many capture cycles
+ */
+static METRICS_LOG_INTERVAL_CYCLES: u64 = 20;
+
+/**
+ * Upper bound for the capture and compaction back-off after repeated failures
+ */
+static MAX_BACKOFF_SECS: u64 = 600;
+
+/**
+ * How often to look for old segments to downsample, when tiering is enabled.  Each check
+ * downsamples at most one segment, so a backlog is worked through gradually.
+ */
+static TIERING_CHECK_INTERVAL_SECS: u64 = 600;
+
+/**
+ * How often to look for screenshots without embeddings, when embeddings are enabled.  While
+ * there's a backlog, the next batch is embedded after EMBEDDING_BACKLOG_PAUSE_MILLIS instead.
+ */
+static EMBEDDING_CHECK_INTERVAL_SECS: u64 = 60;
+
+/**
+ * The pause between batches while working through a backlog, so that embedding the history
+ * doesn't keep a CPU core busy for hours
+ */
+static EMBEDDING_BACKLOG_PAUSE_MILLIS: u64 = 1000;
+
+
+/**
+ * Exponential back-off for a stage that keeps failing, eg when screen recording
+ * permission was revoked or the disk is full.  Doubles the base delay per consecutive
+ * failure, up to MAX_BACKOFF_SECS.
+ */
+fn backoff_delay(base: Duration, consecutive_failures: u32) -> Duration {
+    if consecutive_failures == 0 {
+        return base;
+    }
+    let factor = 1u32 << consecutive_failures.min(5);
+    base.saturating_mul(factor).min(Duration::from_secs(MAX_BACKOFF_SECS)).max(base)
+}
+
+
+/**
+ * Per-stage counters and timings, shared between the stage worker and anyone reading the metrics
+ */
+#[derive(Default)]
+pub struct StageMetrics {
+    processed: AtomicU64,
+    dropped: AtomicU64,
+    errors: AtomicU64,
+    total_micros: AtomicU64,
+    max_micros: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StageMetricsSnapshot {
+    pub processed: u64,
+    pub dropped: u64,
+    pub errors: u64,
+    pub avg_millis: f64,
+    pub max_millis: f64,
+}
+
+impl StageMetrics {
+
+    fn record(&self, elapsed: Duration) {
+        let micros = elapsed.as_micros() as u64;
+        self.processed.fetch_add(1, Ordering::Relaxed);
+        self.total_micros.fetch_add(micros, Ordering::Relaxed);
+        self.max_micros.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    fn record_drop(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> StageMetricsSnapshot {
+        let processed = self.processed.load(Ordering::Relaxed);
+        let total_micros = self.total_micros.load(Ordering::Relaxed);
+        let avg_millis = if processed == 0 { 0.0 } else { total_micros as f64 / processed as f64 / 1000.0 };
+        StageMetricsSnapshot {
+            processed,
+            dropped: self.dropped.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            avg_millis,
+            max_millis: self.max_micros.load(Ordering::Relaxed) as f64 / 1000.0,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct PipelineMetrics {
+    pub capture: StageMetrics,
+    pub ocr: StageMetrics,
+    pub persist: StageMetrics,
+    pub plugins: StageMetrics,
+    pub embeddings: StageMetrics,
+}
+
+impl PipelineMetrics {
+
+    pub fn snapshot(&self) -> HashMap<String, StageMetricsSnapshot> {
+        let mut map = HashMap::new();
+        map.insert("capture".to_string(), self.capture.snapshot());
+        map.insert("ocr".to_string(), self.ocr.snapshot());
+        map.insert("persist".to_string(), self.persist.snapshot());
+        map.insert("plugins".to_string(), self.plugins.snapshot());
+        map.insert("embeddings".to_string(), self.embeddings.snapshot());
+        map
+    }
+}
+
+/**
+ * The frontmost app context of a capture cycle, which is shared by all frames in the cycle
+ */
+#[derive(Clone)]
+struct CaptureCycle {
+    frontmost_app: String,
+    frontmost_browser_tab: String,
+    frontmost_app_or_tab_changed: bool,
+
+    // Plugins expect a single screenshot per capture cycle, so only one frame
+    // per cycle is dispatched to them
+    dispatch_to_plugins: bool,
+}
+
+struct PipelineFrame {
+    frame: CapturedFrame,
+    cycle: CaptureCycle,
+}
+
+struct OcrFrame {
+    frame: CapturedFrame,
+    cycle: CaptureCycle,
+    ocr_layout: OcrLayout,
+}
+
+struct PluginFrame {
+    saved_screenshot: ScreenshotSaveResult,
+    cycle: CaptureCycle,
+}
+
+enum SendOutcome<T> {
+    Sent,
+    Dropped(T),
+    Disconnected,
+}
+
+/**
+ * Send to a bounded channel according to the back-pressure policy
+ */
+fn send_with_backpressure<T>(sender: &SyncSender<T>, item: T, policy: BackpressurePolicy) -> SendOutcome<T> {
+    match policy {
+        BackpressurePolicy::Block => match sender.send(item) {
+            Ok(()) => SendOutcome::Sent,
+            Err(_) => SendOutcome::Disconnected,
+        },
+        BackpressurePolicy::DropNewest => match sender.try_send(item) {
+            Ok(()) => SendOutcome::Sent,
+            Err(TrySendError::Full(item)) => SendOutcome::Dropped(item),
+            Err(TrySendError::Disconnected(_)) => SendOutcome::Disconnected,
+        },
+    }
+}
+
+/**
+ * The capture pipeline: capture -> OCR -> persist -> plugin dispatch.
+ *
+ * Each stage runs on its own thread and hands frames to the next stage over a bounded
+ * channel.  A slow OCR or DB stage is handled according to the configured back-pressure
+ * policy, and a slow plugin (eg a vision model taking minutes) only ever causes frames
+ * to be skipped by the plugin, never delayed captures.
+ */
+pub struct CapturePipeline {
+    // Config files live in the app data dir, the DB and media in the data root
+    app_data_dir: PathBuf,
+    data_root: PathBuf,
+    db_filename_path: PathBuf,
+    config: Arc<RwLock<ScreentapConfig>>,
+    metrics: Arc<PipelineMetrics>,
+}
+
+impl CapturePipeline {
+
+    pub fn new(app_data_dir: PathBuf, data_root: PathBuf, db_filename_path: PathBuf, config: ScreentapConfig) -> Self {
+        Self {
+            app_data_dir,
+            data_root,
+            db_filename_path,
+            config: Arc::new(RwLock::new(config)),
+            metrics: Arc::new(PipelineMetrics::default()),
+        }
+    }
+
+    pub fn metrics(&self) -> Arc<PipelineMetrics> {
+        self.metrics.clone()
+    }
+
+    /**
+     * The core config, which the capture stage replaces when the config file is reloaded
+     */
+    pub fn config(&self) -> Arc<RwLock<ScreentapConfig>> {
+        self.config.clone()
+    }
+
+    fn get_incoming_dir(data_root: &Path) -> PathBuf {
+        data_root.join(INCOMING_DIR_NAME)
+    }
+
+    /**
+     * Remove frames left in the incoming dir by a previous run, since they were never saved to the DB
+     */
+    fn reset_incoming_dir(incoming_dir: &Path) -> std::io::Result<()> {
+        if incoming_dir.exists() {
+            for entry in std::fs::read_dir(incoming_dir)? {
+                let path = entry?.path();
+                if path.is_file() {
+                    println!("Removing unsaved frame from previous run: {}", path.display());
+                    std::fs::remove_file(path)?;
+                }
+            }
+        } else {
+            std::fs::create_dir_all(incoming_dir)?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Spawn the stage workers.  The capture stage also applies config updates from the
+     * config watcher, and forwards FocusGuard config updates to the plugin stage.
+     */
+    pub fn spawn(self, app_handle: tauri::AppHandle, focus_guard_option: Option<focusguard::FocusGuard>, config_update_receiver: Receiver<ConfigUpdate>) -> std::io::Result<()> {
+
+        let incoming_dir = CapturePipeline::get_incoming_dir(&self.data_root);
+        CapturePipeline::reset_incoming_dir(&incoming_dir)?;
+
+        let queue_capacity = self.config.read().unwrap().pipeline_queue_capacity;
+
+        let (ocr_sender, ocr_receiver) = mpsc::sync_channel::<PipelineFrame>(queue_capacity);
+        let (persist_sender, persist_receiver) = mpsc::sync_channel::<OcrFrame>(queue_capacity);
+        let (plugin_sender, plugin_receiver) = mpsc::sync_channel::<PluginFrame>(queue_capacity);
+        let (focusguard_config_sender, focusguard_config_receiver) = mpsc::channel::<FocusGuardConfig>();
+
+        self.spawn_plugin_stage(app_handle.clone(), focus_guard_option, plugin_receiver, focusguard_config_receiver);
+        self.spawn_persist_stage(app_handle, persist_receiver, plugin_sender);
+        self.spawn_embedding_stage();
+        self.spawn_ocr_stage(ocr_receiver, persist_sender);
+        self.spawn_capture_stage(incoming_dir, ocr_sender, config_update_receiver, focusguard_config_sender);
+
+        Ok(())
+
+    }
+
+    fn spawn_capture_stage(&self, incoming_dir: PathBuf, ocr_sender: SyncSender<PipelineFrame>, config_update_receiver: Receiver<ConfigUpdate>, focusguard_config_sender: Sender<FocusGuardConfig>) {
+
+        let config = self.config.clone();
+        let metrics = self.metrics.clone();
+        let config_path = ScreentapConfig::get_config_path(&self.app_data_dir);
+        let mut storage_guard = storage_stats::StorageGuard::new(self.data_root.clone(), self.db_filename_path.clone());
+
+        thread::spawn(move || {
+
+            let (mut last_frontmost_app, mut last_browser_tab) = utils::get_frontmost_app_via_applescript();
+            let mut cycles: u64 = 0;
+            let mut consecutive_failed_cycles: u32 = 0;
+            let mut paused_for_storage = false;
+
+            loop {
+
+                let cycle_start = Instant::now();
+
+                // Apply any config changes picked up by the config watcher
+                while let Ok(config_update) = config_update_receiver.try_recv() {
+                    match config_update {
+                        ConfigUpdate::Core(new_config) => {
+                            println!("Applying reloaded config from {}", config_path.display());
+                            frame_cache::set_max_mb(new_config.frame_cache_max_mb);
+                            *config.write().unwrap() = new_config;
+                        },
+                        ConfigUpdate::FocusGuard(new_focusguard_config) => {
+                            let _ = focusguard_config_sender.send(new_focusguard_config);
+                        }
+                    }
+                }
+
+                let cur_config = config.read().unwrap().clone();
+
+                // Don't fill up the disk, see storage_quota_mb and min_free_disk_mb
+                let storage_status = storage_guard.status(&cur_config);
+                if storage_status.pauses_capture(&cur_config) {
+                    if !paused_for_storage {
+                        println!("Pausing capture until there is enough storage: {:?}", storage_status);
+                        paused_for_storage = true;
+                    }
+                    thread::sleep(Duration::from_secs(cur_config.capture_interval_secs).saturating_sub(cycle_start.elapsed()));
+                    continue;
+                } else if paused_for_storage {
+                    println!("Resuming capture, storage is {:?}", storage_status);
+                    paused_for_storage = false;
+                }
+
+                // Get the name of the frontmost app and browser tab (if applicable)
+                let (cur_frontmost_app, cur_browser_tab) = utils::get_frontmost_app_via_applescript();
+                let frontmost_app_or_tab_changed = utils::frontmost_app_or_browser_tab_changed(&cur_frontmost_app, &last_frontmost_app, &cur_browser_tab, &last_browser_tab);
+                println!("Capturing screenshot.  cur_frontmost_app: {} last_frontmost_app: {} cur_browser_tab: {}, last_browser_tab: {} frontmost_app_or_tab_changed: {} ", &cur_frontmost_app, last_frontmost_app, cur_browser_tab, last_browser_tab, frontmost_app_or_tab_changed);
+
+                // Capture a screenshot of each display into the incoming dir
+                let mut frames = vec![];
+                for frame_result in screenshot::capture_frames(&incoming_dir, &cur_config, &cur_frontmost_app) {
+                    match frame_result {
+                        Ok(frame) => frames.push(frame),
+                        Err(e) => {
+                            println!("Error capturing screenshot: {}", e);
+                            metrics.capture.record_error();
+                        }
+                    }
+                }
+
+                // If nothing could be captured, back off rather than retrying on every interval
+                if frames.is_empty() {
+                    consecutive_failed_cycles = consecutive_failed_cycles.saturating_add(1);
+                } else {
+                    consecutive_failed_cycles = 0;
+                }
+
+                // Dispatch the main display to plugins, or the first captured display if the main display isn't captured
+                let plugin_frame_index = frames.iter().position(|frame| frame.capture_meta.display.is_main).unwrap_or(0);
+
+                for (index, frame) in frames.into_iter().enumerate() {
+
+                    let pipeline_frame = PipelineFrame {
+                        frame,
+                        cycle: CaptureCycle {
+                            frontmost_app: cur_frontmost_app.clone(),
+                            frontmost_browser_tab: cur_browser_tab.clone(),
+                            frontmost_app_or_tab_changed,
+                            dispatch_to_plugins: index == plugin_frame_index,
+                        },
+                    };
+
+                    match send_with_backpressure(&ocr_sender, pipeline_frame, cur_config.pipeline_backpressure) {
+                        SendOutcome::Sent => {},
+                        SendOutcome::Dropped(dropped) => {
+                            println!("OCR queue is full, dropping capture {}", dropped.frame.incoming_image_path.display());
+                            metrics.capture.record_drop();
+                            let _ = std::fs::remove_file(&dropped.frame.incoming_image_path);
+                        },
+                        SendOutcome::Disconnected => {
+                            println!("OCR stage has stopped, stopping capture stage");
+                            return;
+                        }
+                    }
+                }
+
+                metrics.capture.record(cycle_start.elapsed());
+
+                // Update the last_ tracking variables to the current values
+                last_frontmost_app = cur_frontmost_app;
+                last_browser_tab = cur_browser_tab;
+
+                cycles += 1;
+                if cycles % METRICS_LOG_INTERVAL_CYCLES == 0 {
+                    println!("Capture pipeline metrics: {:?}", metrics.snapshot());
+                }
+
+                // Keep captures on a fixed schedule regardless of how long this cycle took
+                let capture_interval = backoff_delay(
+                    Duration::from_secs(cur_config.capture_interval_secs),
+                    consecutive_failed_cycles
+                );
+                if consecutive_failed_cycles > 0 {
+                    println!("Capture failed {} times in a row, next capture in {:?}", consecutive_failed_cycles, capture_interval);
+                }
+                thread::sleep(capture_interval.saturating_sub(cycle_start.elapsed()));
+
+            }
+        });
+
+    }
+
+    fn spawn_ocr_stage(&self, ocr_receiver: Receiver<PipelineFrame>, persist_sender: SyncSender<OcrFrame>) {
+
+        let metrics = self.metrics.clone();
+
+        thread::spawn(move || {
+
+            for PipelineFrame { frame, cycle } in ocr_receiver {
+
+                let start = Instant::now();
+                let ocr_layout = match screenshot::ocr_frame(&frame) {
+                    Ok(ocr_layout) => ocr_layout,
+                    Err(e) => {
+                        println!("Error extracting text from screenshot: {}", e);
+                        metrics.ocr.record_error();
+                        let _ = std::fs::remove_file(&frame.incoming_image_path);
+                        continue;
+                    }
+                };
+                metrics.ocr.record(start.elapsed());
+
+                // Block rather than drop here, so that back-pressure propagates to the capture stage
+                if persist_sender.send(OcrFrame { frame, cycle, ocr_layout }).is_err() {
+                    println!("Persist stage has stopped, stopping OCR stage");
+                    return;
+                }
+            }
+
+        });
+
+    }
+
+    fn spawn_persist_stage(&self, app_handle: tauri::AppHandle, persist_receiver: Receiver<OcrFrame>, plugin_sender: SyncSender<PluginFrame>) {
+
+        let data_root = self.data_root.clone();
+        let db_filename_path = self.db_filename_path.clone();
+        let config = self.config.clone();
+        let metrics = self.metrics.clone();
+
+        thread::spawn(move || {
+
+            let initial_config = config.read().unwrap().clone();
+
+            // Compaction runs on this thread since it must not interleave with saving screenshots to the DB
+            let mut compaction_helper = match compaction::CompactionHelper::new(
+                data_root.clone(),
+                db_filename_path.clone(),
+                compaction::CompactionPolicy::from_config(&initial_config),
+                video::create_encoder(&initial_config),
+                video::create_frame_extractor(&initial_config),
+            ) {
+                Ok(compaction_helper) => {
+                    // Finish or roll back any compaction that was interrupted by the app exiting
+                    if let Err(e) = compaction_helper.recover_incomplete_jobs() {
+                        println!("Error recovering incomplete compaction jobs: {}", e);
+                        metrics.persist.record_error();
+                    }
+                    match compaction_helper.reconcile() {
+                        Ok(report) if !report.is_empty() => println!("Reconciled screenshots with the DB: {:?}", report),
+                        Ok(_) => {},
+                        Err(e) => {
+                            println!("Error reconciling screenshots with the DB: {}", e);
+                            metrics.persist.record_error();
+                        }
+                    }
+                    Some(compaction_helper)
+                },
+                Err(e) => {
+                    println!("Error creating compaction helper, compaction is disabled: {}", e);
+                    None
+                }
+            };
+
+            // Tiering also rewrites mp4 files and DB rows, so it runs on this thread too.  Interrupted
+            // jobs are recovered even if tiering was disabled since.
+            let mut tiering_helper = tiering::TieringHelper::new(
+                data_root.clone(),
+                db_filename_path.clone(),
+                tiering::tiers_from_config(&initial_config),
+                video::create_encoder(&initial_config),
+                video::create_frame_extractor(&initial_config),
+            );
+            if let Err(e) = tiering_helper.recover_incomplete_jobs() {
+                println!("Error recovering incomplete tiering jobs: {}", e);
+                metrics.persist.record_error();
+            }
+
+            // After a failed compaction, wait before retrying so that a persistent failure
+            // doesn't re-encode the same frames on every screenshot
+            let mut consecutive_compaction_failures: u32 = 0;
+            let mut next_compaction_attempt = Instant::now();
+            let mut consecutive_tiering_failures: u32 = 0;
+            let mut next_tiering_attempt = Instant::now();
+            let mut next_retention_check = Instant::now();
+
+            for OcrFrame { frame, cycle, ocr_layout } in persist_receiver {
+
+                let start = Instant::now();
+                let cur_config = config.read().unwrap().clone();
+
+                // Compact screenshots to mp4 if necessary
+                if let Some(ref mut compaction_helper) = compaction_helper {
+                    if Instant::now() >= next_compaction_attempt {
+                        compaction_helper.set_policy(compaction::CompactionPolicy::from_config(&cur_config));
+                        compaction_helper.set_video_backend(
+                            video::create_encoder(&cur_config),
+                            video::create_frame_extractor(&cur_config)
+                        );
+
+                        match compaction_helper.compact_screenshots_to_mp4(frame.now) {
+                            Ok(_) => consecutive_compaction_failures = 0,
+                            Err(e) => {
+                                consecutive_compaction_failures = consecutive_compaction_failures.saturating_add(1);
+                                let delay = backoff_delay(Duration::from_secs(cur_config.capture_interval_secs), consecutive_compaction_failures);
+                                println!("Error compacting screenshots, retrying in {:?}: {}", delay, e);
+                                metrics.persist.record_error();
+                                next_compaction_attempt = Instant::now() + delay;
+                            }
+                        }
+                    }
+                }
+
+                // Downsample an old segment if one is due
+                if cur_config.tiering_enabled && Instant::now() >= next_tiering_attempt {
+                    tiering_helper.set_tiers(tiering::tiers_from_config(&cur_config));
+                    tiering_helper.set_video_backend(
+                        video::create_encoder(&cur_config),
+                        video::create_frame_extractor(&cur_config)
+                    );
+
+                    let check_interval = Duration::from_secs(TIERING_CHECK_INTERVAL_SECS);
+                    match tiering_helper.tier_next_segment(frame.now) {
+                        Ok(_) => {
+                            consecutive_tiering_failures = 0;
+                            next_tiering_attempt = Instant::now() + check_interval;
+                        },
+                        Err(e) => {
+                            consecutive_tiering_failures = consecutive_tiering_failures.saturating_add(1);
+                            let delay = backoff_delay(check_interval, consecutive_tiering_failures);
+                            println!("Error downsampling old segments, retrying in {:?}: {}", delay, e);
+                            metrics.persist.record_error();
+                            next_tiering_attempt = Instant::now() + delay;
+                        }
+                    }
+                }
+
+                // Delete the oldest history if the data root is over the quota
+                let quota_bytes = storage_stats::get_quota_bytes(&cur_config);
+                if let (Some(quota_bytes), StorageQuotaAction::DeleteOldest) = (quota_bytes, cur_config.storage_quota_action) {
+                    if Instant::now() >= next_retention_check {
+                        next_retention_check = Instant::now() + Duration::from_secs(storage_stats::STORAGE_CHECK_INTERVAL_SECS);
+                        let retention_result = storage_stats::get_data_root_usage_bytes(&data_root, &db_filename_path)
+                            .and_then(|used_bytes| match used_bytes.checked_sub(quota_bytes) {
+                                Some(bytes_to_free) if bytes_to_free > 0 => retention::delete_oldest_segments(&data_root, &db_filename_path, bytes_to_free, video::create_frame_extractor(&cur_config).as_ref()).map(Some),
+                                _ => Ok(None),
+                            });
+                        match retention_result {
+                            Ok(Some(report)) => println!("Deleted old history to stay within the storage quota: {:?}", report),
+                            Ok(None) => {},
+                            Err(e) => {
+                                println!("Error deleting old history to stay within the storage quota: {}", e);
+                                metrics.persist.record_error();
+                            }
+                        }
+                    }
+                }
+
+                let timestamp = frame.now.timestamp();
+                let saved_screenshot = match screenshot::persist_frame(&data_root, &db_filename_path, frame, &ocr_layout) {
+                    Ok(saved_screenshot) => saved_screenshot,
+                    Err(e) => {
+                        println!("Error saving screenshot: {}", e);
+                        metrics.persist.record_error();
+                        continue;
+                    }
+                };
+
+                // Alert on new matches of watched searches
+                match saved_searches::evaluate_watches(
+                    &data_root,
+                    &db_filename_path,
+                    saved_screenshot.screenshot_id,
+                    saved_screenshot.capture_meta.display.display_id,
+                    &saved_screenshot.ocr_text,
+                    timestamp,
+                ) {
+                    Ok(alerts) => {
+                        for alert in &alerts {
+                            println!("Watched search {} matched screenshot #{}", alert.saved_search_name, alert.document_id);
+                            saved_searches::notify_alert(&app_handle, alert);
+                        }
+                    },
+                    Err(e) => {
+                        println!("Error checking watched searches: {}", e);
+                        metrics.persist.record_error();
+                    }
+                }
+
+                metrics.persist.record(start.elapsed());
+
+                if !cycle.dispatch_to_plugins {
+                    continue;
+                }
+
+                // Plugins can be very slow, so never wait for them
+                match plugin_sender.try_send(PluginFrame { saved_screenshot, cycle }) {
+                    Ok(()) => {},
+                    Err(TrySendError::Full(_)) => {
+                        println!("Plugins are busy, skipping plugins for this screenshot");
+                        metrics.plugins.record_drop();
+                    },
+                    Err(TrySendError::Disconnected(_)) => {
+                        println!("Plugin stage has stopped, stopping persist stage");
+                        return;
+                    }
+                }
+            }
+
+        });
+
+    }
+
+    /**
+     * Embed the OCR text of saved screenshots in batches, which also works through the history
+     * from before embeddings were enabled.  Loading the model and running it on the CPU is slow,
+     * so this has a thread of its own and never holds up saving screenshots.
+     */
+    fn spawn_embedding_stage(&self) {
+
+        let app_data_dir = self.app_data_dir.clone();
+        let data_root = self.data_root.clone();
+        let db_filename_path = self.db_filename_path.clone();
+        let config = self.config.clone();
+        let metrics = self.metrics.clone();
+
+        thread::spawn(move || {
+
+            let check_interval = Duration::from_secs(EMBEDDING_CHECK_INTERVAL_SECS);
+            let mut consecutive_embedding_failures: u32 = 0;
+
+            loop {
+
+                let cur_config = config.read().unwrap().clone();
+                if !cur_config.embeddings_enabled {
+                    thread::sleep(check_interval);
+                    continue;
+                }
+
+                let start = Instant::now();
+                let embedding_result = embeddings::get_embedder(&embeddings::get_model_dir(&app_data_dir, &cur_config))
+                    .and_then(|embedder| embeddings::embed_pending_screenshots(&data_root, &db_filename_path, embedder.as_ref(), embeddings::EMBEDDING_BATCH_SIZE));
+                let delay = match embedding_result {
+                    Ok(embedded) => {
+                        consecutive_embedding_failures = 0;
+                        if embedded > 0 {
+                            metrics.embeddings.record(start.elapsed());
+                        }
+                        if embedded < embeddings::EMBEDDING_BATCH_SIZE as usize {
+                            check_interval
+                        } else {
+                            Duration::from_millis(EMBEDDING_BACKLOG_PAUSE_MILLIS)
+                        }
+                    },
+                    Err(e) => {
+                        consecutive_embedding_failures = consecutive_embedding_failures.saturating_add(1);
+                        let delay = backoff_delay(check_interval, consecutive_embedding_failures);
+                        println!("Error embedding screenshots, retrying in {:?}: {}", delay, e);
+                        metrics.embeddings.record_error();
+                        delay
+                    }
+                };
+                thread::sleep(delay);
+            }
+
+        });
+
+    }
+
+    fn spawn_plugin_stage(&self, app_handle: tauri::AppHandle, focus_guard_option: Option<focusguard::FocusGuard>, plugin_receiver: Receiver<PluginFrame>, focusguard_config_receiver: Receiver<FocusGuardConfig>) {
+
+        let app_data_dir = self.app_data_dir.clone();
+        let data_root = self.data_root.clone();
+        let db_filename_path = self.db_filename_path.clone();
+        let metrics = self.metrics.clone();
+
+        thread::spawn(move || {
+
+            let mut focus_guard_option = focus_guard_option;
+
+            for PluginFrame { saved_screenshot, cycle } in plugin_receiver {
+
+                // Apply any FocusGuard config changes
+                while let Ok(new_focusguard_config) = focusguard_config_receiver.try_recv() {
+                    match focus_guard_option {
+                        Some(ref mut focus_guard) => {
+                            if let Err(e) = focus_guard.apply_config(new_focusguard_config) {
+                                println!("Failed to apply FocusGuard config: {}", e);
+                            }
+                        },
+                        None => {
+                            // The plugin was not enabled at startup, so try to start it now
+                            focus_guard_option = focusguard::FocusGuard::new_from_config(
+                                app_data_dir.clone(),
+                                data_root.join(&db_filename_path),
+                            );
+                        }
+                    }
+                }
+
+                let start = Instant::now();
+
+                let ScreenshotSaveResult { png_data, ocr_text, image_path, screenshot_id, .. } = saved_screenshot;
+
+                // Invoke plugins
+                // TODO: any way to avoid this confusing "ref mut" stuff?
+                if let Some(ref mut focus_guard) = focus_guard_option {
+                    focus_guard.handle_screentap_event(
+                        &app_handle,
+                        png_data,
+                        image_path.as_path(),
+                        screenshot_id,
+                        ocr_text,
+                        &cycle.frontmost_app,
+                        &cycle.frontmost_browser_tab,
+                        cycle.frontmost_app_or_tab_changed
+                    );
+                }
+
+                metrics.plugins.record(start.elapsed());
+            }
+
+        });
+
+    }
+
+}
@@ -3,8 +3,10 @@ use std::path::PathBuf;
 use toml;
 use std::fs;
 use serde::Deserialize;
+use std::str::FromStr;
+use crate::plugins::focusguard::LlavaBackendType;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct FocusGuardConfig {
     pub job_title: String,
     pub job_role: String,
@@ -24,13 +26,15 @@ impl FocusGuardConfig {
             .join("focusguard")
     }
 
-    pub fn new(app_data_dir: &Path) -> Option<FocusGuardConfig> {
+    pub fn get_config_path(app_data_dir: &Path) -> PathBuf {
+        FocusGuardConfig::get_focusguard_root_dir(app_data_dir)
+            .join("config.toml")
+    }
 
-        let focusguard_root_dir = FocusGuardConfig::get_focusguard_root_dir(app_data_dir);
+    pub fn new(app_data_dir: &Path) -> Option<FocusGuardConfig> {
 
         // Build path to config.toml in expected place
-        let toml_config = focusguard_root_dir
-            .join("config.toml");
+        let toml_config = FocusGuardConfig::get_config_path(app_data_dir);
 
         // If config.toml not found, return None
         if !toml_config.exists() {
@@ -46,13 +50,50 @@ impl FocusGuardConfig {
             println!("FocusGuard config found at path: {}", toml_config.display());
         }
 
-        let config_str = fs::read_to_string(toml_config)
-            .expect("Failed to read focusguard config file");
+        match FocusGuardConfig::load(app_data_dir) {
+            Ok(focusguard_config) => Some(focusguard_config),
+            Err(e) => {
+                println!("Invalid FocusGuard config: {}", e);
+                None
+            }
+        }
+
+    }
+
+    /**
+     * Read, parse and validate config.toml without panicking, so that it can be
+     * used to reload the config while the app is running.
+     */
+    pub fn load(app_data_dir: &Path) -> Result<FocusGuardConfig, String> {
+
+        let toml_config = FocusGuardConfig::get_config_path(app_data_dir);
+
+        let config_str = fs::read_to_string(&toml_config)
+            .map_err(|e| format!("Failed to read {}: {}", toml_config.display(), e))?;
 
         let focusguard_config = toml::from_str::<FocusGuardConfig>(&config_str)
-            .expect("Failed to parse focusguard config");
+            .map_err(|e| format!("Failed to parse {}: {}", toml_config.display(), e))?;
+
+        focusguard_config.validate()?;
+
+        Ok(focusguard_config)
+
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+
+        let llava_backend = LlavaBackendType::from_str(&self.llava_backend)
+            .map_err(|_| format!("Invalid llava_backend: {}", self.llava_backend))?;
+
+        if llava_backend == LlavaBackendType::OpenAI && self.openai_api_key.is_empty() {
+            return Err("OpenAI API key is required for OpenAI backend".to_string());
+        }
+
+        if !(0.1..=1.0).contains(&self.image_resize_scale) {
+            return Err(format!("image_resize_scale must be between 0.1 and 1.0, got {}", self.image_resize_scale));
+        }
 
-        Some(focusguard_config)
+        Ok(())
 
     }
 }
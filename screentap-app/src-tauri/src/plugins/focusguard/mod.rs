@@ -1,5 +1,3 @@
-extern crate screen_ocr_swift_rs;
-
 use std::time::{Instant, Duration};
 use serde::Serialize;
 use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
@@ -13,6 +11,7 @@ use base64::engine::Engine as _;
 use base64::engine::general_purpose::STANDARD as BASE64;
 use std::error::Error;
 use std::fs;
+use std::io::Write;
 use std::os::unix::fs::PermissionsExt;
 use ollama_rs::{
     generation::completion::request::GenerationRequest,
@@ -23,6 +22,7 @@ use tokio::runtime;
 use rusqlite::Result;
 use image_hasher::{HasherConfig, ImageHash};
 use event::FocusGuardCallbackEvent;
+use crate::frame_format::FrameFormat;
 use result::{FocusGuardCallbackResult, SkipVisionModelReason};
 use rusqlite::params;
 
@@ -259,6 +259,35 @@ impl FocusGuard {
 
     }
 
+    /**
+     * Apply a reloaded config to the running plugin.  The config is validated first, and
+     * if it is invalid the current config is left untouched and the error is returned.
+     * 
+     * Runtime state (the IDLE/PRIMED state machine, last alert time and previous phash)
+     * is preserved across reloads.
+     */
+    pub fn apply_config(&mut self, config: config::FocusGuardConfig) -> Result<(), String> {
+
+        config.validate()?;
+
+        let llava_backend = LlavaBackendType::from_str(&config.llava_backend)
+            .map_err(|_| format!("Invalid llava_backend: {}", config.llava_backend))?;
+
+        self.job_title = config.job_title;
+        self.job_role = config.job_role;
+        self.openai_api_key = config.openai_api_key;
+        self.duration_between_alerts = Duration::from_secs(config.duration_between_alerts_secs);
+        self.llava_backend = llava_backend;
+        self.productivity_score_threshold = config.productivity_score_threshold;
+        self.image_resize_scale = config.image_resize_scale;
+        self.dev_mode = config.dev_mode;
+
+        println!("FocusGuard config reloaded.  llava_backend: {} job_title: {}", self.llava_backend, self.job_title);
+
+        Ok(())
+
+    }
+
     /**
      * If this is called twice in a row with the same frontmost_app or browser tab, it means the user is "lingering" on 
      * that app/tab rather than just in transit between apps.  It should invoke the vision model
@@ -394,7 +423,7 @@ impl FocusGuard {
                 LlavaBackendType::OpenAI => self.invoke_openai_vision_model(&prompt, &resized_png_data),
                 LlavaBackendType::Ollama => self.invoke_ollama_vision_model(&prompt, &resized_png_data),
                 LlavaBackendType::LlamaFile => self.invoke_openai_vision_model(&prompt, &resized_png_data),
-                LlavaBackendType::LlamaFileSubprocess => self.invoke_subprocess_vision_model(&prompt, cb_event.png_image_path),
+                LlavaBackendType::LlamaFileSubprocess => self.invoke_subprocess_vision_model(&prompt, cb_event.png_image_path, cb_event.png_data),
             };
 
             cb_result.invoked_vision_model = true;
@@ -492,7 +521,7 @@ impl FocusGuard {
      */
     fn resize_image(png_data: &[u8], scale: f32) -> Option<Vec<u8>> {
 
-        screen_ocr_swift_rs::resize_image(png_data, scale)
+        crate::native::resize_image(png_data, scale)
 
     }
 
@@ -731,7 +760,7 @@ impl FocusGuard {
         utils::download_file(url, dest_file)
     }
 
-    fn invoke_subprocess_vision_model(&self, prompt: &str, png_image_path: &Path) -> String {
+    fn invoke_subprocess_vision_model(&self, prompt: &str, png_image_path: &Path, png_data: &[u8]) -> String {
 
         let full_prompt = format!("### User: {}\n ### Assistant:", prompt);
         let llamafile_path = self.app_data_dir.join("plugins").join("focusguard").join("llava-v1.5-7b-q4.llamafile");
@@ -752,6 +781,28 @@ impl FocusGuard {
             }
         } 
 
+        // llamafile only reads PNG and JPEG images, so other frame formats are passed as a temp PNG
+        let tmp_png_file;
+        let png_image_path = match FrameFormat::from_path(png_image_path) {
+            FrameFormat::Png | FrameFormat::Jpeg => png_image_path,
+            _ => {
+                tmp_png_file = match tempfile::Builder::new().suffix(".png").tempfile() {
+                    Ok(mut tmp_png_file) => match tmp_png_file.write_all(png_data) {
+                        Ok(()) => tmp_png_file,
+                        Err(e) => {
+                            println!("Error writing temp png for Llamafile: {}", e);
+                            return "".to_string();
+                        }
+                    },
+                    Err(e) => {
+                        println!("Error creating temp png for Llamafile: {}", e);
+                        return "".to_string();
+                    }
+                };
+                tmp_png_file.path()
+            }
+        };
+
         println!("Invoking LlamaFile subprocess ..");
         // sh ./llava-v1.5-7b-q4.llamafile -ngl 9999 --image ~/Desktop/2024_02_15_10_24_53.png -e -p '### User: On a scale of 1 to 10, how much does this screenshot indicate..'
         let output = Command::new(llamafile_path)
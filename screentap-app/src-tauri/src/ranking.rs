@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use chrono::NaiveDateTime;
+use serde::Serialize;
+
+use crate::cluster;
+use crate::config::ScreentapConfig;
+use crate::db::ScreenshotRecord;
+
+
+/**
+ * The bm25() weights of the columns of the OCR text index, in column order.  Only the OCR text
+ * is indexed so far.  The frontmost app, window title and URL get their own weights when
+ * they're added to the index.
+ */
+pub static BM25_COLUMN_WEIGHTS: [f64; 1] = [1.0];
+
+// The dwell time at which the dwell score reaches 1, so content that stayed on screen all day
+// doesn't outrank everything else
+static DWELL_SATURATION_SECS: f64 = 20.0 * 60.0;
+
+
+/**
+ * How search hits are scored, see config_sample.toml.  The score of a hit is the weighted sum
+ * of its text relevance, recency and dwell time scores, which are each between 0 and 1.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct RankingModel {
+    pub text_weight: f64,
+    pub recency_weight: f64,
+    pub recency_half_life_days: f64,
+    pub dwell_weight: f64,
+
+    // How long a single capture counts as being on screen
+    pub capture_interval_secs: u64,
+
+    // How many exact hits are scored for each result, so that the dwell time of a hit can be
+    // measured from the near-identical hits around it
+    pub candidates_per_result: i32,
+}
+
+impl RankingModel {
+
+    pub fn from_config(config: &ScreentapConfig) -> Self {
+        RankingModel {
+            text_weight: config.ranking_text_weight,
+            recency_weight: config.ranking_recency_weight,
+            recency_half_life_days: config.ranking_recency_half_life_days,
+            dwell_weight: config.ranking_dwell_weight,
+            capture_interval_secs: config.capture_interval_secs,
+            candidates_per_result: config.ranking_candidates_per_result.min(i32::MAX as u32) as i32,
+        }
+    }
+
+}
+
+impl Default for RankingModel {
+    fn default() -> Self {
+        RankingModel::from_config(&ScreentapConfig::default())
+    }
+}
+
+/**
+ * Why a hit ranked where it did: its raw signals, the score of each and the weighted total
+ */
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RankingExplanation {
+
+    // The FTS5 bm25 score, where lower is better, and relative to the best hit
+    pub bm25: f64,
+    pub text_score: f64,
+
+    // Halves every recency_half_life_days
+    pub age_days: f64,
+    pub recency_score: f64,
+
+    // How long near-identical text matching the search stayed on the same display
+    pub dwell_secs: i64,
+    pub dwell_score: f64,
+
+    pub score: f64,
+}
+
+
+/**
+ * Score hits with their bm25 scores and sort them by score, best first.  Ties go to the newest.
+ *
+ * The dwell time of a hit is the time span of the near-identical hits around it, see
+ * cluster::cluster_screenshots(), so the candidates should include more hits than are shown.
+ */
+pub fn rank_hits(hits: Vec<(ScreenshotRecord, f64)>, model: &RankingModel, now: NaiveDateTime) -> Vec<(ScreenshotRecord, RankingExplanation)> {
+
+    let dwell_secs_by_id = get_dwell_secs_by_id(&hits, model.capture_interval_secs as i64);
+
+    // bm25 scores are negative, and only comparable within a query
+    let best_bm25 = hits.iter().map(|(_, bm25)| *bm25).fold(0.0, f64::min);
+
+    let mut ranked_hits: Vec<(ScreenshotRecord, RankingExplanation)> = hits.into_iter()
+        .map(|(record, bm25)| {
+
+            let text_score = if best_bm25 < 0.0 { (bm25 / best_bm25).clamp(0.0, 1.0) } else { 1.0 };
+
+            let age_days = ((now.timestamp() - record.get_timestamp() as i64).max(0) as f64) / 86400.0;
+            let recency_score = 0.5_f64.powf(age_days / model.recency_half_life_days);
+
+            let dwell_secs = dwell_secs_by_id.get(&(record.get_id() as i64)).copied().unwrap_or(model.capture_interval_secs as i64);
+            let dwell_score = ((1.0 + dwell_secs as f64 / 60.0).ln() / (1.0 + DWELL_SATURATION_SECS / 60.0).ln()).min(1.0);
+
+            let score = model.text_weight * text_score + model.recency_weight * recency_score + model.dwell_weight * dwell_score;
+
+            let explanation = RankingExplanation { bm25, text_score, age_days, recency_score, dwell_secs, dwell_score, score };
+            (record, explanation)
+        })
+        .collect();
+
+    ranked_hits.sort_by(|(a, a_explanation), (b, b_explanation)| {
+        b_explanation.score.total_cmp(&a_explanation.score).then(b.get_timestamp().cmp(&a.get_timestamp()))
+    });
+
+    ranked_hits
+
+}
+
+/**
+ * The dwell time of each hit, which is the time span of its cluster plus one capture, since a
+ * single capture was on screen for about one capture interval
+ */
+fn get_dwell_secs_by_id(hits: &[(ScreenshotRecord, f64)], capture_interval_secs: i64) -> HashMap<i64, i64> {
+
+    let records = hits.iter().map(|(record, _)| record.clone()).collect();
+
+    let mut dwell_secs_by_id = HashMap::new();
+    for cluster in cluster::cluster_screenshots(records) {
+        let dwell_secs = (cluster.last_timestamp - cluster.first_timestamp) as i64 + capture_interval_secs;
+        for screenshot_id in cluster.screenshot_ids {
+            dwell_secs_by_id.insert(screenshot_id, dwell_secs);
+        }
+    }
+
+    dwell_secs_by_id
+
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use super::{rank_hits, RankingModel, BM25_COLUMN_WEIGHTS};
+    use crate::db;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_rank_hits() {
+
+        let tmp_dir = tempdir().unwrap();
+        let data_root = tmp_dir.path();
+        let db_filename = Path::new("test.db");
+        db::create_db(data_root, db_filename).unwrap();
+
+        // A budget spreadsheet that was on screen for 20 minutes, then a one-frame flash of a
+        // notification that mentions the budget twice
+        let spreadsheet = "Q3 budget spreadsheet with the totals per team, travel costs, hardware costs and the hiring plan for next quarter";
+        let mut captures: Vec<(i64, &str)> = (0..40).map(|capture| (capture * 30, spreadsheet)).collect();
+        captures.push((25 * 60, "Budget budget"));
+        let flash_id = *db::insert_test_screenshots(data_root, db_filename, &captures).last().unwrap();
+
+        let hits = db::find_screenshots_ocr_bm25("budget", &BM25_COLUMN_WEIGHTS, data_root, db_filename, 100, None).unwrap();
+        assert_eq!(hits.len(), 41);
+
+        // The flash is the best text match
+        assert_eq!(hits[0].0.get_id() as i64, flash_id);
+
+        let now = db::test_capture_time(60 * 60);
+        let ranked_hits = rank_hits(hits.clone(), &RankingModel::default(), now);
+        let flash_rank = ranked_hits.iter().position(|(record, _)| record.get_id() as i64 == flash_id).unwrap();
+        assert_eq!(flash_rank, 40);
+
+        let (_, explanation) = &ranked_hits[0];
+        assert_eq!(explanation.dwell_secs, 20 * 60);
+        assert!(explanation.text_score < 1.0);
+        assert_eq!(ranked_hits[40].1.text_score, 1.0);
+        assert_eq!(ranked_hits[40].1.dwell_secs, 30);
+
+        // Without the dwell time, text relevance wins
+        let model = RankingModel { dwell_weight: 0.0, ..RankingModel::default() };
+        assert_eq!(rank_hits(hits, &model, now)[0].0.get_id() as i64, flash_id);
+    }
+
+}
@@ -0,0 +1,120 @@
+use std::io::Cursor;
+use std::path::Path;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat};
+
+use crate::db;
+use crate::error::ScreentapError;
+use crate::native;
+use crate::ocr_layout::{OcrLayout, OcrRect};
+use crate::video::FrameExtractor;
+
+
+// How much a region is upscaled before it's OCR'd again.  Vision reads small text more
+// accurately when it's larger.
+static REOCR_SCALE: f32 = 2.0;
+
+// The longest side of an upscaled region in pixels, so that a large selection isn't upscaled
+// into an image that's slow to OCR
+static REOCR_MAX_SIDE: u32 = 4096;
+
+
+/**
+ * OCR a region of a screenshot again, from the loose screenshot or its mp4 frame, cropped and
+ * upscaled.  More accurate than the stored OCR text for small text, at the cost of a second
+ * OCR pass.  Returns the region's text in reading order.
+ */
+pub fn reocr_region(dataset_root: &Path, db_filename: &Path, screenshot_id: i64, region: &OcrRect, frame_extractor: &dyn FrameExtractor) -> Result<String, ScreentapError> {
+
+    let image_data = db::get_screenshot_image_data(dataset_root, db_filename, screenshot_id, frame_extractor)?;
+    let crop_png = crop_and_upscale(&image_data, region, REOCR_SCALE)?;
+
+    let ocr_lines = native::extract_layout_from_image_data(&crop_png)
+        .ok_or_else(|| ScreentapError::Ocr(format!("No OCR result for region of screenshot #{}", screenshot_id)))?;
+
+    Ok(OcrLayout::from(ocr_lines).text())
+
+}
+
+/**
+ * Crop a normalized region out of an image and upscale it, returning a PNG.  The region is
+ * clamped to the image.
+ */
+pub fn crop_and_upscale(image_data: &[u8], region: &OcrRect, scale: f32) -> Result<Vec<u8>, ScreentapError> {
+
+    let image = decode_image(image_data)?;
+    let (image_width, image_height) = (image.width() as f32, image.height() as f32);
+
+    let left = (region.x.clamp(0.0, 1.0) * image_width).floor();
+    let top = (region.y.clamp(0.0, 1.0) * image_height).floor();
+    let right = ((region.x + region.width).clamp(0.0, 1.0) * image_width).ceil();
+    let bottom = ((region.y + region.height).clamp(0.0, 1.0) * image_height).ceil();
+    if right <= left || bottom <= top {
+        return Err(ScreentapError::Ocr(format!("Empty region {:?}", region)));
+    }
+
+    let crop = image.crop_imm(left as u32, top as u32, (right - left) as u32, (bottom - top) as u32);
+
+    // Don't upscale past the max side, but never shrink the crop
+    let longest_side = crop.width().max(crop.height()) as f32;
+    let scale = scale.min(REOCR_MAX_SIDE as f32 / longest_side).max(1.0);
+    let crop = if scale > 1.0 {
+        let width = (crop.width() as f32 * scale).round() as u32;
+        let height = (crop.height() as f32 * scale).round() as u32;
+        crop.resize_exact(width, height, FilterType::CatmullRom)
+    } else {
+        crop
+    };
+
+    let mut png_data = vec![];
+    crop.write_to(&mut Cursor::new(&mut png_data), ImageFormat::Png)?;
+    Ok(png_data)
+
+}
+
+/**
+ * Decode a screenshot in any frame format.  The image crate can't decode AVIF, so anything it
+ * can't decode is converted to PNG by ImageIO first.
+ */
+pub fn decode_image(image_data: &[u8]) -> Result<DynamicImage, ScreentapError> {
+    match image::load_from_memory(image_data) {
+        Ok(image) => Ok(image),
+        Err(e) => {
+            let png_data = native::resize_image(image_data, 1.0).ok_or(e)?;
+            Ok(image::load_from_memory(&png_data)?)
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use super::crop_and_upscale;
+    use crate::ocr_layout::OcrRect;
+    use image::{ImageFormat, Rgb, RgbImage};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_crop_and_upscale() {
+
+        let mut image = RgbImage::new(200, 100);
+        image.put_pixel(150, 75, Rgb([255, 0, 0]));
+        let mut png_data = vec![];
+        image.write_to(&mut Cursor::new(&mut png_data), ImageFormat::Png).unwrap();
+
+        // The bottom right quarter, doubled
+        let region = OcrRect { x: 0.5, y: 0.5, width: 0.5, height: 0.5 };
+        let crop = image::load_from_memory(&crop_and_upscale(&png_data, &region, 2.0).unwrap()).unwrap();
+        assert_eq!((crop.width(), crop.height()), (200, 100));
+
+        // Regions are clamped to the image
+        let region = OcrRect { x: 0.9, y: -0.5, width: 0.5, height: 1.0 };
+        let crop = image::load_from_memory(&crop_and_upscale(&png_data, &region, 1.0).unwrap()).unwrap();
+        assert_eq!((crop.width(), crop.height()), (20, 50));
+
+        let region = OcrRect { x: 1.5, y: 0.0, width: 0.5, height: 0.5 };
+        assert!(crop_and_upscale(&png_data, &region, 2.0).is_err());
+    }
+
+}
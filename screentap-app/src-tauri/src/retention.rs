@@ -0,0 +1,218 @@
+use std::path::Path;
+use rusqlite::{params, OptionalExtension};
+
+use crate::annotations;
+use crate::db;
+use crate::error::ScreentapError;
+use crate::storage::StorageLayout;
+use crate::video::FrameExtractor;
+
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RetentionReport {
+    pub segments_deleted: usize,
+    // Segments kept because their pinned screenshots could not be unpacked
+    pub segments_skipped: usize,
+    pub screenshots_deleted: usize,
+    pub bytes_freed: u64,
+}
+
+/**
+ * Delete the oldest compacted segments, with their screenshots, until at least bytes_to_free
+ * bytes were freed or there are no segments left.  Loose screenshots are never deleted, since
+ * they are the most recent history.  Pinned screenshots in a segment are unpacked first, so
+ * they're kept as loose screenshots.  A segment whose pinned screenshots can't be unpacked
+ * is kept, and the next oldest one is deleted instead.
+ *
+ * The DB rows are deleted before the mp4, so a crash in between leaves an unreferenced mp4
+ * rather than screenshots pointing to a missing file.
+ */
+pub fn delete_oldest_segments(data_root: &Path, db_filename: &Path, bytes_to_free: u64, frame_extractor: &dyn FrameExtractor) -> Result<RetentionReport, ScreentapError> {
+
+    let mut conn = db::get_db_conn(data_root, db_filename)?;
+    let layout = StorageLayout::new(data_root);
+    let mut report = RetentionReport::default();
+
+    while report.bytes_freed < bytes_to_free {
+
+        let oldest_segment = conn.query_row(
+            "SELECT id, mp4_file_path, size_bytes FROM segments ORDER BY start_timestamp, id LIMIT 1 OFFSET ?1",
+            params![report.segments_skipped as i64],
+            |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, i64>(2)?))
+        ).optional()?;
+
+        let Some((segment_id, mp4_file_path, size_bytes)) = oldest_segment else {
+            break;
+        };
+
+        // Deleting the segment would delete the pinned screenshots that are still in it
+        if let Err(e) = annotations::unpack_pinned_in_segment(data_root, db_filename, &mp4_file_path, frame_extractor) {
+            println!("Error unpacking the pinned screenshots of {}, keeping it: {}", mp4_file_path, e);
+            report.segments_skipped += 1;
+            continue;
+        }
+
+        let tx = conn.transaction()?;
+
+        let screenshots_deleted = tx.execute("DELETE FROM documents WHERE mp4_file_path = ?1", params![mp4_file_path])?;
+        tx.execute("DELETE FROM segments WHERE id = ?1", params![segment_id])?;
+        tx.commit()?;
+
+        let mp4_path = layout.to_absolute(&mp4_file_path);
+        match std::fs::remove_file(&mp4_path) {
+            Ok(()) => {},
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+            Err(e) => println!("Error deleting {}, it is no longer used: {}", mp4_path.display(), e),
+        }
+
+        println!("Retention deleted {} with {} screenshots", mp4_file_path, screenshots_deleted);
+
+        report.segments_deleted += 1;
+        report.screenshots_deleted += screenshots_deleted;
+        report.bytes_freed += size_bytes.max(0) as u64;
+    }
+
+    Ok(report)
+
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use super::delete_oldest_segments;
+    use crate::config::ScreentapConfig;
+    use crate::db;
+    use crate::error::ScreentapError;
+    use crate::video;
+    use chrono::{Duration, NaiveDate};
+    use rusqlite::params;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    /**
+     * Returns the same PNG for every frame, or fails if there is none
+     */
+    struct StubFrameExtractor {
+        png_data: Option<Vec<u8>>,
+    }
+
+    impl video::FrameExtractor for StubFrameExtractor {
+        fn extract_frame(&self, _mp4_path: &Path, _frame_id: i32) -> Result<Vec<u8>, ScreentapError> {
+            self.png_data.clone().ok_or_else(|| ScreentapError::Encode("not supported".to_string()))
+        }
+        fn count_frames(&self, _mp4_path: &Path) -> Result<usize, ScreentapError> {
+            Ok(0)
+        }
+    }
+
+    /**
+     * Two 100 byte segments of two screenshots each, on consecutive days
+     */
+    fn create_segments(data_root: &Path, db_filename: &Path) {
+
+        db::create_db(data_root, db_filename).unwrap();
+        let conn = db::get_db_conn(data_root, db_filename).unwrap();
+
+        for day in 1..=2 {
+            let mp4_file_path = format!("media/2024/03/0{}/segment.mp4", day);
+            std::fs::create_dir_all(data_root.join(&mp4_file_path).parent().unwrap()).unwrap();
+            std::fs::write(data_root.join(&mp4_file_path), vec![0u8; 100]).unwrap();
+
+            let start = NaiveDate::from_ymd_opt(2024, 3, day).unwrap().and_hms_opt(10, 0, 0).unwrap();
+            for frame_id in 0..2 {
+                let png_path = data_root.join(format!("{}_{}.png", day, frame_id));
+                let id = db::save_screenshot_meta(&png_path, &format!("day{}", day), data_root, db_filename, start + Duration::seconds(frame_id), &db::CaptureMeta::default()).unwrap();
+                conn.execute(
+                    "UPDATE documents SET mp4_file_path = ?1, mp4_frame_id = ?2 WHERE id = ?3",
+                    params![mp4_file_path, frame_id, id],
+                ).unwrap();
+            }
+            conn.execute(
+                "INSERT INTO segments (mp4_file_path, start_timestamp, end_timestamp, frame_count, size_bytes, created_at) VALUES (?1, ?2, ?3, 2, 100, ?4)",
+                params![mp4_file_path, start.timestamp(), start.timestamp() + 1, start],
+            ).unwrap();
+        }
+    }
+
+    /**
+     * Pin a screenshot without unpacking it, like one that was compacted while it was being tagged
+     */
+    fn pin_in_place(data_root: &Path, db_filename: &Path, document_id: i64) {
+        let conn = db::get_db_conn(data_root, db_filename).unwrap();
+        conn.execute("INSERT OR IGNORE INTO tags (name) VALUES ('keep')", []).unwrap();
+        conn.execute(
+            "INSERT INTO document_tags (document_id, tag_id, created_at) SELECT ?1, id, 0 FROM tags WHERE name = 'keep'",
+            params![document_id],
+        ).unwrap();
+    }
+
+    #[test]
+    fn test_delete_oldest_segments() {
+
+        let tmp_dir = tempdir().unwrap();
+        let data_root = tmp_dir.path();
+        let db_filename = Path::new("test.db");
+        create_segments(data_root, db_filename);
+
+        let frame_extractor = video::create_frame_extractor(&ScreentapConfig::default());
+        let report = delete_oldest_segments(data_root, db_filename, 50, frame_extractor.as_ref()).unwrap();
+        assert_eq!((report.segments_deleted, report.screenshots_deleted, report.bytes_freed), (1, 2, 100));
+        assert!(!data_root.join("media/2024/03/01/segment.mp4").exists());
+        assert!(data_root.join("media/2024/03/02/segment.mp4").exists());
+
+        let conn = db::get_db_conn(data_root, db_filename).unwrap();
+        let count = |sql: &str| conn.query_row(sql, [], |row| row.get::<_, i64>(0)).unwrap();
+        assert_eq!(count("SELECT COUNT(*) FROM documents"), 2);
+        assert_eq!(count("SELECT COUNT(*) FROM segments"), 1);
+        assert_eq!(count("SELECT COUNT(*) FROM ocr_text_index WHERE ocr_text MATCH 'day1'"), 0);
+        assert_eq!(count("SELECT COUNT(*) FROM ocr_text_index WHERE ocr_text MATCH 'day2'"), 2);
+
+        // Stops once there is nothing left to delete
+        let report = delete_oldest_segments(data_root, db_filename, 1000, frame_extractor.as_ref()).unwrap();
+        assert_eq!(report.segments_deleted, 1);
+        assert_eq!(count("SELECT COUNT(*) FROM documents"), 0);
+    }
+
+    #[test]
+    fn test_delete_oldest_segments_keeps_pinned_screenshots() {
+
+        let tmp_dir = tempdir().unwrap();
+        let data_root = tmp_dir.path();
+        let db_filename = Path::new("test.db");
+        create_segments(data_root, db_filename);
+        pin_in_place(data_root, db_filename, 1);
+
+        let png_data = b"not really a png".to_vec();
+        let frame_extractor = StubFrameExtractor { png_data: Some(png_data.clone()) };
+        let report = delete_oldest_segments(data_root, db_filename, 50, &frame_extractor).unwrap();
+        assert_eq!((report.segments_deleted, report.segments_skipped, report.screenshots_deleted), (1, 0, 1));
+        assert!(!data_root.join("media/2024/03/01/segment.mp4").exists());
+
+        // The pinned screenshot was unpacked into a loose PNG before its segment was deleted
+        let screenshot = db::find_screenshots_by_ids(data_root, db_filename, &[1]).unwrap().remove(0);
+        assert!(screenshot.get_mp4_file_path().is_empty());
+        assert_eq!(std::fs::read(screenshot.get_file_path()).unwrap(), png_data);
+    }
+
+    #[test]
+    fn test_delete_oldest_segments_skips_segments_it_cannot_unpack() {
+
+        let tmp_dir = tempdir().unwrap();
+        let data_root = tmp_dir.path();
+        let db_filename = Path::new("test.db");
+        create_segments(data_root, db_filename);
+        pin_in_place(data_root, db_filename, 1);
+
+        // The first segment is kept with its pinned screenshot, and the second one deleted instead
+        let frame_extractor = StubFrameExtractor { png_data: None };
+        let report = delete_oldest_segments(data_root, db_filename, 1000, &frame_extractor).unwrap();
+        assert_eq!((report.segments_deleted, report.segments_skipped, report.screenshots_deleted), (1, 1, 2));
+        assert!(data_root.join("media/2024/03/01/segment.mp4").exists());
+        assert!(!data_root.join("media/2024/03/02/segment.mp4").exists());
+
+        let screenshot = db::find_screenshots_by_ids(data_root, db_filename, &[1]).unwrap().remove(0);
+        assert!(screenshot.get_mp4_file_path().ends_with("media/2024/03/01/segment.mp4"));
+    }
+
+}
@@ -0,0 +1,334 @@
+use std::path::Path;
+use rusqlite::{params, OptionalExtension};
+use serde::Serialize;
+use tauri::api::notification::Notification;
+use tauri::Manager;
+
+use crate::db;
+use crate::error::ScreentapError;
+use crate::search::{self, SearchMode, SearchQuery};
+
+
+/**
+ * Emitted to the frontend with an Alert when a watched search matches a new screenshot
+ */
+pub static WATCH_ALERT_EVENT: &str = "watch-alert-event";
+
+/**
+ * A watch that matched a screenshot this recently doesn't alert again, so text that stays on
+ * screen alerts once rather than with every capture
+ */
+static WATCH_REARM_SECS: i64 = 300;
+
+
+/**
+ * A search saved by name, so it can be run again from the UI or the CLI.  Watched searches are
+ * also checked against each new screenshot, see evaluate_watches().
+ */
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SavedSearch {
+    pub id: i64,
+    pub name: String,
+    pub term: String,
+
+    // See search::SearchMode::as_str()
+    pub mode: String,
+
+    // Only search screenshots of this display, or all displays if None
+    pub display_id: Option<u32>,
+
+    pub watch: bool,
+    pub created_at: i64,
+
+    // The timestamp of the last screenshot the watch matched, whether or not it alerted
+    pub last_matched_at: Option<i64>,
+}
+
+impl SavedSearch {
+
+    pub fn search_mode(&self) -> SearchMode {
+        SearchMode::parse(&self.mode).unwrap_or_default()
+    }
+
+    pub fn query(&self, limit: i32) -> SearchQuery<'_> {
+        SearchQuery { display_id: self.display_id, ..SearchQuery::new(&self.term, self.search_mode(), limit) }
+    }
+
+}
+
+/**
+ * A new match of a watched search, recorded in the alerts table
+ */
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Alert {
+    pub id: i64,
+    pub saved_search_id: i64,
+    pub saved_search_name: String,
+    pub term: String,
+
+    // The screenshot that matched.  Alerts are kept when the screenshot is deleted.
+    pub document_id: i64,
+    pub timestamp: i64,
+}
+
+
+static SAVED_SEARCH_COLUMNS: &str = "id, name, term, mode, display_id, watch, created_at, last_matched_at";
+
+fn row_to_saved_search(row: &rusqlite::Row) -> rusqlite::Result<SavedSearch> {
+    Ok(SavedSearch {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        term: row.get(2)?,
+        mode: row.get(3)?,
+        display_id: row.get(4)?,
+        watch: row.get(5)?,
+        created_at: row.get(6)?,
+        last_matched_at: row.get(7)?,
+    })
+}
+
+/**
+ * Save the term, mode and display of a search, or update the saved search with the same name.
+ * Returns its id.
+ *
+ * Watches of semantic and hybrid searches alert on exact matches, see search::screenshot_matches(),
+ * so the term of every search with an exact part must be valid search syntax.  Otherwise each
+ * new screenshot would fail to match it.
+ */
+pub fn save_search(dataset_root: &Path, db_filename: &Path, name: &str, query: &SearchQuery, watch: bool, now: i64) -> Result<i64, ScreentapError> {
+
+    if name.trim().is_empty() || query.term.trim().is_empty() {
+        return Err(ScreentapError::InvalidInput("Saved searches need a name and a search term".to_string()));
+    }
+
+    let needs_match_syntax = matches!(query.mode, SearchMode::Exact | SearchMode::Semantic | SearchMode::Hybrid);
+    if needs_match_syntax && !db::is_valid_match_query(query.term, dataset_root, db_filename)? {
+        return Err(ScreentapError::InvalidInput(format!(
+            "{} is not valid search syntax.  Put it in double quotes to search for it as is, or save it as a fuzzy search.",
+            query.term
+        )));
+    }
+
+    let conn = db::get_db_conn(dataset_root, db_filename)?;
+
+    conn.execute(
+        "INSERT INTO saved_searches (name, term, mode, display_id, watch, created_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+            ON CONFLICT (name) DO UPDATE SET term = excluded.term, mode = excluded.mode, display_id = excluded.display_id, watch = excluded.watch",
+        params![name.trim(), query.term, query.mode.as_str(), query.display_id, watch, now],
+    )?;
+
+    // Not RETURNING, which needs SQLite 3.35, and last_insert_rowid() isn't set when the upsert updates
+    let id = conn.query_row("SELECT id FROM saved_searches WHERE name = ?1", params![name.trim()], |row| row.get(0))?;
+
+    Ok(id)
+
+}
+
+pub fn list_saved_searches(dataset_root: &Path, db_filename: &Path) -> Result<Vec<SavedSearch>, ScreentapError> {
+
+    let conn = db::get_db_conn(dataset_root, db_filename)?;
+
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM saved_searches ORDER BY name", SAVED_SEARCH_COLUMNS))?;
+    let saved_searches = stmt.query_map([], row_to_saved_search)?.collect::<Result<Vec<_>, _>>()?;
+
+    Ok(saved_searches)
+
+}
+
+/**
+ * Get a saved search by id, or by name if id_or_name isn't a number
+ */
+pub fn get_saved_search(dataset_root: &Path, db_filename: &Path, id_or_name: &str) -> Result<Option<SavedSearch>, ScreentapError> {
+
+    let conn = db::get_db_conn(dataset_root, db_filename)?;
+
+    let saved_search = match id_or_name.parse::<i64>() {
+        Ok(id) => conn.query_row(&format!("SELECT {} FROM saved_searches WHERE id = ?1", SAVED_SEARCH_COLUMNS), params![id], row_to_saved_search),
+        Err(_) => conn.query_row(&format!("SELECT {} FROM saved_searches WHERE name = ?1", SAVED_SEARCH_COLUMNS), params![id_or_name], row_to_saved_search),
+    }.optional()?;
+
+    Ok(saved_search)
+
+}
+
+/**
+ * Delete a saved search and its alerts.  Returns false if there was no such saved search.
+ */
+pub fn delete_saved_search(dataset_root: &Path, db_filename: &Path, id: i64) -> Result<bool, ScreentapError> {
+    let conn = db::get_db_conn(dataset_root, db_filename)?;
+    Ok(conn.execute("DELETE FROM saved_searches WHERE id = ?1", params![id])? > 0)
+}
+
+/**
+ * Turn watching a saved search on or off.  Returns false if there was no such saved search.
+ */
+pub fn set_watch(dataset_root: &Path, db_filename: &Path, id: i64, watch: bool) -> Result<bool, ScreentapError> {
+    let conn = db::get_db_conn(dataset_root, db_filename)?;
+    Ok(conn.execute("UPDATE saved_searches SET watch = ?1 WHERE id = ?2", params![watch, id])? > 0)
+}
+
+/**
+ * Check a new screenshot against the watched searches, and record an alert for each watch it
+ * matches that hasn't matched in the last WATCH_REARM_SECS.  Returns the new alerts.
+ *
+ * A watch that fails to match, eg because its term is invalid FTS5 syntax, is skipped.
+ */
+pub fn evaluate_watches(dataset_root: &Path, db_filename: &Path, document_id: i64, display_id: u32, ocr_text: &str, timestamp: i64) -> Result<Vec<Alert>, ScreentapError> {
+
+    let watches: Vec<SavedSearch> = list_saved_searches(dataset_root, db_filename)?
+        .into_iter()
+        .filter(|saved_search| saved_search.watch && saved_search.display_id.unwrap_or(display_id) == display_id)
+        .collect();
+
+    if watches.is_empty() || ocr_text.trim().is_empty() {
+        return Ok(vec![]);
+    }
+
+    let conn = db::get_db_conn(dataset_root, db_filename)?;
+
+    let mut alerts = vec![];
+    for watch in watches {
+
+        match search::screenshot_matches(&watch.term, watch.search_mode(), document_id, ocr_text, dataset_root, db_filename) {
+            Ok(true) => {},
+            Ok(false) => continue,
+            Err(e) => {
+                println!("Error checking watched search {}: {}", watch.name, e);
+                continue;
+            }
+        }
+
+        conn.execute("UPDATE saved_searches SET last_matched_at = ?1 WHERE id = ?2", params![timestamp, watch.id])?;
+
+        if let Some(last_matched_at) = watch.last_matched_at {
+            if timestamp - last_matched_at < WATCH_REARM_SECS {
+                continue;
+            }
+        }
+
+        conn.execute(
+            "INSERT INTO alerts (saved_search_id, document_id, timestamp) VALUES (?1, ?2, ?3)",
+            params![watch.id, document_id, timestamp],
+        )?;
+        alerts.push(Alert {
+            id: conn.last_insert_rowid(),
+            saved_search_id: watch.id,
+            saved_search_name: watch.name,
+            term: watch.term,
+            document_id,
+            timestamp,
+        });
+    }
+
+    Ok(alerts)
+
+}
+
+/**
+ * The most recent alerts, newest first
+ */
+pub fn list_alerts(dataset_root: &Path, db_filename: &Path, limit: i32) -> Result<Vec<Alert>, ScreentapError> {
+
+    let conn = db::get_db_conn(dataset_root, db_filename)?;
+
+    let mut stmt = conn.prepare(
+        "SELECT a.id, a.saved_search_id, s.name, s.term, a.document_id, a.timestamp FROM alerts a
+            JOIN saved_searches s ON s.id = a.saved_search_id
+            ORDER BY a.timestamp DESC, a.id DESC
+            LIMIT ?1"
+    )?;
+    let alerts = stmt.query_map(params![limit], |row| {
+        Ok(Alert {
+            id: row.get(0)?,
+            saved_search_id: row.get(1)?,
+            saved_search_name: row.get(2)?,
+            term: row.get(3)?,
+            document_id: row.get(4)?,
+            timestamp: row.get(5)?,
+        })
+    })?
+    .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(alerts)
+
+}
+
+/**
+ * Tell the frontend about a new alert, and show a desktop notification
+ */
+pub fn notify_alert(app_handle: &tauri::AppHandle, alert: &Alert) {
+
+    if let Err(e) = app_handle.emit_all(WATCH_ALERT_EVENT, alert) {
+        eprintln!("Error emitting event {}: {}", WATCH_ALERT_EVENT, e);
+    }
+
+    let notification = Notification::new(&app_handle.config().tauri.bundle.identifier)
+        .title(format!("Screentap: {}", alert.saved_search_name))
+        .body(format!("\"{}\" appeared on screen", alert.term));
+    if let Err(e) = notification.show() {
+        eprintln!("Error showing notification for watched search {}: {}", alert.saved_search_name, e);
+    }
+
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use super::{delete_saved_search, evaluate_watches, get_saved_search, list_alerts, list_saved_searches, save_search, set_watch};
+    use crate::db;
+    use crate::error::ScreentapError;
+    use crate::search::{SearchMode, SearchQuery};
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_saved_searches_and_watches() {
+
+        let tmp_dir = tempdir().unwrap();
+        let data_root = tmp_dir.path();
+        let db_filename = Path::new("test.db");
+        db::create_db(data_root, db_filename).unwrap();
+
+        let ticket_id = save_search(data_root, db_filename, "Ticket", &SearchQuery::new("ACME1234", SearchMode::Exact, 0), false, 0).unwrap();
+        let customer_id = save_search(data_root, db_filename, "Customer", &SearchQuery::new("initech", SearchMode::Fuzzy, 0), true, 0).unwrap();
+
+        // Saving with an existing name updates the saved search
+        assert_eq!(save_search(data_root, db_filename, "Ticket", &SearchQuery::new("ACME 1234", SearchMode::Exact, 0), false, 0).unwrap(), ticket_id);
+        assert_eq!(get_saved_search(data_root, db_filename, "Ticket").unwrap().unwrap().term, "ACME 1234");
+        assert_eq!(get_saved_search(data_root, db_filename, &customer_id.to_string()).unwrap().unwrap().search_mode(), SearchMode::Fuzzy);
+        assert!(matches!(save_search(data_root, db_filename, " ", &SearchQuery::new("term", SearchMode::Exact, 0), false, 0), Err(ScreentapError::InvalidInput(_))));
+
+        // Terms that every exact match would fail on are refused, unless they're only matched fuzzily
+        assert!(matches!(save_search(data_root, db_filename, "Draft", &SearchQuery::new("ACME (draft", SearchMode::Hybrid, 0), true, 0), Err(ScreentapError::InvalidInput(_))));
+        assert!(save_search(data_root, db_filename, "Draft", &SearchQuery::new("\"ACME (draft\"", SearchMode::Exact, 0), false, 0).is_ok());
+        assert!(save_search(data_root, db_filename, "Draft", &SearchQuery::new("ACME (draft", SearchMode::Fuzzy, 0), false, 0).is_ok());
+        assert!(delete_saved_search(data_root, db_filename, get_saved_search(data_root, db_filename, "Draft").unwrap().unwrap().id).unwrap());
+
+        let capture = |seconds: i64, ocr_text: &str| {
+            let document_id = db::insert_test_screenshots(data_root, db_filename, &[(seconds, ocr_text)])[0];
+            evaluate_watches(data_root, db_filename, document_id, 0, ocr_text, db::test_capture_time(seconds).timestamp()).unwrap()
+        };
+
+        // Only watched searches alert, and only once while the match stays on screen
+        assert_eq!(capture(0, "Invoice for ACME 1234").len(), 0);
+        assert_eq!(capture(30, "Call with In1tech tomorrow").len(), 1);
+        assert_eq!(capture(60, "Call with In1tech tomorrow").len(), 0);
+        assert_eq!(capture(300, "Initech contract").len(), 0);
+        assert_eq!(capture(1000, "Initech contract").len(), 1);
+
+        set_watch(data_root, db_filename, ticket_id, true).unwrap();
+        let alerts = capture(1030, "Reply to ACME 1234");
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].saved_search_name, "Ticket");
+
+        let alerts = list_alerts(data_root, db_filename, 10).unwrap();
+        assert_eq!(alerts.iter().map(|alert| alert.document_id).collect::<Vec<_>>(), vec![6, 5, 2]);
+
+        // Deleting a saved search deletes its alerts
+        assert!(delete_saved_search(data_root, db_filename, customer_id).unwrap());
+        assert_eq!(list_alerts(data_root, db_filename, 10).unwrap().len(), 1);
+        assert_eq!(list_saved_searches(data_root, db_filename).unwrap().len(), 1);
+    }
+
+}
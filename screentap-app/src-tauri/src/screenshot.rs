@@ -1,61 +1,306 @@
-extern crate screen_ocr_swift_rs;
-
 use chrono::Local;
+use chrono::NaiveDateTime;
+use std::io::Cursor;
 use std::path::Path;
 use std::path::PathBuf;
 
 use super::utils;
 use super::db;
-use std::error::Error;
+use super::db::CaptureMeta;
+use super::config::{CaptureMode, ScreentapConfig};
+use super::error::ScreentapError;
+use super::frame_format::{self, FrameFormat};
+use super::native::{self, DisplayInfo, WindowRect};
+use super::ocr_layout::{self, OcrLayout};
+use super::storage::StorageLayout;
+
 
+/**
+ * A screenshot that has been captured and written to the incoming dir, but not yet OCR'd or saved to the DB.
+ * The file is in the configured frame format, while png_data is always the PNG as captured.
+ */
+pub struct CapturedFrame {
+    pub png_data: Vec<u8>,
+    pub incoming_image_path: PathBuf,
+    pub capture_meta: CaptureMeta,
+    pub now: NaiveDateTime,
+}
 
 pub struct ScreenshotSaveResult {
     pub png_data: Vec<u8>,
     pub ocr_text: String,
-    pub png_image_path: PathBuf,
+    pub image_path: PathBuf,
     pub screenshot_id: i64,
+    pub capture_meta: CaptureMeta,
 }
 
 /**
- * Helper function to save a screenshot and OCR text to the dataset directory and DB
- * 
- * Return a Result with a generic Error, or a ScreenshotSaveResult
+ * Helper function to get the displays to capture.  If capture_displays is empty, all
+ * connected displays are returned, otherwise only the connected displays in capture_displays.
  */
-pub fn save_screenshot(dataset_root: &Path, db_filename: &Path) -> Result<ScreenshotSaveResult, Box<dyn Error>> {
+pub fn get_displays_to_capture(capture_displays: &[u32]) -> Vec<DisplayInfo> {
+    native::get_displays()
+        .into_iter()
+        .filter(|display| capture_displays.is_empty() || capture_displays.contains(&display.display_id))
+        .collect()
+}
+
+/**
+ * Helper function to capture a screenshot of each display into the incoming dir
+ *
+ * In active window mode, only the displays showing part of the frontmost window are captured,
+ * and each capture is cropped to the window.  If the frontmost window can't be found, it falls
+ * back to full screen captures.
+ *
+ * Returns one Result per display, since a failure on one display shouldn't lose the others
+ */
+pub fn capture_frames(incoming_dir: &Path, config: &ScreentapConfig, frontmost_app: &str) -> Vec<Result<CapturedFrame, ScreentapError>> {
 
     let now = Local::now().naive_utc();
 
-    let timestamp_png_filename = utils::generate_filename(now, "png");
-    let dataset_root_path = Path::new(dataset_root);
-    let target_png_file_path = dataset_root_path.join(timestamp_png_filename.clone());
+    let displays = get_displays_to_capture(&config.capture_displays);
+    if displays.is_empty() {
+        return vec![Err(ScreentapError::Capture("No displays to capture".to_string()))];
+    }
+
+    let window_bounds = match config.capture_mode_for_app(frontmost_app) {
+        CaptureMode::FullScreen => None,
+        CaptureMode::ActiveWindow => {
+            let window_bounds = native::get_frontmost_window_bounds();
+            if window_bounds.is_none() {
+                println!("Could not get frontmost window bounds for {}, capturing full screen", frontmost_app);
+            }
+            window_bounds
+        },
+    };
+
+    let capture_metas: Vec<CaptureMeta> = displays.into_iter().filter_map(|display| {
+        let window_rect = match window_bounds {
+            Some(ref window_bounds) => {
+                // Skip displays that aren't showing any part of the window
+                Some(window_bounds.intersect(&WindowRect::from(&display))?)
+            },
+            None => None,
+        };
+        Some(CaptureMeta {
+            display,
+            window_rect,
+            frontmost_app: frontmost_app.to_string(),
+        })
+    }).collect();
+
+    capture_metas.into_iter().map(|capture_meta| {
+        capture_frame(incoming_dir, capture_meta, now, config.frame_format, config.frame_quality)
+    }).collect()
+
+}
+
+/**
+ * Helper function to capture a screenshot of a single display and write it to the incoming dir
+ * in the given frame format
+ */
+pub fn capture_frame(incoming_dir: &Path, capture_meta: CaptureMeta, now: NaiveDateTime, frame_format: FrameFormat, frame_quality: u8) -> Result<CapturedFrame, ScreentapError> {
+
+    let display = &capture_meta.display;
 
-    let png_data = screen_ocr_swift_rs::screen_capture_to_file(target_png_file_path.to_str().unwrap());
-    let ocr_text = screen_ocr_swift_rs::extract_text(target_png_file_path.to_str().unwrap());
+    let timestamp_filename = utils::generate_display_filename(now, display.display_id, frame_format.extension());
+    let incoming_image_path = incoming_dir.join(timestamp_filename);
 
-    // Save screenshot meta to the DB
+    let mut png_data = match native::screen_capture_display(display.display_id) {
+        Some(png_data) => png_data,
+        None => return Err(ScreentapError::Capture(format!("Failed to capture display {}", display.display_id))),
+    };
+
+    // Crop to the window so that the OCR only sees the window contents
+    if let Some(ref window_rect) = capture_meta.window_rect {
+        png_data = crop_to_window(&png_data, display, window_rect)?;
+    }
+
+    std::fs::write(&incoming_image_path, frame_format::encode_frame(&png_data, frame_format, frame_quality)?)?;
+
+    Ok(CapturedFrame {
+        png_data,
+        incoming_image_path,
+        capture_meta,
+        now,
+    })
+
+}
+
+/**
+ * Helper function to extract the lines and words of text from a captured frame using OCR.  This
+ * reads the PNG in memory rather than the file, which may be in a lossy frame format.
+ */
+pub fn ocr_frame(frame: &CapturedFrame) -> Result<OcrLayout, ScreentapError> {
+    native::extract_layout_from_image_data(&frame.png_data)
+        .map(OcrLayout::from)
+        .ok_or_else(|| ScreentapError::Ocr(format!("No OCR result for {}", frame.incoming_image_path.display())))
+}
+
+/**
+ * Helper function to move a captured frame from the incoming dir to its media dir in the
+ * dataset directory and save it to the DB along with its OCR text and layout.  The OCR text
+ * is the layout's lines in reading order.
+ *
+ * Return a Result with a ScreentapError, or a ScreenshotSaveResult
+ */
+pub fn persist_frame(dataset_root: &Path, db_filename: &Path, frame: CapturedFrame, ocr_layout: &OcrLayout) -> Result<ScreenshotSaveResult, ScreentapError> {
+
+    let CapturedFrame { png_data, incoming_image_path, capture_meta, now } = frame;
+    let ocr_text = ocr_layout.text();
+
+    let image_filename = incoming_image_path.file_name()
+        .and_then(|image_filename| image_filename.to_str())
+        .ok_or_else(|| ScreentapError::Capture(format!("Invalid incoming image path {}", incoming_image_path.display())))?;
+    let target_image_file_path = StorageLayout::new(dataset_root).create_media_path(now, image_filename)?;
+    std::fs::rename(&incoming_image_path, &target_image_file_path)?;
+
+    // Save screenshot meta to the DB.  The frame format is recorded from the file extension.
     let save_result = db::save_screenshot_meta(
-        target_png_file_path.as_path(), 
-        ocr_text.to_string().as_str(),
+        target_image_file_path.as_path(),
+        ocr_text.as_str(),
         dataset_root,
         db_filename,
-        now
+        now,
+        &capture_meta
     );
 
     let current_time_formatted = now.format("%Y-%m-%d %H:%M:%S").to_string();
     match save_result {
-        Ok(screenshot_id) => { 
-            format!("Screenshot #{} saved to DB successfully at {}", screenshot_id, current_time_formatted); 
+        Ok(screenshot_id) => {
+            println!("Screenshot #{} saved to DB successfully at {}", screenshot_id, current_time_formatted);
+
+            // The screenshot is still searchable without its layout, so this isn't fatal
+            if let Err(e) = ocr_layout::save_ocr_layout(dataset_root, db_filename, screenshot_id, ocr_layout) {
+                println!("Error saving the OCR layout of screenshot #{}: {}", screenshot_id, e);
+            }
+
             Ok(ScreenshotSaveResult {
                 png_data,
                 ocr_text,
-                png_image_path: target_png_file_path,
-                screenshot_id
+                image_path: target_image_file_path,
+                screenshot_id,
+                capture_meta,
             })
         },
-        Err(e) => { 
-            format!("Error occurred: {} at {}", e, current_time_formatted); 
-            Err(e.into())
+        Err(e) => {
+            println!("Error occurred: {} at {}", e, current_time_formatted);
+
+            // Without a DB row nothing references the image, so it would never be compacted or deleted
+            if let Err(remove_error) = std::fs::remove_file(&target_image_file_path) {
+                println!("Error removing {} after the failed save: {}", target_image_file_path.display(), remove_error);
+            }
+            Err(e)
         }
     }
-    
+
+}
+
+/**
+ * Crop a display capture to the part of a window on that display.  The window rect is
+ * in points, while the capture is in pixels, so the rect is scaled by the display's
+ * backing scale factor (eg 2x on Retina displays).
+ */
+fn crop_to_window(png_data: &[u8], display: &DisplayInfo, window_rect: &WindowRect) -> Result<Vec<u8>, ScreentapError> {
+
+    let img = image::load_from_memory(png_data)?;
+
+    let (x, y, width, height) = window_rect_to_pixels(window_rect, display, img.width(), img.height())
+        .ok_or_else(|| ScreentapError::Capture("Window is not visible on display".to_string()))?;
+
+    let cropped = img.crop_imm(x, y, width, height);
+
+    let mut cropped_png_data = Cursor::new(Vec::new());
+    cropped.write_to(&mut cropped_png_data, image::ImageOutputFormat::Png)?;
+
+    Ok(cropped_png_data.into_inner())
+
+}
+
+/**
+ * Convert a window rect in global display coordinates (points) into a pixel rect
+ * (x, y, width, height) relative to a capture of the given display
+ */
+fn window_rect_to_pixels(window_rect: &WindowRect, display: &DisplayInfo, image_width: u32, image_height: u32) -> Option<(u32, u32, u32, u32)> {
+
+    if display.width <= 0 || display.height <= 0 {
+        return None;
+    }
+
+    let visible_rect = window_rect.intersect(&WindowRect::from(display))?;
+
+    let scale_x = image_width as f64 / display.width as f64;
+    let scale_y = image_height as f64 / display.height as f64;
+
+    let x = (((visible_rect.x - display.x) as f64 * scale_x).round() as u32).min(image_width);
+    let y = (((visible_rect.y - display.y) as f64 * scale_y).round() as u32).min(image_height);
+    let width = ((visible_rect.width as f64 * scale_x).round() as u32).min(image_width - x);
+    let height = ((visible_rect.height as f64 * scale_y).round() as u32).min(image_height - y);
+
+    if width == 0 || height == 0 {
+        return None;
+    }
+
+    Some((x, y, width, height))
+
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use super::{persist_frame, window_rect_to_pixels, CapturedFrame};
+    use crate::db::CaptureMeta;
+    use crate::native::{DisplayInfo, WindowRect};
+    use crate::ocr_layout::OcrLayout;
+    use crate::storage::StorageLayout;
+    use chrono::NaiveDate;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    fn secondary_retina_display() -> DisplayInfo {
+        DisplayInfo { display_id: 2, x: 1440, y: 0, width: 1440, height: 900, is_main: false }
+    }
+
+    #[test]
+    fn test_window_rect_to_pixels_scales_to_retina() {
+        let window = WindowRect { x: 1540, y: 100, width: 400, height: 300 };
+        let pixels = window_rect_to_pixels(&window, &secondary_retina_display(), 2880, 1800);
+        assert_eq!(pixels, Some((200, 200, 800, 600)));
+    }
+
+    #[test]
+    fn test_window_rect_to_pixels_clips_to_display() {
+        // The window straddles the main display and the secondary display
+        let window = WindowRect { x: 1240, y: 0, width: 400, height: 900 };
+        let pixels = window_rect_to_pixels(&window, &secondary_retina_display(), 2880, 1800);
+        assert_eq!(pixels, Some((0, 0, 400, 1800)));
+    }
+
+    #[test]
+    fn test_window_rect_to_pixels_window_on_other_display() {
+        let window = WindowRect { x: 0, y: 0, width: 400, height: 300 };
+        let pixels = window_rect_to_pixels(&window, &secondary_retina_display(), 2880, 1800);
+        assert_eq!(pixels, None);
+    }
+
+    #[test]
+    fn test_persist_frame_removes_image_when_db_save_fails() {
+
+        let tmp_dir = tempdir().unwrap();
+        let data_root = tmp_dir.path();
+        let now = NaiveDate::from_ymd_opt(2024, 3, 7).unwrap().and_hms_opt(10, 0, 0).unwrap();
+
+        let incoming_image_path = data_root.join("incoming.png");
+        std::fs::write(&incoming_image_path, b"png").unwrap();
+        let frame = CapturedFrame { png_data: b"png".to_vec(), incoming_image_path: incoming_image_path.clone(), capture_meta: CaptureMeta::default(), now };
+
+        // The DB was never created, so there is no documents table to insert into
+        assert!(persist_frame(data_root, Path::new("test.db"), frame, &OcrLayout::default()).is_err());
+
+        let target_image_path = StorageLayout::new(data_root).create_media_path(now, "incoming.png").unwrap();
+        assert!(!target_image_path.exists());
+        assert!(!incoming_image_path.exists());
+    }
+
 }
@@ -0,0 +1,619 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use chrono::Local;
+
+use crate::annotations;
+use crate::cluster::{self, ScreenshotCluster};
+use crate::db::{self, ScreenshotRecord};
+use crate::embeddings::{self, Embedder};
+use crate::error::ScreentapError;
+use crate::ranking::{self, RankingExplanation, RankingModel};
+use crate::video::FrameExtractor;
+
+
+// How many trigram index candidates are scored for each fuzzy result
+static FUZZY_CANDIDATES_PER_RESULT: i32 = 8;
+
+// The fraction of the query's trigrams a screenshot's OCR text must contain to be a fuzzy hit
+static FUZZY_MIN_SIMILARITY: f64 = 0.7;
+
+// Caps the size of the trigram index query for long search terms
+static FUZZY_MAX_QUERY_TRIGRAMS: usize = 64;
+
+// How many hits of each search are merged for each hybrid result
+static HYBRID_CANDIDATES_PER_RESULT: i32 = 4;
+
+// How many hits are clustered for each cluster returned, see search_screenshot_clusters()
+static CLUSTER_CANDIDATES_PER_RESULT: i32 = 8;
+
+// How many hits are checked for the tag for each result, when searching within a tag
+static TAG_CANDIDATES_PER_RESULT: i32 = 16;
+
+// The k of reciprocal rank fusion, which damps the weight of the top few hits of each search
+static RRF_K: f64 = 60.0;
+
+
+/**
+ * How search terms are matched against the OCR text of screenshots
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SearchMode {
+
+    // Whole words, using the FTS5 query syntax of the OCR text index
+    #[default]
+    Exact,
+
+    // Substrings that survive OCR noise, eg "screentap" finds "scre entap" and "scr33ntap"
+    Fuzzy,
+
+    // Exact hits first, followed by fuzzy hits that the exact search missed
+    Blended,
+
+    // Screenshots whose text means something similar, see embeddings::Embedder
+    Semantic,
+
+    // Exact and semantic hits, merged by reciprocal rank fusion
+    Hybrid,
+}
+
+impl SearchMode {
+
+    pub fn parse(mode: &str) -> Option<SearchMode> {
+        match mode {
+            "exact" => Some(SearchMode::Exact),
+            "fuzzy" => Some(SearchMode::Fuzzy),
+            "blended" => Some(SearchMode::Blended),
+            "semantic" => Some(SearchMode::Semantic),
+            "hybrid" => Some(SearchMode::Hybrid),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SearchMode::Exact => "exact",
+            SearchMode::Fuzzy => "fuzzy",
+            SearchMode::Blended => "blended",
+            SearchMode::Semantic => "semantic",
+            SearchMode::Hybrid => "hybrid",
+        }
+    }
+
+}
+
+/**
+ * What to search for, and which screenshots to search
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct SearchQuery<'a> {
+    pub term: &'a str,
+    pub mode: SearchMode,
+
+    // Only search the screenshots of this display
+    pub display_id: Option<u32>,
+
+    // Only search the screenshots with this tag.  Without a search term, all of them are hits.
+    pub tag: Option<&'a str>,
+
+    // The most hits, or clusters of hits, to return
+    pub limit: i32,
+}
+
+impl<'a> SearchQuery<'a> {
+
+    pub fn new(term: &'a str, mode: SearchMode, limit: i32) -> Self {
+        SearchQuery { term, mode, display_id: None, tag: None, limit }
+    }
+
+    fn with_limit(&self, limit: i32) -> Self {
+        SearchQuery { limit, ..*self }
+    }
+
+}
+
+/**
+ * The models that score hits.  The semantic and hybrid modes need an embedder, and fall back to
+ * exact search without one.  Exact hits are ordered by the ranking model.
+ */
+#[derive(Clone, Copy)]
+pub struct SearchContext<'a> {
+    pub embedder: Option<&'a dyn Embedder>,
+
+    // Semantic hits must be more similar to the term than this, see embeddings::find_similar_screenshots()
+    pub min_similarity: f32,
+
+    pub ranking: &'a RankingModel,
+}
+
+
+/**
+ * Search screenshots by their OCR text and notes, see SearchQuery
+ */
+pub fn search_screenshots(query: &SearchQuery, context: &SearchContext, dataset_root: &Path, db_filename: &Path, frame_extractor: &dyn FrameExtractor) -> Result<Vec<ScreenshotRecord>, ScreentapError> {
+
+    let mut screenshots = find_screenshots(query, context, dataset_root, db_filename)?;
+
+    db::load_base64_images(&mut screenshots, frame_extractor);
+
+    Ok(screenshots)
+
+}
+
+/**
+ * Like search_screenshots(), with near-identical hits grouped into clusters so that text that
+ * stayed on screen for a long time doesn't crowd out the other hits.  Returns up to limit
+ * clusters, with only the images of their representatives loaded.
+ */
+pub fn search_screenshot_clusters(query: &SearchQuery, context: &SearchContext, dataset_root: &Path, db_filename: &Path, frame_extractor: &dyn FrameExtractor) -> Result<Vec<ScreenshotCluster>, ScreentapError> {
+
+    let hits = find_screenshots(&query.with_limit(query.limit * CLUSTER_CANDIDATES_PER_RESULT), context, dataset_root, db_filename)?;
+
+    let mut clusters = cluster::cluster_screenshots(hits);
+    clusters.truncate(query.limit.max(0) as usize);
+
+    // The size and time span of a cluster come from all of the screenshots of its display, not
+    // just the hits.  With a tag, only the tagged hits are clustered.
+    if query.tag.is_none() {
+        for cluster in clusters.iter_mut() {
+            let display_id = cluster.representative.get_display_id();
+            cluster::extend_cluster(cluster, |start_timestamp, end_timestamp| {
+                db::find_screenshots_in_range(dataset_root, db_filename, start_timestamp, end_timestamp, Some(display_id))
+            })?;
+        }
+
+        // Extending can join clusters that were apart because the hits between them didn't make the cut
+        let mut clustered_ids = HashSet::new();
+        clusters.retain(|cluster| {
+            let is_new = !clustered_ids.contains(&(cluster.representative.get_id() as i64));
+            if is_new {
+                clustered_ids.extend(cluster.screenshot_ids.iter().copied());
+            }
+            is_new
+        });
+    }
+
+    // Load the images in one batch, so that frames from the same mp4 are extracted together
+    let mut representatives: Vec<ScreenshotRecord> = clusters.iter().map(|cluster| cluster.representative.clone()).collect();
+    db::load_base64_images(&mut representatives, frame_extractor);
+    for (cluster, representative) in clusters.iter_mut().zip(representatives) {
+        cluster.representative = representative;
+    }
+
+    Ok(clusters)
+
+}
+
+/**
+ * Exact search, with an explanation of the score of each hit.  See ranking::rank_hits().
+ */
+pub fn explain_search(term: &str, ranking: &RankingModel, dataset_root: &Path, db_filename: &Path, limit: i32, display_id: Option<u32>) -> Result<Vec<(ScreenshotRecord, RankingExplanation)>, ScreentapError> {
+
+    let candidates = db::find_screenshots_ocr_bm25(term, &ranking::BM25_COLUMN_WEIGHTS, dataset_root, db_filename, limit * ranking.candidates_per_result, display_id)?;
+
+    let mut ranked_hits = ranking::rank_hits(candidates, ranking, Local::now().naive_utc());
+    ranked_hits.truncate(limit.max(0) as usize);
+
+    Ok(ranked_hits)
+
+}
+
+fn find_exact_hits(term: &str, ranking: &RankingModel, dataset_root: &Path, db_filename: &Path, limit: i32, display_id: Option<u32>) -> Result<Vec<ScreenshotRecord>, ScreentapError> {
+    let ranked_hits = explain_search(term, ranking, dataset_root, db_filename, limit, display_id)?;
+    Ok(ranked_hits.into_iter().map(|(record, _)| record).collect())
+}
+
+/**
+ * Search screenshots without loading their images.  With a tag, only the hits with the tag are
+ * kept, and without a search term all screenshots with the tag are returned, newest first.
+ */
+pub fn find_screenshots(query: &SearchQuery, context: &SearchContext, dataset_root: &Path, db_filename: &Path) -> Result<Vec<ScreenshotRecord>, ScreentapError> {
+
+    let Some(tag) = query.tag else {
+        return find_untagged_screenshots(query, context, dataset_root, db_filename);
+    };
+
+    let tagged_ids = annotations::find_tagged_screenshot_ids(dataset_root, db_filename, tag)?;
+
+    let hits = if query.term.trim().is_empty() {
+        db::find_screenshots_by_ids(dataset_root, db_filename, &tagged_ids)?
+    } else {
+        let tagged_ids: HashSet<i64> = tagged_ids.into_iter().collect();
+        find_untagged_screenshots(&query.with_limit(query.limit * TAG_CANDIDATES_PER_RESULT), context, dataset_root, db_filename)?
+            .into_iter()
+            .filter(|record| tagged_ids.contains(&(record.get_id() as i64)))
+            .collect()
+    };
+
+    Ok(hits.into_iter()
+        .filter(|record| query.display_id.is_none() || query.display_id == Some(record.get_display_id()))
+        .take(query.limit.max(0) as usize)
+        .collect())
+
+}
+
+/**
+ * Search screenshots whatever their tags.  Screenshots with a note matching the term come
+ * first, since a note is what the screenshot was kept for.
+ */
+fn find_untagged_screenshots(query: &SearchQuery, context: &SearchContext, dataset_root: &Path, db_filename: &Path) -> Result<Vec<ScreenshotRecord>, ScreentapError> {
+
+    let SearchQuery { term, limit, display_id, .. } = *query;
+
+    // Notes are only matched exactly, so invalid FTS5 syntax only hides the note hits
+    let note_hits = match db::find_screenshots_notes(term, dataset_root, db_filename, limit, display_id) {
+        Ok(note_hits) => note_hits,
+        Err(e) => {
+            println!("Note search for {} failed, only showing OCR text hits: {}", term, e);
+            vec![]
+        }
+    };
+
+    let ocr_hits = find_screenshots_ocr(query, context, dataset_root, db_filename)?;
+
+    let mut seen_ids = HashSet::new();
+    Ok(note_hits.into_iter()
+        .chain(ocr_hits)
+        .filter(|record| seen_ids.insert(record.get_id()))
+        .take(limit.max(0) as usize)
+        .collect())
+
+}
+
+/**
+ * Search screenshots by their OCR text only
+ */
+fn find_screenshots_ocr(query: &SearchQuery, context: &SearchContext, dataset_root: &Path, db_filename: &Path) -> Result<Vec<ScreenshotRecord>, ScreentapError> {
+
+    let SearchQuery { term, mode, limit, display_id, .. } = *query;
+    let ranking = context.ranking;
+
+    if let SearchMode::Semantic | SearchMode::Hybrid = mode {
+        match context.embedder {
+            Some(embedder) => return search_screenshots_semantic(query, embedder, context, dataset_root, db_filename),
+            None => {
+                println!("Embeddings are disabled, falling back to exact search");
+                return find_exact_hits(term, ranking, dataset_root, db_filename, limit, display_id);
+            }
+        }
+    }
+
+    let match_query = get_trigram_match_query(term);
+
+    // Terms shorter than a trigram can only be matched exactly
+    if mode == SearchMode::Exact || match_query.is_empty() {
+        return find_exact_hits(term, ranking, dataset_root, db_filename, limit, display_id);
+    }
+
+    let exact_hits = if mode == SearchMode::Blended {
+        // OCR noise pasted into the search box is often invalid FTS5 syntax, which shouldn't
+        // hide the fuzzy hits
+        match find_exact_hits(term, ranking, dataset_root, db_filename, limit, display_id) {
+            Ok(exact_hits) => exact_hits,
+            Err(e) => {
+                println!("Exact search for {} failed, only showing fuzzy hits: {}", term, e);
+                vec![]
+            }
+        }
+    } else {
+        vec![]
+    };
+
+    let candidates = db::find_screenshots_trigram(&match_query, dataset_root, db_filename, limit * FUZZY_CANDIDATES_PER_RESULT, display_id)?;
+    let fuzzy_hits = rank_fuzzy_hits(term, candidates);
+
+    Ok(blend_hits(exact_hits, fuzzy_hits, limit as usize))
+
+}
+
+/**
+ * Does a single screenshot match the search term?  Used to check new screenshots against
+ * watched searches, see saved_searches::evaluate_watches().  Semantic and hybrid searches are
+ * matched like exact searches, since a single similarity score has nothing to rank against.
+ */
+pub fn screenshot_matches(term: &str, mode: SearchMode, document_id: i64, ocr_text: &str, dataset_root: &Path, db_filename: &Path) -> Result<bool, ScreentapError> {
+
+    let fuzzy_matches = || {
+        let term_trigrams = get_trigrams(&normalize_ocr_text(term));
+        get_similarity(&term_trigrams, &get_trigrams(&normalize_ocr_text(ocr_text))) >= FUZZY_MIN_SIMILARITY
+    };
+
+    match mode {
+        SearchMode::Fuzzy => Ok(fuzzy_matches()),
+        SearchMode::Blended => {
+            // Like blended search, invalid FTS5 syntax only rules out the exact match
+            match db::ocr_text_matches(term, document_id, dataset_root, db_filename) {
+                Ok(true) => Ok(true),
+                Ok(false) => Ok(fuzzy_matches()),
+                Err(e) => {
+                    println!("Exact match of {} failed, only matching fuzzily: {}", term, e);
+                    Ok(fuzzy_matches())
+                }
+            }
+        },
+        SearchMode::Exact | SearchMode::Semantic | SearchMode::Hybrid => db::ocr_text_matches(term, document_id, dataset_root, db_filename),
+    }
+
+}
+
+/**
+ * Semantic search, which is hybrid search if the mode is SearchMode::Hybrid
+ */
+fn search_screenshots_semantic(query: &SearchQuery, embedder: &dyn Embedder, context: &SearchContext, dataset_root: &Path, db_filename: &Path) -> Result<Vec<ScreenshotRecord>, ScreentapError> {
+
+    let SearchQuery { term, mode, limit, display_id, .. } = *query;
+
+    let candidate_limit = if mode == SearchMode::Hybrid { limit * HYBRID_CANDIDATES_PER_RESULT } else { limit };
+    let semantic_ids: Vec<i64> = embeddings::find_similar_screenshots(term, embedder, dataset_root, db_filename, candidate_limit, display_id, context.min_similarity)?
+        .into_iter()
+        .map(|(id, _)| id)
+        .collect();
+
+    let ids = if mode == SearchMode::Hybrid {
+        // Ordered by the ranking model, so the best keyword hits count the most
+        let exact_ids: Vec<i64> = match find_exact_hits(term, context.ranking, dataset_root, db_filename, candidate_limit, display_id) {
+            Ok(exact_hits) => exact_hits.iter().map(|record| record.get_id() as i64).collect(),
+            Err(e) => {
+                println!("Exact search for {} failed, only showing semantic hits: {}", term, e);
+                vec![]
+            }
+        };
+        reciprocal_rank_fusion(&[exact_ids, semantic_ids])
+    } else {
+        semantic_ids
+    };
+
+    let ids: Vec<i64> = ids.into_iter().take(limit.max(0) as usize).collect();
+    db::find_screenshots_by_ids(dataset_root, db_filename, &ids)
+
+}
+
+/**
+ * Merge ranked lists of ids by summing 1 / (RRF_K + rank) over the lists each id is in.  This
+ * only needs the ranks, so bm25 scores and cosine similarities don't have to be comparable.
+ */
+fn reciprocal_rank_fusion(ranked_ids: &[Vec<i64>]) -> Vec<i64> {
+
+    let mut scores: HashMap<i64, f64> = HashMap::new();
+    for ids in ranked_ids {
+        for (rank, id) in ids.iter().enumerate() {
+            *scores.entry(*id).or_insert(0.0) += 1.0 / (RRF_K + rank as f64 + 1.0);
+        }
+    }
+
+    // Ties go to the newest screenshot, which has the highest id
+    let mut ids: Vec<(i64, f64)> = scores.into_iter().collect();
+    ids.sort_by(|(a, a_score), (b, b_score)| b_score.total_cmp(a_score).then(b.cmp(a)));
+    ids.into_iter().map(|(id, _)| id).collect()
+
+}
+
+/**
+ * Exact hits keep their order and come first, since they're the most likely to be what was
+ * searched for.  Fuzzy hits are appended unless they were already an exact hit.
+ */
+fn blend_hits(exact_hits: Vec<ScreenshotRecord>, fuzzy_hits: Vec<(ScreenshotRecord, f64)>, limit: usize) -> Vec<ScreenshotRecord> {
+
+    let exact_ids: HashSet<i32> = exact_hits.iter().map(|record| record.get_id()).collect();
+
+    exact_hits.into_iter()
+        .chain(fuzzy_hits.into_iter().map(|(record, _)| record).filter(|record| !exact_ids.contains(&record.get_id())))
+        .take(limit)
+        .collect()
+
+}
+
+/**
+ * Score the trigram index candidates by how many of the term's trigrams their OCR text contains,
+ * after both are normalized for common OCR misreads.  Returns the hits with their similarity,
+ * most similar and then newest first.
+ */
+fn rank_fuzzy_hits(term: &str, candidates: Vec<ScreenshotRecord>) -> Vec<(ScreenshotRecord, f64)> {
+
+    let term_trigrams = get_trigrams(&normalize_ocr_text(term));
+
+    // The same OCR text is often captured many times in a row, so only score it once
+    let mut similarity_by_text: HashMap<String, f64> = HashMap::new();
+
+    let mut hits: Vec<(ScreenshotRecord, f64)> = candidates.into_iter()
+        .map(|record| {
+            let similarity = *similarity_by_text.entry(record.get_ocr_text().to_string()).or_insert_with(|| {
+                get_similarity(&term_trigrams, &get_trigrams(&normalize_ocr_text(record.get_ocr_text())))
+            });
+            (record, similarity)
+        })
+        .filter(|(_, similarity)| *similarity >= FUZZY_MIN_SIMILARITY)
+        .collect();
+
+    hits.sort_by(|(a, a_similarity), (b, b_similarity)| {
+        b_similarity.total_cmp(a_similarity).then(b.get_timestamp().cmp(&a.get_timestamp()))
+    });
+
+    hits
+
+}
+
+/**
+ * Build an FTS5 query for the trigram index that matches any of the term's trigrams.  Returns
+ * an empty string if the term has no trigrams.
+ */
+fn get_trigram_match_query(term: &str) -> String {
+
+    let mut seen = HashSet::new();
+    let mut quoted_trigrams = vec![];
+
+    for word in term.to_lowercase().split_whitespace() {
+        let chars: Vec<char> = word.chars().collect();
+        for window in chars.windows(3) {
+            let trigram: String = window.iter().collect();
+            if seen.insert(trigram.clone()) {
+                // Quoting makes FTS5 treat the trigram as a string, even if it has operators in it
+                quoted_trigrams.push(format!("\"{}\"", trigram.replace('"', "\"\"")));
+            }
+        }
+    }
+
+    quoted_trigrams.truncate(FUZZY_MAX_QUERY_TRIGRAMS);
+    quoted_trigrams.join(" OR ")
+
+}
+
+/**
+ * Lowercase the text, map characters that OCR often confuses to a single character, and drop
+ * whitespace and punctuation, since OCR often splits words or misreads them as punctuation
+ */
+fn normalize_ocr_text(text: &str) -> String {
+    text.to_lowercase()
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .map(|c| match c {
+            '0' => 'o',
+            '1' | 'i' => 'l',
+            '5' => 's',
+            '3' => 'e',
+            _ => c,
+        })
+        .collect()
+}
+
+fn get_trigrams(text: &str) -> HashSet<String> {
+    let chars: Vec<char> = text.chars().collect();
+    chars.windows(3).map(|window| window.iter().collect()).collect()
+}
+
+/**
+ * The fraction of the term's trigrams that are in the text
+ */
+fn get_similarity(term_trigrams: &HashSet<String>, text_trigrams: &HashSet<String>) -> f64 {
+    if term_trigrams.is_empty() {
+        return 0.0;
+    }
+    term_trigrams.intersection(text_trigrams).count() as f64 / term_trigrams.len() as f64
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use super::{get_trigram_match_query, normalize_ocr_text, reciprocal_rank_fusion, search_screenshots, SearchContext, SearchMode, SearchQuery};
+    use crate::annotations;
+    use crate::db;
+    use crate::embeddings::{self, test::KeywordEmbedder};
+    use crate::config::ScreentapConfig;
+    use crate::ranking::RankingModel;
+    use crate::video;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_get_trigram_match_query() {
+        assert_eq!(get_trigram_match_query("Tap"), "\"tap\"");
+        assert_eq!(get_trigram_match_query("abab x"), "\"aba\" OR \"bab\"");
+        assert_eq!(get_trigram_match_query("a\"b"), "\"a\"\"b\"");
+        assert_eq!(get_trigram_match_query("ab"), "");
+        assert_eq!(normalize_ocr_text("Scr33n-Tap 10"), "screentaplo");
+    }
+
+    #[test]
+    fn test_search_modes() {
+
+        let tmp_dir = tempdir().unwrap();
+        let data_root = tmp_dir.path();
+        let db_filename = Path::new("test.db");
+        db::create_db(data_root, db_filename).unwrap();
+
+        db::insert_test_screenshots(data_root, db_filename, &[(0, "Welcome to screentap"), (1, "scre entap settings"), (2, "SCR33NTAP"), (3, "something else entirely")]);
+
+        let frame_extractor = video::create_frame_extractor(&ScreentapConfig::default());
+        let ranking = RankingModel::default();
+        let context = SearchContext { embedder: Some(&KeywordEmbedder), min_similarity: 0.0, ranking: &ranking };
+        let search = |term: &str, mode: SearchMode| -> Vec<i32> {
+            search_screenshots(&SearchQuery::new(term, mode, 10), &context, data_root, db_filename, frame_extractor.as_ref()).unwrap()
+                .iter()
+                .map(|record| record.get_id())
+                .collect()
+        };
+
+        assert_eq!(search("screentap", SearchMode::Exact), vec![1]);
+
+        // All three contain screentap once normalized, so the newest comes first
+        assert_eq!(search("screentap", SearchMode::Fuzzy), vec![3, 2, 1]);
+
+        // Exact hits come first
+        assert_eq!(search("screentap", SearchMode::Blended), vec![1, 3, 2]);
+
+        // Substrings only match fuzzily
+        assert_eq!(search("entire", SearchMode::Exact), Vec::<i32>::new());
+        assert_eq!(search("entire", SearchMode::Fuzzy), vec![4]);
+    }
+
+    #[test]
+    fn test_search_notes_and_tags() {
+
+        let tmp_dir = tempdir().unwrap();
+        let data_root = tmp_dir.path();
+        let db_filename = Path::new("test.db");
+        db::create_db(data_root, db_filename).unwrap();
+
+        db::insert_test_screenshots(data_root, db_filename, &[(0, "Flight to Lisbon"), (1, "Invoice for the flight"), (2, "Recipe of the day")]);
+        let frame_extractor = video::create_frame_extractor(&ScreentapConfig::default());
+        annotations::add_note(data_root, db_filename, 3, "dinner for the flight home", 0, frame_extractor.as_ref()).unwrap();
+        annotations::add_tag(data_root, db_filename, 1, "travel", 0, frame_extractor.as_ref()).unwrap();
+        annotations::add_tag(data_root, db_filename, 3, "travel", 0, frame_extractor.as_ref()).unwrap();
+
+        let ranking = RankingModel::default();
+        let context = SearchContext { embedder: None, min_similarity: 0.0, ranking: &ranking };
+        let search = |term: &str, tag: Option<&str>| -> Vec<i32> {
+            let query = SearchQuery { tag, ..SearchQuery::new(term, SearchMode::Exact, 10) };
+            search_screenshots(&query, &context, data_root, db_filename, frame_extractor.as_ref()).unwrap()
+                .iter()
+                .map(|record| record.get_id())
+                .collect()
+        };
+
+        // The note hit comes first
+        assert_eq!(search("flight", None)[0], 3);
+        assert_eq!(search("flight", None).len(), 3);
+
+        assert_eq!(search("flight", Some("travel")), vec![3, 1]);
+        assert_eq!(search("invoice", Some("travel")), Vec::<i32>::new());
+        assert_eq!(search("", Some("Travel")), vec![3, 1]);
+    }
+
+
+    #[test]
+    fn test_semantic_and_hybrid_search() {
+
+        assert_eq!(reciprocal_rank_fusion(&[vec![1, 2, 3], vec![3, 1]]), vec![1, 3, 2]);
+
+        let tmp_dir = tempdir().unwrap();
+        let data_root = tmp_dir.path();
+        let db_filename = Path::new("test.db");
+        db::create_db(data_root, db_filename).unwrap();
+
+        db::insert_test_screenshots(data_root, db_filename, &[(0, "Flight to Lisbon"), (1, "Invoice for the flight"), (2, "Recipe of the day")]);
+        embeddings::embed_pending_screenshots(data_root, db_filename, &KeywordEmbedder, 10).unwrap();
+
+        let frame_extractor = video::create_frame_extractor(&ScreentapConfig::default());
+        let ranking = RankingModel::default();
+        let context = SearchContext { embedder: Some(&KeywordEmbedder), min_similarity: 0.0, ranking: &ranking };
+        let search = |term: &str, mode: SearchMode| -> Vec<i32> {
+            search_screenshots(&SearchQuery::new(term, mode, 2), &context, data_root, db_filename, frame_extractor.as_ref()).unwrap()
+                .iter()
+                .map(|record| record.get_id())
+                .collect()
+        };
+
+        // "flights" isn't a word in any screenshot, but its embedding matches
+        assert_eq!(search("flights", SearchMode::Exact), Vec::<i32>::new());
+        assert_eq!(search("flights", SearchMode::Semantic), vec![1, 2]);
+
+        // Screenshot 2 is both an exact and a semantic hit for invoice, and the others are unrelated
+        assert_eq!(search("invoice", SearchMode::Hybrid), vec![2]);
+
+        // Without an embedder, semantic search is exact search
+        let context = SearchContext { embedder: None, min_similarity: 0.0, ranking: &ranking };
+        let exact_hits = search_screenshots(&SearchQuery::new("flights", SearchMode::Semantic, 2), &context, data_root, db_filename, frame_extractor.as_ref()).unwrap();
+        assert!(exact_hits.is_empty());
+    }
+
+}
@@ -0,0 +1,324 @@
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use chrono::NaiveDateTime;
+use rusqlite::{params, Connection};
+
+use crate::error::ScreentapError;
+
+
+/**
+ * Screenshots and compacted mp4 files are stored under this dir in the data root
+ */
+static MEDIA_DIR_NAME: &str = "media";
+
+/**
+ * The PRAGMA user_version of a DB whose paths are relative to the data root and whose
+ * media files live under media/YYYY/MM/DD
+ */
+pub const RELATIVE_MEDIA_PATHS_DB_VERSION: i32 = 1;
+
+
+/**
+ * Decides where screenshot and mp4 files live inside the data root:
+ *
+ *   <data root>/media/YYYY/MM/DD/<file>
+ *
+ * The DB stores paths relative to the data root, so that the data root can be moved.
+ */
+#[derive(Debug, Clone)]
+pub struct StorageLayout {
+    data_root: PathBuf,
+}
+
+impl StorageLayout {
+
+    pub fn new(data_root: &Path) -> Self {
+        Self { data_root: data_root.to_path_buf() }
+    }
+
+    pub fn media_root(&self) -> PathBuf {
+        self.data_root.join(MEDIA_DIR_NAME)
+    }
+
+    /**
+     * The dir for media captured at the given time, relative to the data root
+     */
+    pub fn relative_media_dir(timestamp: NaiveDateTime) -> PathBuf {
+        PathBuf::from(MEDIA_DIR_NAME).join(timestamp.format("%Y/%m/%d").to_string())
+    }
+
+    /**
+     * The absolute path for a media file captured at the given time, creating its dir if needed
+     */
+    pub fn create_media_path(&self, timestamp: NaiveDateTime, filename: &str) -> std::io::Result<PathBuf> {
+        let media_dir = self.data_root.join(StorageLayout::relative_media_dir(timestamp));
+        std::fs::create_dir_all(&media_dir)?;
+        Ok(media_dir.join(filename))
+    }
+
+    /**
+     * Convert a path to the form stored in the DB.  Paths inside the data root become
+     * relative, and anything else is kept as is.
+     */
+    pub fn to_relative(&self, path: &Path) -> PathBuf {
+        match path.strip_prefix(&self.data_root) {
+            Ok(relative_path) => relative_path.to_path_buf(),
+            Err(_) => path.to_path_buf(),
+        }
+    }
+
+    /**
+     * Convert a path stored in the DB to an absolute path.  Empty paths (eg the mp4 path of
+     * a screenshot that was not compacted yet) stay empty.
+     */
+    pub fn to_absolute(&self, stored_path: &str) -> PathBuf {
+        if stored_path.is_empty() {
+            return PathBuf::new();
+        }
+        self.data_root.join(stored_path)
+    }
+
+    pub fn to_relative_string(&self, path: &Path) -> String {
+        self.to_relative(path).to_string_lossy().into_owned()
+    }
+
+    pub fn to_absolute_string(&self, stored_path: &str) -> String {
+        self.to_absolute(stored_path).to_string_lossy().into_owned()
+    }
+
+    /**
+     * All files with the given extension under the media dir, plus any left in the top level
+     * of the data root by older versions
+     */
+    pub fn list_media_files(&self, extension: &str) -> std::io::Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        collect_files(&self.data_root, Some(extension), false, &mut files)?;
+        let media_root = self.media_root();
+        if media_root.is_dir() {
+            collect_files(&media_root, Some(extension), true, &mut files)?;
+        }
+        Ok(files)
+    }
+
+    /**
+     * Every file under the media dir
+     */
+    pub fn list_media_files_recursive(&self) -> std::io::Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        let media_root = self.media_root();
+        if media_root.is_dir() {
+            collect_files(&media_root, None, true, &mut files)?;
+        }
+        Ok(files)
+    }
+
+}
+
+fn collect_files(dir: &Path, extension: Option<&str>, recursive: bool, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            if recursive {
+                collect_files(&path, extension, recursive, files)?;
+            }
+            continue;
+        }
+        let matches_extension = match extension {
+            Some(extension) => path.extension().is_some_and(|ext| ext == extension),
+            None => true,
+        };
+        if matches_extension {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Is this stored path a file directly in the data root, ie from the flat layout used before
+ * the media dir existed?  Such paths were stored as absolute paths.
+ */
+fn is_flat_layout_path(layout: &StorageLayout, stored_path: &str) -> bool {
+    let relative_path = layout.to_relative(Path::new(stored_path));
+    relative_path.is_relative() && relative_path.components().count() == 1
+        && matches!(relative_path.components().next(), Some(Component::Normal(_)))
+}
+
+/**
+ * Move a file from the flat layout into the media dir.  This is safe to repeat, since a
+ * file that is already at the target is left alone.
+ */
+fn move_to_media_dir(layout: &StorageLayout, stored_path: &str, timestamp: NaiveDateTime) -> Result<String, ScreentapError> {
+
+    let source = layout.to_absolute(stored_path);
+    let filename = source.file_name()
+        .ok_or_else(|| ScreentapError::Io(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Invalid media path {}", stored_path))))?
+        .to_string_lossy()
+        .into_owned();
+    let target = layout.create_media_path(timestamp, &filename)?;
+
+    // The file may be missing if a screenshot was already compacted, or if a previous
+    // run of this migration moved it before it was interrupted
+    if source.exists() && !target.exists() {
+        std::fs::rename(&source, &target)?;
+    }
+
+    Ok(layout.to_relative_string(&target))
+
+}
+
+/**
+ * Migrate a DB from the flat layout, where every file was in the data root and stored with
+ * its absolute path, to the media/YYYY/MM/DD layout with paths relative to the data root.
+ *
+ * Files are moved before the DB is updated, and moving is idempotent, so an interrupted
+ * migration is simply run again on the next startup.
+ */
+pub fn migrate_to_relative_media_paths(conn: &mut Connection, data_root: &Path) -> Result<(), ScreentapError> {
+
+    let user_version: i32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if user_version >= RELATIVE_MEDIA_PATHS_DB_VERSION {
+        return Ok(());
+    }
+
+    println!("Migrating screenshots in {} to the media dir layout", data_root.display());
+
+    let layout = StorageLayout::new(data_root);
+
+    let rows = {
+        let mut stmt = conn.prepare("SELECT id, timestamp, file_path, mp4_file_path FROM documents ORDER BY timestamp, id")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+            ))
+        })?.collect::<Result<Vec<_>, _>>()?;
+        rows
+    };
+
+    // An mp4 holds many screenshots, so it's moved once, into the dir of its first screenshot
+    let mut moved_mp4_files: HashMap<String, String> = HashMap::new();
+    let mut updates = Vec::with_capacity(rows.len());
+
+    for (id, timestamp, file_path, mp4_file_path) in rows {
+
+        let timestamp = NaiveDateTime::from_timestamp_opt(timestamp, 0).unwrap_or_default();
+
+        let new_file_path = if is_flat_layout_path(&layout, &file_path) {
+            move_to_media_dir(&layout, &file_path, timestamp)?
+        } else {
+            layout.to_relative_string(Path::new(&file_path))
+        };
+
+        let new_mp4_file_path = if mp4_file_path.is_empty() {
+            mp4_file_path
+        } else if let Some(new_mp4_file_path) = moved_mp4_files.get(&mp4_file_path) {
+            new_mp4_file_path.clone()
+        } else {
+            let new_mp4_file_path = if is_flat_layout_path(&layout, &mp4_file_path) {
+                move_to_media_dir(&layout, &mp4_file_path, timestamp)?
+            } else {
+                layout.to_relative_string(Path::new(&mp4_file_path))
+            };
+            moved_mp4_files.insert(mp4_file_path, new_mp4_file_path.clone());
+            new_mp4_file_path
+        };
+
+        updates.push((id, new_file_path, new_mp4_file_path));
+    }
+
+    let tx = conn.transaction()?;
+    for (id, file_path, mp4_file_path) in updates {
+        tx.execute(
+            "UPDATE documents SET file_path = ?1, mp4_file_path = ?2 WHERE id = ?3",
+            params![file_path, mp4_file_path, id],
+        )?;
+    }
+    tx.execute_batch(&format!("PRAGMA user_version = {}", RELATIVE_MEDIA_PATHS_DB_VERSION))?;
+    tx.commit()?;
+
+    Ok(())
+
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use super::StorageLayout;
+    use crate::db;
+    use chrono::NaiveDate;
+    use rusqlite::params;
+    use std::path::{Path, PathBuf};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_relative_media_dir() {
+        let timestamp = NaiveDate::from_ymd_opt(2024, 3, 7).unwrap().and_hms_opt(12, 30, 0).unwrap();
+        assert_eq!(StorageLayout::relative_media_dir(timestamp), PathBuf::from("media/2024/03/07"));
+    }
+
+    #[test]
+    fn test_relative_and_absolute_paths() {
+        let layout = StorageLayout::new(Path::new("/data/screentap"));
+
+        let absolute_path = Path::new("/data/screentap/media/2024/03/07/a.png");
+        assert_eq!(layout.to_relative(absolute_path), PathBuf::from("media/2024/03/07/a.png"));
+        assert_eq!(layout.to_absolute("media/2024/03/07/a.png"), absolute_path);
+
+        // Paths outside the data root are kept absolute
+        let outside_path = Path::new("/elsewhere/a.png");
+        assert_eq!(layout.to_relative(outside_path), outside_path);
+        assert_eq!(layout.to_absolute("/elsewhere/a.png"), outside_path);
+
+        assert_eq!(layout.to_absolute(""), PathBuf::new());
+    }
+
+    #[test]
+    fn test_migrate_flat_layout() {
+
+        let tmp_dir = tempdir().unwrap();
+        let data_root = tmp_dir.path();
+        let db_filename = Path::new("test.db");
+        db::create_db(data_root, db_filename).unwrap();
+
+        // Recreate the state of a DB from before the media dir: absolute paths, flat files
+        let timestamp = NaiveDate::from_ymd_opt(2024, 3, 7).unwrap().and_hms_opt(12, 30, 0).unwrap();
+        let mut conn = db::get_db_conn(data_root, db_filename).unwrap();
+        let png_path = data_root.join("2024_03_07_12_30_00.png");
+        let compacted_png_path = data_root.join("2024_03_07_12_29_30.png");
+        let mp4_path = data_root.join("2024_03_07_12_29_30.mp4");
+        std::fs::write(&png_path, b"png").unwrap();
+        std::fs::write(&mp4_path, b"mp4").unwrap();
+        conn.execute(
+            "INSERT INTO documents (timestamp, ocr_text, file_path, mp4_file_path, mp4_frame_id) VALUES (?1, '', ?2, ?3, 0)",
+            params![timestamp.timestamp() - 30, compacted_png_path.to_str().unwrap(), mp4_path.to_str().unwrap()],
+        ).unwrap();
+        conn.execute(
+            "INSERT INTO documents (timestamp, ocr_text, file_path, mp4_file_path, mp4_frame_id) VALUES (?1, '', ?2, '', -1)",
+            params![timestamp.timestamp(), png_path.to_str().unwrap()],
+        ).unwrap();
+        conn.execute_batch("PRAGMA user_version = 0").unwrap();
+
+        super::migrate_to_relative_media_paths(&mut conn, data_root).unwrap();
+
+        let paths: Vec<(String, String)> = conn
+            .prepare("SELECT file_path, mp4_file_path FROM documents ORDER BY timestamp").unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?))).unwrap()
+            .collect::<Result<_, _>>().unwrap();
+        assert_eq!(paths, vec![
+            ("media/2024/03/07/2024_03_07_12_29_30.png".to_string(), "media/2024/03/07/2024_03_07_12_29_30.mp4".to_string()),
+            ("media/2024/03/07/2024_03_07_12_30_00.png".to_string(), "".to_string()),
+        ]);
+        assert!(!png_path.exists());
+        assert!(!mp4_path.exists());
+        assert!(data_root.join("media/2024/03/07/2024_03_07_12_30_00.png").exists());
+        assert!(data_root.join("media/2024/03/07/2024_03_07_12_29_30.mp4").exists());
+
+        // Running it again does nothing
+        super::migrate_to_relative_media_paths(&mut conn, data_root).unwrap();
+    }
+
+}
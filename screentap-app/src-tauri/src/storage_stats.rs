@@ -0,0 +1,475 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use chrono::{Local, TimeZone};
+use rusqlite::Connection;
+use serde::Serialize;
+
+use crate::config::{ScreentapConfig, StorageQuotaAction};
+use crate::db;
+use crate::error::ScreentapError;
+use crate::pipeline::INCOMING_DIR_NAME;
+use crate::storage::StorageLayout;
+
+
+// By default, capture is paused once the disk has less than 1 GB free
+pub const DEFAULT_MIN_FREE_DISK_MB: u64 = 1024;
+
+/**
+ * How often the quota and free disk space are re-checked, since adding up the usage
+ * touches every loose screenshot
+ */
+pub(crate) static STORAGE_CHECK_INTERVAL_SECS: u64 = 60;
+
+
+/**
+ * Bytes used per kind of data.  Everything except focusguard_bytes lives in the data root
+ * and counts towards the storage quota.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct CategoryUsage {
+
+    // The DB files, not including the OCR index
+    pub db_bytes: u64,
+
+    // The full text index of the OCR text.  0 if sqlite was built without the dbstat table.
+    pub fts_index_bytes: u64,
+
+    // Screenshots that weren't compacted yet, including ones waiting for OCR
+    pub loose_png_bytes: u64,
+
+    // Compacted mp4 segments
+    pub mp4_bytes: u64,
+
+    // Files downloaded or written by the FocusGuard plugin, eg the llamafile
+    pub focusguard_bytes: u64,
+}
+
+impl CategoryUsage {
+
+    /**
+     * The bytes that count towards the storage quota
+     */
+    pub fn data_root_bytes(&self) -> u64 {
+        self.db_bytes + self.fts_index_bytes + self.loose_png_bytes + self.mp4_bytes
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.data_root_bytes() + self.focusguard_bytes
+    }
+}
+
+/**
+ * Bytes used by the screenshots of one day or one app.  A screenshot in an mp4 is
+ * attributed an equal share of the mp4's size.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct UsageBucket {
+    pub key: String,
+    pub screenshots: u64,
+    pub bytes: u64,
+}
+
+/**
+ * Whether capture can continue, as decided by check_storage()
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum StorageStatus {
+    #[default]
+    Ok,
+
+    // The disk with the data root has less than min_free_disk_mb free
+    LowDisk { free_bytes: u64 },
+
+    // The data root uses more than storage_quota_mb
+    OverQuota { used_bytes: u64, quota_bytes: u64 },
+}
+
+impl StorageStatus {
+
+    /**
+     * Low disk space always pauses capture, while an exceeded quota only does if it
+     * isn't handled by deleting old history
+     */
+    pub fn pauses_capture(&self, config: &ScreentapConfig) -> bool {
+        match self {
+            StorageStatus::Ok => false,
+            StorageStatus::LowDisk { .. } => true,
+            StorageStatus::OverQuota { .. } => config.storage_quota_action == StorageQuotaAction::PauseCapture,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StorageStats {
+    pub data_root: PathBuf,
+    pub categories: CategoryUsage,
+    pub total_bytes: u64,
+
+    // Newest day first
+    pub per_day: Vec<UsageBucket>,
+
+    // Biggest app first
+    pub per_app: Vec<UsageBucket>,
+
+    pub quota_bytes: Option<u64>,
+    pub free_disk_bytes: Option<u64>,
+    pub status: StorageStatus,
+}
+
+
+/**
+ * Add up the storage used by screentap, by category, day and app
+ */
+pub fn collect_storage_stats(app_data_dir: &Path, data_root: &Path, db_filename: &Path, config: &ScreentapConfig) -> Result<StorageStats, ScreentapError> {
+
+    let conn = db::get_db_conn(data_root, db_filename)?;
+
+    let fts_index_bytes = get_fts_index_bytes(&conn);
+    let categories = CategoryUsage {
+        db_bytes: get_db_bytes(&conn, data_root, db_filename).saturating_sub(fts_index_bytes),
+        fts_index_bytes,
+        loose_png_bytes: get_loose_png_bytes(&conn, data_root)?,
+        mp4_bytes: get_mp4_bytes(&conn)?,
+        focusguard_bytes: get_dir_bytes(&app_data_dir.join("plugins").join("focusguard")),
+    };
+
+    let (per_day, per_app) = get_usage_per_day_and_app(&conn, data_root)?;
+
+    Ok(StorageStats {
+        data_root: data_root.to_path_buf(),
+        total_bytes: categories.total_bytes(),
+        status: get_storage_status(data_root, categories.data_root_bytes(), config),
+        categories,
+        per_day,
+        per_app,
+        quota_bytes: get_quota_bytes(config),
+        free_disk_bytes: fs2::available_space(data_root).ok(),
+    })
+
+}
+
+/**
+ * The bytes used in the data root, without the per day and per app breakdown.  The OCR
+ * index is part of the DB file, so it doesn't need to be measured separately.
+ */
+pub fn get_data_root_usage_bytes(data_root: &Path, db_filename: &Path) -> Result<u64, ScreentapError> {
+    let conn = db::get_db_conn(data_root, db_filename)?;
+    Ok(get_db_bytes(&conn, data_root, db_filename) + get_loose_png_bytes(&conn, data_root)? + get_mp4_bytes(&conn)?)
+}
+
+/**
+ * Check the free disk space and the quota.  Errors reading the usage are logged and
+ * treated as Ok, so that a broken DB doesn't stop capture.
+ */
+pub fn check_storage(data_root: &Path, db_filename: &Path, config: &ScreentapConfig) -> StorageStatus {
+    let used_bytes = match get_data_root_usage_bytes(data_root, db_filename) {
+        Ok(used_bytes) => used_bytes,
+        Err(e) => {
+            println!("Error measuring storage usage: {}", e);
+            0
+        }
+    };
+    get_storage_status(data_root, used_bytes, config)
+}
+
+/**
+ * The quota in bytes, or None if there is no quota
+ */
+pub fn get_quota_bytes(config: &ScreentapConfig) -> Option<u64> {
+    match config.storage_quota_mb {
+        0 => None,
+        quota_mb => Some(quota_mb * 1024 * 1024),
+    }
+}
+
+fn get_storage_status(data_root: &Path, used_bytes: u64, config: &ScreentapConfig) -> StorageStatus {
+
+    if config.min_free_disk_mb > 0 {
+        if let Ok(free_bytes) = fs2::available_space(data_root) {
+            if free_bytes < config.min_free_disk_mb * 1024 * 1024 {
+                return StorageStatus::LowDisk { free_bytes };
+            }
+        }
+    }
+
+    match get_quota_bytes(config) {
+        Some(quota_bytes) if used_bytes > quota_bytes => StorageStatus::OverQuota { used_bytes, quota_bytes },
+        _ => StorageStatus::Ok,
+    }
+
+}
+
+
+/**
+ * Re-checks the storage status at most every STORAGE_CHECK_INTERVAL_SECS, so that the
+ * capture stage can ask before every capture
+ */
+pub struct StorageGuard {
+    data_root: PathBuf,
+    db_filename: PathBuf,
+    last_check: Option<Instant>,
+    status: StorageStatus,
+}
+
+impl StorageGuard {
+
+    pub fn new(data_root: PathBuf, db_filename: PathBuf) -> Self {
+        Self {
+            data_root,
+            db_filename,
+            last_check: None,
+            status: StorageStatus::Ok,
+        }
+    }
+
+    pub fn status(&mut self, config: &ScreentapConfig) -> StorageStatus {
+        let check_due = !matches!(
+            self.last_check,
+            Some(last_check) if last_check.elapsed() < Duration::from_secs(STORAGE_CHECK_INTERVAL_SECS)
+        );
+        if check_due {
+            self.status = check_storage(&self.data_root, &self.db_filename, config);
+            self.last_check = Some(Instant::now());
+        }
+        self.status
+    }
+
+}
+
+
+/**
+ * The size of the DB, not counting free pages.  sqlite doesn't shrink the file when rows are
+ * deleted but reuses the pages, so without this deleting old history would never get the
+ * usage under the quota.
+ */
+fn get_db_bytes(conn: &Connection, data_root: &Path, db_filename: &Path) -> u64 {
+    let free_bytes = conn.query_row(
+        "SELECT freelist_count * page_size FROM pragma_freelist_count(), pragma_page_size()",
+        [],
+        |row| row.get::<_, i64>(0)
+    ).map(|bytes| bytes.max(0) as u64).unwrap_or(0);
+    get_db_file_bytes(data_root, db_filename).saturating_sub(free_bytes)
+}
+
+/**
+ * The size of the DB files, including the write-ahead log or rollback journal if there is one
+ */
+fn get_db_file_bytes(data_root: &Path, db_filename: &Path) -> u64 {
+    let db_path = data_root.join(db_filename);
+    ["", "-wal", "-journal"].iter()
+        .map(|suffix| {
+            let mut path = db_path.clone().into_os_string();
+            path.push(suffix);
+            get_file_bytes(Path::new(&path))
+        })
+        .sum()
+}
+
+/**
+ * The size of the OCR full text indexes, from the dbstat virtual table.  That table is only
+ * there if sqlite was built with SQLITE_ENABLE_DBSTAT_VTAB, otherwise this is 0.
+ */
+fn get_fts_index_bytes(conn: &Connection) -> u64 {
+    conn.query_row(
+        "SELECT COALESCE(SUM(pgsize), 0) FROM dbstat WHERE name LIKE 'ocr_text_index%' OR name LIKE 'ocr_trigram_index%'",
+        [],
+        |row| row.get::<_, i64>(0)
+    ).map(|bytes| bytes.max(0) as u64).unwrap_or(0)
+}
+
+fn get_loose_png_bytes(conn: &Connection, data_root: &Path) -> Result<u64, ScreentapError> {
+    let layout = StorageLayout::new(data_root);
+    let mut stmt = conn.prepare("SELECT file_path FROM documents WHERE mp4_file_path = ''")?;
+    let loose_png_bytes: u64 = stmt.query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?
+        .iter()
+        .map(|file_path| get_file_bytes(&layout.to_absolute(file_path)))
+        .sum();
+    Ok(loose_png_bytes + get_dir_bytes(&data_root.join(INCOMING_DIR_NAME)))
+}
+
+fn get_mp4_bytes(conn: &Connection) -> Result<u64, ScreentapError> {
+    let mp4_bytes: i64 = conn.query_row("SELECT COALESCE(SUM(size_bytes), 0) FROM segments", [], |row| row.get(0))?;
+    Ok(mp4_bytes.max(0) as u64)
+}
+
+fn get_usage_per_day_and_app(conn: &Connection, data_root: &Path) -> Result<(Vec<UsageBucket>, Vec<UsageBucket>), ScreentapError> {
+
+    let layout = StorageLayout::new(data_root);
+
+    // Each screenshot in an mp4 gets an equal share of the mp4's size
+    let mut stmt = conn.prepare(
+        "SELECT d.timestamp, d.frontmost_app, d.file_path, d.mp4_file_path, COALESCE(s.size_bytes, 0), COALESCE(c.screenshots, 1)
+            FROM documents d
+            LEFT JOIN segments s ON s.mp4_file_path = d.mp4_file_path
+            LEFT JOIN (
+                SELECT mp4_file_path, COUNT(*) AS screenshots FROM documents WHERE mp4_file_path != '' GROUP BY mp4_file_path
+            ) c ON c.mp4_file_path = d.mp4_file_path"
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, String>(3)?,
+            row.get::<_, i64>(4)?,
+            row.get::<_, i64>(5)?,
+        ))
+    })?;
+
+    let mut per_day: HashMap<String, UsageBucket> = HashMap::new();
+    let mut per_app: HashMap<String, UsageBucket> = HashMap::new();
+
+    for row in rows {
+        let (timestamp, frontmost_app, file_path, mp4_file_path, mp4_bytes, mp4_screenshots) = row?;
+
+        let bytes = if mp4_file_path.is_empty() {
+            get_file_bytes(&layout.to_absolute(&file_path))
+        } else {
+            mp4_bytes.max(0) as u64 / mp4_screenshots.max(1) as u64
+        };
+
+        // Days are in local time, like the compaction windows
+        let day = Local.timestamp_opt(timestamp, 0)
+            .single()
+            .map(|datetime| datetime.format("%Y-%m-%d").to_string())
+            .unwrap_or_default();
+        let app = if frontmost_app.is_empty() { "unknown".to_string() } else { frontmost_app };
+
+        for (buckets, key) in [(&mut per_day, day), (&mut per_app, app)] {
+            let bucket = buckets.entry(key.clone()).or_insert_with(|| UsageBucket { key, ..Default::default() });
+            bucket.screenshots += 1;
+            bucket.bytes += bytes;
+        }
+    }
+
+    let mut per_day: Vec<UsageBucket> = per_day.into_values().collect();
+    per_day.sort_by(|a, b| b.key.cmp(&a.key));
+
+    let mut per_app: Vec<UsageBucket> = per_app.into_values().collect();
+    per_app.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.key.cmp(&b.key)));
+
+    Ok((per_day, per_app))
+
+}
+
+fn get_file_bytes(path: &Path) -> u64 {
+    std::fs::metadata(path).map(|metadata| metadata.len()).unwrap_or(0)
+}
+
+fn get_dir_bytes(dir: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return 0;
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| {
+            let path = entry.path();
+            if path.is_dir() { get_dir_bytes(&path) } else { get_file_bytes(&path) }
+        })
+        .sum()
+}
+
+/**
+ * Format a byte count for humans, eg 1.5 GB
+ */
+pub fn format_bytes(bytes: u64) -> String {
+    let units = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, units[unit])
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use super::{collect_storage_stats, format_bytes, StorageStatus};
+    use crate::config::ScreentapConfig;
+    use crate::db;
+    use chrono::{Local, TimeZone};
+    use rusqlite::params;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_collect_storage_stats() {
+
+        let tmp_dir = tempdir().unwrap();
+        let app_data_dir = tmp_dir.path();
+        let data_root = app_data_dir;
+        let db_filename = Path::new("test.db");
+        db::create_db(data_root, db_filename).unwrap();
+
+        // Two screenshots of an editor compacted into a 100 byte mp4, and a loose 10 byte
+        // screenshot of a browser on the next day
+        let mp4_file_path = "media/2024/03/07/segment.mp4";
+        std::fs::create_dir_all(data_root.join("media/2024/03/07")).unwrap();
+        std::fs::write(data_root.join(mp4_file_path), vec![0u8; 100]).unwrap();
+        std::fs::write(data_root.join("loose.png"), vec![0u8; 10]).unwrap();
+
+        let day1 = Local.with_ymd_and_hms(2024, 3, 7, 12, 0, 0).unwrap().naive_utc();
+        let day2 = Local.with_ymd_and_hms(2024, 3, 8, 12, 0, 0).unwrap().naive_utc();
+        let screenshots = [(day1, "com.editor", mp4_file_path), (day1, "com.editor", mp4_file_path), (day2, "com.browser", "")];
+
+        for (index, (now, app, mp4)) in screenshots.iter().enumerate() {
+            let capture_meta = db::CaptureMeta { frontmost_app: app.to_string(), ..Default::default() };
+            let png_path = data_root.join(if mp4.is_empty() { "loose.png".to_string() } else { format!("{}.png", index) });
+            let id = db::save_screenshot_meta(&png_path, "text", data_root, db_filename, *now, &capture_meta).unwrap();
+            let conn = db::get_db_conn(data_root, db_filename).unwrap();
+            conn.execute("UPDATE documents SET mp4_file_path = ?1 WHERE id = ?2", params![mp4, id]).unwrap();
+        }
+        let conn = db::get_db_conn(data_root, db_filename).unwrap();
+        conn.execute(
+            "INSERT INTO segments (mp4_file_path, start_timestamp, end_timestamp, frame_count, size_bytes, created_at) VALUES (?1, ?2, ?2, 2, 100, ?3)",
+            params![mp4_file_path, day1.timestamp(), day1],
+        ).unwrap();
+
+        // A FocusGuard model, outside the quota
+        std::fs::create_dir_all(app_data_dir.join("plugins/focusguard")).unwrap();
+        std::fs::write(app_data_dir.join("plugins/focusguard/model.llamafile"), vec![0u8; 1000]).unwrap();
+
+        let config = ScreentapConfig { min_free_disk_mb: 0, storage_quota_mb: 0, ..Default::default() };
+        let stats = collect_storage_stats(app_data_dir, data_root, db_filename, &config).unwrap();
+
+        assert_eq!(stats.categories.mp4_bytes, 100);
+        assert_eq!(stats.categories.loose_png_bytes, 10);
+        assert_eq!(stats.categories.focusguard_bytes, 1000);
+        assert!(stats.categories.db_bytes > 0);
+        assert_eq!(stats.total_bytes, stats.categories.data_root_bytes() + 1000);
+        assert_eq!(stats.status, StorageStatus::Ok);
+
+        let per_day: Vec<(&str, u64, u64)> = stats.per_day.iter().map(|bucket| (bucket.key.as_str(), bucket.screenshots, bucket.bytes)).collect();
+        assert_eq!(per_day, vec![("2024-03-08", 1, 10), ("2024-03-07", 2, 100)]);
+
+        let per_app: Vec<(&str, u64, u64)> = stats.per_app.iter().map(|bucket| (bucket.key.as_str(), bucket.screenshots, bucket.bytes)).collect();
+        assert_eq!(per_app, vec![("com.editor", 2, 100), ("com.browser", 1, 10)]);
+
+        // Grow the mp4 past a 1 MB quota
+        let config = ScreentapConfig { min_free_disk_mb: 0, storage_quota_mb: 1, ..Default::default() };
+        std::fs::write(data_root.join(mp4_file_path), vec![0u8; 2 * 1024 * 1024]).unwrap();
+        let conn = db::get_db_conn(data_root, db_filename).unwrap();
+        conn.execute("UPDATE segments SET size_bytes = ?1", params![2 * 1024 * 1024]).unwrap();
+        let stats = collect_storage_stats(app_data_dir, data_root, db_filename, &config).unwrap();
+        assert!(matches!(stats.status, StorageStatus::OverQuota { quota_bytes: 1048576, .. }));
+        assert!(stats.status.pauses_capture(&config));
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KB");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024), "3.0 GB");
+    }
+
+}
@@ -0,0 +1,569 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use chrono::{Duration, Local, NaiveDateTime};
+use image::imageops::FilterType;
+use image_hasher::{HasherConfig, ImageHash};
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::annotations;
+use crate::compaction::CompactionJobState;
+use crate::config::ScreentapConfig;
+use crate::db;
+use crate::error::ScreentapError;
+use crate::storage::StorageLayout;
+use crate::video::{FrameExtractor, VideoEncoder};
+
+
+// By default, segments are downsampled to tier 1 after a week and to tier 2 after a month
+pub const DEFAULT_TIER1_AFTER_DAYS: u32 = 7;
+pub const DEFAULT_TIER2_AFTER_DAYS: u32 = 30;
+
+/**
+ * How many frames to hold in memory at once while downsampling a segment
+ */
+const EXTRACT_BATCH_SIZE: usize = 32;
+
+
+/**
+ * A level of downsampling.  Tier 0 is the full rate, full resolution mp4 written by compaction.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tier {
+    pub tier: i64,
+
+    // Segments whose last screenshot is older than this are moved to this tier
+    pub min_age: Duration,
+
+    // The resolution of the frames relative to the original screenshots
+    pub scale: f32,
+
+    // A frame is only kept if its perceptual hash differs from the last kept frame of the same
+    // display by at least this many bits, out of 256.  Dropped frames are replaced by the last kept one.
+    pub min_phash_distance: u32,
+}
+
+/**
+ * The tiers for the config, from the least to the most downsampled
+ */
+pub fn tiers_from_config(config: &ScreentapConfig) -> Vec<Tier> {
+    vec![
+        Tier {
+            tier: 1,
+            min_age: Duration::days(config.tiering_tier1_after_days as i64),
+            scale: 0.5,
+            min_phash_distance: 4,
+        },
+        Tier {
+            tier: 2,
+            min_age: Duration::days(config.tiering_tier2_after_days as i64),
+            scale: 0.25,
+            min_phash_distance: 16,
+        },
+    ]
+}
+
+
+/**
+ * The result of downsampling one segment
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct TieringReport {
+    pub mp4_file_path: PathBuf,
+    pub tier: i64,
+    pub frames_before: usize,
+    pub frames_after: usize,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+#[derive(Debug, Clone)]
+struct SegmentToTier {
+    id: i64,
+    mp4_file_path: PathBuf,
+    tier: i64,
+}
+
+
+/**
+ * Re-encode old segments at a lower resolution, keeping only frames that changed.
+ *
+ * Every screenshot row keeps its OCR text, and its mp4_frame_id is remapped to the kept frame
+ * that replaced it.  Like compaction, each step is journaled (in tiering_jobs), so a crash
+ * leaves either the old or the new mp4 in use and recover_incomplete_jobs() removes the other.
+ */
+pub struct TieringHelper {
+    data_root: PathBuf,
+    db_filename_path: PathBuf,
+    tiers: Vec<Tier>,
+    video_encoder: Box<dyn VideoEncoder>,
+    frame_extractor: Box<dyn FrameExtractor>,
+}
+
+impl TieringHelper {
+
+    pub fn new(data_root: PathBuf, db_filename_path: PathBuf, tiers: Vec<Tier>, video_encoder: Box<dyn VideoEncoder>, frame_extractor: Box<dyn FrameExtractor>) -> Self {
+        Self {
+            data_root,
+            db_filename_path,
+            tiers,
+            video_encoder,
+            frame_extractor,
+        }
+    }
+
+    /**
+     * Change the tiers, eg after the config is reloaded
+     */
+    pub fn set_tiers(&mut self, tiers: Vec<Tier>) {
+        self.tiers = tiers;
+    }
+
+    /**
+     * Change the video encoder and frame extractor, eg after the video backend is changed in the config
+     */
+    pub fn set_video_backend(&mut self, video_encoder: Box<dyn VideoEncoder>, frame_extractor: Box<dyn FrameExtractor>) {
+        self.video_encoder = video_encoder;
+        self.frame_extractor = frame_extractor;
+    }
+
+    fn storage_layout(&self) -> StorageLayout {
+        StorageLayout::new(&self.data_root)
+    }
+
+    fn get_db_conn(&self) -> Result<Connection, ScreentapError> {
+        db::get_db_conn(&self.data_root, &self.db_filename_path)
+    }
+
+    /**
+     * The resolution of a tier's frames relative to the original screenshots
+     */
+    fn scale_for_tier(&self, tier: i64) -> f32 {
+        self.tiers.iter()
+            .find(|candidate| candidate.tier == tier)
+            .map(|candidate| candidate.scale)
+            .unwrap_or(1.0)
+    }
+
+    /**
+     * Downsample the oldest segment that is due for a deeper tier at time now, if any.  Only
+     * one segment is done per call, since this runs on the same thread as saving screenshots.
+     */
+    pub fn tier_next_segment(&self, now: NaiveDateTime) -> Result<Option<TieringReport>, ScreentapError> {
+
+        let mut conn = self.get_db_conn()?;
+        let layout = self.storage_layout();
+
+        // Check the deepest tier first, so that a segment skips tiers it is already too old for
+        for tier in self.tiers.iter().rev() {
+            let segment = conn.query_row(
+                "SELECT id, mp4_file_path, tier FROM segments WHERE tier < ?1 AND end_timestamp < ?2 ORDER BY start_timestamp LIMIT 1",
+                params![tier.tier, (now - tier.min_age).timestamp()],
+                |row| Ok(SegmentToTier {
+                    id: row.get(0)?,
+                    mp4_file_path: layout.to_absolute(&row.get::<_, String>(1)?),
+                    tier: row.get(2)?,
+                })
+            ).optional()?;
+
+            if let Some(segment) = segment {
+                return self.tier_segment(&mut conn, &segment, tier).map(Some);
+            }
+        }
+
+        Ok(None)
+
+    }
+
+    fn tier_segment(&self, conn: &mut Connection, segment: &SegmentToTier, tier: &Tier) -> Result<TieringReport, ScreentapError> {
+
+        let layout = self.storage_layout();
+        let old_mp4_str = layout.to_relative_string(&segment.mp4_file_path);
+
+        println!("Downsampling {} from tier {} to tier {}", segment.mp4_file_path.display(), segment.tier, tier.tier);
+
+        // Pinned screenshots are kept at full quality, eg ones that were compacted while they were being tagged
+        let unpacked = annotations::unpack_pinned_in_segment(&self.data_root, &self.db_filename_path, &old_mp4_str, self.frame_extractor.as_ref())?;
+
+        // The frames that screenshots point to, with the display each frame came from
+        let frames = {
+            let mut stmt = conn.prepare(
+                "SELECT mp4_frame_id, MIN(display_id) FROM documents WHERE mp4_file_path = ?1 GROUP BY mp4_frame_id ORDER BY mp4_frame_id"
+            )?;
+            let frames = stmt.query_map(params![old_mp4_str], |row| Ok((row.get::<_, i32>(0)?, row.get::<_, u32>(1)?)))?
+                .collect::<Result<Vec<_>, _>>()?;
+            frames
+        };
+        if frames.is_empty() && unpacked > 0 {
+            // Every screenshot of the segment was pinned, so nothing uses the mp4 anymore
+            let bytes_before = std::fs::metadata(&segment.mp4_file_path).map(|metadata| metadata.len()).unwrap_or(0);
+            conn.execute("DELETE FROM segments WHERE id = ?1", params![segment.id])?;
+            remove_mp4_files(&segment.mp4_file_path)?;
+            return Ok(TieringReport {
+                mp4_file_path: segment.mp4_file_path.clone(),
+                tier: tier.tier,
+                frames_before: unpacked,
+                frames_after: 0,
+                bytes_before,
+                bytes_after: 0,
+            });
+        }
+        if frames.is_empty() {
+            return Err(ScreentapError::Encode(format!("No screenshots point to {}", segment.mp4_file_path.display())));
+        }
+
+        // Work on the same volume as the data root, since the frames of a segment can be large
+        let work_dir = tempfile::Builder::new().prefix("tiering").tempdir_in(&self.data_root)?;
+        let scale = tier.scale / self.scale_for_tier(segment.tier);
+        let hasher = HasherConfig::new().hash_size(16, 16).preproc_dct().to_hasher();
+
+        let mut kept_frames: Vec<PathBuf> = vec![];
+        let mut new_frame_ids: HashMap<i32, i32> = HashMap::new();
+        let mut last_kept_by_display: HashMap<u32, (ImageHash, i32)> = HashMap::new();
+
+        for batch in frames.chunks(EXTRACT_BATCH_SIZE) {
+
+            let frame_ids: Vec<i32> = batch.iter().map(|(frame_id, _)| *frame_id).collect();
+            let png_frames = self.frame_extractor.extract_frames(&segment.mp4_file_path, &frame_ids)?;
+
+            for ((old_frame_id, display_id), png_data) in batch.iter().zip(png_frames) {
+
+                let img = image::load_from_memory(&png_data)?;
+                let width = ((img.width() as f32 * scale) as u32).max(2);
+                let height = ((img.height() as f32 * scale) as u32).max(2);
+                let resized = img.resize_exact(width, height, FilterType::Triangle);
+                let phash = hasher.hash_image(&resized);
+
+                if let Some((last_phash, last_frame_id)) = last_kept_by_display.get(display_id) {
+                    if last_phash.dist(&phash) < tier.min_phash_distance {
+                        new_frame_ids.insert(*old_frame_id, *last_frame_id);
+                        continue;
+                    }
+                }
+
+                let new_frame_id = kept_frames.len() as i32;
+                let kept_frame = work_dir.path().join(format!("{:06}.png", new_frame_id));
+                resized.save(&kept_frame)?;
+                kept_frames.push(kept_frame);
+
+                new_frame_ids.insert(*old_frame_id, new_frame_id);
+                last_kept_by_display.insert(*display_id, (phash, new_frame_id));
+            }
+        }
+
+        let new_mp4 = get_tiered_mp4_path(&segment.mp4_file_path, tier.tier);
+        let new_mp4_str = layout.to_relative_string(&new_mp4);
+        let job_id = insert_job(conn, segment.id, &old_mp4_str, &new_mp4_str)?;
+
+        if let Err(e) = self.encode_and_verify(&kept_frames, &new_mp4) {
+            println!("Error encoding {}, rolling back tiering job #{}: {}", new_mp4.display(), job_id, e);
+            if let Err(rollback_error) = remove_mp4_files(&new_mp4).and_then(|_| delete_job(conn, job_id)) {
+                println!("Error rolling back tiering job #{}: {}", job_id, rollback_error);
+            }
+            return Err(e);
+        }
+
+        let bytes_before = std::fs::metadata(&segment.mp4_file_path).map(|metadata| metadata.len()).unwrap_or(0);
+        let bytes_after = std::fs::metadata(&new_mp4)?.len();
+
+        // Point every screenshot at its kept frame in the new mp4, all at once
+        let tx = conn.transaction()?;
+        for (old_frame_id, new_frame_id) in &new_frame_ids {
+            tx.execute(
+                "UPDATE documents SET mp4_file_path = ?1, mp4_frame_id = ?2 WHERE mp4_file_path = ?3 AND mp4_frame_id = ?4",
+                params![new_mp4_str, new_frame_id, old_mp4_str, old_frame_id],
+            )?;
+        }
+        tx.execute(
+            "UPDATE segments SET mp4_file_path = ?1, tier = ?2, frame_count = ?3, size_bytes = ?4 WHERE id = ?5",
+            params![new_mp4_str, tier.tier, kept_frames.len() as i64, bytes_after as i64, segment.id],
+        )?;
+        set_job_state(&tx, job_id, CompactionJobState::Committed)?;
+        tx.commit()?;
+
+        remove_mp4_files(&segment.mp4_file_path)?;
+        delete_job(conn, job_id)?;
+
+        let report = TieringReport {
+            mp4_file_path: new_mp4,
+            tier: tier.tier,
+            frames_before: frames.len(),
+            frames_after: kept_frames.len(),
+            bytes_before,
+            bytes_after,
+        };
+        println!("Downsampled segment: {:?}", report);
+
+        Ok(report)
+
+    }
+
+    /**
+     * Write the mp4 to a partial path, and only move it to the target path once it has one frame per kept frame
+     */
+    fn encode_and_verify(&self, frames: &[PathBuf], target_mp4: &Path) -> Result<(), ScreentapError> {
+        let partial_mp4 = get_partial_mp4_path(target_mp4);
+        self.video_encoder.encode(frames, &partial_mp4)?;
+        let frame_count = self.frame_extractor.count_frames(&partial_mp4)?;
+        if frame_count != frames.len() {
+            return Err(ScreentapError::Encode(format!(
+                "{} has {} frames, expected {}", partial_mp4.display(), frame_count, frames.len()
+            )));
+        }
+        std::fs::rename(&partial_mp4, target_mp4)?;
+        Ok(())
+    }
+
+    /**
+     * Clean up after tiering jobs that were interrupted.  Pending jobs never changed the DB, so
+     * the new mp4 is removed.  Committed jobs only need the old mp4 removed.
+     */
+    pub fn recover_incomplete_jobs(&self) -> Result<(), ScreentapError> {
+
+        let conn = self.get_db_conn()?;
+        let layout = self.storage_layout();
+
+        let jobs = {
+            let mut stmt = conn.prepare("SELECT id, old_mp4_file_path, new_mp4_file_path, state FROM tiering_jobs ORDER BY id")?;
+            let jobs = stmt.query_map([], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?, row.get::<_, String>(3)?))
+            })?.collect::<Result<Vec<_>, _>>()?;
+            jobs
+        };
+
+        for (job_id, old_mp4_file_path, new_mp4_file_path, state) in jobs {
+            println!("Recovering tiering job #{} for {} in state {}", job_id, old_mp4_file_path, state);
+            match CompactionJobState::parse(&state) {
+                Some(CompactionJobState::Committed) => remove_mp4_files(&layout.to_absolute(&old_mp4_file_path))?,
+                _ => remove_mp4_files(&layout.to_absolute(&new_mp4_file_path))?,
+            }
+            delete_job(&conn, job_id)?;
+        }
+
+        Ok(())
+
+    }
+
+}
+
+
+/**
+ * The path of a segment's mp4 at a new tier, eg 2024_03_07_10_00_00_tier2.mp4
+ */
+fn get_tiered_mp4_path(mp4_file_path: &Path, tier: i64) -> PathBuf {
+    let stem = mp4_file_path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default();
+    let base_stem = match stem.rsplit_once("_tier") {
+        Some((base_stem, tier_suffix)) if tier_suffix.parse::<i64>().is_ok() => base_stem,
+        _ => stem,
+    };
+    mp4_file_path.with_file_name(format!("{}_tier{}.mp4", base_stem, tier))
+}
+
+fn get_partial_mp4_path(mp4_file_path: &Path) -> PathBuf {
+    mp4_file_path.with_extension("partial.mp4")
+}
+
+/**
+ * Remove an mp4 and its partial file, if they exist
+ */
+fn remove_mp4_files(mp4_file_path: &Path) -> Result<(), ScreentapError> {
+    for path in [get_partial_mp4_path(mp4_file_path), mp4_file_path.to_path_buf()] {
+        match std::fs::remove_file(&path) {
+            Ok(()) => {},
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+fn insert_job(conn: &Connection, segment_id: i64, old_mp4_file_path: &str, new_mp4_file_path: &str) -> Result<i64, ScreentapError> {
+    conn.execute(
+        "INSERT INTO tiering_jobs (segment_id, old_mp4_file_path, new_mp4_file_path, state, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, ?5, ?5)",
+        params![segment_id, old_mp4_file_path, new_mp4_file_path, CompactionJobState::Pending.as_str(), Local::now().naive_utc()],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+fn set_job_state(conn: &Connection, job_id: i64, state: CompactionJobState) -> Result<(), ScreentapError> {
+    conn.execute(
+        "UPDATE tiering_jobs SET state = ?1, updated_at = ?2 WHERE id = ?3",
+        params![state.as_str(), Local::now().naive_utc(), job_id],
+    )?;
+    Ok(())
+}
+
+fn delete_job(conn: &Connection, job_id: i64) -> Result<(), ScreentapError> {
+    conn.execute("DELETE FROM tiering_jobs WHERE id = ?1", params![job_id])?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use super::{get_tiered_mp4_path, Tier, TieringHelper};
+    use crate::db;
+    use crate::error::ScreentapError;
+    use crate::video::{FrameExtractor, VideoEncoder};
+    use chrono::{Duration, NaiveDate, NaiveDateTime};
+    use image::{ImageBuffer, Rgba};
+    use rusqlite::params;
+    use std::path::{Path, PathBuf};
+    use tempfile::tempdir;
+
+    /**
+     * Writes the number of frames instead of an mp4
+     */
+    struct FakeVideoEncoder {}
+
+    impl VideoEncoder for FakeVideoEncoder {
+        fn encode(&self, frames: &[PathBuf], target_mp4: &Path) -> Result<(), ScreentapError> {
+            std::fs::write(target_mp4, frames.len().to_string())?;
+            Ok(())
+        }
+    }
+
+    /**
+     * Frames alternate between two images in pairs: 0 and 1 are split into left and right
+     * halves, 2 and 3 into top and bottom halves, etc
+     */
+    struct FakeFrameExtractor {}
+
+    impl FrameExtractor for FakeFrameExtractor {
+        fn extract_frame(&self, _mp4_path: &Path, frame_id: i32) -> Result<Vec<u8>, ScreentapError> {
+            let split_vertically = (frame_id / 2) % 2 == 0;
+            let img = ImageBuffer::from_fn(64, 48, |x, y| {
+                let first_half = if split_vertically { x < 32 } else { y < 24 };
+                if first_half { Rgba([255, 255, 255, 255]) } else { Rgba([0, 0, 0, 255]) }
+            });
+            let mut png_data = vec![];
+            image::DynamicImage::ImageRgba8(img)
+                .write_to(&mut std::io::Cursor::new(&mut png_data), image::ImageOutputFormat::Png)?;
+            Ok(png_data)
+        }
+
+        fn count_frames(&self, mp4_path: &Path) -> Result<usize, ScreentapError> {
+            let frame_count = std::fs::read_to_string(mp4_path)?;
+            frame_count.parse().map_err(|_| ScreentapError::Encode(format!("Not a fake mp4: {}", mp4_path.display())))
+        }
+    }
+
+    static MP4_FILE_PATH: &str = "media/2024/03/07/2024_03_07_10_00_00.mp4";
+
+    /**
+     * When the screenshots in the segment of create_segment() were taken
+     */
+    fn segment_start() -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 3, 7).unwrap().and_hms_opt(10, 0, 0).unwrap()
+    }
+
+    /**
+     * A segment of 4 screenshots taken 30 seconds apart from segment_start(), and a tiering
+     * helper with one tier for segments older than 7 days.  Returns the path of the mp4.
+     */
+    fn create_segment(data_root: &Path, db_filename: &Path) -> (PathBuf, TieringHelper) {
+
+        db::create_db(data_root, db_filename).unwrap();
+
+        let mp4_file_path = data_root.join(MP4_FILE_PATH);
+        std::fs::create_dir_all(mp4_file_path.parent().unwrap()).unwrap();
+        std::fs::write(&mp4_file_path, "4").unwrap();
+
+        let start = segment_start();
+        let conn = db::get_db_conn(data_root, db_filename).unwrap();
+        for frame_id in 0..4 {
+            conn.execute(
+                "INSERT INTO documents (timestamp, ocr_text, file_path, mp4_file_path, mp4_frame_id) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![(start + Duration::seconds(30 * frame_id as i64)).timestamp(), format!("text {}", frame_id), format!("{}.png", frame_id), MP4_FILE_PATH, frame_id],
+            ).unwrap();
+        }
+        conn.execute(
+            "INSERT INTO segments (mp4_file_path, start_timestamp, end_timestamp, frame_count, size_bytes, created_at) VALUES (?1, ?2, ?3, 4, 1, ?4)",
+            params![MP4_FILE_PATH, start.timestamp(), (start + Duration::seconds(90)).timestamp(), start],
+        ).unwrap();
+
+        let tiers = vec![Tier { tier: 1, min_age: Duration::days(7), scale: 0.5, min_phash_distance: 4 }];
+        let tiering_helper = TieringHelper::new(
+            data_root.to_path_buf(),
+            db_filename.to_path_buf(),
+            tiers,
+            Box::new(FakeVideoEncoder {}),
+            Box::new(FakeFrameExtractor {}),
+        );
+
+        (mp4_file_path, tiering_helper)
+    }
+
+    #[test]
+    fn test_tier_next_segment() {
+
+        let tmp_dir = tempdir().unwrap();
+        let data_root = tmp_dir.path();
+        let db_filename = Path::new("test.db");
+
+        // A compacted segment of 4 screenshots from 10 days ago
+        let (mp4_file_path, tiering_helper) = create_segment(data_root, db_filename);
+        let start = segment_start();
+        let conn = db::get_db_conn(data_root, db_filename).unwrap();
+
+        // Not old enough yet
+        assert!(tiering_helper.tier_next_segment(start + Duration::days(1)).unwrap().is_none());
+
+        let report = tiering_helper.tier_next_segment(start + Duration::days(10)).unwrap().unwrap();
+        assert_eq!((report.frames_before, report.frames_after), (4, 2));
+        assert_eq!(report.mp4_file_path, get_tiered_mp4_path(&mp4_file_path, 1));
+        assert!(!mp4_file_path.exists());
+        assert!(report.mp4_file_path.exists());
+
+        // Each screenshot keeps its OCR text and points at the kept frame that replaced it
+        let mut stmt = conn.prepare("SELECT ocr_text, mp4_file_path, mp4_frame_id FROM documents ORDER BY id").unwrap();
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, i32>(2)?)))
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let frame_ids: Vec<i32> = rows.iter().map(|(_, _, frame_id)| *frame_id).collect();
+        assert_eq!(frame_ids, vec![0, 0, 1, 1]);
+        assert_eq!(rows[3].0, "text 3");
+        assert!(rows.iter().all(|(_, mp4_file_path, _)| mp4_file_path == "media/2024/03/07/2024_03_07_10_00_00_tier1.mp4"));
+
+        let (tier, frame_count): (i64, i64) = conn.query_row("SELECT tier, frame_count FROM segments", [], |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+        assert_eq!((tier, frame_count), (1, 2));
+
+        // Already at the deepest tier
+        assert!(tiering_helper.tier_next_segment(start + Duration::days(100)).unwrap().is_none());
+    }
+
+    /**
+     * A screenshot that was pinned while it was compacted is unpacked instead of downsampled
+     */
+    #[test]
+    fn test_tier_next_segment_unpacks_pinned_screenshots() {
+
+        let tmp_dir = tempdir().unwrap();
+        let data_root = tmp_dir.path();
+        let db_filename = Path::new("test.db");
+
+        let (mp4_file_path, tiering_helper) = create_segment(data_root, db_filename);
+        let start = segment_start();
+        let conn = db::get_db_conn(data_root, db_filename).unwrap();
+        conn.execute("INSERT INTO notes (document_id, text, created_at, updated_at) VALUES (1, 'keep', 0, 0)", []).unwrap();
+
+        let report = tiering_helper.tier_next_segment(start + Duration::days(10)).unwrap().unwrap();
+        assert_eq!((report.frames_before, report.frames_after), (3, 2));
+
+        let screenshot = db::find_screenshots_by_ids(data_root, db_filename, &[1]).unwrap().remove(0);
+        assert_eq!(screenshot.get_mp4_file_path(), "");
+        assert_eq!(std::fs::read(screenshot.get_file_path()).unwrap(), FakeFrameExtractor {}.extract_frame(&mp4_file_path, 0).unwrap());
+
+        let mut stmt = conn.prepare("SELECT mp4_frame_id FROM documents WHERE mp4_file_path != '' ORDER BY id").unwrap();
+        let frame_ids = stmt.query_map([], |row| row.get::<_, i32>(0)).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(frame_ids, vec![0, 1, 1]);
+    }
+
+    #[test]
+    fn test_get_tiered_mp4_path() {
+        assert_eq!(get_tiered_mp4_path(Path::new("/a/2024_03_07.mp4"), 1), PathBuf::from("/a/2024_03_07_tier1.mp4"));
+        assert_eq!(get_tiered_mp4_path(Path::new("/a/2024_03_07_tier1.mp4"), 2), PathBuf::from("/a/2024_03_07_tier2.mp4"));
+    }
+
+}
@@ -13,6 +13,17 @@ pub fn generate_filename(now: NaiveDateTime, extension: &str) -> PathBuf {
     PathBuf::from(filename)
 }
 
+/**
+ * Helper function to generate a filename based on the current time and the captured display,
+ * so that screenshots of different displays taken in the same capture cycle don't collide
+ */
+pub fn generate_display_filename(now: NaiveDateTime, display_id: u32, extension: &str) -> PathBuf {
+
+    let formatted_time = now.format("%Y_%m_%d_%H_%M_%S").to_string();
+    let filename = format!("{}_d{}.{}", formatted_time, display_id, extension);
+    PathBuf::from(filename)
+}
+
 
 pub fn execute_applescript(script: &str) -> String {
     let output = std::process::Command::new("osascript")
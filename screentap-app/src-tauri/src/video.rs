@@ -0,0 +1,509 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::config::{ScreentapConfig, VideoBackend};
+use crate::error::ScreentapError;
+use crate::frame_format::FrameFormat;
+
+
+/**
+ * Default name of the ffmpeg binary, which is looked up on the PATH
+ */
+pub static DEFAULT_FFMPEG_PATH: &str = "ffmpeg";
+
+/**
+ * Default maximum number of frames between keyframes.  At one frame per second, seeking
+ * to any frame decodes at most this many frames.
+ */
+pub const DEFAULT_KEYFRAME_INTERVAL: u32 = 30;
+
+/**
+ * Every frame starts with this signature, see split_png_stream()
+ */
+static PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+
+
+/**
+ * Writes a list of screenshot images to an mp4 file.
+ *
+ * The contract shared by every implementation: frame N of the mp4 is frames[N], so the
+ * mp4_frame_id stored in the DB is the index of the screenshot in the list that was encoded.
+ */
+pub trait VideoEncoder: Send {
+    fn encode(&self, frames: &[PathBuf], target_mp4: &Path) -> Result<(), ScreentapError>;
+}
+
+/**
+ * Reads frames back out of an mp4 written by a VideoEncoder
+ */
+pub trait FrameExtractor: Send + Sync {
+
+    // Get a single frame as PNG data
+    fn extract_frame(&self, mp4_path: &Path, frame_id: i32) -> Result<Vec<u8>, ScreentapError>;
+
+    // Get several frames of one mp4 as PNG data, in the order of frame_ids.  Backends that
+    // can decode many frames in one pass override this, the default extracts them one by one.
+    fn extract_frames(&self, mp4_path: &Path, frame_ids: &[i32]) -> Result<Vec<Vec<u8>>, ScreentapError> {
+        frame_ids.iter()
+            .map(|&frame_id| self.extract_frame(mp4_path, frame_id))
+            .collect()
+    }
+
+    // Count the frames by decoding the whole video track, which is used to verify an mp4
+    // before the screenshots it replaces are deleted
+    fn count_frames(&self, mp4_path: &Path) -> Result<usize, ScreentapError>;
+}
+
+
+/**
+ * Create the encoder for the configured video backend
+ */
+pub fn create_encoder(config: &ScreentapConfig) -> Box<dyn VideoEncoder> {
+    match resolve_backend(config.video_backend) {
+        #[cfg(target_os = "macos")]
+        VideoBackend::Avfoundation => Box::new(SwiftVideoEncoder {
+            use_bitrate_key: config.use_bitrate_key,
+            keyframe_interval: config.keyframe_interval,
+        }),
+        _ => Box::new(FfmpegVideoEncoder::new(&config.ffmpeg_path, config.keyframe_interval)),
+    }
+}
+
+/**
+ * Create the frame extractor for the configured video backend
+ */
+pub fn create_frame_extractor(config: &ScreentapConfig) -> Box<dyn FrameExtractor> {
+    match resolve_backend(config.video_backend) {
+        #[cfg(target_os = "macos")]
+        VideoBackend::Avfoundation => Box::new(SwiftFrameExtractor {}),
+        _ => Box::new(FfmpegFrameExtractor::new(&config.ffmpeg_path)),
+    }
+}
+
+/**
+ * Pick the concrete backend for "auto": AVFoundation on macOS, ffmpeg everywhere else
+ */
+pub fn resolve_backend(video_backend: VideoBackend) -> VideoBackend {
+    match video_backend {
+        VideoBackend::Auto if cfg!(target_os = "macos") => VideoBackend::Avfoundation,
+        VideoBackend::Auto => VideoBackend::Ffmpeg,
+        other => other,
+    }
+}
+
+
+/**
+ * Encoder that uses AVFoundation via the swift bridge
+ */
+#[cfg(target_os = "macos")]
+pub struct SwiftVideoEncoder {
+
+    // Use AVVideoAverageBitRateKey instead of AVVideoQualityKey
+    pub use_bitrate_key: bool,
+
+    // The maximum number of frames between keyframes
+    pub keyframe_interval: u32,
+}
+
+#[cfg(target_os = "macos")]
+impl VideoEncoder for SwiftVideoEncoder {
+
+    fn encode(&self, frames: &[PathBuf], target_mp4: &Path) -> Result<(), ScreentapError> {
+
+        if frames.is_empty() {
+            return Err(ScreentapError::Encode("No frames to encode".to_string()));
+        }
+
+        let frame_paths = frames.iter()
+            .map(|frame| match frame.to_str() {
+                Some(frame_path) if !frame_path.contains('\n') => Ok(frame_path),
+                _ => Err(ScreentapError::Encode(format!("Unsupported frame path {}", frame.display()))),
+            })
+            .collect::<Result<Vec<&str>, ScreentapError>>()?;
+
+        screen_ocr_swift_rs::write_images_to_mp4(
+            &frame_paths,
+            target_mp4.to_str().unwrap_or_default(),
+            self.use_bitrate_key,
+            self.keyframe_interval
+        );
+
+        // The swift encoder doesn't report errors, so check that it actually wrote something
+        if !target_mp4.exists() {
+            return Err(ScreentapError::Encode(format!("Failed to write mp4 file {}", target_mp4.display())));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub struct SwiftFrameExtractor {}
+
+#[cfg(target_os = "macos")]
+impl FrameExtractor for SwiftFrameExtractor {
+    fn extract_frame(&self, mp4_path: &Path, frame_id: i32) -> Result<Vec<u8>, ScreentapError> {
+        screen_ocr_swift_rs::extract_frame_from_mp4(mp4_path.to_str().unwrap_or_default(), frame_id as isize)
+            .map(|frame_data| frame_data.to_vec())
+            .ok_or_else(|| ScreentapError::Encode(format!("Failed to extract frame {} from {}", frame_id, mp4_path.display())))
+    }
+
+    fn extract_frames(&self, mp4_path: &Path, frame_ids: &[i32]) -> Result<Vec<Vec<u8>>, ScreentapError> {
+        let frame_ids_isize: Vec<isize> = frame_ids.iter().map(|&frame_id| frame_id as isize).collect();
+        let frames = screen_ocr_swift_rs::extract_frames_from_mp4(mp4_path.to_str().unwrap_or_default(), &frame_ids_isize)
+            .ok_or_else(|| ScreentapError::Encode(format!("Failed to extract frames from {}", mp4_path.display())))?;
+
+        frames.into_iter()
+            .zip(frame_ids)
+            .map(|(frame_data, frame_id)| frame_data.ok_or_else(|| {
+                ScreentapError::Encode(format!("Failed to extract frame {} from {}", frame_id, mp4_path.display()))
+            }))
+            .collect()
+    }
+
+    fn count_frames(&self, mp4_path: &Path) -> Result<usize, ScreentapError> {
+        screen_ocr_swift_rs::get_mp4_frame_count(mp4_path.to_str().unwrap_or_default())
+            .ok_or_else(|| ScreentapError::Encode(format!("Failed to count frames in {}", mp4_path.display())))
+    }
+}
+
+
+/**
+ * Encoder that runs an ffmpeg subprocess with libx264.  Works on any platform with ffmpeg installed.
+ */
+pub struct FfmpegVideoEncoder {
+    ffmpeg_path: PathBuf,
+    keyframe_interval: u32,
+}
+
+impl FfmpegVideoEncoder {
+    pub fn new(ffmpeg_path: &str, keyframe_interval: u32) -> Self {
+        Self { ffmpeg_path: PathBuf::from(ffmpeg_path), keyframe_interval }
+    }
+}
+
+impl VideoEncoder for FfmpegVideoEncoder {
+
+    fn encode(&self, frames: &[PathBuf], target_mp4: &Path) -> Result<(), ScreentapError> {
+
+        let first_frame = frames.first()
+            .ok_or_else(|| ScreentapError::Encode("No frames to encode".to_string()))?;
+
+        // Frames can differ in size (eg active window captures or several displays), so every
+        // frame is scaled to fit the size of the first one, keeping its aspect ratio, and padded
+        // with black rather than stretched.  libx264 with yuv420p needs even dimensions.
+        let (width, height) = image::image_dimensions(first_frame)?;
+        let width = (width & !1).max(2);
+        let height = (height & !1).max(2);
+
+        // ffmpeg reads an image sequence from a numbered pattern, so link the frames into
+        // a temp dir in the order they should appear in the mp4.  The pattern has a single
+        // extension, so screenshots in other formats are converted to PNG.
+        let sequence_dir = tempfile::tempdir()?;
+        for (frame_id, frame) in frames.iter().enumerate() {
+            let sequence_path = sequence_dir.path().join(format!("{:06}.png", frame_id));
+            match FrameFormat::from_path(frame) {
+                FrameFormat::Png => link_or_copy(frame, &sequence_path)?,
+                _ => image::open(frame)?.save_with_format(&sequence_path, image::ImageFormat::Png)?,
+            }
+        }
+
+        let output = Command::new(&self.ffmpeg_path)
+            .arg("-y")
+            .args(["-loglevel", "error"])
+            .args(["-framerate", "1"])
+            .arg("-i").arg(sequence_dir.path().join("%06d.png"))
+            .arg("-vf").arg(format!(
+                "scale={w}:{h}:force_original_aspect_ratio=decrease,pad={w}:{h}:(ow-iw)/2:(oh-ih)/2:black,setsar=1",
+                w = width, h = height
+            ))
+            .args(["-c:v", "libx264"])
+            .arg("-g").arg(self.keyframe_interval.to_string())
+            .args(["-pix_fmt", "yuv420p"])
+            .arg(target_mp4)
+            .output()
+            .map_err(|e| ScreentapError::Encode(format!("Failed to run {}: {}", self.ffmpeg_path.display(), e)))?;
+
+        if !output.status.success() {
+            return Err(ScreentapError::Encode(format!(
+                "ffmpeg failed to write {}: {}",
+                target_mp4.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+pub struct FfmpegFrameExtractor {
+    ffmpeg_path: PathBuf,
+}
+
+impl FfmpegFrameExtractor {
+    pub fn new(ffmpeg_path: &str) -> Self {
+        Self { ffmpeg_path: PathBuf::from(ffmpeg_path) }
+    }
+}
+
+impl FrameExtractor for FfmpegFrameExtractor {
+
+    fn extract_frame(&self, mp4_path: &Path, frame_id: i32) -> Result<Vec<u8>, ScreentapError> {
+
+        // Frame N is at N seconds, since both backends write one frame per second.  Seeking
+        // on the input jumps to the keyframe before it, so only the frames after that keyframe
+        // are decoded rather than the whole video.
+        let output = Command::new(&self.ffmpeg_path)
+            .args(["-loglevel", "error"])
+            .arg("-ss").arg(frame_id.to_string())
+            .arg("-i").arg(mp4_path)
+            .args(["-frames:v", "1"])
+            .args(["-f", "image2pipe"])
+            .args(["-c:v", "png"])
+            .arg("-")
+            .output()
+            .map_err(|e| ScreentapError::Encode(format!("Failed to run {}: {}", self.ffmpeg_path.display(), e)))?;
+
+        if !output.status.success() || output.stdout.is_empty() {
+            return Err(ScreentapError::Encode(format!(
+                "ffmpeg failed to extract frame {} from {}: {}",
+                frame_id,
+                mp4_path.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        Ok(output.stdout)
+    }
+
+    fn extract_frames(&self, mp4_path: &Path, frame_ids: &[i32]) -> Result<Vec<Vec<u8>>, ScreentapError> {
+
+        if frame_ids.is_empty() {
+            return Ok(vec![]);
+        }
+
+        // Select every requested frame in one decoding pass.  ffmpeg writes the selected frames
+        // in the order they appear in the video, so sort and dedupe the ids to match.
+        let mut sorted_frame_ids = frame_ids.to_vec();
+        sorted_frame_ids.sort_unstable();
+        sorted_frame_ids.dedup();
+
+        let select_expr = sorted_frame_ids.iter()
+            .map(|frame_id| format!("eq(n\\,{})", frame_id))
+            .collect::<Vec<_>>()
+            .join("+");
+
+        let output = Command::new(&self.ffmpeg_path)
+            .args(["-loglevel", "error"])
+            .arg("-i").arg(mp4_path)
+            .arg("-vf").arg(format!("select={}", select_expr))
+            .args(["-vsync", "0"])
+            .args(["-f", "image2pipe"])
+            .args(["-c:v", "png"])
+            .arg("-")
+            .output()
+            .map_err(|e| ScreentapError::Encode(format!("Failed to run {}: {}", self.ffmpeg_path.display(), e)))?;
+
+        let frames = split_png_stream(&output.stdout);
+        if !output.status.success() || frames.len() != sorted_frame_ids.len() {
+            return Err(ScreentapError::Encode(format!(
+                "ffmpeg extracted {} of {} frames from {}: {}",
+                frames.len(),
+                sorted_frame_ids.len(),
+                mp4_path.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        let frames_by_id: HashMap<i32, Vec<u8>> = sorted_frame_ids.into_iter().zip(frames).collect();
+        Ok(frame_ids.iter().map(|frame_id| frames_by_id[frame_id].clone()).collect())
+    }
+
+    fn count_frames(&self, mp4_path: &Path) -> Result<usize, ScreentapError> {
+
+        // Decode every frame to a null muxer, and read the final frame count from the progress report
+        let output = Command::new(&self.ffmpeg_path)
+            .args(["-loglevel", "error"])
+            .arg("-nostats")
+            .args(["-progress", "pipe:1"])
+            .arg("-i").arg(mp4_path)
+            .args(["-map", "0:v:0"])
+            .args(["-f", "null"])
+            .arg("-")
+            .output()
+            .map_err(|e| ScreentapError::Encode(format!("Failed to run {}: {}", self.ffmpeg_path.display(), e)))?;
+
+        if !output.status.success() {
+            return Err(ScreentapError::Encode(format!(
+                "ffmpeg failed to decode {}: {}",
+                mp4_path.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| line.strip_prefix("frame="))
+            .last()
+            .and_then(|frame_count| frame_count.trim().parse().ok())
+            .ok_or_else(|| ScreentapError::Encode(format!("ffmpeg did not report a frame count for {}", mp4_path.display())))
+    }
+}
+
+/**
+ * Split the output of ffmpeg's image2pipe muxer, which is a series of PNG files back to
+ * back, into the individual PNG files.  Walks the chunks of each file up to its IEND chunk.
+ */
+fn split_png_stream(data: &[u8]) -> Vec<Vec<u8>> {
+
+    let mut frames = vec![];
+    let mut frame_start = 0;
+
+    while data.len() >= frame_start + PNG_SIGNATURE.len() && data[frame_start..].starts_with(&PNG_SIGNATURE) {
+
+        let mut pos = frame_start + PNG_SIGNATURE.len();
+        loop {
+            // Each chunk is a 4 byte length, a 4 byte type, the data and a 4 byte CRC
+            if data.len() < pos + 8 {
+                return frames;
+            }
+            let length = u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+            let chunk_type = &data[pos + 4..pos + 8];
+            pos += 12 + length;
+            if pos > data.len() {
+                return frames;
+            }
+            if chunk_type == b"IEND" {
+                break;
+            }
+        }
+
+        frames.push(data[frame_start..pos].to_vec());
+        frame_start = pos;
+    }
+
+    frames
+}
+
+/**
+ * Symlink where possible to avoid copying hundreds of screenshots, otherwise copy
+ */
+fn link_or_copy(source: &Path, target: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        let source = std::fs::canonicalize(source)?;
+        std::os::unix::fs::symlink(source, target)
+    }
+    #[cfg(not(unix))]
+    {
+        std::fs::copy(source, target).map(|_| ())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use super::{split_png_stream, FfmpegFrameExtractor, FfmpegVideoEncoder, FrameExtractor, VideoEncoder, DEFAULT_FFMPEG_PATH, DEFAULT_KEYFRAME_INTERVAL};
+    use image::{ImageBuffer, Rgba};
+    use tempfile::tempdir;
+
+    fn ffmpeg_available() -> bool {
+        std::process::Command::new(DEFAULT_FFMPEG_PATH).arg("-version").output().is_ok()
+    }
+
+    #[test]
+    fn test_ffmpeg_encode_and_extract_frames() {
+
+        if !ffmpeg_available() {
+            println!("ffmpeg is not installed, skipping test_ffmpeg_encode_and_extract_frames");
+            return;
+        }
+
+        let tmp_dir = tempdir().unwrap();
+
+        // Each frame is a solid color, so the extracted frames can be told apart
+        let colors = [[255, 0, 0, 255], [0, 255, 0, 255], [0, 0, 255, 255]];
+        let frames: Vec<_> = colors.iter().enumerate().map(|(i, color)| {
+            let frame_path = tmp_dir.path().join(format!("{}.png", i));
+            ImageBuffer::from_pixel(64, 48, Rgba(*color)).save(&frame_path).unwrap();
+            frame_path
+        }).collect();
+
+        let target_mp4 = tmp_dir.path().join("test.mp4");
+        FfmpegVideoEncoder::new(DEFAULT_FFMPEG_PATH, DEFAULT_KEYFRAME_INTERVAL).encode(&frames, &target_mp4).unwrap();
+        assert!(std::fs::metadata(&target_mp4).unwrap().len() > 0);
+
+        let extractor = FfmpegFrameExtractor::new(DEFAULT_FFMPEG_PATH);
+        for (frame_id, color) in colors.iter().enumerate() {
+            let png_data = extractor.extract_frame(&target_mp4, frame_id as i32).unwrap();
+            let img = image::load_from_memory(&png_data).unwrap().to_rgba8();
+            assert_eq!(img.dimensions(), (64, 48));
+
+            // Compression is lossy, so only check that the dominant channel is right
+            let pixel = img.get_pixel(32, 24);
+            let dominant_channel = (0..3).max_by_key(|&channel| pixel[channel]).unwrap();
+            let expected_channel = (0..3).max_by_key(|&channel| color[channel]).unwrap();
+            assert_eq!(dominant_channel, expected_channel, "Wrong frame for frame_id {}", frame_id);
+        }
+
+        assert_eq!(extractor.count_frames(&target_mp4).unwrap(), colors.len());
+
+        // Batch extraction returns the frames in the requested order, including repeats
+        let batch = extractor.extract_frames(&target_mp4, &[2, 0, 2]).unwrap();
+        assert_eq!(batch.len(), 3);
+        assert_eq!(batch[0], batch[2]);
+        assert_eq!(batch[1], extractor.extract_frame(&target_mp4, 0).unwrap());
+
+        // Asking for a frame past the end is an error
+        assert!(extractor.extract_frame(&target_mp4, 10).is_err());
+        assert!(extractor.extract_frames(&target_mp4, &[0, 10]).is_err());
+    }
+
+    #[test]
+    fn test_ffmpeg_encode_pads_frames_of_other_sizes() {
+
+        if !ffmpeg_available() {
+            println!("ffmpeg is not installed, skipping test_ffmpeg_encode_pads_frames_of_other_sizes");
+            return;
+        }
+
+        let tmp_dir = tempdir().unwrap();
+
+        // A wide white frame, then a narrow white frame that's fit into it with black bars
+        let frames: Vec<_> = [(128, 48), (32, 48)].iter().enumerate().map(|(i, (width, height))| {
+            let frame_path = tmp_dir.path().join(format!("{}.png", i));
+            ImageBuffer::from_pixel(*width, *height, Rgba([255u8, 255, 255, 255])).save(&frame_path).unwrap();
+            frame_path
+        }).collect();
+
+        let target_mp4 = tmp_dir.path().join("test.mp4");
+        FfmpegVideoEncoder::new(DEFAULT_FFMPEG_PATH, DEFAULT_KEYFRAME_INTERVAL).encode(&frames, &target_mp4).unwrap();
+
+        let png_data = FfmpegFrameExtractor::new(DEFAULT_FFMPEG_PATH).extract_frame(&target_mp4, 1).unwrap();
+        let img = image::load_from_memory(&png_data).unwrap().to_rgba8();
+        assert_eq!(img.dimensions(), (128, 48));
+        assert!(img.get_pixel(64, 24)[0] > 200);
+        assert!(img.get_pixel(8, 24)[0] < 50);
+        assert!(img.get_pixel(120, 24)[0] < 50);
+    }
+
+    #[test]
+    fn test_split_png_stream() {
+
+        let mut png_data = vec![];
+        image::DynamicImage::new_rgba8(4, 4)
+            .write_to(&mut std::io::Cursor::new(&mut png_data), image::ImageOutputFormat::Png)
+            .unwrap();
+
+        let mut stream = png_data.clone();
+        stream.extend_from_slice(&png_data);
+        assert_eq!(split_png_stream(&stream), vec![png_data.clone(), png_data.clone()]);
+
+        // A truncated trailing frame is dropped
+        stream.extend_from_slice(&png_data[..png_data.len() - 1]);
+        assert_eq!(split_png_stream(&stream).len(), 2);
+    }
+
+}